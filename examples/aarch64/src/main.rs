@@ -8,6 +8,7 @@ mod hal;
 mod logger;
 #[cfg(platform = "qemu")]
 mod pl011;
+mod rng;
 #[cfg(platform = "qemu")]
 use pl011 as uart;
 #[cfg(platform = "crosvm")]
@@ -24,6 +25,7 @@ use core::{
 use fdt::{node::FdtNode, standard_nodes::Compatible, Fdt};
 use hal::HalImpl;
 use log::{debug, error, info, trace, warn, LevelFilter};
+use rng::VirtIORng;
 use smccc::{psci::system_off, Hvc};
 use virtio_drivers::{
     device::{
@@ -38,7 +40,7 @@ use virtio_drivers::{
     transport::{
         mmio::{MmioTransport, VirtIOHeader},
         pci::{
-            bus::{BarInfo, Cam, Command, DeviceFunction, MemoryBarType, PciRoot},
+            bus::{BarAllocator, BarInfo, Cam, DeviceFunction, PciRoot},
             virtio_device_type, PciTransport,
         },
         DeviceType, Transport,
@@ -140,6 +142,7 @@ fn virtio_device(transport: impl Transport) {
         DeviceType::GPU => virtio_gpu(transport),
         DeviceType::Network => virtio_net(transport),
         DeviceType::Console => virtio_console(transport),
+        DeviceType::EntropySource => virtio_rng(transport),
         DeviceType::Socket => match virtio_socket(transport) {
             Ok(()) => info!("virtio-socket test finished successfully"),
             Err(e) => error!("virtio-socket test finished with error '{e:?}'"),
@@ -210,7 +213,7 @@ fn virtio_net<T: Transport>(transport: T) {
 fn virtio_console<T: Transport>(transport: T) {
     let mut console =
         VirtIOConsole::<HalImpl, T>::new(transport).expect("Failed to create console driver");
-    let info = console.info();
+    let info = console.info().expect("Failed to read console info");
     info!("VirtIO console {}x{}", info.rows, info.columns);
     for &c in b"Hello world on console!\n" {
         console.send(c).expect("Failed to send character");
@@ -220,6 +223,14 @@ fn virtio_console<T: Transport>(transport: T) {
     info!("virtio-console test finished");
 }
 
+fn virtio_rng<T: Transport>(transport: T) {
+    let mut rng = VirtIORng::<HalImpl, T>::new(transport).expect("Failed to create rng driver");
+    let mut buf = [0; 16];
+    rng.fill(&mut buf).expect("Failed to get random bytes");
+    info!("Random bytes: {:02x?}", buf);
+    info!("virtio-rng test finished");
+}
+
 fn virtio_socket<T: Transport>(transport: T) -> virtio_drivers::Result<()> {
     let mut socket = VsockConnectionManager::new(
         VirtIOSocket::<HalImpl, T>::new(transport).expect("Failed to create socket driver"),
@@ -285,7 +296,7 @@ impl From<u8> for PciRangeType {
 
 fn enumerate_pci(pci_node: FdtNode, cam: Cam) {
     let reg = pci_node.reg().expect("PCI node missing reg property.");
-    let mut allocator = PciMemory32Allocator::for_pci_ranges(&pci_node);
+    let mut allocator = bar_allocator_for_pci_ranges(&pci_node);
 
     for region in reg {
         info!(
@@ -304,7 +315,14 @@ fn enumerate_pci(pci_node: FdtNode, cam: Cam) {
             );
             if let Some(virtio_type) = virtio_device_type(&info) {
                 info!("  VirtIO {:?}", virtio_type);
-                allocate_bars(&mut pci_root, device_function, &mut allocator);
+                pci_root
+                    .allocate_bars(device_function, &mut allocator)
+                    .expect("Failed to allocate BARs");
+                let (status, command) = pci_root.get_status_command(device_function);
+                debug!(
+                    "Allocated BARs and enabled device, status {:?} command {:?}",
+                    status, command
+                );
                 dump_bar_contents(&mut pci_root, device_function, 4);
                 let mut transport =
                     PciTransport::new::<HalImpl>(&mut pci_root, device_function).unwrap();
@@ -319,71 +337,50 @@ fn enumerate_pci(pci_node: FdtNode, cam: Cam) {
     }
 }
 
-/// Allocates 32-bit memory addresses for PCI BARs.
-struct PciMemory32Allocator {
-    start: u32,
-    end: u32,
-}
-
-impl PciMemory32Allocator {
-    /// Creates a new allocator based on the ranges property of the given PCI node.
-    pub fn for_pci_ranges(pci_node: &FdtNode) -> Self {
-        let ranges = pci_node
-            .property("ranges")
-            .expect("PCI node missing ranges property.");
-        let mut memory_32_address = 0;
-        let mut memory_32_size = 0;
-        for i in 0..ranges.value.len() / 28 {
-            let range = &ranges.value[i * 28..(i + 1) * 28];
-            let prefetchable = range[0] & 0x80 != 0;
-            let range_type = PciRangeType::from(range[0] & 0x3);
-            let bus_address = u64::from_be_bytes(range[4..12].try_into().unwrap());
-            let cpu_physical = u64::from_be_bytes(range[12..20].try_into().unwrap());
-            let size = u64::from_be_bytes(range[20..28].try_into().unwrap());
-            info!(
-                "range: {:?} {}prefetchable bus address: {:#018x} host physical address: {:#018x} size: {:#018x}",
-                range_type,
-                if prefetchable { "" } else { "non-" },
-                bus_address,
-                cpu_physical,
-                size,
-            );
-            // Use the largest range within the 32-bit address space for 32-bit memory, even if it
-            // is marked as a 64-bit range. This is necessary because crosvm doesn't currently
-            // provide any 32-bit ranges.
-            if !prefetchable
-                && matches!(range_type, PciRangeType::Memory32 | PciRangeType::Memory64)
-                && size > memory_32_size.into()
-                && bus_address + size < u32::MAX.into()
-            {
-                assert_eq!(bus_address, cpu_physical);
-                memory_32_address = u32::try_from(cpu_physical).unwrap();
-                memory_32_size = u32::try_from(size).unwrap();
-            }
-        }
-        if memory_32_size == 0 {
-            panic!("No 32-bit PCI memory region found.");
-        }
-        Self {
-            start: memory_32_address,
-            end: memory_32_address + memory_32_size,
+/// Creates a BAR allocator based on the ranges property of the given PCI node.
+fn bar_allocator_for_pci_ranges(pci_node: &FdtNode) -> BarAllocator {
+    let ranges = pci_node
+        .property("ranges")
+        .expect("PCI node missing ranges property.");
+    let mut memory_32_address = 0;
+    let mut memory_32_size = 0;
+    for i in 0..ranges.value.len() / 28 {
+        let range = &ranges.value[i * 28..(i + 1) * 28];
+        let prefetchable = range[0] & 0x80 != 0;
+        let range_type = PciRangeType::from(range[0] & 0x3);
+        let bus_address = u64::from_be_bytes(range[4..12].try_into().unwrap());
+        let cpu_physical = u64::from_be_bytes(range[12..20].try_into().unwrap());
+        let size = u64::from_be_bytes(range[20..28].try_into().unwrap());
+        info!(
+            "range: {:?} {}prefetchable bus address: {:#018x} host physical address: {:#018x} size: {:#018x}",
+            range_type,
+            if prefetchable { "" } else { "non-" },
+            bus_address,
+            cpu_physical,
+            size,
+        );
+        // Use the largest range within the 32-bit address space for 32-bit memory, even if it
+        // is marked as a 64-bit range. This is necessary because crosvm doesn't currently
+        // provide any 32-bit ranges.
+        if !prefetchable
+            && matches!(range_type, PciRangeType::Memory32 | PciRangeType::Memory64)
+            && size > memory_32_size.into()
+            && bus_address + size < u32::MAX.into()
+        {
+            assert_eq!(bus_address, cpu_physical);
+            memory_32_address = u32::try_from(cpu_physical).unwrap();
+            memory_32_size = u32::try_from(size).unwrap();
         }
     }
-
-    /// Allocates a 32-bit memory address region for a PCI BAR of the given power-of-2 size.
-    ///
-    /// It will have alignment matching the size. The size must be a power of 2.
-    pub fn allocate_memory_32(&mut self, size: u32) -> u32 {
-        assert!(size.is_power_of_two());
-        let allocated_address = align_up(self.start, size);
-        assert!(allocated_address + size <= self.end);
-        self.start = allocated_address + size;
-        allocated_address
+    if memory_32_size == 0 {
+        panic!("No 32-bit PCI memory region found.");
     }
-}
-
-const fn align_up(value: u32, alignment: u32) -> u32 {
-    ((value - 1) | (alignment - 1)) + 1
+    // I/O BARs aren't required for the VirtIO driver, so don't give the allocator an I/O window.
+    BarAllocator::new(
+        memory_32_address..memory_32_address + memory_32_size,
+        0..0,
+        0..0,
+    )
 }
 
 fn dump_bar_contents(root: &mut PciRoot, device_function: DeviceFunction, bar_index: u8) {
@@ -405,59 +402,6 @@ fn dump_bar_contents(root: &mut PciRoot, device_function: DeviceFunction, bar_in
     trace!("End of dump");
 }
 
-/// Allocates appropriately-sized memory regions and assigns them to the device's BARs.
-fn allocate_bars(
-    root: &mut PciRoot,
-    device_function: DeviceFunction,
-    allocator: &mut PciMemory32Allocator,
-) {
-    let mut bar_index = 0;
-    while bar_index < 6 {
-        let info = root.bar_info(device_function, bar_index).unwrap();
-        debug!("BAR {}: {}", bar_index, info);
-        // Ignore I/O bars, as they aren't required for the VirtIO driver.
-        if let BarInfo::Memory {
-            address_type, size, ..
-        } = info
-        {
-            match address_type {
-                MemoryBarType::Width32 => {
-                    if size > 0 {
-                        let address = allocator.allocate_memory_32(size);
-                        debug!("Allocated address {:#010x}", address);
-                        root.set_bar_32(device_function, bar_index, address);
-                    }
-                }
-                MemoryBarType::Width64 => {
-                    if size > 0 {
-                        let address = allocator.allocate_memory_32(size);
-                        debug!("Allocated address {:#010x}", address);
-                        root.set_bar_64(device_function, bar_index, address.into());
-                    }
-                }
-
-                _ => panic!("Memory BAR address type {:?} not supported.", address_type),
-            }
-        }
-
-        bar_index += 1;
-        if info.takes_two_entries() {
-            bar_index += 1;
-        }
-    }
-
-    // Enable the device to use its BARs.
-    root.set_command(
-        device_function,
-        Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER,
-    );
-    let (status, command) = root.get_status_command(device_function);
-    debug!(
-        "Allocated BARs and enabled device, status {:?} command {:?}",
-        status, command
-    );
-}
-
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     error!("{}", info);
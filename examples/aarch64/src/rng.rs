@@ -0,0 +1,82 @@
+//! Driver for a VirtIO entropy device.
+//!
+//! `virtio-drivers` doesn't have a driver for this device type, so this is implemented here using
+//! only the public API of the crate, as an example of a driver outside the crate.
+
+use bitflags::bitflags;
+use virtio_drivers::{
+    queue::{QueueOptions, VirtQueue},
+    transport::Transport,
+    Error, Hal, Result,
+};
+
+const QUEUE_REQUEST: u16 = 0;
+const QUEUE_SIZE: usize = 4;
+const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
+    .union(Features::RING_PACKED)
+    .union(Features::VERSION_1);
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct Features: u64 {
+        // The entropy device has no device-specific features, only the device independent ones.
+        const RING_EVENT_IDX        = 1 << 29;
+        const VERSION_1             = 1 << 32;
+        const RING_PACKED           = 1 << 34;
+    }
+}
+
+/// Driver for a VirtIO entropy device, which provides random bytes from the host.
+pub struct VirtIORng<H: Hal, T: Transport> {
+    transport: T,
+    request_queue: VirtQueue<H, QUEUE_SIZE>,
+}
+
+impl<H: Hal, T: Transport> VirtIORng<H, T> {
+    /// Creates a new VirtIO entropy driver.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+        let options = QueueOptions::from_features(negotiated_features.bits());
+        let request_queue = VirtQueue::new(&mut transport, QUEUE_REQUEST, options)?;
+        transport.finish_init();
+        Ok(Self {
+            transport,
+            request_queue,
+        })
+    }
+
+    /// Fills the given buffer with random bytes from the device, blocking until it is full.
+    pub fn fill(&mut self, buf: &mut [u8]) -> Result {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let len = self.request(&mut buf[filled..])?;
+            if len == 0 {
+                return Err(Error::IoError);
+            }
+            filled += len;
+        }
+        Ok(())
+    }
+
+    /// Asks the device for random bytes to fill the given buffer, and returns how many it wrote.
+    fn request(&mut self, buf: &mut [u8]) -> Result<usize> {
+        // Safe because the buffer isn't accessed until the token is popped below.
+        let token = unsafe { self.request_queue.add(&[], &mut [buf]) }?;
+        if self.request_queue.should_notify() {
+            self.request_queue.notify(&mut self.transport);
+        }
+        while self.request_queue.peek_used() != Some(token) {
+            self.request_queue.wait_used();
+        }
+        // Safe because this is the same buffer as was passed to `add` with the token.
+        let len = unsafe { self.request_queue.pop_used(token, &[], &mut [buf]) }?;
+        Ok(len as usize)
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIORng<H, T> {
+    fn drop(&mut self) {
+        // Stop the device from accessing the queue before its memory is freed.
+        self.transport.queue_unset(QUEUE_REQUEST);
+    }
+}
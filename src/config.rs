@@ -0,0 +1,80 @@
+//! Typed access to fields of the device-specific configuration space through a [`Transport`].
+
+use crate::transport::Transport;
+use crate::volatile::{ReadOnly, Volatile, WriteOnly};
+use crate::Result;
+use zerocopy::{AsBytes, FromBytes};
+
+/// A field of a configuration space struct which may be read through a transport.
+///
+/// This is implemented for `Option`s of the register types so that [`read_config!`] can infer the
+/// type of the value from the type of the field.
+pub(crate) trait ConfigReadable<T> {
+    /// Reads the value of the field at the given offset within the configuration space.
+    fn read_config<Tr: Transport>(self, transport: &Tr, offset: usize) -> Result<T>;
+}
+
+impl<T: AsBytes + Copy + FromBytes> ConfigReadable<T> for Option<ReadOnly<T>> {
+    fn read_config<Tr: Transport>(self, transport: &Tr, offset: usize) -> Result<T> {
+        transport.read_config_space(offset)
+    }
+}
+
+impl<T: AsBytes + Copy + FromBytes> ConfigReadable<T> for Option<Volatile<T>> {
+    fn read_config<Tr: Transport>(self, transport: &Tr, offset: usize) -> Result<T> {
+        transport.read_config_space(offset)
+    }
+}
+
+/// A field of a configuration space struct which may be written through a transport.
+pub(crate) trait ConfigWritable<T> {
+    /// Writes the given value to the field at the given offset within the configuration space.
+    fn write_config<Tr: Transport>(self, transport: &mut Tr, offset: usize, value: T) -> Result;
+}
+
+impl<T: AsBytes + Copy> ConfigWritable<T> for Option<WriteOnly<T>> {
+    fn write_config<Tr: Transport>(self, transport: &mut Tr, offset: usize, value: T) -> Result {
+        transport.write_config_space(offset, value)
+    }
+}
+
+impl<T: AsBytes + Copy> ConfigWritable<T> for Option<Volatile<T>> {
+    fn write_config<Tr: Transport>(self, transport: &mut Tr, offset: usize, value: T) -> Result {
+        transport.write_config_space(offset, value)
+    }
+}
+
+/// Reads the given field of the given configuration space struct through the given transport.
+///
+/// The field must be a [`ReadOnly`] or [`Volatile`] register.
+macro_rules! read_config {
+    ($transport:expr, $struct:ty, $field:ident) => {{
+        let field = Option::<$struct>::None.map(|config| config.$field);
+        $crate::config::ConfigReadable::read_config(
+            field,
+            &$transport,
+            core::mem::offset_of!($struct, $field),
+        )
+    }};
+}
+
+/// Writes the given value to the given field of the given configuration space struct through the
+/// given transport.
+///
+/// The field must be a [`WriteOnly`] or [`Volatile`] register.
+#[cfg(feature = "alloc")]
+macro_rules! write_config {
+    ($transport:expr, $struct:ty, $field:ident, $value:expr) => {{
+        let field = Option::<$struct>::None.map(|config| config.$field);
+        $crate::config::ConfigWritable::write_config(
+            field,
+            &mut $transport,
+            core::mem::offset_of!($struct, $field),
+            $value,
+        )
+    }};
+}
+
+pub(crate) use read_config;
+#[cfg(feature = "alloc")]
+pub(crate) use write_config;
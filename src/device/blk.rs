@@ -1,9 +1,12 @@
 //! Driver for VirtIO block devices.
 
+use crate::config::read_config;
 use crate::hal::Hal;
-use crate::queue::VirtQueue;
+use crate::queue::{InterruptModerator, QueueOptions, UsedWaker, VirtQueue};
+#[cfg(feature = "stats")]
+use crate::queue::{QueueStats, QueueTracer};
 use crate::transport::Transport;
-use crate::volatile::{volread, Volatile};
+use crate::volatile::Volatile;
 use crate::{Error, Result};
 use bitflags::bitflags;
 use log::info;
@@ -14,7 +17,10 @@ const QUEUE_SIZE: u16 = 16;
 const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
     .union(BlkFeature::FLUSH)
     .union(BlkFeature::RING_INDIRECT_DESC)
-    .union(BlkFeature::RING_EVENT_IDX);
+    .union(BlkFeature::RING_EVENT_IDX)
+    .union(BlkFeature::RING_PACKED)
+    .union(BlkFeature::IN_ORDER)
+    .union(BlkFeature::NOTIFICATION_DATA);
 
 /// Driver for a VirtIO block device.
 ///
@@ -47,28 +53,35 @@ pub struct VirtIOBlk<H: Hal, T: Transport> {
     queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
     capacity: u64,
     negotiated_features: BlkFeature,
+    moderator: Option<InterruptModerator>,
 }
 
 impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     /// Create a new VirtIO-Blk driver.
-    pub fn new(mut transport: T) -> Result<Self> {
+    ///
+    /// The request queue will have up to 16 descriptors, or fewer if the device doesn't support that
+    /// many.
+    pub fn new(transport: T) -> Result<Self> {
+        Self::with_queue_size(transport, QUEUE_SIZE)
+    }
+
+    /// Create a new VirtIO-Blk driver whose request queue has up to `queue_size` descriptors.
+    ///
+    /// The queue size is limited to the maximum supported by the device, and rounded down to a power
+    /// of 2. A bigger queue allows more requests to be outstanding at once with the non-blocking
+    /// API, but a queue of more than 16 descriptors requires the `alloc` feature.
+    pub fn with_queue_size(mut transport: T, queue_size: u16) -> Result<Self> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
         // Read configuration space.
-        let config = transport.config_space::<BlkConfig>()?;
-        info!("config: {:?}", config);
-        // Safe because config is a valid pointer to the device configuration space.
-        let capacity = unsafe {
-            volread!(config, capacity_low) as u64 | (volread!(config, capacity_high) as u64) << 32
-        };
+        let capacity = transport.read_config(|| {
+            Ok(read_config!(transport, BlkConfig, capacity_low)? as u64
+                | (read_config!(transport, BlkConfig, capacity_high)? as u64) << 32)
+        })?;
         info!("found a block device of size {}KB", capacity / 2);
 
-        let queue = VirtQueue::new(
-            &mut transport,
-            QUEUE,
-            negotiated_features.contains(BlkFeature::RING_INDIRECT_DESC),
-            negotiated_features.contains(BlkFeature::RING_EVENT_IDX),
-        )?;
+        let options = QueueOptions::from_features(negotiated_features.bits());
+        let queue = VirtQueue::with_size(&mut transport, QUEUE, queue_size, options)?;
         transport.finish_init();
 
         Ok(VirtIOBlk {
@@ -76,6 +89,7 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
             queue,
             capacity,
             negotiated_features,
+            moderator: None,
         })
     }
 
@@ -91,9 +105,29 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
 
     /// Acknowledges a pending interrupt, if any.
     ///
-    /// Returns true if there was an interrupt to acknowledge.
+    /// Returns true if there was an interrupt to acknowledge. If interrupt moderation is enabled,
+    /// this also switches the queue to polling mode until [`poll_done`](Self::poll_done) switches
+    /// it back.
     pub fn ack_interrupt(&mut self) -> bool {
-        self.transport.ack_interrupt()
+        let interrupt = self.transport.ack_interrupt();
+        if interrupt {
+            if let Some(moderator) = &mut self.moderator {
+                moderator.interrupt(&mut self.queue);
+            }
+        }
+        interrupt
+    }
+
+    /// Returns the counters of the device's queue.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    /// Sets a tracer to be called for each operation on the device's queue, or removes it.
+    #[cfg(feature = "stats")]
+    pub fn set_tracer(&mut self, tracer: Option<&'static dyn QueueTracer>) {
+        self.queue.set_tracer(tracer);
     }
 
     /// Enables interrupts from the device.
@@ -106,6 +140,32 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         self.queue.set_dev_notify(false);
     }
 
+    /// Enables adaptive interrupt moderation with the given budget, or disables it with `None`.
+    ///
+    /// While it is enabled, [`ack_interrupt`](Self::ack_interrupt) suppresses further interrupts
+    /// and the driver should then poll for completed requests with [`peek_used`](Self::peek_used),
+    /// completing at most `budget` at a time and reporting how many it completed to
+    /// [`poll_done`](Self::poll_done), until that says to wait for an interrupt again. See
+    /// [`InterruptModerator`] for the policy. Disabling it re-enables interrupts, after which the
+    /// driver should check for requests completed in the meantime.
+    pub fn set_interrupt_moderation(&mut self, budget: Option<usize>) {
+        if let Some(mut moderator) = self.moderator.take() {
+            moderator.reset(&mut self.queue);
+        }
+        self.moderator = budget.map(InterruptModerator::new);
+    }
+
+    /// Records that the driver completed `completed` requests in a round of polling, and returns
+    /// whether it should keep polling rather than waiting for an interrupt.
+    ///
+    /// Always returns false if interrupt moderation isn't enabled.
+    pub fn poll_done(&mut self, completed: usize) -> bool {
+        match &mut self.moderator {
+            Some(moderator) => moderator.poll_done(&mut self.queue, completed),
+            None => false,
+        }
+    }
+
     /// Sends the given request to the device and waits for a response, with no extra data.
     fn request(&mut self, request: BlkReq) -> Result {
         let mut resp = BlkResp::default();
@@ -139,6 +199,57 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         resp.status.into()
     }
 
+    /// Sends the given request to the device and asynchronously waits for a response, including
+    /// the given data.
+    ///
+    /// # Safety
+    ///
+    /// The returned future must be polled to completion once it has been polled.
+    async unsafe fn request_read_async(
+        &mut self,
+        request: BlkReq,
+        data: &mut [u8],
+        waker: &UsedWaker,
+    ) -> Result {
+        let mut resp = BlkResp::default();
+        // Safe because our caller promises to poll the future to completion.
+        unsafe {
+            self.queue.add_notify_pop_async(
+                &[request.as_bytes()],
+                &mut [data, resp.as_bytes_mut()],
+                &mut self.transport,
+                waker,
+            )
+        }
+        .await?;
+        resp.status.into()
+    }
+
+    /// Sends the given request and data to the device and asynchronously waits for a response.
+    ///
+    /// # Safety
+    ///
+    /// The returned future must be polled to completion once it has been polled.
+    async unsafe fn request_write_async(
+        &mut self,
+        request: BlkReq,
+        data: &[u8],
+        waker: &UsedWaker,
+    ) -> Result {
+        let mut resp = BlkResp::default();
+        // Safe because our caller promises to poll the future to completion.
+        unsafe {
+            self.queue.add_notify_pop_async(
+                &[request.as_bytes(), data],
+                &mut [resp.as_bytes_mut()],
+                &mut self.transport,
+                waker,
+            )
+        }
+        .await?;
+        resp.status.into()
+    }
+
     /// Requests the device to flush any pending writes to storage.
     ///
     /// This will be ignored if the device doesn't support the `VIRTIO_BLK_F_FLUSH` feature.
@@ -188,6 +299,47 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         )
     }
 
+    /// Reads one or more blocks into the given buffer asynchronously.
+    ///
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    ///
+    /// The returned future resolves once the read completes or there is an error. As it borrows
+    /// the driver until then, the interrupt handler can't call
+    /// [`ack_interrupt`](Self::ack_interrupt) in the meantime, but should instead call
+    /// [`UsedWaker::wake`] on `waker` to wake it, as for [`VirtQueue::add_notify_pop_async`].
+    ///
+    /// This must not be used while requests submitted with
+    /// [`read_blocks_nb`](Self::read_blocks_nb) or [`write_blocks_nb`](Self::write_blocks_nb) are
+    /// outstanding, as the future only completes once its own request is next to be popped.
+    ///
+    /// # Safety
+    ///
+    /// Once the returned future has been polled, it must be polled until it completes. It must not
+    /// be dropped or leaked (e.g. with [`core::mem::forget`]) before then, as the device may still
+    /// be writing to `buf`.
+    pub async unsafe fn read_blocks_async(
+        &mut self,
+        block_id: usize,
+        buf: &mut [u8],
+        waker: &UsedWaker,
+    ) -> Result {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        // Safe because our caller promises to poll the future to completion.
+        unsafe {
+            self.request_read_async(
+                BlkReq {
+                    type_: ReqType::In,
+                    reserved: 0,
+                    sector: block_id as u64,
+                },
+                buf,
+                waker,
+            )
+            .await
+        }
+    }
+
     /// Submits a request to read one or more blocks, but returns immediately without waiting for
     /// the read to complete.
     ///
@@ -251,6 +403,30 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         req: &mut BlkReq,
         buf: &mut [u8],
         resp: &mut BlkResp,
+    ) -> Result<u16> {
+        let token = self.read_blocks_nb_deferred(block_id, req, buf, resp)?;
+        self.submit_deferred();
+        Ok(token)
+    }
+
+    /// Submits a request to read one or more blocks like [`read_blocks_nb`](Self::read_blocks_nb),
+    /// but doesn't make it available to the device until
+    /// [`submit_deferred`](Self::submit_deferred) is called.
+    ///
+    /// This lets several requests, for example to read ahead, be submitted to the device together
+    /// with a single notification.
+    ///
+    /// # Safety
+    ///
+    /// `req`, `buf` and `resp` are still borrowed by the underlying VirtIO block device even after
+    /// this method returns. Thus, it is the caller's responsibility to guarantee that they are not
+    /// accessed before the request is completed in order to avoid data races.
+    pub unsafe fn read_blocks_nb_deferred(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
     ) -> Result<u16> {
         assert_ne!(buf.len(), 0);
         assert_eq!(buf.len() % SECTOR_SIZE, 0);
@@ -259,13 +435,14 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
             reserved: 0,
             sector: block_id as u64,
         };
-        let token = self
-            .queue
-            .add(&[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])?;
-        if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
-        }
-        Ok(token)
+        self.queue
+            .add_deferred(&[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])
+    }
+
+    /// Makes all requests started by [`read_blocks_nb_deferred`](Self::read_blocks_nb_deferred)
+    /// available to the device, and notifies it at most once.
+    pub fn submit_deferred(&mut self) {
+        self.queue.publish_notify(&mut self.transport);
     }
 
     /// Completes a read operation which was started by `read_blocks_nb`.
@@ -304,6 +481,47 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         )
     }
 
+    /// Writes the contents of the given buffer to a block or blocks asynchronously.
+    ///
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    ///
+    /// The returned future resolves once the write completes or there is an error. As it borrows
+    /// the driver until then, the interrupt handler can't call
+    /// [`ack_interrupt`](Self::ack_interrupt) in the meantime, but should instead call
+    /// [`UsedWaker::wake`] on `waker` to wake it, as for [`VirtQueue::add_notify_pop_async`].
+    ///
+    /// This must not be used while requests submitted with
+    /// [`read_blocks_nb`](Self::read_blocks_nb) or [`write_blocks_nb`](Self::write_blocks_nb) are
+    /// outstanding, as the future only completes once its own request is next to be popped.
+    ///
+    /// # Safety
+    ///
+    /// Once the returned future has been polled, it must be polled until it completes. It must not
+    /// be dropped or leaked (e.g. with [`core::mem::forget`]) before then, as the device may still
+    /// be reading from `buf`.
+    pub async unsafe fn write_blocks_async(
+        &mut self,
+        block_id: usize,
+        buf: &[u8],
+        waker: &UsedWaker,
+    ) -> Result {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        // Safe because our caller promises to poll the future to completion.
+        unsafe {
+            self.request_write_async(
+                BlkReq {
+                    type_: ReqType::Out,
+                    sector: block_id as u64,
+                    ..Default::default()
+                },
+                buf,
+                waker,
+            )
+            .await
+        }
+    }
+
     /// Submits a request to write one or more blocks, but returns immediately without waiting for
     /// the write to complete.
     ///
@@ -345,7 +563,7 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
             .queue
             .add(&[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])?;
         if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
+            self.queue.notify(&mut self.transport);
         }
         Ok(token)
     }
@@ -561,7 +779,14 @@ mod tests {
         },
     };
     use alloc::{sync::Arc, vec};
-    use core::{mem::size_of, ptr::NonNull};
+    use core::{
+        future::Future,
+        mem::size_of,
+        pin::pin,
+        ptr::NonNull,
+        sync::atomic::Ordering,
+        task::{Context, Poll, Waker},
+    };
     use std::{sync::Mutex, thread};
 
     #[test]
@@ -597,6 +822,39 @@ mod tests {
         assert_eq!(blk.readonly(), true);
     }
 
+    #[test]
+    fn queue_size() {
+        let mut config_space = BlkConfig {
+            capacity_low: Volatile::new(66),
+            capacity_high: Volatile::new(0),
+            size_max: Volatile::new(0),
+            seg_max: Volatile::new(0),
+            cylinders: Volatile::new(0),
+            heads: Volatile::new(0),
+            sectors: Volatile::new(0),
+            blk_size: Volatile::new(0),
+            physical_block_exp: Volatile::new(0),
+            alignment_offset: Volatile::new(0),
+            min_io_size: Volatile::new(0),
+            opt_io_size: Volatile::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 256,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let _blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::with_queue_size(transport, 128)
+            .unwrap();
+
+        assert_eq!(state.lock().unwrap().queues[usize::from(QUEUE)].size, 128);
+    }
+
     #[test]
     fn read() {
         let mut config_space = BlkConfig {
@@ -632,22 +890,254 @@ mod tests {
             State::wait_until_queue_notified(&state, QUEUE);
             println!("Transmit queue was notified.");
 
-            state
-                .lock()
-                .unwrap()
-                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+            state.lock().unwrap().read_write_queue(QUEUE, |request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::In,
+                        reserved: 0,
+                        sector: 42
+                    }
+                    .as_bytes()
+                );
+
+                let mut response = vec![0; SECTOR_SIZE];
+                response[0..9].copy_from_slice(b"Test data");
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+
+                response
+            });
+        });
+
+        // Read a block from the device.
+        let mut buffer = [0; 512];
+        blk.read_blocks(42, &mut buffer).unwrap();
+        assert_eq!(&buffer[0..9], b"Test data");
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn read_async() {
+        let mut config_space = BlkConfig {
+            capacity_low: Volatile::new(66),
+            capacity_high: Volatile::new(0),
+            size_max: Volatile::new(0),
+            seg_max: Volatile::new(0),
+            cylinders: Volatile::new(0),
+            heads: Volatile::new(0),
+            sectors: Volatile::new(0),
+            blk_size: Volatile::new(0),
+            physical_block_exp: Volatile::new(0),
+            alignment_offset: Volatile::new(0),
+            min_io_size: Volatile::new(0),
+            opt_io_size: Volatile::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let mut cx = Context::from_waker(Waker::noop());
+        let used_waker = UsedWaker::new();
+
+        // Start reading a block from the device.
+        let mut buffer = [0; 512];
+        {
+            let mut read = pin!(unsafe { blk.read_blocks_async(42, &mut buffer, &used_waker) });
+            assert_eq!(read.as_mut().poll(&mut cx), Poll::Pending);
+            assert!(state.lock().unwrap().queues[usize::from(QUEUE)]
+                .notified
+                .swap(false, Ordering::SeqCst));
+
+            // Simulate the device handling the request.
+            state.lock().unwrap().read_write_queue(QUEUE, |request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::In,
+                        reserved: 0,
+                        sector: 42
+                    }
+                    .as_bytes()
+                );
+
+                let mut response = vec![0; SECTOR_SIZE];
+                response[0..9].copy_from_slice(b"Test data");
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+
+                response
+            });
+
+            // The interrupt handler wakes the task, which then polls the future again.
+            used_waker.wake();
+            assert_eq!(read.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+        }
+        assert_eq!(&buffer[0..9], b"Test data");
+    }
+
+    #[test]
+    fn read_deferred() {
+        let mut config_space = BlkConfig {
+            capacity_low: Volatile::new(66),
+            capacity_high: Volatile::new(0),
+            size_max: Volatile::new(0),
+            seg_max: Volatile::new(0),
+            cylinders: Volatile::new(0),
+            heads: Volatile::new(0),
+            sectors: Volatile::new(0),
+            blk_size: Volatile::new(0),
+            physical_block_exp: Volatile::new(0),
+            alignment_offset: Volatile::new(0),
+            min_io_size: Volatile::new(0),
+            opt_io_size: Volatile::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start reading two blocks, but don't submit them to the device yet.
+        let mut requests = [BlkReq::default(), BlkReq::default()];
+        let mut buffers = [[0; SECTOR_SIZE]; 2];
+        let mut responses = [BlkResp::default(), BlkResp::default()];
+        let mut tokens = [0; 2];
+        for (i, ((request, buffer), response)) in requests
+            .iter_mut()
+            .zip(buffers.iter_mut())
+            .zip(responses.iter_mut())
+            .enumerate()
+        {
+            tokens[i] =
+                unsafe { blk.read_blocks_nb_deferred(42 + i, request, buffer, response) }.unwrap();
+        }
+        assert!(!state.lock().unwrap().queues[usize::from(QUEUE)]
+            .notified
+            .load(Ordering::SeqCst));
+
+        // Submitting them should notify the device once.
+        blk.submit_deferred();
+        assert!(state.lock().unwrap().queues[usize::from(QUEUE)]
+            .notified
+            .swap(false, Ordering::SeqCst));
+
+        // Simulate the device handling both requests.
+        for sector in [42, 43] {
+            state.lock().unwrap().read_write_queue(QUEUE, |request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::In,
+                        reserved: 0,
+                        sector,
+                    }
+                    .as_bytes()
+                );
+
+                let mut response = vec![sector as u8; SECTOR_SIZE];
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+                response
+            });
+        }
+
+        for i in 0..2 {
+            assert_eq!(blk.peek_used(), Some(tokens[i]));
+            unsafe {
+                blk.complete_read_blocks(
+                    tokens[i],
+                    &requests[i],
+                    &mut buffers[i],
+                    &mut responses[i],
+                )
+            }
+            .unwrap();
+            assert_eq!(buffers[i], [42 + i as u8; SECTOR_SIZE]);
+        }
+    }
+
+    #[test]
+    fn read_packed() {
+        let mut config_space = BlkConfig {
+            capacity_low: Volatile::new(66),
+            capacity_high: Volatile::new(0),
+            size_max: Volatile::new(0),
+            seg_max: Volatile::new(0),
+            cylinders: Volatile::new(0),
+            heads: Volatile::new(0),
+            sectors: Volatile::new(0),
+            blk_size: Volatile::new(0),
+            physical_block_exp: Volatile::new(0),
+            alignment_offset: Volatile::new(0),
+            min_io_size: Volatile::new(0),
+            opt_io_size: Volatile::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: (BlkFeature::RING_INDIRECT_DESC | BlkFeature::RING_PACKED).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_ne!(
+            state.lock().unwrap().driver_features & BlkFeature::RING_PACKED.bits(),
+            0
+        );
+
+        // Start a thread to simulate the device handling two read requests.
+        let handle = thread::spawn(move || {
+            for sector in [42, 43] {
+                println!("Device waiting for a request.");
+                State::wait_until_queue_notified(&state, QUEUE);
+                println!("Transmit queue was notified.");
+
+                state.lock().unwrap().read_write_queue(QUEUE, |request| {
                     assert_eq!(
                         request,
                         BlkReq {
                             type_: ReqType::In,
                             reserved: 0,
-                            sector: 42
+                            sector
                         }
                         .as_bytes()
                     );
 
                     let mut response = vec![0; SECTOR_SIZE];
-                    response[0..9].copy_from_slice(b"Test data");
+                    response[0] = sector as u8;
                     response.extend_from_slice(
                         BlkResp {
                             status: RespStatus::OK,
@@ -657,12 +1147,15 @@ mod tests {
 
                     response
                 });
+            }
         });
 
-        // Read a block from the device.
+        // Read two blocks from the device.
         let mut buffer = [0; 512];
         blk.read_blocks(42, &mut buffer).unwrap();
-        assert_eq!(&buffer[0..9], b"Test data");
+        assert_eq!(buffer[0], 42);
+        blk.read_blocks(43, &mut buffer).unwrap();
+        assert_eq!(buffer[0], 43);
 
         handle.join().unwrap();
     }
@@ -702,33 +1195,30 @@ mod tests {
             State::wait_until_queue_notified(&state, QUEUE);
             println!("Transmit queue was notified.");
 
-            state
-                .lock()
-                .unwrap()
-                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
-                    assert_eq!(
-                        &request[0..size_of::<BlkReq>()],
-                        BlkReq {
-                            type_: ReqType::Out,
-                            reserved: 0,
-                            sector: 42
-                        }
-                        .as_bytes()
-                    );
-                    let data = &request[size_of::<BlkReq>()..];
-                    assert_eq!(data.len(), SECTOR_SIZE);
-                    assert_eq!(&data[0..9], b"Test data");
-
-                    let mut response = Vec::new();
-                    response.extend_from_slice(
-                        BlkResp {
-                            status: RespStatus::OK,
-                        }
-                        .as_bytes(),
-                    );
-
-                    response
-                });
+            state.lock().unwrap().read_write_queue(QUEUE, |request| {
+                assert_eq!(
+                    &request[0..size_of::<BlkReq>()],
+                    BlkReq {
+                        type_: ReqType::Out,
+                        reserved: 0,
+                        sector: 42
+                    }
+                    .as_bytes()
+                );
+                let data = &request[size_of::<BlkReq>()..];
+                assert_eq!(data.len(), SECTOR_SIZE);
+                assert_eq!(&data[0..9], b"Test data");
+
+                let mut response = Vec::new();
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+
+                response
+            });
         });
 
         // Write a block to the device.
@@ -777,30 +1267,27 @@ mod tests {
             State::wait_until_queue_notified(&state, QUEUE);
             println!("Transmit queue was notified.");
 
-            state
-                .lock()
-                .unwrap()
-                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
-                    assert_eq!(
-                        request,
-                        BlkReq {
-                            type_: ReqType::Flush,
-                            reserved: 0,
-                            sector: 0,
-                        }
-                        .as_bytes()
-                    );
-
-                    let mut response = Vec::new();
-                    response.extend_from_slice(
-                        BlkResp {
-                            status: RespStatus::OK,
-                        }
-                        .as_bytes(),
-                    );
-
-                    response
-                });
+            state.lock().unwrap().read_write_queue(QUEUE, |request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::Flush,
+                        reserved: 0,
+                        sector: 0,
+                    }
+                    .as_bytes()
+                );
+
+                let mut response = Vec::new();
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+
+                response
+            });
         });
 
         // Request to flush.
@@ -844,31 +1331,28 @@ mod tests {
             State::wait_until_queue_notified(&state, QUEUE);
             println!("Transmit queue was notified.");
 
-            state
-                .lock()
-                .unwrap()
-                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
-                    assert_eq!(
-                        request,
-                        BlkReq {
-                            type_: ReqType::GetId,
-                            reserved: 0,
-                            sector: 0,
-                        }
-                        .as_bytes()
-                    );
-
-                    let mut response = Vec::new();
-                    response.extend_from_slice(b"device_id\0\0\0\0\0\0\0\0\0\0\0");
-                    response.extend_from_slice(
-                        BlkResp {
-                            status: RespStatus::OK,
-                        }
-                        .as_bytes(),
-                    );
-
-                    response
-                });
+            state.lock().unwrap().read_write_queue(QUEUE, |request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::GetId,
+                        reserved: 0,
+                        sector: 0,
+                    }
+                    .as_bytes()
+                );
+
+                let mut response = Vec::new();
+                response.extend_from_slice(b"device_id\0\0\0\0\0\0\0\0\0\0\0");
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+
+                response
+            });
         });
 
         let mut id = [0; 20];
@@ -1,18 +1,22 @@
 //! Driver for VirtIO console devices.
 
+use crate::config::read_config;
 use crate::hal::Hal;
-use crate::queue::VirtQueue;
+use crate::queue::{QueueOptions, VirtQueue};
+#[cfg(feature = "stats")]
+use crate::queue::{QueueStats, QueueTracer};
 use crate::transport::Transport;
-use crate::volatile::{volread, ReadOnly, WriteOnly};
+use crate::volatile::{ReadOnly, WriteOnly};
 use crate::{Result, PAGE_SIZE};
 use alloc::boxed::Box;
 use bitflags::bitflags;
-use core::ptr::NonNull;
 
 const QUEUE_RECEIVEQ_PORT_0: u16 = 0;
 const QUEUE_TRANSMITQ_PORT_0: u16 = 1;
 const QUEUE_SIZE: usize = 2;
-const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
+const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
+    .union(Features::RING_PACKED)
+    .union(Features::NOTIFICATION_DATA);
 
 /// Driver for a VirtIO console device.
 ///
@@ -27,7 +31,7 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
 /// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
 /// let mut console = VirtIOConsole::<HalImpl, _>::new(transport)?;
 ///
-/// let info = console.info();
+/// let info = console.info()?;
 /// println!("VirtIO console {}x{}", info.rows, info.columns);
 ///
 /// for &c in b"Hello console!\n" {
@@ -41,7 +45,6 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
 /// ```
 pub struct VirtIOConsole<H: Hal, T: Transport> {
     transport: T,
-    config_space: NonNull<Config>,
     receiveq: VirtQueue<H, QUEUE_SIZE>,
     transmitq: VirtQueue<H, QUEUE_SIZE>,
     queue_buf_rx: Box<[u8; PAGE_SIZE]>,
@@ -51,18 +54,6 @@ pub struct VirtIOConsole<H: Hal, T: Transport> {
     receive_token: Option<u16>,
 }
 
-// SAFETY: The config space can be accessed from any thread.
-unsafe impl<H: Hal, T: Transport + Send> Send for VirtIOConsole<H, T> where
-    VirtQueue<H, QUEUE_SIZE>: Send
-{
-}
-
-// SAFETY: A `&VirtIOConsole` only allows reading the config space.
-unsafe impl<H: Hal, T: Transport + Sync> Sync for VirtIOConsole<H, T> where
-    VirtQueue<H, QUEUE_SIZE>: Sync
-{
-}
-
 /// Information about a console device, read from its configuration space.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ConsoleInfo {
@@ -78,19 +69,9 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
     /// Creates a new VirtIO console driver.
     pub fn new(mut transport: T) -> Result<Self> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
-        let config_space = transport.config_space::<Config>()?;
-        let receiveq = VirtQueue::new(
-            &mut transport,
-            QUEUE_RECEIVEQ_PORT_0,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
-        let transmitq = VirtQueue::new(
-            &mut transport,
-            QUEUE_TRANSMITQ_PORT_0,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
+        let options = QueueOptions::from_features(negotiated_features.bits());
+        let receiveq = VirtQueue::new(&mut transport, QUEUE_RECEIVEQ_PORT_0, options)?;
+        let transmitq = VirtQueue::new(&mut transport, QUEUE_TRANSMITQ_PORT_0, options)?;
 
         // Safe because no alignment or initialisation is required for [u8], the DMA buffer is
         // dereferenceable, and the lifetime of the reference matches the lifetime of the DMA buffer
@@ -100,7 +81,6 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
         transport.finish_init();
         let mut console = VirtIOConsole {
             transport,
-            config_space,
             receiveq,
             transmitq,
             queue_buf_rx,
@@ -113,18 +93,14 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
     }
 
     /// Returns a struct with information about the console device, such as the number of rows and columns.
-    pub fn info(&self) -> ConsoleInfo {
-        // Safe because config_space is a valid pointer to the device configuration space.
-        unsafe {
-            let columns = volread!(self.config_space, cols);
-            let rows = volread!(self.config_space, rows);
-            let max_ports = volread!(self.config_space, max_nr_ports);
-            ConsoleInfo {
-                rows,
-                columns,
-                max_ports,
-            }
-        }
+    pub fn info(&self) -> Result<ConsoleInfo> {
+        self.transport.read_config(|| {
+            Ok(ConsoleInfo {
+                rows: read_config!(self.transport, Config, rows)?,
+                columns: read_config!(self.transport, Config, cols)?,
+                max_ports: read_config!(self.transport, Config, max_nr_ports)?,
+            })
+        })
     }
 
     /// Makes a request to the device to receive data, if there is not already an outstanding
@@ -138,7 +114,7 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
                     .add(&[], &mut [self.queue_buf_rx.as_mut_slice()])
             }?);
             if self.receiveq.should_notify() {
-                self.transport.notify(QUEUE_RECEIVEQ_PORT_0);
+                self.receiveq.notify(&mut self.transport);
             }
         }
         Ok(())
@@ -156,6 +132,23 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
         self.finish_receive()
     }
 
+    /// Returns the sum of the counters of all of the device's queues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        [&self.receiveq, &self.transmitq]
+            .iter()
+            .map(|queue| queue.stats())
+            .sum()
+    }
+
+    /// Sets a tracer to be called for each operation on any of the device's queues, or removes
+    /// it.
+    #[cfg(feature = "stats")]
+    pub fn set_tracer(&mut self, tracer: Option<&'static dyn QueueTracer>) {
+        self.receiveq.set_tracer(tracer);
+        self.transmitq.set_tracer(tracer);
+    }
+
     /// If there is an outstanding receive request and it has finished, completes it.
     ///
     /// Returns true if new data has been received.
@@ -172,8 +165,9 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
                         &mut [self.queue_buf_rx.as_mut_slice()],
                     )?
                 };
-                flag = true;
-                assert_ne!(len, 0);
+                // The device shouldn't return an empty buffer, but if it does then treat it as no
+                // data received.
+                flag = len != 0;
                 self.cursor = 0;
                 self.pending_len = len as usize;
                 // Clear `receive_token` so that when the buffer is used up the next call to
@@ -297,7 +291,7 @@ mod tests {
         // Make a character available, and simulate an interrupt.
         {
             let mut state = state.lock().unwrap();
-            state.write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, &[42]);
+            state.write_to_queue(QUEUE_RECEIVEQ_PORT_0, &[42]);
 
             state.interrupt_pending = true;
         }
@@ -340,7 +334,7 @@ mod tests {
             let data = state
                 .lock()
                 .unwrap()
-                .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_0);
+                .read_from_queue(QUEUE_TRANSMITQ_PORT_0);
             assert_eq!(data, b"Q");
         });
 
@@ -348,4 +342,38 @@ mod tests {
 
         handle.join().unwrap();
     }
+
+    /// Tests that the driver uses the queue size required by a legacy transport, even though it is
+    /// bigger than the driver's usual queue size.
+    #[test]
+    fn legacy_queue_size() {
+        let mut config_space = Config {
+            cols: ReadOnly::new(0),
+            rows: ReadOnly::new(0),
+            max_nr_ports: ReadOnly::new(0),
+            emerg_wr: WriteOnly::default(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            requires_max_queue_size: true,
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 8,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(state.lock().unwrap().queues[0].size, 8);
+        assert_eq!(state.lock().unwrap().queues[1].size, 8);
+
+        // The queues still work.
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue(QUEUE_RECEIVEQ_PORT_0, &[42]);
+        assert_eq!(console.recv(true).unwrap(), Some(42));
+    }
 }
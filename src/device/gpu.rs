@@ -1,9 +1,12 @@
 //! Driver for VirtIO GPU devices.
 
+use crate::config::read_config;
 use crate::hal::{BufferDirection, Dma, Hal};
-use crate::queue::VirtQueue;
+use crate::queue::{QueueOptions, VirtQueue};
+#[cfg(feature = "stats")]
+use crate::queue::{QueueStats, QueueTracer};
 use crate::transport::Transport;
-use crate::volatile::{volread, ReadOnly, Volatile, WriteOnly};
+use crate::volatile::{ReadOnly, Volatile, WriteOnly};
 use crate::{pages, Error, Result, PAGE_SIZE};
 use alloc::boxed::Box;
 use bitflags::bitflags;
@@ -11,7 +14,9 @@ use log::info;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 const QUEUE_SIZE: u16 = 2;
-const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
+const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
+    .union(Features::RING_PACKED)
+    .union(Features::NOTIFICATION_DATA);
 
 /// A virtio based graphics adapter.
 ///
@@ -43,28 +48,20 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
         // read configuration space
-        let config_space = transport.config_space::<Config>()?;
-        unsafe {
-            let events_read = volread!(config_space, events_read);
-            let num_scanouts = volread!(config_space, num_scanouts);
-            info!(
-                "events_read: {:#x}, num_scanouts: {:#x}",
-                events_read, num_scanouts
-            );
-        }
+        let (events_read, num_scanouts) = transport.read_config(|| {
+            Ok((
+                read_config!(transport, Config, events_read)?,
+                read_config!(transport, Config, num_scanouts)?,
+            ))
+        })?;
+        info!(
+            "events_read: {:#x}, num_scanouts: {:#x}",
+            events_read, num_scanouts
+        );
 
-        let control_queue = VirtQueue::new(
-            &mut transport,
-            QUEUE_TRANSMIT,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
-        let cursor_queue = VirtQueue::new(
-            &mut transport,
-            QUEUE_CURSOR,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
+        let options = QueueOptions::from_features(negotiated_features.bits());
+        let control_queue = VirtQueue::new(&mut transport, QUEUE_TRANSMIT, options)?;
+        let cursor_queue = VirtQueue::new(&mut transport, QUEUE_CURSOR, options)?;
 
         let queue_buf_send = FromZeroes::new_box_slice_zeroed(PAGE_SIZE);
         let queue_buf_recv = FromZeroes::new_box_slice_zeroed(PAGE_SIZE);
@@ -88,6 +85,23 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         self.transport.ack_interrupt()
     }
 
+    /// Returns the sum of the counters of all of the device's queues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        [&self.control_queue, &self.cursor_queue]
+            .iter()
+            .map(|queue| queue.stats())
+            .sum()
+    }
+
+    /// Sets a tracer to be called for each operation on any of the device's queues, or removes
+    /// it.
+    #[cfg(feature = "stats")]
+    pub fn set_tracer(&mut self, tracer: Option<&'static dyn QueueTracer>) {
+        self.control_queue.set_tracer(tracer);
+        self.cursor_queue.set_tracer(tracer);
+    }
+
     /// Get the resolution (width, height).
     pub fn resolution(&mut self) -> Result<(u32, u32)> {
         let display_info = self.get_display_info()?;
@@ -1,13 +1,15 @@
 //! Driver for VirtIO input devices.
 
 use super::common::Feature;
+use crate::config::{read_config, write_config};
 use crate::hal::Hal;
-use crate::queue::VirtQueue;
+use crate::queue::{QueueOptions, VirtQueue};
+#[cfg(feature = "stats")]
+use crate::queue::{QueueStats, QueueTracer};
 use crate::transport::Transport;
-use crate::volatile::{volread, volwrite, ReadOnly, WriteOnly};
+use crate::volatile::{ReadOnly, WriteOnly};
 use crate::Result;
 use alloc::boxed::Box;
-use core::ptr::NonNull;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 /// Virtual human interface devices such as keyboards, mice and tablets.
@@ -20,7 +22,6 @@ pub struct VirtIOInput<H: Hal, T: Transport> {
     event_queue: VirtQueue<H, QUEUE_SIZE>,
     status_queue: VirtQueue<H, QUEUE_SIZE>,
     event_buf: Box<[InputEvent; 32]>,
-    config: NonNull<Config>,
 }
 
 impl<H: Hal, T: Transport> VirtIOInput<H, T> {
@@ -30,27 +31,16 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
 
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
-        let config = transport.config_space::<Config>()?;
-
-        let mut event_queue = VirtQueue::new(
-            &mut transport,
-            QUEUE_EVENT,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
-        let status_queue = VirtQueue::new(
-            &mut transport,
-            QUEUE_STATUS,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
+        let options = QueueOptions::from_features(negotiated_features.bits());
+        let mut event_queue = VirtQueue::new(&mut transport, QUEUE_EVENT, options)?;
+        let status_queue = VirtQueue::new(&mut transport, QUEUE_STATUS, options)?;
         for (i, event) in event_buf.as_mut().iter_mut().enumerate() {
             // Safe because the buffer lasts as long as the queue.
             let token = unsafe { event_queue.add(&[], &mut [event.as_bytes_mut()])? };
             assert_eq!(token, i as u16);
         }
         if event_queue.should_notify() {
-            transport.notify(QUEUE_EVENT);
+            event_queue.notify(&mut transport);
         }
 
         transport.finish_init();
@@ -60,7 +50,6 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
             event_queue,
             status_queue,
             event_buf,
-            config,
         })
     }
 
@@ -69,6 +58,23 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
         self.transport.ack_interrupt()
     }
 
+    /// Returns the sum of the counters of all of the device's queues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        [&self.event_queue, &self.status_queue]
+            .iter()
+            .map(|queue| queue.stats())
+            .sum()
+    }
+
+    /// Sets a tracer to be called for each operation on any of the device's queues, or removes
+    /// it.
+    #[cfg(feature = "stats")]
+    pub fn set_tracer(&mut self, tracer: Option<&'static dyn QueueTracer>) {
+        self.event_queue.set_tracer(tracer);
+        self.status_queue.set_tracer(tracer);
+    }
+
     /// Pop the pending event.
     pub fn pop_pending_event(&mut self) -> Option<InputEvent> {
         if let Some(token) = self.event_queue.peek_used() {
@@ -90,7 +96,7 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
                 // was just freed by `pop_used`.
                 assert_eq!(new_token, token);
                 if self.event_queue.should_notify() {
-                    self.transport.notify(QUEUE_EVENT);
+                    self.event_queue.notify(&mut self.transport);
                 }
                 return Some(event_saved);
             }
@@ -105,33 +111,20 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
         select: InputConfigSelect,
         subsel: u8,
         out: &mut [u8],
-    ) -> u8 {
-        let size;
-        let data;
-        // Safe because config points to a valid MMIO region for the config space.
-        unsafe {
-            volwrite!(self.config, select, select as u8);
-            volwrite!(self.config, subsel, subsel);
-            size = volread!(self.config, size);
-            data = volread!(self.config, data);
-        }
+    ) -> Result<u8> {
+        write_config!(self.transport, Config, select, select as u8)?;
+        write_config!(self.transport, Config, subsel, subsel)?;
+        let (size, data) = self.transport.read_config(|| {
+            Ok((
+                read_config!(self.transport, Config, size)?,
+                read_config!(self.transport, Config, data)?,
+            ))
+        })?;
         out[..size as usize].copy_from_slice(&data[..size as usize]);
-        size
+        Ok(size)
     }
 }
 
-// SAFETY: The config space can be accessed from any thread.
-unsafe impl<H: Hal, T: Transport + Send> Send for VirtIOInput<H, T> where
-    VirtQueue<H, QUEUE_SIZE>: Send
-{
-}
-
-// SAFETY: An '&VirtIOInput` can't do anything, all methods take `&mut self`.
-unsafe impl<H: Hal, T: Transport + Sync> Sync for VirtIOInput<H, T> where
-    VirtQueue<H, QUEUE_SIZE>: Sync
-{
-}
-
 impl<H: Hal, T: Transport> Drop for VirtIOInput<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
@@ -209,7 +202,9 @@ pub struct InputEvent {
 
 const QUEUE_EVENT: u16 = 0;
 const QUEUE_STATUS: u16 = 1;
-const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX;
+const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX
+    .union(Feature::RING_PACKED)
+    .union(Feature::NOTIFICATION_DATA);
 
 // a parameter that can change
 const QUEUE_SIZE: usize = 32;
@@ -1,8 +1,13 @@
-use alloc::vec;
+use alloc::{sync::Arc, vec::Vec};
+use core::array;
 
 use super::net_buf::{RxBuffer, TxBuffer};
-use super::{EthernetAddress, VirtIONetRaw};
-use crate::{hal::Hal, transport::Transport, Error, Result};
+use super::{EthernetAddress, VirtIONetRaw, NET_HDR_SIZE};
+use crate::hal::{BufferDirection, Hal};
+use crate::queue::DmaPool;
+#[cfg(feature = "stats")]
+use crate::queue::{QueueStats, QueueTracer};
+use crate::{transport::Transport, Error, Result};
 
 /// Driver for a VirtIO network device.
 ///
@@ -10,6 +15,10 @@ use crate::{hal::Hal, transport::Transport, Error, Result};
 /// reception rather than the raw slices. On initialization, it pre-allocates
 /// all receive buffers and puts them all in the receive queue.
 ///
+/// Receive buffers, and transmit buffers allocated with [`new_tx_buffer`](Self::new_tx_buffer),
+/// come from [`DmaPool`]s which are shared with the device once, so packets are not copied or
+/// mapped separately for the device.
+///
 /// The virtio network device is a virtual ethernet card.
 ///
 /// It has enhanced rapidly and demonstrates clearly how support for new
@@ -19,25 +28,36 @@ use crate::{hal::Hal, transport::Transport, Error, Result};
 /// A third command queue is used to control advanced filtering features.
 pub struct VirtIONet<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
     inner: VirtIONetRaw<H, T, QUEUE_SIZE>,
-    rx_buffers: [Option<RxBuffer>; QUEUE_SIZE],
+    rx_buffers: [Option<RxBuffer<H>>; QUEUE_SIZE],
+    tx_pool: Arc<DmaPool<H>>,
 }
 
 impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE> {
     /// Create a new VirtIO-Net driver.
+    ///
+    /// `buf_len` is the length of each receive and transmit buffer in the driver's pools, including
+    /// the header.
     pub fn new(transport: T, buf_len: usize) -> Result<Self> {
         let mut inner = VirtIONetRaw::new(transport)?;
+        let rx_pool = DmaPool::new(QUEUE_SIZE, buf_len, BufferDirection::DeviceToDriver)?;
+        let tx_pool = DmaPool::new(QUEUE_SIZE, buf_len, BufferDirection::DriverToDevice)?;
+        inner.set_dma_pools(Some(rx_pool.clone()), Some(tx_pool.clone()))?;
 
-        const NONE_BUF: Option<RxBuffer> = None;
-        let mut rx_buffers = [NONE_BUF; QUEUE_SIZE];
+        let mut rx_buffers: [Option<RxBuffer<H>>; QUEUE_SIZE] = array::from_fn(|_| None);
         for (i, rx_buf_place) in rx_buffers.iter_mut().enumerate() {
-            let mut rx_buf = RxBuffer::new(i, buf_len);
+            // The pool has a buffer for every queue entry.
+            let mut rx_buf = RxBuffer::new(i, rx_pool.alloc().unwrap());
             // Safe because the buffer lives as long as the queue.
             let token = unsafe { inner.receive_begin(rx_buf.as_bytes_mut())? };
             assert_eq!(token, i as u16);
             *rx_buf_place = Some(rx_buf);
         }
 
-        Ok(VirtIONet { inner, rx_buffers })
+        Ok(VirtIONet {
+            inner,
+            rx_buffers,
+            tx_pool,
+        })
     }
 
     /// Acknowledge interrupt.
@@ -55,6 +75,36 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
         self.inner.enable_interrupts()
     }
 
+    /// Enables adaptive moderation of receive interrupts with the given budget, or disables it
+    /// with `None`.
+    ///
+    /// While it is enabled, the driver should poll with [`receive`](Self::receive) after each
+    /// interrupt, receiving at most `budget` packets at a time and reporting how many it received
+    /// to [`rx_poll_done`](Self::rx_poll_done), until that says to wait for an interrupt again.
+    /// See [`VirtIONetRaw::set_rx_moderation`].
+    pub fn set_rx_moderation(&mut self, budget: Option<usize>) {
+        self.inner.set_rx_moderation(budget)
+    }
+
+    /// Records that the driver received `received` packets in a round of polling, and returns
+    /// whether it should keep polling rather than waiting for an interrupt.
+    pub fn rx_poll_done(&mut self, received: usize) -> bool {
+        self.inner.rx_poll_done(received)
+    }
+
+    /// Returns the sum of the counters of the receive and transmit queues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.inner.stats()
+    }
+
+    /// Sets a tracer to be called for each operation on the receive and transmit queues, or
+    /// removes it.
+    #[cfg(feature = "stats")]
+    pub fn set_tracer(&mut self, tracer: Option<&'static dyn QueueTracer>) {
+        self.inner.set_tracer(tracer)
+    }
+
     /// Get MAC address.
     pub fn mac_address(&self) -> EthernetAddress {
         self.inner.mac_address()
@@ -75,7 +125,7 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
     ///
     /// It will try to pop a buffer that completed data reception in the
     /// NIC queue.
-    pub fn receive(&mut self) -> Result<RxBuffer> {
+    pub fn receive(&mut self) -> Result<RxBuffer<H>> {
         if let Some(token) = self.inner.poll_receive() {
             let mut rx_buf = self.rx_buffers[token as usize]
                 .take()
@@ -98,7 +148,7 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
     /// Gives back the ownership of `rx_buf`, and recycles it for next use.
     ///
     /// It will add the buffer back to the NIC queue.
-    pub fn recycle_rx_buffer(&mut self, mut rx_buf: RxBuffer) -> Result {
+    pub fn recycle_rx_buffer(&mut self, mut rx_buf: RxBuffer<H>) -> Result {
         // Safe because we take the ownership of `rx_buf` back to `rx_buffers`,
         // it lives as long as the queue.
         let new_token = unsafe { self.inner.receive_begin(rx_buf.as_bytes_mut()) }?;
@@ -112,14 +162,51 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
         Ok(())
     }
 
+    /// Resets the receive queue without resetting the rest of the device, for example to recover
+    /// from the device no longer using it. This fails with [`Error::Unsupported`] if the device
+    /// doesn't support `VIRTIO_F_RING_RESET`.
+    ///
+    /// All receive buffers which haven't been taken by [`receive`](Self::receive) are added to the
+    /// queue again afterwards.
+    pub fn reset_receive_queue(&mut self) -> Result {
+        let mut pending: Vec<_> = self
+            .rx_buffers
+            .iter_mut()
+            .flatten()
+            .map(|rx_buf| (rx_buf.idx, rx_buf.as_bytes_mut()))
+            .collect();
+        // Safe because these are all the buffers which are in the receive queue, along with the
+        // tokens they were added with.
+        unsafe {
+            self.inner
+                .reset_receive_queue(QUEUE_SIZE as u16, &mut pending)?;
+        }
+        let rx_buffers: Vec<_> = self
+            .rx_buffers
+            .iter_mut()
+            .filter_map(Option::take)
+            .collect();
+        for rx_buf in rx_buffers {
+            self.recycle_rx_buffer(rx_buf)?;
+        }
+        Ok(())
+    }
+
     /// Allocate a new buffer for transmitting.
-    pub fn new_tx_buffer(&self, buf_len: usize) -> TxBuffer {
-        TxBuffer(vec![0; buf_len])
+    ///
+    /// The buffer comes from the driver's transmit pool if one is free and the packet fits in it,
+    /// or is allocated on the heap otherwise.
+    pub fn new_tx_buffer(&self, buf_len: usize) -> TxBuffer<H> {
+        match self.tx_pool.alloc() {
+            Some(buf) if NET_HDR_SIZE + buf_len <= buf.len() => TxBuffer::from_pool(buf, buf_len),
+            _ => TxBuffer::new_heap(buf_len),
+        }
     }
 
     /// Sends a [`TxBuffer`] to the network, and blocks until the request
     /// completed.
-    pub fn send(&mut self, tx_buf: TxBuffer) -> Result {
-        self.inner.send(tx_buf.packet())
+    pub fn send(&mut self, tx_buf: TxBuffer<H>) -> Result {
+        self.inner.transmit_wait(tx_buf.as_bytes())?;
+        Ok(())
     }
 }
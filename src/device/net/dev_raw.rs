@@ -1,10 +1,20 @@
-use super::{Config, EthernetAddress, Features, VirtioNetHdr};
+use super::{Config, EthernetAddress, Features, Status, VirtioNetHdr};
 use super::{MIN_BUFFER_LEN, NET_HDR_SIZE, QUEUE_RECEIVE, QUEUE_TRANSMIT, SUPPORTED_FEATURES};
-use crate::hal::Hal;
-use crate::queue::VirtQueue;
+use crate::config::read_config;
+use crate::hal::{Deadline, Hal};
+#[cfg(feature = "alloc")]
+use crate::queue::DmaPool;
+use crate::queue::{
+    InterruptModerator, QueueOptions, UsedWaker, VirtQueue, VirtQueueConsumer, VirtQueueProducer,
+};
+#[cfg(feature = "stats")]
+use crate::queue::{QueueStats, QueueTracer};
+use crate::sync::SpinLock;
 use crate::transport::Transport;
-use crate::volatile::volread;
 use crate::{Error, Result};
+#[cfg(feature = "alloc")]
+use alloc::sync::Arc;
+use core::time::Duration;
 use log::{debug, info, warn};
 use zerocopy::AsBytes;
 
@@ -15,57 +25,73 @@ use zerocopy::AsBytes;
 /// management. For more higher-level fucntions such as receive buffer backing,
 /// see [`VirtIONet`].
 ///
+/// The driver may be [split](Self::split) into independent transmit and receive handles, which can
+/// be used concurrently from different CPUs.
+///
 /// [`VirtIONet`]: super::VirtIONet
 pub struct VirtIONetRaw<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
-    transport: T,
+    /// The transport, which is shared by the transmit and receive handles to notify the device.
+    transport: SpinLock<T>,
+    negotiated_features: Features,
     mac: EthernetAddress,
     recv_queue: VirtQueue<H, QUEUE_SIZE>,
     send_queue: VirtQueue<H, QUEUE_SIZE>,
+    rx_moderator: Option<InterruptModerator>,
 }
 
 impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZE> {
     /// Create a new VirtIO-Net driver.
-    pub fn new(mut transport: T) -> Result<Self> {
+    ///
+    /// The transmit and receive queues will each have up to `QUEUE_SIZE` descriptors, or fewer if
+    /// the device doesn't support that many.
+    pub fn new(transport: T) -> Result<Self> {
+        Self::with_queue_size(transport, QUEUE_SIZE as u16)
+    }
+
+    /// Create a new VirtIO-Net driver whose transmit and receive queues each have up to
+    /// `queue_size` descriptors.
+    ///
+    /// The queue size is limited to the maximum supported by the device, and rounded down to a power
+    /// of 2. Queues of more than `QUEUE_SIZE` descriptors require the `alloc` feature.
+    pub fn with_queue_size(mut transport: T, queue_size: u16) -> Result<Self> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
         info!("negotiated_features {:?}", negotiated_features);
         // read configuration space
-        let config = transport.config_space::<Config>()?;
-        let mac;
-        // Safe because config points to a valid MMIO region for the config space.
-        unsafe {
-            mac = volread!(config, mac);
-            debug!(
-                "Got MAC={:02x?}, status={:?}",
-                mac,
-                volread!(config, status)
-            );
-        }
-        let send_queue = VirtQueue::new(
-            &mut transport,
-            QUEUE_TRANSMIT,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
-        let recv_queue = VirtQueue::new(
-            &mut transport,
-            QUEUE_RECEIVE,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
+        let (mac, status) = transport.read_config(|| {
+            Ok((
+                read_config!(transport, Config, mac)?,
+                Status::from_bits_truncate(read_config!(transport, Config, status)?),
+            ))
+        })?;
+        debug!("Got MAC={:02x?}, status={:?}", mac, status);
+        let options = QueueOptions::from_features(negotiated_features.bits());
+        let send_queue = VirtQueue::with_size(&mut transport, QUEUE_TRANSMIT, queue_size, options)?;
+        let recv_queue = VirtQueue::with_size(&mut transport, QUEUE_RECEIVE, queue_size, options)?;
 
         transport.finish_init();
 
         Ok(VirtIONetRaw {
-            transport,
+            transport: SpinLock::new(transport),
+            negotiated_features,
             mac,
             recv_queue,
             send_queue,
+            rx_moderator: None,
         })
     }
 
     /// Acknowledge interrupt.
+    ///
+    /// If receive interrupt moderation is enabled, this also switches the receive queue to polling
+    /// mode until [`rx_poll_done`](Self::rx_poll_done) switches it back.
     pub fn ack_interrupt(&mut self) -> bool {
-        self.transport.ack_interrupt()
+        let interrupt = self.transport.get_mut().ack_interrupt();
+        if interrupt {
+            if let Some(moderator) = &mut self.rx_moderator {
+                moderator.interrupt(&mut self.recv_queue);
+            }
+        }
+        interrupt
     }
 
     /// Disable interrupts.
@@ -80,6 +106,63 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
         self.recv_queue.set_dev_notify(true);
     }
 
+    /// Enables adaptive moderation of receive interrupts with the given budget, or disables it
+    /// with `None`.
+    ///
+    /// While it is enabled, [`ack_interrupt`](Self::ack_interrupt) suppresses further receive
+    /// interrupts and the driver should then poll for received packets with
+    /// [`poll_receive`](Self::poll_receive), completing at most `budget` at a time and reporting
+    /// how many it completed to [`rx_poll_done`](Self::rx_poll_done), until that says to wait for an
+    /// interrupt again. See [`InterruptModerator`] for the policy. Disabling it re-enables receive
+    /// interrupts, after which the driver should check for packets received in the meantime.
+    pub fn set_rx_moderation(&mut self, budget: Option<usize>) {
+        if let Some(mut moderator) = self.rx_moderator.take() {
+            moderator.reset(&mut self.recv_queue);
+        }
+        self.rx_moderator = budget.map(InterruptModerator::new);
+    }
+
+    /// Records that the driver received `received` packets in a round of polling, and returns
+    /// whether it should keep polling rather than waiting for an interrupt.
+    ///
+    /// Always returns false if receive interrupt moderation isn't enabled.
+    pub fn rx_poll_done(&mut self, received: usize) -> bool {
+        match &mut self.rx_moderator {
+            Some(moderator) => moderator.poll_done(&mut self.recv_queue, received),
+            None => false,
+        }
+    }
+
+    /// Returns the sum of the counters of all of the device's queues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        [&self.recv_queue, &self.send_queue]
+            .iter()
+            .map(|queue| queue.stats())
+            .sum()
+    }
+
+    /// Sets a tracer to be called for each operation on any of the device's queues, or removes
+    /// it.
+    #[cfg(feature = "stats")]
+    pub fn set_tracer(&mut self, tracer: Option<&'static dyn QueueTracer>) {
+        self.recv_queue.set_tracer(tracer);
+        self.send_queue.set_tracer(tracer);
+    }
+
+    /// Sets the pools whose buffers are passed to the device directly, without being shared for
+    /// each packet, for the receive and transmit queues respectively. See
+    /// [`VirtQueue::set_dma_pool`].
+    #[cfg(feature = "alloc")]
+    pub fn set_dma_pools(
+        &mut self,
+        rx_pool: Option<Arc<DmaPool<H>>>,
+        tx_pool: Option<Arc<DmaPool<H>>>,
+    ) -> Result {
+        self.recv_queue.set_dma_pool(rx_pool)?;
+        self.send_queue.set_dma_pool(tx_pool)
+    }
+
     /// Get MAC address.
     pub fn mac_address(&self) -> EthernetAddress {
         self.mac
@@ -90,24 +173,26 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
         self.send_queue.available_desc() >= 2
     }
 
-    /// Whether the length of the receive buffer is valid.
-    fn check_rx_buf_len(rx_buf: &[u8]) -> Result<()> {
-        if rx_buf.len() < MIN_BUFFER_LEN {
-            warn!("Receive buffer len {} is too small", rx_buf.len());
-            Err(Error::InvalidParam)
-        } else {
-            Ok(())
-        }
-    }
-
-    /// Whether the length of the transmit buffer is valid.
-    fn check_tx_buf_len(tx_buf: &[u8]) -> Result<()> {
-        if tx_buf.len() < NET_HDR_SIZE {
-            warn!("Transmit buffer len {} is too small", tx_buf.len());
-            Err(Error::InvalidParam)
-        } else {
-            Ok(())
-        }
+    /// Splits the driver into a transmit handle and a receive handle, which may be used
+    /// concurrently from different threads or CPUs.
+    ///
+    /// Each handle has its own virtqueue. They only share the transport, which is locked briefly
+    /// while notifying the device, so neither handle should be used from an interrupt handler which
+    /// may interrupt the other on the same CPU.
+    pub fn split(&mut self) -> (NetTx<'_, H, T, QUEUE_SIZE>, NetRx<'_, H, T, QUEUE_SIZE>) {
+        let (send_producer, send_consumer) = self.send_queue.split();
+        let tx = NetTx {
+            transport: &self.transport,
+            producer: send_producer,
+            consumer: send_consumer,
+        };
+        let (recv_producer, recv_consumer) = self.recv_queue.split();
+        let rx = NetRx {
+            transport: &self.transport,
+            producer: recv_producer,
+            consumer: recv_consumer,
+        };
+        (tx, rx)
     }
 
     /// Fill the header of the `buffer` with [`VirtioNetHdr`].
@@ -147,12 +232,34 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     /// [`poll_transmit`]: Self::poll_transmit
     /// [`transmit_complete`]: Self::transmit_complete
     pub unsafe fn transmit_begin(&mut self, tx_buf: &[u8]) -> Result<u16> {
-        Self::check_tx_buf_len(tx_buf)?;
-        let token = self.send_queue.add(&[tx_buf], &mut [])?;
-        if self.send_queue.should_notify() {
-            self.transport.notify(QUEUE_TRANSMIT);
-        }
-        Ok(token)
+        self.split().0.transmit_begin(tx_buf)
+    }
+
+    /// Submits a request to transmit a buffer like [`transmit_begin`], but doesn't make it
+    /// available to the device until [`transmit_submit`] is called.
+    ///
+    /// This lets a burst of packets be submitted to the device together with a single
+    /// notification.
+    ///
+    /// # Safety
+    ///
+    /// `tx_buf` is still borrowed by the underlying VirtIO net device even after
+    /// this method returns. Thus, it is the caller's responsibility to guarantee
+    /// that they are not accessed before the request is completed in order to
+    /// avoid data races.
+    ///
+    /// [`transmit_begin`]: Self::transmit_begin
+    /// [`transmit_submit`]: Self::transmit_submit
+    pub unsafe fn transmit_begin_deferred(&mut self, tx_buf: &[u8]) -> Result<u16> {
+        self.split().0.transmit_begin_deferred(tx_buf)
+    }
+
+    /// Makes all transmission requests started by [`transmit_begin_deferred`] available to the
+    /// device, and notifies it at most once.
+    ///
+    /// [`transmit_begin_deferred`]: Self::transmit_begin_deferred
+    pub fn transmit_submit(&mut self) {
+        self.split().0.transmit_submit()
     }
 
     /// Fetches the token of the next completed transmission request from the
@@ -172,8 +279,7 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     ///
     /// [`transmit_begin`]: Self::transmit_begin
     pub unsafe fn transmit_complete(&mut self, token: u16, tx_buf: &[u8]) -> Result<usize> {
-        let len = self.send_queue.pop_used(token, &[tx_buf], &mut [])?;
-        Ok(len as usize)
+        self.split().0.transmit_complete(token, tx_buf)
     }
 
     /// Submits a request to receive a buffer immediately without waiting for
@@ -199,12 +305,7 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     /// [`poll_receive`]: Self::poll_receive
     /// [`receive_complete`]: Self::receive_complete
     pub unsafe fn receive_begin(&mut self, rx_buf: &mut [u8]) -> Result<u16> {
-        Self::check_rx_buf_len(rx_buf)?;
-        let token = self.recv_queue.add(&[], &mut [rx_buf])?;
-        if self.recv_queue.should_notify() {
-            self.transport.notify(QUEUE_RECEIVE);
-        }
-        Ok(token)
+        self.split().1.receive_begin(rx_buf)
     }
 
     /// Fetches the token of the next completed reception request from the
@@ -231,32 +332,357 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
         token: u16,
         rx_buf: &mut [u8],
     ) -> Result<(usize, usize)> {
-        let len = self.recv_queue.pop_used(token, &[], &mut [rx_buf])? as usize;
+        self.split().1.receive_complete(token, rx_buf)
+    }
+
+    /// Resets the receive queue and sets it up again with up to `queue_size` descriptors, without
+    /// resetting the rest of the device.
+    ///
+    /// This can be used to recover a receive queue which the device has stopped using, for example
+    /// after [`receive_wait_timeout`](Self::receive_wait_timeout) times out, or to change its size.
+    /// It fails with [`Error::Unsupported`] if the device doesn't support `VIRTIO_F_RING_RESET`.
+    ///
+    /// Any reception requests started by [`receive_begin`] which haven't been completed are
+    /// cancelled, and their buffers given back to the caller. They must not be passed to
+    /// [`receive_complete`] afterwards.
+    ///
+    /// # Safety
+    ///
+    /// `pending` must contain the token and buffer of every request started by [`receive_begin`]
+    /// which hasn't been completed, with the same buffers as were passed to [`receive_begin`] when
+    /// it returned the tokens.
+    ///
+    /// [`receive_begin`]: Self::receive_begin
+    /// [`receive_complete`]: Self::receive_complete
+    pub unsafe fn reset_receive_queue(
+        &mut self,
+        queue_size: u16,
+        pending: &mut [(u16, &mut [u8])],
+    ) -> Result {
+        if !self.negotiated_features.contains(Features::RING_RESET) {
+            return Err(Error::Unsupported);
+        }
+        if !self.recv_queue.is_reset() {
+            self.recv_queue.reset(self.transport.get_mut())?;
+        }
+        let mut result = Ok(());
+        for (token, rx_buf) in pending.iter_mut() {
+            // Safe because our caller promises that this is the buffer which was added with the
+            // token.
+            let reclaimed = unsafe { self.recv_queue.reclaim(*token, &[], &mut [rx_buf]) };
+            result = result.and(reclaimed);
+        }
+        self.recv_queue
+            .reenable(self.transport.get_mut(), queue_size)?;
+        result
+    }
+
+    /// Sends a packet to the network, and blocks until the request completed.
+    pub fn send(&mut self, tx_buf: &[u8]) -> Result {
+        self.split().0.send(tx_buf)
+    }
+
+    /// Transmits a buffer which starts with a [`VirtioNetHdr`] followed by the packet, and blocks
+    /// until the transmission completes. Returns the number of bytes transmitted.
+    pub fn transmit_wait(&mut self, tx_buf: &[u8]) -> Result<usize> {
+        self.split().0.transmit_wait(tx_buf)
+    }
+
+    /// Sends a packet to the network asynchronously.
+    ///
+    /// The returned future resolves once the request completes. As it borrows the driver until
+    /// then, the interrupt handler can't call [`ack_interrupt`](Self::ack_interrupt) in the
+    /// meantime, but should instead call [`UsedWaker::wake`] on `waker` to wake it, as for
+    /// [`VirtQueue::add_notify_pop_async`].
+    ///
+    /// This must not be used while packets submitted with
+    /// [`transmit_begin`](Self::transmit_begin) are outstanding, as the future only completes once
+    /// its own packet is next to be popped.
+    ///
+    /// # Safety
+    ///
+    /// Once the returned future has been polled, it must be polled until it completes. It must not
+    /// be dropped or leaked (e.g. with [`core::mem::forget`]) before then, as the device may still
+    /// be reading from `tx_buf`.
+    pub async unsafe fn send_async(&mut self, tx_buf: &[u8], waker: &UsedWaker) -> Result {
+        let header = VirtioNetHdr::default();
+        // Safe because our caller promises to poll the future to completion.
+        unsafe {
+            if tx_buf.is_empty() {
+                // Special case sending an empty packet, to avoid adding an empty buffer to the
+                // virtqueue.
+                self.send_queue
+                    .add_notify_pop_async(
+                        &[header.as_bytes()],
+                        &mut [],
+                        self.transport.get_mut(),
+                        waker,
+                    )
+                    .await?;
+            } else {
+                self.send_queue
+                    .add_notify_pop_async(
+                        &[header.as_bytes(), tx_buf],
+                        &mut [],
+                        self.transport.get_mut(),
+                        waker,
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits asynchronously for a packet to be received.
+    ///
+    /// After completion, the `rx_buf` will contain a header followed by the
+    /// received packet. It returns the length of the header and the length of
+    /// the packet.
+    ///
+    /// As the returned future borrows the driver until it completes, the interrupt handler can't
+    /// call [`ack_interrupt`](Self::ack_interrupt) in the meantime, but should instead call
+    /// [`UsedWaker::wake`] on `waker` to wake it, as for [`VirtQueue::add_notify_pop_async`].
+    ///
+    /// This must not be used while buffers submitted with [`receive_begin`](Self::receive_begin)
+    /// are outstanding, as the future only completes once its own buffer is next to be popped.
+    ///
+    /// # Safety
+    ///
+    /// Once the returned future has been polled, it must be polled until it completes. It must not
+    /// be dropped or leaked (e.g. with [`core::mem::forget`]) before then, as the device may still
+    /// write to `rx_buf`.
+    pub async unsafe fn receive_async(
+        &mut self,
+        rx_buf: &mut [u8],
+        waker: &UsedWaker,
+    ) -> Result<(usize, usize)> {
+        check_rx_buf_len(rx_buf)?;
+        // Safe because our caller promises to poll the future to completion.
+        let len = unsafe {
+            self.recv_queue
+                .add_notify_pop_async(&[], &mut [rx_buf], self.transport.get_mut(), waker)
+                .await?
+        } as usize;
         let packet_len = len.checked_sub(NET_HDR_SIZE).ok_or(Error::IoError)?;
         Ok((NET_HDR_SIZE, packet_len))
     }
 
+    /// Blocks and waits for a packet to be received.
+    ///
+    /// After completion, the `rx_buf` will contain a header followed by the
+    /// received packet. It returns the length of the header and the length of
+    /// the packet.
+    pub fn receive_wait(&mut self, rx_buf: &mut [u8]) -> Result<(usize, usize)> {
+        self.split().1.receive_wait(rx_buf)
+    }
+
+    /// Like [`receive_wait`](Self::receive_wait), but gives up with [`Error::Timeout`] if no packet
+    /// is received within `timeout`, according to [`Hal::now`].
+    ///
+    /// On timeout the receive queue is reset so that the device stops accessing `rx_buf`, and must
+    /// be set up again with [`reset_receive_queue`](Self::reset_receive_queue) before receiving
+    /// anything else. Fails with [`Error::Unsupported`] if `VIRTIO_F_RING_RESET` hasn't been
+    /// negotiated, as then there would be no way to stop the device, or if the HAL doesn't have a
+    /// clock.
+    pub fn receive_wait_timeout(
+        &mut self,
+        rx_buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<(usize, usize)> {
+        if !self.negotiated_features.contains(Features::RING_RESET) {
+            return Err(Error::Unsupported);
+        }
+        let deadline = Deadline::<H>::after(timeout)?;
+        let token = unsafe { self.receive_begin(rx_buf)? };
+        while self.poll_receive().is_none() {
+            // If the transport can't reset the queue after all then the device may still access
+            // `rx_buf`, so all we can do is keep waiting for it.
+            if deadline.expired() && self.recv_queue.stop(self.transport.get_mut()).is_ok() {
+                // Safe because this is the same buffer as was passed to `receive_begin`, and the
+                // device is no longer accessing it.
+                unsafe { self.recv_queue.reclaim(token, &[], &mut [rx_buf]) }?;
+                return Err(Error::Timeout);
+            }
+            self.recv_queue.wait_used();
+        }
+        unsafe { self.receive_complete(token, rx_buf) }
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for VirtIONetRaw<H, T, QUEUE_SIZE> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        self.transport.get_mut().queue_unset(QUEUE_RECEIVE);
+        self.transport.get_mut().queue_unset(QUEUE_TRANSMIT);
+    }
+}
+
+/// The transmit half of a [split](VirtIONetRaw::split) [`VirtIONetRaw`].
+pub struct NetTx<'a, H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    transport: &'a SpinLock<T>,
+    /// The half of the transmit queue which adds packets.
+    producer: VirtQueueProducer<'a, H, QUEUE_SIZE>,
+    /// The half of the transmit queue which pops packets once they have been sent.
+    consumer: VirtQueueConsumer<'a, H, QUEUE_SIZE>,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> NetTx<'_, H, T, QUEUE_SIZE> {
+    /// Whether can send packet.
+    pub fn can_send(&self) -> bool {
+        self.producer.available_desc() >= 2
+    }
+
+    /// Submits a request to transmit a buffer immediately without waiting for
+    /// the transmission to complete, as for [`VirtIONetRaw::transmit_begin`].
+    ///
+    /// # Safety
+    ///
+    /// `tx_buf` is still borrowed by the underlying VirtIO net device even after
+    /// this method returns. Thus, it is the caller's responsibility to guarantee
+    /// that they are not accessed before the request is completed in order to
+    /// avoid data races.
+    pub unsafe fn transmit_begin(&mut self, tx_buf: &[u8]) -> Result<u16> {
+        let token = self.transmit_begin_deferred(tx_buf)?;
+        self.transmit_submit();
+        Ok(token)
+    }
+
+    /// Submits a request to transmit a buffer without making it available to the device yet, as
+    /// for [`VirtIONetRaw::transmit_begin_deferred`].
+    ///
+    /// # Safety
+    ///
+    /// `tx_buf` is still borrowed by the underlying VirtIO net device even after
+    /// this method returns. Thus, it is the caller's responsibility to guarantee
+    /// that they are not accessed before the request is completed in order to
+    /// avoid data races.
+    pub unsafe fn transmit_begin_deferred(&mut self, tx_buf: &[u8]) -> Result<u16> {
+        check_tx_buf_len(tx_buf)?;
+        self.producer.add_deferred(&[tx_buf], &mut [])
+    }
+
+    /// Makes all deferred transmission requests available to the device, and notifies it at most
+    /// once.
+    pub fn transmit_submit(&mut self) {
+        self.producer.publish_notify(&mut *self.transport.lock());
+    }
+
+    /// Fetches the token of the next completed transmission request from the
+    /// used ring and returns it, without removing it from the used ring. If
+    /// there are no pending completed requests it returns [`None`].
+    pub fn poll_transmit(&self) -> Option<u16> {
+        self.consumer.peek_used()
+    }
+
+    /// Completes a transmission operation which was started by [`transmit_begin`].
+    /// Returns number of bytes transmitted.
+    ///
+    /// # Safety
+    ///
+    /// The same buffer must be passed in again as was passed to
+    /// [`transmit_begin`] when it returned the token.
+    ///
+    /// [`transmit_begin`]: Self::transmit_begin
+    pub unsafe fn transmit_complete(&mut self, token: u16, tx_buf: &[u8]) -> Result<usize> {
+        let len = self.consumer.pop_used(token, &[tx_buf], &mut [])?;
+        Ok(len as usize)
+    }
+
     /// Sends a packet to the network, and blocks until the request completed.
     pub fn send(&mut self, tx_buf: &[u8]) -> Result {
         let header = VirtioNetHdr::default();
-        if tx_buf.is_empty() {
+        let inputs: &[&[u8]] = if tx_buf.is_empty() {
             // Special case sending an empty packet, to avoid adding an empty buffer to the
             // virtqueue.
-            self.send_queue.add_notify_wait_pop(
-                &[header.as_bytes()],
-                &mut [],
-                &mut self.transport,
-            )?;
+            &[header.as_bytes()]
         } else {
-            self.send_queue.add_notify_wait_pop(
-                &[header.as_bytes(), tx_buf],
-                &mut [],
-                &mut self.transport,
-            )?;
+            &[header.as_bytes(), tx_buf]
+        };
+        // Safe because we don't return until the token has been popped, so the buffers remain
+        // valid and are not otherwise accessed until then.
+        let token = unsafe { self.producer.add(inputs, &mut []) }?;
+        if self.producer.should_notify() {
+            self.producer.notify(&mut *self.transport.lock());
+        }
+        while !self.consumer.can_pop() {
+            self.consumer.wait_used();
         }
+        // Safe because these are the same buffers as were passed to `add` above.
+        unsafe { self.consumer.pop_used(token, inputs, &mut []) }?;
         Ok(())
     }
 
+    /// Transmits a buffer which starts with a [`VirtioNetHdr`] followed by the packet, and blocks
+    /// until the transmission completes. Returns the number of bytes transmitted.
+    pub fn transmit_wait(&mut self, tx_buf: &[u8]) -> Result<usize> {
+        // Safe because we don't return until the token has been popped.
+        let token = unsafe { self.transmit_begin(tx_buf)? };
+        while self.poll_transmit().is_none() {
+            self.consumer.wait_used();
+        }
+        // Safe because this is the same buffer as was passed to `transmit_begin`.
+        unsafe { self.transmit_complete(token, tx_buf) }
+    }
+}
+
+/// The receive half of a [split](VirtIONetRaw::split) [`VirtIONetRaw`].
+pub struct NetRx<'a, H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    transport: &'a SpinLock<T>,
+    /// The half of the receive queue which adds buffers for the device to fill.
+    producer: VirtQueueProducer<'a, H, QUEUE_SIZE>,
+    /// The half of the receive queue which pops buffers once they have been filled.
+    consumer: VirtQueueConsumer<'a, H, QUEUE_SIZE>,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> NetRx<'_, H, T, QUEUE_SIZE> {
+    /// Submits a request to receive a buffer immediately without waiting for
+    /// the reception to complete, as for [`VirtIONetRaw::receive_begin`].
+    ///
+    /// # Safety
+    ///
+    /// `rx_buf` is still borrowed by the underlying VirtIO net device even after
+    /// this method returns. Thus, it is the caller's responsibility to guarantee
+    /// that they are not accessed before the request is completed in order to
+    /// avoid data races.
+    pub unsafe fn receive_begin(&mut self, rx_buf: &mut [u8]) -> Result<u16> {
+        check_rx_buf_len(rx_buf)?;
+        let token = self.producer.add(&[], &mut [rx_buf])?;
+        if self.producer.should_notify() {
+            self.producer.notify(&mut *self.transport.lock());
+        }
+        Ok(token)
+    }
+
+    /// Fetches the token of the next completed reception request from the
+    /// used ring and returns it, without removing it from the used ring. If
+    /// there are no pending completed requests it returns [`None`].
+    pub fn poll_receive(&self) -> Option<u16> {
+        self.consumer.peek_used()
+    }
+
+    /// Completes a reception operation which was started by [`receive_begin`].
+    ///
+    /// After completion, the `rx_buf` will contain a header followed by the
+    /// received packet. It returns the length of the header and the length of
+    /// the packet.
+    ///
+    /// # Safety
+    ///
+    /// The same buffer must be passed in again as was passed to
+    /// [`receive_begin`] when it returned the token.
+    ///
+    /// [`receive_begin`]: Self::receive_begin
+    pub unsafe fn receive_complete(
+        &mut self,
+        token: u16,
+        rx_buf: &mut [u8],
+    ) -> Result<(usize, usize)> {
+        let len = self.consumer.pop_used(token, &[], &mut [rx_buf])? as usize;
+        let packet_len = len.checked_sub(NET_HDR_SIZE).ok_or(Error::IoError)?;
+        Ok((NET_HDR_SIZE, packet_len))
+    }
+
     /// Blocks and waits for a packet to be received.
     ///
     /// After completion, the `rx_buf` will contain a header followed by the
@@ -265,17 +691,28 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     pub fn receive_wait(&mut self, rx_buf: &mut [u8]) -> Result<(usize, usize)> {
         let token = unsafe { self.receive_begin(rx_buf)? };
         while self.poll_receive().is_none() {
-            core::hint::spin_loop();
+            self.consumer.wait_used();
         }
         unsafe { self.receive_complete(token, rx_buf) }
     }
 }
 
-impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for VirtIONetRaw<H, T, QUEUE_SIZE> {
-    fn drop(&mut self) {
-        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(QUEUE_RECEIVE);
-        self.transport.queue_unset(QUEUE_TRANSMIT);
+/// Whether the length of the receive buffer is valid.
+fn check_rx_buf_len(rx_buf: &[u8]) -> Result<()> {
+    if rx_buf.len() < MIN_BUFFER_LEN {
+        warn!("Receive buffer len {} is too small", rx_buf.len());
+        Err(Error::InvalidParam)
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether the length of the transmit buffer is valid.
+fn check_tx_buf_len(tx_buf: &[u8]) -> Result<()> {
+    if tx_buf.len() < NET_HDR_SIZE {
+        warn!("Transmit buffer len {} is too small", tx_buf.len());
+        Err(Error::InvalidParam)
+    } else {
+        Ok(())
     }
 }
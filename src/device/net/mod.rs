@@ -6,7 +6,7 @@ mod dev_raw;
 #[cfg(feature = "alloc")]
 mod net_buf;
 
-pub use self::dev_raw::VirtIONetRaw;
+pub use self::dev_raw::{NetRx, NetTx, VirtIONetRaw};
 #[cfg(feature = "alloc")]
 pub use self::{dev::VirtIONet, net_buf::RxBuffer, net_buf::TxBuffer};
 
@@ -77,6 +77,10 @@ bitflags! {
         const RING_INDIRECT_DESC = 1 << 28;
         const RING_EVENT_IDX = 1 << 29;
         const VERSION_1 = 1 << 32; // legacy
+        const RING_PACKED = 1 << 34;
+        const IN_ORDER = 1 << 35;
+        const NOTIFICATION_DATA = 1 << 38;
+        const RING_RESET = 1 << 40;
     }
 }
 
@@ -99,7 +103,7 @@ bitflags! {
 #[repr(C)]
 struct Config {
     mac: ReadOnly<EthernetAddress>,
-    status: ReadOnly<Status>,
+    status: ReadOnly<u16>,
     max_virtqueue_pairs: ReadOnly<u16>,
     mtu: ReadOnly<u16>,
 }
@@ -152,4 +156,8 @@ const QUEUE_RECEIVE: u16 = 0;
 const QUEUE_TRANSMIT: u16 = 1;
 const SUPPORTED_FEATURES: Features = Features::MAC
     .union(Features::STATUS)
-    .union(Features::RING_EVENT_IDX);
+    .union(Features::RING_EVENT_IDX)
+    .union(Features::RING_PACKED)
+    .union(Features::IN_ORDER)
+    .union(Features::NOTIFICATION_DATA)
+    .union(Features::RING_RESET);
@@ -1,45 +1,103 @@
 use super::{VirtioNetHdr, NET_HDR_SIZE};
+use crate::hal::Hal;
+use crate::queue::DmaBuffer;
 use alloc::{vec, vec::Vec};
-use core::{convert::TryInto, mem::size_of};
-use zerocopy::AsBytes;
+use core::convert::TryInto;
 
 /// A buffer used for transmitting.
-pub struct TxBuffer(pub(crate) Vec<u8>);
+///
+/// It holds a [`VirtioNetHdr`] followed by the packet, so that both can be passed to the device
+/// together.
+pub struct TxBuffer<H: Hal> {
+    buf: TxStorage<H>,
+    packet_len: usize,
+}
+
+/// The memory backing a [`TxBuffer`].
+enum TxStorage<H: Hal> {
+    /// A buffer from the driver's transmit pool, which is passed to the device directly.
+    Pool(DmaBuffer<H>),
+    /// A buffer on the heap, which is shared with the device when it is sent.
+    Heap(Vec<u8>),
+}
 
 /// A buffer used for receiving.
-pub struct RxBuffer {
-    pub(crate) buf: Vec<usize>, // for alignment
+pub struct RxBuffer<H: Hal> {
+    pub(crate) buf: DmaBuffer<H>,
     pub(crate) packet_len: usize,
     pub(crate) idx: u16,
 }
 
-impl TxBuffer {
+impl<H: Hal> TxBuffer<H> {
     /// Constructs the buffer from the given slice.
+    ///
+    /// The buffer is allocated on the heap, so it may be copied when it is sent. Use
+    /// [`VirtIONet::new_tx_buffer`](super::VirtIONet::new_tx_buffer) to avoid this.
     pub fn from(buf: &[u8]) -> Self {
-        Self(Vec::from(buf))
+        let mut bytes = vec![0; NET_HDR_SIZE + buf.len()];
+        bytes[NET_HDR_SIZE..].copy_from_slice(buf);
+        Self {
+            buf: TxStorage::Heap(bytes),
+            packet_len: buf.len(),
+        }
+    }
+
+    /// Uses a buffer from the transmit pool for a packet of the given length, which must fit in it
+    /// after the header. The header and packet are zeroed.
+    pub(crate) fn from_pool(mut buf: DmaBuffer<H>, packet_len: usize) -> Self {
+        buf[..NET_HDR_SIZE + packet_len].fill(0);
+        Self {
+            buf: TxStorage::Pool(buf),
+            packet_len,
+        }
+    }
+
+    /// Allocates a zeroed buffer on the heap for a packet of the given length.
+    pub(crate) fn new_heap(packet_len: usize) -> Self {
+        Self {
+            buf: TxStorage::Heap(vec![0; NET_HDR_SIZE + packet_len]),
+            packet_len,
+        }
     }
 
     /// Returns the network packet length.
     pub fn packet_len(&self) -> usize {
-        self.0.len()
+        self.packet_len
     }
 
     /// Returns the network packet as a slice.
     pub fn packet(&self) -> &[u8] {
-        self.0.as_slice()
+        &self.as_bytes()[NET_HDR_SIZE..]
     }
 
     /// Returns the network packet as a mutable slice.
     pub fn packet_mut(&mut self) -> &mut [u8] {
-        self.0.as_mut_slice()
+        &mut self.as_bytes_mut()[NET_HDR_SIZE..]
+    }
+
+    /// Returns the header followed by the packet.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        let len = NET_HDR_SIZE + self.packet_len;
+        match &self.buf {
+            TxStorage::Pool(buf) => &buf[..len],
+            TxStorage::Heap(buf) => &buf[..len],
+        }
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        let len = NET_HDR_SIZE + self.packet_len;
+        match &mut self.buf {
+            TxStorage::Pool(buf) => &mut buf[..len],
+            TxStorage::Heap(buf) => &mut buf[..len],
+        }
     }
 }
 
-impl RxBuffer {
-    /// Allocates a new buffer with length `buf_len`.
-    pub(crate) fn new(idx: usize, buf_len: usize) -> Self {
+impl<H: Hal> RxBuffer<H> {
+    /// Wraps a buffer from the receive pool.
+    pub(crate) fn new(idx: usize, buf: DmaBuffer<H>) -> Self {
         Self {
-            buf: vec![0; buf_len / size_of::<usize>()],
+            buf,
             packet_len: 0,
             idx: idx.try_into().unwrap(),
         }
@@ -57,27 +115,28 @@ impl RxBuffer {
 
     /// Returns all data in the buffer, including both the header and the packet.
     pub fn as_bytes(&self) -> &[u8] {
-        self.buf.as_bytes()
+        &self.buf
     }
 
     /// Returns all data in the buffer with the mutable reference,
     /// including both the header and the packet.
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        self.buf.as_bytes_mut()
+        &mut self.buf
     }
 
     /// Returns the reference of the header.
     pub fn header(&self) -> &VirtioNetHdr {
+        // Safe because buffers from a `DmaPool` are aligned to at least `align_of::<usize>()`.
         unsafe { &*(self.buf.as_ptr() as *const VirtioNetHdr) }
     }
 
     /// Returns the network packet as a slice.
     pub fn packet(&self) -> &[u8] {
-        &self.buf.as_bytes()[NET_HDR_SIZE..NET_HDR_SIZE + self.packet_len]
+        &self.buf[NET_HDR_SIZE..NET_HDR_SIZE + self.packet_len]
     }
 
     /// Returns the network packet as a mutable slice.
     pub fn packet_mut(&mut self) -> &mut [u8] {
-        &mut self.buf.as_bytes_mut()[NET_HDR_SIZE..NET_HDR_SIZE + self.packet_len]
+        &mut self.buf[NET_HDR_SIZE..NET_HDR_SIZE + self.packet_len]
     }
 }
@@ -2,11 +2,13 @@ use super::{
     protocol::VsockAddr, vsock::ConnectionInfo, DisconnectReason, SocketError, VirtIOSocket,
     VsockEvent, VsockEventType,
 };
-use crate::{transport::Transport, Hal, Result};
+#[cfg(feature = "stats")]
+use crate::queue::{QueueStats, QueueTracer};
+use crate::{hal::Deadline, transport::Transport, Error, Hal, Result};
 use alloc::{boxed::Box, vec::Vec};
 use core::cmp::min;
 use core::convert::TryInto;
-use core::hint::spin_loop;
+use core::time::Duration;
 use log::debug;
 use zerocopy::FromZeroes;
 
@@ -82,6 +84,19 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
         self.driver.guest_cid()
     }
 
+    /// Returns the sum of the counters of all of the device's queues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.driver.stats()
+    }
+
+    /// Sets a tracer to be called for each operation on any of the device's queues, or removes
+    /// it.
+    #[cfg(feature = "stats")]
+    pub fn set_tracer(&mut self, tracer: Option<&'static dyn QueueTracer>) {
+        self.driver.set_tracer(tracer)
+    }
+
     /// Allows incoming connections on the given port number.
     pub fn listen(&mut self, port: u32) {
         if !self.listening_ports.contains(&port) {
@@ -241,13 +256,34 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
         self.driver.credit_update(&connection.info)
     }
 
-    /// Blocks until we get some event from the vsock device.
+    /// Blocks until we get some event from the vsock device, calling [`Hal::wait_for_interrupt`]
+    /// between polls.
     pub fn wait_for_event(&mut self) -> Result<VsockEvent> {
         loop {
             if let Some(event) = self.poll()? {
                 return Ok(event);
             } else {
-                spin_loop();
+                H::wait_for_interrupt();
+            }
+        }
+    }
+
+    /// Blocks until we get some event from the vsock device, or returns [`Error::Timeout`] if
+    /// there is none within `timeout`, according to [`Hal::now`]. Fails with
+    /// [`Error::Unsupported`] if the HAL doesn't have a clock.
+    ///
+    /// As for [`wait_for_event`](Self::wait_for_event), this calls [`Hal::wait_for_interrupt`]
+    /// between polls. The receive buffers stay with the device on timeout, so this may be called
+    /// again.
+    pub fn wait_for_event_timeout(&mut self, timeout: Duration) -> Result<VsockEvent> {
+        let deadline = Deadline::<H>::after(timeout)?;
+        loop {
+            if let Some(event) = self.poll()? {
+                return Ok(event);
+            } else if deadline.expired() {
+                return Err(Error::Timeout);
+            } else {
+                H::wait_for_interrupt();
             }
         }
     }
@@ -390,7 +426,7 @@ mod tests {
     use crate::{
         device::socket::{
             protocol::{SocketType, VirtioVsockConfig, VirtioVsockHdr, VirtioVsockOp},
-            vsock::{VsockBufferStatus, QUEUE_SIZE, RX_QUEUE_IDX, TX_QUEUE_IDX},
+            vsock::{VsockBufferStatus, RX_QUEUE_IDX, TX_QUEUE_IDX},
         },
         hal::fake::FakeHal,
         transport::{
@@ -404,6 +440,38 @@ mod tests {
     use std::{sync::Mutex, thread};
     use zerocopy::{AsBytes, FromBytes};
 
+    #[test]
+    fn wait_for_event_timeout() {
+        let mut config_space = VirtioVsockConfig {
+            guest_cid_low: ReadOnly::new(66),
+            guest_cid_high: ReadOnly::new(0),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Socket,
+            max_queue_size: 32,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state,
+        };
+        let mut socket = VsockConnectionManager::new(
+            VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap(),
+        );
+
+        assert_eq!(
+            socket.wait_for_event_timeout(Duration::from_millis(10)),
+            Err(Error::Timeout)
+        );
+        assert_eq!(socket.poll(), Ok(None));
+    }
+
     #[test]
     fn send_recv() {
         let host_cid = 2;
@@ -449,7 +517,7 @@ mod tests {
                     state
                         .lock()
                         .unwrap()
-                        .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX)
+                        .read_from_queue(TX_QUEUE_IDX)
                         .as_slice()
                 )
                 .unwrap(),
@@ -468,7 +536,7 @@ mod tests {
             );
 
             // Accept connection and give the peer enough credit to send the message.
-            state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+            state.lock().unwrap().write_to_queue(
                 RX_QUEUE_IDX,
                 VirtioVsockHdr {
                     op: VirtioVsockOp::Response.into(),
@@ -487,10 +555,7 @@ mod tests {
 
             // Expect the guest to send some data.
             State::wait_until_queue_notified(&state, TX_QUEUE_IDX);
-            let request = state
-                .lock()
-                .unwrap()
-                .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX);
+            let request = state.lock().unwrap().read_from_queue(TX_QUEUE_IDX);
             assert_eq!(
                 request.len(),
                 size_of::<VirtioVsockHdr>() + hello_from_guest.len()
@@ -536,7 +601,7 @@ mod tests {
             state
                 .lock()
                 .unwrap()
-                .write_to_queue::<QUEUE_SIZE>(RX_QUEUE_IDX, &response);
+                .write_to_queue(RX_QUEUE_IDX, &response);
 
             // Expect a shutdown.
             State::wait_until_queue_notified(&state, TX_QUEUE_IDX);
@@ -545,7 +610,7 @@ mod tests {
                     state
                         .lock()
                         .unwrap()
-                        .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX)
+                        .read_from_queue(TX_QUEUE_IDX)
                         .as_slice()
                 )
                 .unwrap(),
@@ -658,7 +723,7 @@ mod tests {
         let handle = thread::spawn(move || {
             // Send a connection request for a port the guest isn't listening on.
             println!("Host sending connection request to wrong port");
-            state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+            state.lock().unwrap().write_to_queue(
                 RX_QUEUE_IDX,
                 VirtioVsockHdr {
                     op: VirtioVsockOp::Request.into(),
@@ -683,7 +748,7 @@ mod tests {
                     state
                         .lock()
                         .unwrap()
-                        .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX)
+                        .read_from_queue(TX_QUEUE_IDX)
                         .as_slice()
                 )
                 .unwrap(),
@@ -703,7 +768,7 @@ mod tests {
 
             // Send a connection request for a port the guest is listening on.
             println!("Host sending connection request to right port");
-            state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+            state.lock().unwrap().write_to_queue(
                 RX_QUEUE_IDX,
                 VirtioVsockHdr {
                     op: VirtioVsockOp::Request.into(),
@@ -728,7 +793,7 @@ mod tests {
                     state
                         .lock()
                         .unwrap()
-                        .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX)
+                        .read_from_queue(TX_QUEUE_IDX)
                         .as_slice()
                 )
                 .unwrap(),
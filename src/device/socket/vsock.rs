@@ -3,23 +3,26 @@
 
 use super::error::SocketError;
 use super::protocol::{Feature, VirtioVsockConfig, VirtioVsockHdr, VirtioVsockOp, VsockAddr};
-use crate::hal::Hal;
-use crate::queue::VirtQueue;
+use crate::config::read_config;
+use crate::hal::{BufferDirection, Hal};
+use crate::queue::{DmaBuffer, DmaPool, QueueOptions, UsedWaker, VirtQueue};
+#[cfg(feature = "stats")]
+use crate::queue::{QueueStats, QueueTracer};
 use crate::transport::Transport;
-use crate::volatile::volread;
 use crate::{Error, Result};
-use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::mem::size_of;
-use core::ptr::{null_mut, NonNull};
 use log::debug;
-use zerocopy::{AsBytes, FromBytes, FromZeroes};
+use zerocopy::{AsBytes, FromBytes};
 
 pub(crate) const RX_QUEUE_IDX: u16 = 0;
 pub(crate) const TX_QUEUE_IDX: u16 = 1;
 const EVENT_QUEUE_IDX: u16 = 2;
 
 pub(crate) const QUEUE_SIZE: usize = 8;
-const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX;
+const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX
+    .union(Feature::RING_PACKED)
+    .union(Feature::NOTIFICATION_DATA);
 
 /// The size in bytes of each buffer used in the RX virtqueue. This must be bigger than size_of::<VirtioVsockHdr>().
 const RX_BUFFER_SIZE: usize = 512;
@@ -220,19 +223,9 @@ pub struct VirtIOSocket<H: Hal, T: Transport> {
     /// The guest_cid field contains the guest’s context ID, which uniquely identifies
     /// the device for its lifetime. The upper 32 bits of the CID are reserved and zeroed.
     guest_cid: u64,
-    rx_queue_buffers: [NonNull<[u8; RX_BUFFER_SIZE]>; QUEUE_SIZE],
-}
-
-// SAFETY: The `rx_queue_buffers` can be accessed from any thread.
-unsafe impl<H: Hal, T: Transport + Send> Send for VirtIOSocket<H, T> where
-    VirtQueue<H, QUEUE_SIZE>: Send
-{
-}
-
-// SAFETY: A `&VirtIOSocket` only allows reading the guest CID from a field.
-unsafe impl<H: Hal, T: Transport + Sync> Sync for VirtIOSocket<H, T> where
-    VirtQueue<H, QUEUE_SIZE>: Sync
-{
+    /// The buffers of the RX queue, indexed by token. They are allocated from a pool which is set
+    /// on the RX queue, so packets are received into them directly.
+    rx_queue_buffers: Vec<DmaBuffer<H>>,
 }
 
 impl<H: Hal, T: Transport> Drop for VirtIOSocket<H, T> {
@@ -242,12 +235,6 @@ impl<H: Hal, T: Transport> Drop for VirtIOSocket<H, T> {
         self.transport.queue_unset(RX_QUEUE_IDX);
         self.transport.queue_unset(TX_QUEUE_IDX);
         self.transport.queue_unset(EVENT_QUEUE_IDX);
-
-        for buffer in self.rx_queue_buffers {
-            // Safe because we obtained the RX buffer pointer from Box::into_raw, and it won't be
-            // used anywhere else after the driver is destroyed.
-            unsafe { drop(Box::from_raw(buffer.as_ptr())) };
-        }
     }
 }
 
@@ -256,48 +243,36 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
     pub fn new(mut transport: T) -> Result<Self> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
-        let config = transport.config_space::<VirtioVsockConfig>()?;
-        debug!("config: {:?}", config);
-        // Safe because config is a valid pointer to the device configuration space.
-        let guest_cid = unsafe {
-            volread!(config, guest_cid_low) as u64 | (volread!(config, guest_cid_high) as u64) << 32
-        };
+        let guest_cid = transport.read_config(|| {
+            Ok(
+                read_config!(transport, VirtioVsockConfig, guest_cid_low)? as u64
+                    | (read_config!(transport, VirtioVsockConfig, guest_cid_high)? as u64) << 32,
+            )
+        })?;
         debug!("guest cid: {guest_cid:?}");
 
-        let mut rx = VirtQueue::new(
-            &mut transport,
-            RX_QUEUE_IDX,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
-        let tx = VirtQueue::new(
-            &mut transport,
-            TX_QUEUE_IDX,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
-        let event = VirtQueue::new(
-            &mut transport,
-            EVENT_QUEUE_IDX,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
+        let options = QueueOptions::from_features(negotiated_features.bits());
+        let mut rx = VirtQueue::new(&mut transport, RX_QUEUE_IDX, options)?;
+        let tx = VirtQueue::new(&mut transport, TX_QUEUE_IDX, options)?;
+        let event = VirtQueue::new(&mut transport, EVENT_QUEUE_IDX, options)?;
 
         // Allocate and add buffers for the RX queue.
-        let mut rx_queue_buffers = [null_mut(); QUEUE_SIZE];
-        for (i, rx_queue_buffer) in rx_queue_buffers.iter_mut().enumerate() {
-            let mut buffer: Box<[u8; RX_BUFFER_SIZE]> = FromZeroes::new_box_zeroed();
-            // Safe because the buffer lives as long as the queue, as specified in the function
-            // safety requirement, and we don't access it until it is popped.
-            let token = unsafe { rx.add(&[], &mut [buffer.as_mut_slice()]) }?;
+        let rx_pool = DmaPool::new(QUEUE_SIZE, RX_BUFFER_SIZE, BufferDirection::DeviceToDriver)?;
+        rx.set_dma_pool(Some(rx_pool.clone()))?;
+        let mut rx_queue_buffers = Vec::with_capacity(QUEUE_SIZE);
+        for i in 0..QUEUE_SIZE {
+            // The pool has a buffer for every queue entry.
+            let mut buffer = rx_pool.alloc().unwrap();
+            // Safe because the buffer lives as long as the queue, and we don't access it until it
+            // is popped.
+            let token = unsafe { rx.add(&[], &mut [&mut buffer]) }?;
             assert_eq!(i, token.into());
-            *rx_queue_buffer = Box::into_raw(buffer);
+            rx_queue_buffers.push(buffer);
         }
-        let rx_queue_buffers = rx_queue_buffers.map(|ptr| NonNull::new(ptr).unwrap());
 
         transport.finish_init();
         if rx.should_notify() {
-            transport.notify(RX_QUEUE_IDX);
+            rx.notify(&mut transport);
         }
 
         Ok(Self {
@@ -315,6 +290,31 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         self.guest_cid
     }
 
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    /// Returns the sum of the counters of all of the device's queues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        [&self.rx, &self.tx, &self.event]
+            .iter()
+            .map(|queue| queue.stats())
+            .sum()
+    }
+
+    /// Sets a tracer to be called for each operation on any of the device's queues, or removes
+    /// it.
+    #[cfg(feature = "stats")]
+    pub fn set_tracer(&mut self, tracer: Option<&'static dyn QueueTracer>) {
+        self.rx.set_tracer(tracer);
+        self.tx.set_tracer(tracer);
+        self.event.set_tracer(tracer);
+    }
+
     /// Sends a request to connect to the given destination.
     ///
     /// This returns as soon as the request is sent; you should wait until `poll` returns a
@@ -362,6 +362,56 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         self.send_packet_to_tx_queue(&header, buffer)
     }
 
+    /// Sends the buffer to the destination asynchronously.
+    ///
+    /// The returned future resolves once the device has taken the packet. As it borrows the driver
+    /// until then, the interrupt handler can't call [`ack_interrupt`](Self::ack_interrupt) in the
+    /// meantime, but should instead call [`UsedWaker::wake`] on `waker` to wake it, as for
+    /// [`VirtQueue::add_notify_pop_async`].
+    ///
+    /// # Safety
+    ///
+    /// Once the returned future has been polled, it must be polled until it completes. It must not
+    /// be dropped or leaked (e.g. with [`core::mem::forget`]) before then, as the device may still
+    /// be reading from `buffer`.
+    pub async unsafe fn send_async(
+        &mut self,
+        buffer: &[u8],
+        connection_info: &mut ConnectionInfo,
+        waker: &UsedWaker,
+    ) -> Result {
+        if (connection_info.peer_free() as usize) < buffer.len() {
+            // Request an update of the cached peer credit, if we haven't already done so, and tell
+            // the caller to try again later.
+            if !connection_info.has_pending_credit_request {
+                let header = VirtioVsockHdr {
+                    op: VirtioVsockOp::CreditRequest.into(),
+                    ..connection_info.new_header(self.guest_cid)
+                };
+                // Safe because our caller promises to poll the future to completion.
+                unsafe {
+                    self.send_packet_to_tx_queue_async(&header, &[], waker)
+                        .await
+                }?;
+                connection_info.has_pending_credit_request = true;
+            }
+            return Err(SocketError::InsufficientBufferSpaceInPeer.into());
+        }
+
+        let len = buffer.len() as u32;
+        let header = VirtioVsockHdr {
+            op: VirtioVsockOp::Rw.into(),
+            len: len.into(),
+            ..connection_info.new_header(self.guest_cid)
+        };
+        connection_info.tx_cnt += len;
+        // Safe because our caller promises to poll the future to completion.
+        unsafe {
+            self.send_packet_to_tx_queue_async(&header, buffer, waker)
+                .await
+        }
+    }
+
     fn check_peer_buffer_is_sufficient(
         &mut self,
         connection_info: &mut ConnectionInfo,
@@ -446,6 +496,37 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         Ok(())
     }
 
+    /// Sends the packet to the TX queue and asynchronously waits for the device to take it.
+    ///
+    /// # Safety
+    ///
+    /// The returned future must be polled to completion once it has been polled.
+    async unsafe fn send_packet_to_tx_queue_async(
+        &mut self,
+        header: &VirtioVsockHdr,
+        buffer: &[u8],
+        waker: &UsedWaker,
+    ) -> Result {
+        // Safe because our caller promises to poll the future to completion.
+        let _len = unsafe {
+            if buffer.is_empty() {
+                self.tx
+                    .add_notify_pop_async(&[header.as_bytes()], &mut [], &mut self.transport, waker)
+                    .await?
+            } else {
+                self.tx
+                    .add_notify_pop_async(
+                        &[header.as_bytes(), buffer],
+                        &mut [],
+                        &mut self.transport,
+                        waker,
+                    )
+                    .await?
+            }
+        };
+        Ok(())
+    }
+
     /// Adds the buffer at the given index in `rx_queue_buffers` back to the RX queue.
     ///
     /// # Safety
@@ -459,8 +540,7 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
             let buffer = self
                 .rx_queue_buffers
                 .get_mut(usize::from(index))
-                .ok_or(Error::WrongToken)?
-                .as_mut();
+                .ok_or(Error::WrongToken)?;
             let new_token = self.rx.add(&[], &mut [buffer])?;
             // If the RX buffer somehow gets assigned a different token, then our safety assumptions
             // are broken and we can't safely continue to do anything with the device.
@@ -468,7 +548,7 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         }
 
         if self.rx.should_notify() {
-            self.transport.notify(RX_QUEUE_IDX);
+            self.rx.notify(&mut self.transport);
         }
 
         Ok(())
@@ -483,24 +563,24 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
             return Ok(None);
         };
 
+        let index = usize::from(token);
         // Safe because we maintain a consistent mapping of tokens to buffers, so we pass the same
         // buffer to `pop_used` as we previously passed to `add` for the token. Once we add the
         // buffer back to the RX queue then we don't access it again until next time it is popped.
-        let (header, body) = unsafe {
-            let buffer = self.rx_queue_buffers[usize::from(token)].as_mut();
-            let _len = self.rx.pop_used(token, &[], &mut [buffer])?;
-
-            // Read the header and body from the buffer. Don't check the result yet, because we need
-            // to add the buffer back to the queue either way.
-            let header_result = read_header_and_body(buffer);
-            if header_result.is_err() {
-                // If there was an error, add the buffer back immediately. Ignore any errors, as we
-                // need to return the first error.
-                let _ = self.add_buffer_to_rx_queue(token);
-            }
+        unsafe {
+            self.rx
+                .pop_used(token, &[], &mut [&mut self.rx_queue_buffers[index]])?;
+        }
 
-            header_result
-        }?;
+        // Check the header and body before returning a reference to the buffer, because if there
+        // is an error we need to add the buffer back to the queue immediately.
+        if let Err(e) = read_header_and_body(&self.rx_queue_buffers[index]) {
+            // Ignore any errors, as we need to return the first error.
+            // Safe because the buffer was just popped, and we don't refer to it again.
+            let _ = unsafe { self.add_buffer_to_rx_queue(token) };
+            return Err(e);
+        }
+        let (header, body) = read_header_and_body(&self.rx_queue_buffers[index])?;
 
         debug!("Received packet {:?}. Op {:?}", header, header.op());
         Ok(Some((header, body, token)))
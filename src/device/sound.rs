@@ -7,16 +7,20 @@ use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use bitflags::bitflags;
-use core::{fmt::Display, hint::spin_loop, mem, ops::RangeInclusive};
+use core::{fmt::Display, mem, ops::RangeInclusive, time::Duration};
 use log::{error, info, warn};
 use num_enum::{FromPrimitive, IntoPrimitive};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 type PCMStateResult<T = ()> = core::result::Result<T, StreamError>;
 
+#[cfg(feature = "stats")]
+use crate::queue::{QueueStats, QueueTracer};
 use crate::{
-    queue::VirtQueue,
+    config::read_config,
+    hal::Deadline,
+    queue::{QueueOptions, VirtQueue},
     transport::Transport,
-    volatile::{volread, ReadOnly},
+    volatile::ReadOnly,
     Error, Hal, Result, PAGE_SIZE,
 };
 
@@ -69,42 +73,28 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
             String::from(negotiated_features)
         );
 
-        let control_queue = VirtQueue::new(
-            &mut transport,
-            CONTROL_QUEUE_IDX,
-            negotiated_features.contains(SoundFeatures::VIRTIO_F_INDIRECT_DESC),
-            negotiated_features.contains(SoundFeatures::VIRTIO_F_EVENT_IDX),
-        )?;
+        // Buffers aren't necessarily popped in the order they were added, so `VIRTIO_F_IN_ORDER`
+        // isn't used even if it was negotiated.
+        let options = QueueOptions {
+            in_order: false,
+            ..QueueOptions::from_features(negotiated_features.bits())
+        };
+
+        let control_queue = VirtQueue::new(&mut transport, CONTROL_QUEUE_IDX, options)?;
         // The driver MUST populate the event queue
         // with empty buffers of at least the struct virtio_snd_event size(struct VirtIOSndEvent Size in config.rs)
-        let event_queue = VirtQueue::new(
-            &mut transport,
-            EVENT_QUEUE_IDX,
-            negotiated_features.contains(SoundFeatures::VIRTIO_F_INDIRECT_DESC),
-            negotiated_features.contains(SoundFeatures::VIRTIO_F_EVENT_IDX),
-        )?;
-        let tx_queue = VirtQueue::new(
-            &mut transport,
-            TX_QUEUE_IDX,
-            negotiated_features.contains(SoundFeatures::VIRTIO_F_INDIRECT_DESC),
-            negotiated_features.contains(SoundFeatures::VIRTIO_F_EVENT_IDX),
-        )?;
-        let rx_queue = VirtQueue::new(
-            &mut transport,
-            RX_QUEUE_IDX,
-            negotiated_features.contains(SoundFeatures::VIRTIO_F_INDIRECT_DESC),
-            negotiated_features.contains(SoundFeatures::VIRTIO_F_EVENT_IDX),
-        )?;
+        let event_queue = VirtQueue::new(&mut transport, EVENT_QUEUE_IDX, options)?;
+        let tx_queue = VirtQueue::new(&mut transport, TX_QUEUE_IDX, options)?;
+        let rx_queue = VirtQueue::new(&mut transport, RX_QUEUE_IDX, options)?;
 
         // read configuration space
-        let config_ptr = transport.config_space::<VirtIOSoundConfig>()?;
-        let (jacks, streams, chmaps) = unsafe {
-            (
-                volread!(config_ptr, jacks),
-                volread!(config_ptr, streams),
-                volread!(config_ptr, chmaps),
-            )
-        };
+        let (jacks, streams, chmaps) = transport.read_config(|| {
+            Ok((
+                read_config!(transport, VirtIOSoundConfig, jacks)?,
+                read_config!(transport, VirtIOSoundConfig, streams)?,
+                read_config!(transport, VirtIOSoundConfig, chmaps)?,
+            ))
+        })?;
         info!(
             "[sound device] config: jacks: {}, streams: {}, chmaps: {}",
             jacks, streams, chmaps
@@ -169,6 +159,30 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
         self.transport.ack_interrupt()
     }
 
+    /// Returns the sum of the counters of all of the device's queues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        [
+            &self.control_queue,
+            &self.event_queue,
+            &self.tx_queue,
+            &self.rx_queue,
+        ]
+        .iter()
+        .map(|queue| queue.stats())
+        .sum()
+    }
+
+    /// Sets a tracer to be called for each operation on any of the device's queues, or removes
+    /// it.
+    #[cfg(feature = "stats")]
+    pub fn set_tracer(&mut self, tracer: Option<&'static dyn QueueTracer>) {
+        self.control_queue.set_tracer(tracer);
+        self.event_queue.set_tracer(tracer);
+        self.tx_queue.set_tracer(tracer);
+        self.rx_queue.set_tracer(tracer);
+    }
+
     fn request<Req: AsBytes, Rsp: FromBytes>(&mut self, req: Req) -> Result<Rsp> {
         req.write_to_prefix(&mut self.queue_buf_send).unwrap();
         self.control_queue.add_notify_wait_pop(
@@ -502,6 +516,30 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
     /// 
     /// This is a blocking method that will not return until the audio playback is complete.
     pub fn pcm_xfer(&mut self, stream_id: u32, frames: &[u8]) -> Result {
+        self.pcm_xfer_until(stream_id, frames, None)
+    }
+
+    /// Transfer PCM frame to device like [`pcm_xfer`](Self::pcm_xfer), but gives up with
+    /// [`Error::Timeout`] if the device stops using the buffers for `timeout`, according to
+    /// [`Hal::now`].
+    ///
+    /// On timeout the TX queue is reset so the device stops accessing the buffers. Fails with
+    /// [`Error::Unsupported`] if `VIRTIO_F_RING_RESET` hasn't been negotiated, as then there would
+    /// be no way to stop it, or if the HAL doesn't have a clock.
+    pub fn pcm_xfer_timeout(&mut self, stream_id: u32, frames: &[u8], timeout: Duration) -> Result {
+        if !self.negotiated_features.contains(SoundFeatures::VIRTIO_F_RING_RESET) {
+            return Err(Error::Unsupported);
+        }
+        let deadline = Deadline::after(timeout)?;
+        self.pcm_xfer_until(stream_id, frames, Some(&deadline))
+    }
+
+    fn pcm_xfer_until(
+        &mut self,
+        stream_id: u32,
+        frames: &[u8],
+        deadline: Option<&Deadline<H>>,
+    ) -> Result {
         const U32_SIZE: usize = mem::size_of::<u32>();
         if !self.set_up {
             self.set_up();
@@ -537,7 +575,7 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
 
         for i in 2..xfer_times {
             if self.tx_queue.should_notify() {
-                self.transport.notify(TX_QUEUE_IDX);
+                self.tx_queue.notify(&mut self.transport);
             }
 
             let start_byte = i * buffer_size;
@@ -547,23 +585,13 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
                 frames.len()
             };
             if turn1 {
-                while let Err(_) = unsafe {
-                    self.tx_queue
-                        .pop_used(token1, &[&buf1], &mut [&mut outputs])
-                } {
-                    spin_loop();
-                }
+                self.pop_xfer((token1, &buf1), (token2, &buf2), &mut outputs, deadline)?;
                 turn1 = false;
                 buf1[U32_SIZE..U32_SIZE + end_byte - start_byte]
                     .copy_from_slice(&frames[start_byte..end_byte]);
                 token1 = unsafe { self.tx_queue.add(&[&buf1], &mut [&mut outputs]).unwrap() }
             } else {
-                while let Err(_) = unsafe {
-                    self.tx_queue
-                        .pop_used(token2, &[&buf2], &mut [&mut outputs])
-                } {
-                    spin_loop();
-                }
+                self.pop_xfer((token2, &buf2), (token1, &buf1), &mut outputs, deadline)?;
                 turn1 = true;
                 buf2[U32_SIZE..U32_SIZE + end_byte - start_byte]
                     .copy_from_slice(&frames[start_byte..end_byte]);
@@ -572,19 +600,37 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
         }
         // wait for the last buffer
         if turn1 {
-            while let Err(_) = unsafe {
-                self.tx_queue
-                    .pop_used(token1, &[&buf1], &mut [&mut outputs])
-            } {
-                spin_loop();
-            }
+            self.pop_xfer((token1, &buf1), (token2, &buf2), &mut outputs, deadline)?;
         } else {
-            while let Err(_) = unsafe {
-                self.tx_queue
-                    .pop_used(token2, &[&buf2], &mut [&mut outputs])
-            } {
-                spin_loop();
+            self.pop_xfer((token2, &buf2), (token1, &buf1), &mut outputs, deadline)?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the device to use the `next` PCM transfer and pops it.
+    ///
+    /// If the deadline passes first, the TX queue is stopped and both the `next` and `other`
+    /// outstanding transfers are reclaimed before returning [`Error::Timeout`]. If the queue can't
+    /// be stopped then the device may still access the buffers, so this keeps waiting for it.
+    fn pop_xfer(
+        &mut self,
+        next: (u16, &[u8]),
+        other: (u16, &[u8]),
+        outputs: &mut [u8],
+        deadline: Option<&Deadline<H>>,
+    ) -> Result {
+        while unsafe { self.tx_queue.pop_used(next.0, &[next.1], &mut [outputs]) }.is_err() {
+            if deadline.is_some_and(Deadline::expired)
+                && self.tx_queue.stop(&mut self.transport).is_ok()
+            {
+                for (token, buf) in [next, other] {
+                    // Safe because these are the buffers which were added with the tokens, and the
+                    // device is no longer accessing them.
+                    unsafe { self.tx_queue.reclaim(token, &[buf], &mut [outputs]) }?;
+                }
+                return Err(Error::Timeout);
             }
+            self.tx_queue.wait_used();
         }
         Ok(())
     }
@@ -605,7 +651,7 @@ impl<H: Hal, T: Transport> VirtIOSound<H, T> {
         buf[U32_SIZE..U32_SIZE + buffer_size].copy_from_slice(frames);
         let token = unsafe { self.tx_queue.add(&[&buf], &mut [&mut self.output_rsp])? };
         if self.tx_queue.should_notify() {
-            self.transport.notify(TX_QUEUE_IDX);
+            self.tx_queue.notify(&mut self.transport);
         }
         self.token_buf.insert(token, buf);
         Ok(token)
@@ -1077,6 +1123,7 @@ impl From<SoundFeatures> for String {
 }
 
 #[derive(Debug, Clone, Copy)]
+#[repr(C)]
 struct VirtIOSoundConfig {
     jacks: ReadOnly<u32>,
     streams: ReadOnly<u32>,
@@ -2,7 +2,7 @@
 pub mod fake;
 
 use crate::{Error, Result, PAGE_SIZE};
-use core::{marker::PhantomData, ptr::NonNull};
+use core::{hint::spin_loop, marker::PhantomData, ptr::NonNull, time::Duration};
 
 /// A physical address as used for virtio.
 pub type PhysAddr = usize;
@@ -138,6 +138,116 @@ pub unsafe trait Hal {
     /// any other thread for the duration of this method call. The `paddr` must be the value
     /// previously returned by the corresponding `share` call.
     unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection);
+
+    /// Returns the current time of a monotonic clock, relative to some arbitrary fixed point, or
+    /// `None` if there is no clock available.
+    ///
+    /// This is used by the blocking methods which take a timeout, such as
+    /// [`VirtQueue::add_notify_wait_pop_timeout`](crate::queue::VirtQueue::add_notify_wait_pop_timeout).
+    /// They fail with [`Error::Unsupported`] if this returns `None`, which the default
+    /// implementation does.
+    fn now() -> Option<Duration> {
+        None
+    }
+
+    /// Waits for something to happen, such as an interrupt from a device, before a blocking method
+    /// checks again whether the device has used its buffers.
+    ///
+    /// Blocking methods such as
+    /// [`VirtQueue::add_notify_wait_pop`](crate::queue::VirtQueue::add_notify_wait_pop) enable
+    /// used buffer notifications on the queue before calling this, so an implementation may halt
+    /// the CPU until the next interrupt arrives (e.g. with `wfi` or `hlt`), or yield to a scheduler.
+    /// It may return early for any reason; blocking methods which take a timeout also rely on it
+    /// returning in time for them to check [`Hal::now`]. The default implementation just spins.
+    fn wait_for_interrupt() {
+        spin_loop();
+    }
+}
+
+/// Port I/O, for devices whose registers are in an I/O BAR.
+///
+/// This is only needed by the
+/// [`LegacyPciTransport`](crate::transport::pci::legacy::LegacyPciTransport) and
+/// [`PortCam`](crate::transport::pci::bus::PortCam). On x86 an implementation would use the `in`
+/// and `out` instructions, while on other architectures the PCI I/O space is usually mapped at some
+/// MMIO window.
+///
+/// # Safety
+///
+/// Implementations of this trait must access exactly the given I/O port with the given width, and
+/// nothing else.
+pub unsafe trait PortIoHal: Hal {
+    /// Reads a byte from the given I/O port.
+    ///
+    /// # Safety
+    ///
+    /// The port must belong to a device which the caller is driving, and be valid to read with the
+    /// given width.
+    unsafe fn port_read_u8(port: u16) -> u8;
+
+    /// Reads a 16-bit value from the given I/O port, as for [`PortIoHal::port_read_u8`].
+    ///
+    /// # Safety
+    ///
+    /// As for [`PortIoHal::port_read_u8`].
+    unsafe fn port_read_u16(port: u16) -> u16;
+
+    /// Reads a 32-bit value from the given I/O port, as for [`PortIoHal::port_read_u8`].
+    ///
+    /// # Safety
+    ///
+    /// As for [`PortIoHal::port_read_u8`].
+    unsafe fn port_read_u32(port: u16) -> u32;
+
+    /// Writes a byte to the given I/O port, as for [`PortIoHal::port_read_u8`].
+    ///
+    /// # Safety
+    ///
+    /// As for [`PortIoHal::port_read_u8`].
+    unsafe fn port_write_u8(port: u16, value: u8);
+
+    /// Writes a 16-bit value to the given I/O port, as for [`PortIoHal::port_read_u8`].
+    ///
+    /// # Safety
+    ///
+    /// As for [`PortIoHal::port_read_u8`].
+    unsafe fn port_write_u16(port: u16, value: u16);
+
+    /// Writes a 32-bit value to the given I/O port, as for [`PortIoHal::port_read_u8`].
+    ///
+    /// # Safety
+    ///
+    /// As for [`PortIoHal::port_read_u8`].
+    unsafe fn port_write_u32(port: u16, value: u32);
+}
+
+/// A point in time after which a blocking operation should give up, according to the clock of a
+/// [`Hal`].
+#[derive(Debug)]
+pub(crate) struct Deadline<H: Hal> {
+    end: Duration,
+    _hal: PhantomData<H>,
+}
+
+impl<H: Hal> Deadline<H> {
+    /// Returns the deadline `timeout` from now, or [`Error::Unsupported`] if the HAL doesn't have a
+    /// clock.
+    pub(crate) fn after(timeout: Duration) -> Result<Self> {
+        let now = H::now().ok_or(Error::Unsupported)?;
+        Ok(Self {
+            end: now.saturating_add(timeout),
+            _hal: PhantomData,
+        })
+    }
+
+    /// Returns whether the deadline has passed.
+    pub(crate) fn expired(&self) -> bool {
+        match H::now() {
+            Some(now) => now >= self.end,
+            // The clock has stopped working, so we can't wait any longer.
+            None => true,
+        }
+    }
 }
 
 /// The direction in which a buffer is passed.
@@ -7,7 +7,9 @@ use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error};
 use core::{
     alloc::Layout,
     ptr::{self, NonNull},
+    time::Duration,
 };
+use std::{sync::OnceLock, thread, time::Instant};
 use zerocopy::FromZeroes;
 
 #[derive(Debug)]
@@ -79,6 +81,16 @@ unsafe impl Hal for FakeHal {
             }
         }
     }
+
+    fn now() -> Option<Duration> {
+        static START: OnceLock<Instant> = OnceLock::new();
+        Some(START.get_or_init(Instant::now).elapsed())
+    }
+
+    fn wait_for_interrupt() {
+        // Let the thread simulating the device run.
+        thread::yield_now();
+    }
 }
 
 fn virt_to_phys(vaddr: usize) -> PhysAddr {
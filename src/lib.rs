@@ -40,6 +40,9 @@
 //! }
 //! # }
 //! ```
+//!
+//! Drivers for device types which aren't supported by this crate can be built on the [`queue`]
+//! module.
 
 #![cfg_attr(not(test), no_std)]
 #![deny(unused_must_use, missing_docs)]
@@ -49,9 +52,11 @@
 #[cfg(any(feature = "alloc", test))]
 extern crate alloc;
 
+mod config;
 pub mod device;
 mod hal;
-mod queue;
+pub mod queue;
+mod sync;
 pub mod transport;
 mod volatile;
 
@@ -60,7 +65,9 @@ use core::{
     ptr::{self, NonNull},
 };
 
-pub use self::hal::{BufferDirection, Hal, PhysAddr};
+pub use self::hal::{BufferDirection, Hal, PhysAddr, PortIoHal};
+#[cfg(feature = "stats")]
+pub use self::queue::{QueueStats, QueueTracer, TraceEvent};
 
 /// The page size in bytes supported by the library (4 KiB).
 pub const PAGE_SIZE: usize = 0x1000;
@@ -93,6 +100,14 @@ pub enum Error {
     ConfigSpaceMissing,
     /// Error from the socket device.
     SocketDeviceError(device::socket::SocketError),
+    /// The device returned a used element whose ID doesn't match any outstanding descriptor
+    /// chain. Only detected with the `hardened` feature.
+    InvalidUsedId,
+    /// The device returned a used element whose length is longer than the device-writable part of
+    /// the descriptor chain. Only detected with the `hardened` feature.
+    InvalidUsedLen,
+    /// The device didn't respond before the timeout expired.
+    Timeout,
 }
 
 impl Display for Error {
@@ -120,6 +135,14 @@ impl Display for Error {
                 )
             }
             Self::SocketDeviceError(e) => write!(f, "Error from the socket device: {e:?}"),
+            Self::InvalidUsedId => {
+                write!(f, "Device used a descriptor chain which wasn't outstanding")
+            }
+            Self::InvalidUsedLen => write!(
+                f,
+                "Device used more bytes than the descriptor chain had space for"
+            ),
+            Self::Timeout => write!(f, "Timed out waiting for the device"),
         }
     }
 }
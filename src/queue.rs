@@ -1,143 +1,301 @@
+//! Virtqueues, the mechanism for bulk data transfer between drivers and VirtIO devices.
+//!
+//! The device drivers in this crate are built on [`VirtQueue`], and it can also be used to write
+//! drivers for other device types outside the crate. Such a driver generally:
+//!
+//! 1. Negotiates features with the device with [`Transport::begin_init`].
+//! 2. Creates a [`VirtQueue`] for each queue of the device with [`VirtQueue::new`], passing
+//!    [`QueueOptions`] for whichever of the ring features were negotiated.
+//! 3. Reads the configuration space if necessary, and tells the device that the driver is ready
+//!    with [`Transport::finish_init`].
+//! 4. Submits requests to the device by adding buffers to a queue with [`VirtQueue::add`] and then
+//!    notifying the device with [`VirtQueue::notify`] if [`VirtQueue::should_notify`] says it
+//!    wants to be notified, and gets the responses back with [`VirtQueue::pop_used`] once
+//!    [`VirtQueue::peek_used`] returns the corresponding token. For simple drivers which only have
+//!    one request outstanding at a time, [`VirtQueue::add_notify_wait_pop`] does all of this.
+//! 5. Calls [`Transport::queue_unset`] for each queue before dropping it.
+//!
+//! # Buffer ownership
+//!
+//! Once buffers have been added to a queue the device may read from the `inputs` and write to the
+//! `outputs` at any time, so the driver must not access or free them until the same token has
+//! been popped, or reclaimed after [resetting](VirtQueue::reset) the queue. This is why `add` and
+//! `pop_used` are `unsafe`. Depending on the [`Hal`] implementation the buffers may be copied to
+//! and from bounce buffers rather than shared with the device directly, so writes by the device
+//! are only guaranteed to be visible once the token has been popped. Buffers allocated from a
+//! [`DmaPool`] which has been set on the queue with [`VirtQueue::set_dma_pool`] are instead always
+//! passed to the device directly.
+//!
+//! Likewise the device may access the queue's rings until the queue is unset or the device is
+//! reset, so a driver must do one of these before dropping a `VirtQueue`, typically in its own
+//! `Drop` implementation.
+//!
+//! # Example
+//!
+//! Sending a request to a device on a queue which has already been set up, and waiting for its
+//! response:
+//!
+//! ```
+//! use virtio_drivers::{queue::VirtQueue, transport::Transport, Hal, Result};
+//!
+//! fn request<H: Hal, T: Transport>(
+//!     queue: &mut VirtQueue<H, 4>,
+//!     transport: &mut T,
+//!     request: &[u8],
+//!     response: &mut [u8],
+//! ) -> Result<u32> {
+//!     // Safe because the buffers aren't accessed until the token is popped below.
+//!     let token = unsafe { queue.add(&[request], &mut [response]) }?;
+//!     if queue.should_notify() {
+//!         queue.notify(transport);
+//!     }
+//!     while queue.peek_used() != Some(token) {
+//!         queue.wait_used();
+//!     }
+//!     // Safe because these are the same buffers as were passed to `add` with the token.
+//!     unsafe { queue.pop_used(token, &[request], &mut [response]) }
+//! }
+//! ```
+//!
+//! See `examples/aarch64/src/rng.rs` for a complete driver for the entropy device.
+
 #![deny(unsafe_op_in_unsafe_fn)]
 
-use crate::hal::{BufferDirection, Dma, Hal, PhysAddr};
+mod moderation;
+mod packed;
+#[cfg(feature = "alloc")]
+mod pool;
+mod split;
+#[cfg(feature = "stats")]
+mod stats;
+
+pub use self::moderation::{InterruptModerator, NotifyMode};
+use self::packed::PackedQueue;
+#[cfg(test)]
+pub(crate) use self::packed::{fake_read_write_packed_queue, FakePackedDevice, PackedDescriptor};
+#[cfg(feature = "alloc")]
+pub use self::pool::{DmaBuffer, DmaPool};
+#[cfg(test)]
+pub(crate) use self::split::fake_read_write_queue;
+pub(crate) use self::split::Descriptor;
+use self::split::SplitQueue;
+#[cfg(feature = "stats")]
+use self::stats::QueueInstruments;
+#[cfg(feature = "stats")]
+pub use self::stats::{QueueStats, QueueTracer, TraceEvent};
+use crate::device::common::Feature;
+use crate::hal::{BufferDirection, Deadline, Dma, Hal, PhysAddr};
+use crate::sync::{AtomicWaker, SpinLock};
 use crate::transport::Transport;
 use crate::{align_up, nonnull_slice_from_raw_parts, pages, Error, Result, PAGE_SIZE};
 #[cfg(feature = "alloc")]
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc, vec};
 use bitflags::bitflags;
-#[cfg(test)]
 use core::cmp::min;
-use core::hint::spin_loop;
+use core::convert::TryInto;
+use core::future::Future;
+use core::marker::PhantomData;
 use core::mem::{size_of, take};
-#[cfg(test)]
-use core::ptr;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
 use core::ptr::NonNull;
-use core::sync::atomic::{fence, AtomicU16, Ordering};
+use core::sync::atomic::{fence, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 /// The mechanism for bulk data transport on virtio devices.
 ///
-/// Each device can have zero or more virtqueues.
+/// Each device can have zero or more virtqueues. A virtqueue uses either the split or the packed
+/// layout, depending on whether the `VIRTIO_F_RING_PACKED` feature was negotiated with the device.
+///
+/// * `SIZE`: The default size of the queue. This is both the number of descriptors, and the number
+///   of slots in the available and used rings. It must be a power of 2 and fit in a [`u16`]. A
+///   queue of a different size may be picked at runtime with [`VirtQueue::with_size`], in which case
+///   `SIZE` is just the largest size for which the queue's bookkeeping is stored inline.
 ///
-/// * `SIZE`: The size of the queue. This is both the number of descriptors, and the number of slots
-///   in the available and used rings. It must be a power of 2 and fit in a [`u16`].
+/// By default the device is trusted to only return buffers which the driver made available to it,
+/// with valid lengths. If the `hardened` feature is enabled then every used element is instead
+/// checked against the driver's private record of outstanding descriptor chains, so that a
+/// malicious or buggy device causes an error rather than a panic or memory corruption.
 #[derive(Debug)]
 pub struct VirtQueue<H: Hal, const SIZE: usize> {
-    /// DMA guard
-    layout: VirtQueueLayout<H>,
-    /// Descriptor table
-    ///
-    /// The device may be able to modify this, even though it's not supposed to, so we shouldn't
-    /// trust values read back from it. Use `desc_shadow` instead to keep track of what we wrote to
-    /// it.
-    desc: NonNull<[Descriptor]>,
-    /// Available ring
-    ///
-    /// The device may be able to modify this, even though it's not supposed to, so we shouldn't
-    /// trust values read back from it. The only field we need to read currently is `idx`, so we
-    /// have `avail_idx` below to use instead.
-    avail: NonNull<AvailRing<SIZE>>,
-    /// Used ring
-    used: NonNull<UsedRing<SIZE>>,
-
-    /// The index of queue
-    queue_idx: u16,
-    /// The number of descriptors currently in use.
-    num_used: u16,
-    /// The head desc index of the free list.
-    free_head: u16,
-    /// Our trusted copy of `desc` that the device can't access.
-    desc_shadow: [Descriptor; SIZE],
-    /// Our trusted copy of `avail.idx`.
-    avail_idx: u16,
-    last_used_idx: u16,
-    /// Whether the `VIRTIO_F_EVENT_IDX` feature has been negotiated.
-    event_idx: bool,
-    #[cfg(feature = "alloc")]
-    indirect: bool,
+    /// The rings and the driver's record of them, which may be shared by the halves of a split
+    /// queue.
+    inner: SpinLock<Inner<H, SIZE>>,
+    /// The waker registered by `poll_used` for each token, if any.
+    wakers: Bookkeeping<Option<Waker>, SIZE>,
+    /// The options the queue was created with, so that it can be set up again after a reset.
+    options: QueueOptions,
+    /// The region of DMA memory whose buffers are passed to the device without being shared.
+    premapped: Option<PremappedRegion>,
+    /// Whether the queue has been reset by `reset` and not yet re-enabled.
+    reset: bool,
+    /// Whether the driver wants used buffer notifications, as last set by `set_dev_notify`.
+    dev_notify: bool,
+    /// The pool whose buffers are passed to the device without being shared, if any. This keeps
+    /// the pool alive as long as the queue might see its buffers.
     #[cfg(feature = "alloc")]
-    indirect_lists: [Option<NonNull<[Descriptor]>>; SIZE],
+    dma_pool: Option<Arc<DmaPool<H>>>,
+    /// Counters and tracer for operations on the queue.
+    #[cfg(feature = "stats")]
+    instruments: QueueInstruments,
+}
+
+/// The ring features which a queue is created with.
+///
+/// Each of these should only be set if the corresponding feature has been negotiated with the
+/// device, as [`from_features`](Self::from_features) does.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QueueOptions {
+    /// Whether to use indirect descriptors, for `VIRTIO_F_INDIRECT_DESC`. The indirect descriptor
+    /// tables are preallocated in DMA memory, with room for up to 16 descriptors for each queue
+    /// entry; buffers with more parts than that use direct descriptors.
+    pub indirect: bool,
+    /// Whether to use the event index fields for notification suppression, for
+    /// `VIRTIO_F_EVENT_IDX`.
+    pub event_idx: bool,
+    /// Whether to use the packed virtqueue layout rather than the split layout, for
+    /// `VIRTIO_F_RING_PACKED`.
+    pub packed: bool,
+    /// Whether the device uses buffers in the order they were made available, which lets it
+    /// complete several at once with a single used element, for `VIRTIO_F_IN_ORDER`. Buffers must
+    /// then also be popped in the order they were added.
+    pub in_order: bool,
+    /// Whether the queue may be [reset](VirtQueue::reset) without resetting the rest of the device,
+    /// for `VIRTIO_F_RING_RESET`. This is needed to give up on buffers after a timeout.
+    pub ring_reset: bool,
+}
+
+impl QueueOptions {
+    /// Returns the options for whichever of the ring features are in the given negotiated feature
+    /// bits.
+    pub fn from_features(negotiated_features: u64) -> Self {
+        let features = Feature::from_bits_truncate(negotiated_features);
+        Self {
+            indirect: features.contains(Feature::RING_INDIRECT_DESC),
+            event_idx: features.contains(Feature::RING_EVENT_IDX),
+            packed: features.contains(Feature::RING_PACKED),
+            in_order: features.contains(Feature::IN_ORDER),
+            ring_reset: features.contains(Feature::RING_RESET),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Inner<H: Hal, const SIZE: usize> {
+    Split(SplitQueue<H, SIZE>),
+    Packed(PackedQueue<H, SIZE>),
+}
+
+/// Forwards a method call to whichever queue layout is in use.
+macro_rules! dispatch {
+    ($self:expr, $queue:ident => $body:expr) => {
+        match $self {
+            Inner::Split($queue) => $body,
+            Inner::Packed($queue) => $body,
+        }
+    };
 }
 
 impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
-    const SIZE_OK: () = assert!(SIZE.is_power_of_two() && SIZE <= u16::MAX as usize);
+    /// Creates a new VirtQueue, using whichever ring features are set in `options`.
+    ///
+    /// The queue will have `SIZE` descriptors, so this fails with [`Error::InvalidParam`] if the
+    /// device doesn't support a queue that big. If the transport
+    /// [requires](Transport::requires_max_queue_size) the maximum size then that is used instead,
+    /// as for [`VirtQueue::with_size`], so the queue's bookkeeping is allocated on the heap if it is
+    /// bigger than `SIZE`.
+    pub fn new<T: Transport>(transport: &mut T, idx: u16, options: QueueOptions) -> Result<Self> {
+        let size = if transport.requires_max_queue_size() {
+            Self::pick_size(transport, idx, SIZE as u16, options.packed)?
+        } else {
+            SIZE as u16
+        };
+        Self::new_inner(transport, idx, size, options)
+    }
 
-    /// Creates a new VirtQueue.
+    /// Creates a new VirtQueue whose size is picked at runtime.
     ///
-    /// * `indirect`: Whether to use indirect descriptors. This should be set if the
-    ///   `VIRTIO_F_INDIRECT_DESC` feature has been negotiated with the device.
-    /// * `event_idx`: Whether to use the `used_event` and `avail_event` fields for notification
-    ///   suppression. This should be set if the `VIRTIO_F_EVENT_IDX` feature has been negotiated
-    ///   with the device.
-    pub fn new<T: Transport>(
+    /// The queue will have the largest power of 2 number of descriptors which is no more than
+    /// either `size` or the maximum queue size supported by the device, unless the transport
+    /// [requires](Transport::requires_max_queue_size) the maximum size. If this is bigger than
+    /// `SIZE` then the queue's bookkeeping is allocated on the heap, so this fails with
+    /// [`Error::InvalidParam`] if the `alloc` feature is not enabled.
+    ///
+    /// The other parameters are as for [`VirtQueue::new`].
+    pub fn with_size<T: Transport>(
         transport: &mut T,
         idx: u16,
-        indirect: bool,
-        event_idx: bool,
+        size: u16,
+        options: QueueOptions,
     ) -> Result<Self> {
-        #[allow(clippy::let_unit_value)]
-        let _ = Self::SIZE_OK;
+        let size = Self::pick_size(transport, idx, size, options.packed)?;
+        Self::new_inner(transport, idx, size, options)
+    }
 
-        if transport.queue_used(idx) {
-            return Err(Error::AlreadyUsed);
+    /// Returns the largest power of 2 queue size which is no more than either `size` or the maximum
+    /// size supported by the device, or the maximum size if the transport requires it.
+    fn pick_size<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        size: u16,
+        packed: bool,
+    ) -> Result<u16> {
+        if transport.requires_max_queue_size() {
+            return transport
+                .max_queue_size(idx)
+                .try_into()
+                .map_err(|_| Error::InvalidParam);
         }
-        if transport.max_queue_size(idx) < SIZE as u32 {
+        let mut size = min(u32::from(size), transport.max_queue_size(idx));
+        if packed {
+            size = min(size, packed::MAX_QUEUE_SIZE.into());
+        }
+        if size == 0 {
             return Err(Error::InvalidParam);
         }
-        let size = SIZE as u16;
-
-        let layout = if transport.requires_legacy_layout() {
-            VirtQueueLayout::allocate_legacy(size)?
-        } else {
-            VirtQueueLayout::allocate_flexible(size)?
-        };
-
-        transport.queue_set(
-            idx,
-            size.into(),
-            layout.descriptors_paddr(),
-            layout.driver_area_paddr(),
-            layout.device_area_paddr(),
-        );
+        // Round down to a power of 2.
+        Ok(1 << (u32::BITS - 1 - size.leading_zeros()))
+    }
 
-        let desc =
-            nonnull_slice_from_raw_parts(layout.descriptors_vaddr().cast::<Descriptor>(), SIZE);
-        let avail = layout.avail_vaddr().cast();
-        let used = layout.used_vaddr().cast();
-
-        let mut desc_shadow: [Descriptor; SIZE] = FromZeroes::new_zeroed();
-        // Link descriptors together.
-        for i in 0..(size - 1) {
-            desc_shadow[i as usize].next = i + 1;
-            // Safe because `desc` is properly aligned, dereferenceable, initialised, and the device
-            // won't access the descriptors for the duration of this unsafe block.
-            unsafe {
-                (*desc.as_ptr())[i as usize].next = i + 1;
-            }
+    fn new_inner<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        size: u16,
+        options: QueueOptions,
+    ) -> Result<Self> {
+        if transport.requires_max_queue_size() && u32::from(size) != transport.max_queue_size(idx) {
+            // The driver can't tell the device to use a different size.
+            return Err(Error::InvalidParam);
         }
-
-        #[cfg(feature = "alloc")]
-        const NONE: Option<NonNull<[Descriptor]>> = None;
-        Ok(VirtQueue {
-            layout,
-            desc,
-            avail,
-            used,
-            queue_idx: idx,
-            num_used: 0,
-            free_head: 0,
-            desc_shadow,
-            avail_idx: 0,
-            last_used_idx: 0,
-            event_idx,
+        let inner = Inner::new(transport, idx, size, options, None)?;
+        let wakers = Bookkeeping::new(size.into(), None)?;
+        Ok(Self {
+            inner: SpinLock::new(inner),
+            wakers,
+            options,
+            premapped: None,
+            reset: false,
+            dev_notify: true,
             #[cfg(feature = "alloc")]
-            indirect,
-            #[cfg(feature = "alloc")]
-            indirect_lists: [NONE; SIZE],
+            dma_pool: None,
+            #[cfg(feature = "stats")]
+            instruments: QueueInstruments::default(),
         })
     }
 
+    /// Returns the size of the queue, i.e. the number of descriptors it has.
+    pub fn size(&self) -> u16 {
+        self.inner.lock().size()
+    }
+
+    /// Returns whether the queue has been [reset](Self::reset) and not yet
+    /// [reenabled](Self::reenable).
+    pub fn is_reset(&self) -> bool {
+        self.reset
+    }
+
     /// Add buffers to the virtqueue, return a token.
     ///
     /// The buffers must not be empty.
@@ -153,143 +311,42 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         inputs: &'a [&'b [u8]],
         outputs: &'a mut [&'b mut [u8]],
     ) -> Result<u16> {
-        if inputs.is_empty() && outputs.is_empty() {
-            return Err(Error::InvalidParam);
-        }
-        let descriptors_needed = inputs.len() + outputs.len();
-        // Only consider indirect descriptors if the alloc feature is enabled, as they require
-        // allocation.
-        #[cfg(feature = "alloc")]
-        if self.num_used as usize + 1 > SIZE
-            || descriptors_needed > SIZE
-            || (!self.indirect && self.num_used as usize + descriptors_needed > SIZE)
-        {
-            return Err(Error::QueueFull);
-        }
-        #[cfg(not(feature = "alloc"))]
-        if self.num_used as usize + descriptors_needed > SIZE {
-            return Err(Error::QueueFull);
-        }
-
-        #[cfg(feature = "alloc")]
-        let head = if self.indirect && descriptors_needed > 1 {
-            self.add_indirect(inputs, outputs)
-        } else {
-            self.add_direct(inputs, outputs)
-        };
-        #[cfg(not(feature = "alloc"))]
-        let head = self.add_direct(inputs, outputs);
-
-        let avail_slot = self.avail_idx & (SIZE as u16 - 1);
-        // Safe because self.avail is properly aligned, dereferenceable and initialised.
-        unsafe {
-            (*self.avail.as_ptr()).ring[avail_slot as usize] = head;
-        }
-
-        // Write barrier so that device sees changes to descriptor table and available ring before
-        // change to available index.
-        fence(Ordering::SeqCst);
-
-        // increase head of avail ring
-        self.avail_idx = self.avail_idx.wrapping_add(1);
-        // Safe because self.avail is properly aligned, dereferenceable and initialised.
-        unsafe {
-            (*self.avail.as_ptr())
-                .idx
-                .store(self.avail_idx, Ordering::Release);
-        }
-
-        Ok(head)
+        // Safe because our caller upholds the same contract.
+        unsafe { self.producer().add(inputs, outputs) }
     }
 
-    fn add_direct<'a, 'b>(
+    /// Adds buffers to the virtqueue like [`add`](Self::add), but doesn't make them available to
+    /// the device until [`publish_notify`](Self::publish_notify) is called.
+    ///
+    /// This allows a batch of several buffer chains to be submitted with a single update to the
+    /// available ring and at most one notification.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add_deferred<'a, 'b>(
         &mut self,
         inputs: &'a [&'b [u8]],
         outputs: &'a mut [&'b mut [u8]],
-    ) -> u16 {
-        // allocate descriptors from free list
-        let head = self.free_head;
-        let mut last = self.free_head;
-
-        for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
-            assert_ne!(buffer.len(), 0);
-
-            // Write to desc_shadow then copy.
-            let desc = &mut self.desc_shadow[usize::from(self.free_head)];
-            // Safe because our caller promises that the buffers live at least until `pop_used`
-            // returns them.
-            unsafe {
-                desc.set_buf::<H>(buffer, direction, DescFlags::NEXT);
-            }
-            last = self.free_head;
-            self.free_head = desc.next;
-
-            self.write_desc(last);
-        }
-
-        // set last_elem.next = NULL
-        self.desc_shadow[usize::from(last)]
-            .flags
-            .remove(DescFlags::NEXT);
-        self.write_desc(last);
-
-        self.num_used += (inputs.len() + outputs.len()) as u16;
-
-        head
+    ) -> Result<u16> {
+        // Safe because our caller upholds the same contract.
+        unsafe { self.producer().add_deferred(inputs, outputs) }
     }
 
-    #[cfg(feature = "alloc")]
-    fn add_indirect<'a, 'b>(
-        &mut self,
-        inputs: &'a [&'b [u8]],
-        outputs: &'a mut [&'b mut [u8]],
-    ) -> u16 {
-        let head = self.free_head;
-
-        // Allocate and fill in indirect descriptor list.
-        let mut indirect_list = Descriptor::new_box_slice_zeroed(inputs.len() + outputs.len());
-        for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
-            let desc = &mut indirect_list[i];
-            // Safe because our caller promises that the buffers live at least until `pop_used`
-            // returns them.
-            unsafe {
-                desc.set_buf::<H>(buffer, direction, DescFlags::NEXT);
-            }
-            desc.next = (i + 1) as u16;
-        }
-        indirect_list
-            .last_mut()
-            .unwrap()
-            .flags
-            .remove(DescFlags::NEXT);
-
-        // Need to store pointer to indirect_list too, because direct_desc.set_buf will only store
-        // the physical DMA address which might be different.
-        assert!(self.indirect_lists[usize::from(head)].is_none());
-        self.indirect_lists[usize::from(head)] = Some(indirect_list.as_mut().into());
-
-        // Write a descriptor pointing to indirect descriptor list. We use Box::leak to prevent the
-        // indirect list from being freed when this function returns; recycle_descriptors is instead
-        // responsible for freeing the memory after the buffer chain is popped.
-        let direct_desc = &mut self.desc_shadow[usize::from(head)];
-        self.free_head = direct_desc.next;
-        unsafe {
-            direct_desc.set_buf::<H>(
-                Box::leak(indirect_list).as_bytes().into(),
-                BufferDirection::DriverToDevice,
-                DescFlags::INDIRECT,
-            );
-        }
-        self.write_desc(head);
-        self.num_used += 1;
-
-        head
+    /// Makes all buffers added with [`add_deferred`](Self::add_deferred) available to the device,
+    /// then notifies it unless it has suppressed notifications.
+    ///
+    /// Returns whether the device was notified.
+    pub fn publish_notify(&mut self, transport: &mut impl Transport) -> bool {
+        self.producer().publish_notify(transport)
     }
 
     /// Add the given buffers to the virtqueue, notifies the device, blocks until the device uses
     /// them, then pops them.
     ///
-    /// This assumes that the device isn't processing any other buffers at the same time.
+    /// This assumes that the device isn't processing any other buffers at the same time. While
+    /// blocked it repeatedly calls [`wait_used`](Self::wait_used).
     ///
     /// The buffers must not be empty.
     pub fn add_notify_wait_pop<'a>(
@@ -304,12 +361,12 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
 
         // Notify the queue.
         if self.should_notify() {
-            transport.notify(self.queue_idx);
+            self.notify(transport);
         }
 
         // Wait until there is at least one element in the used ring.
         while !self.can_pop() {
-            spin_loop();
+            self.wait_used();
         }
 
         // Safe because these are the same buffers as we passed to `add` above and they are still
@@ -317,178 +374,556 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         unsafe { self.pop_used(token, inputs, outputs) }
     }
 
-    /// Advise the device whether used buffer notifications are needed.
+    /// Like [`add_notify_wait_pop`](Self::add_notify_wait_pop), but gives up with
+    /// [`Error::Timeout`] if the device doesn't use the buffers within `timeout`, according to
+    /// [`Hal::now`].
     ///
-    /// See Virtio v1.1 2.6.7 Used Buffer Notification Suppression
-    pub fn set_dev_notify(&mut self, enable: bool) {
-        let avail_ring_flags = if enable { 0x0000 } else { 0x0001 };
-        if !self.event_idx {
-            // Safe because self.avail points to a valid, aligned, initialised, dereferenceable, readable
-            // instance of AvailRing.
-            unsafe {
-                (*self.avail.as_ptr())
-                    .flags
-                    .store(avail_ring_flags, Ordering::Release)
+    /// On timeout the device is stopped from accessing the buffers before they are given back to the
+    /// caller, by [resetting](Self::reset) the queue. The queue then can't be used again until it is
+    /// [reenabled](Self::reenable). This fails with [`Error::Unsupported`] without adding the
+    /// buffers if the queue wasn't created with [`QueueOptions::ring_reset`], as there would be no
+    /// way to stop the device, or if the HAL doesn't have a clock.
+    pub fn add_notify_wait_pop_timeout<'a>(
+        &mut self,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+        transport: &mut impl Transport,
+        timeout: Duration,
+    ) -> Result<u32> {
+        if !self.options.ring_reset {
+            return Err(Error::Unsupported);
+        }
+        let deadline = Deadline::<H>::after(timeout)?;
+        // Safe because we don't return until the same token has been popped or reclaimed, so the
+        // buffers remain valid and are not otherwise accessed until then.
+        let token = unsafe { self.add(inputs, outputs) }?;
+
+        // Notify the queue.
+        if self.should_notify() {
+            self.notify(transport);
+        }
+
+        // Wait until there is at least one element in the used ring.
+        while !self.can_pop() {
+            // If the transport can't reset the queue after all then the device may still access the
+            // buffers, so all we can do is keep waiting for it.
+            if deadline.expired() && self.stop(transport).is_ok() {
+                // Safe because these are the same buffers as we passed to `add` above, and the
+                // device is no longer accessing them.
+                unsafe { self.reclaim(token, inputs, outputs) }?;
+                return Err(Error::Timeout);
             }
+            self.wait_used();
         }
+
+        // Safe because these are the same buffers as we passed to `add` above and they are still
+        // valid.
+        unsafe { self.pop_used(token, inputs, outputs) }
     }
 
-    /// Returns whether the driver should notify the device after adding a new buffer to the
-    /// virtqueue.
+    /// Stops the device from accessing any buffers in the queue by [resetting](Self::reset) it,
+    /// if it hasn't been already, so that they can be [reclaimed](Self::reclaim), for example
+    /// because the device has stopped responding.
     ///
-    /// This will be false if the device has supressed notifications.
-    pub fn should_notify(&self) -> bool {
-        if self.event_idx {
-            // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-            // instance of UsedRing.
-            let avail_event = unsafe { (*self.used.as_ptr()).avail_event.load(Ordering::Acquire) };
-            self.avail_idx >= avail_event.wrapping_add(1)
-        } else {
-            // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-            // instance of UsedRing.
-            unsafe { (*self.used.as_ptr()).flags.load(Ordering::Acquire) & 0x0001 == 0 }
+    /// Fails with [`Error::Unsupported`] if the queue wasn't created with
+    /// [`QueueOptions::ring_reset`] or the transport can't reset it, in which case the device may
+    /// still access the buffers.
+    pub(crate) fn stop(&mut self, transport: &mut impl Transport) -> Result {
+        if self.reset {
+            return Ok(());
+        }
+        if !self.options.ring_reset {
+            return Err(Error::Unsupported);
         }
+        self.reset(transport)
     }
 
-    /// Copies the descriptor at the given index from `desc_shadow` to `desc`, so it can be seen by
-    /// the device.
-    fn write_desc(&mut self, index: u16) {
-        let index = usize::from(index);
-        // Safe because self.desc is properly aligned, dereferenceable and initialised, and nothing
-        // else reads or writes the descriptor during this block.
-        unsafe {
-            (*self.desc.as_ptr())[index] = self.desc_shadow[index].clone();
-        }
+    /// Notifies the device that buffers have been added to the queue.
+    ///
+    /// The notification includes the position at which the next buffer will be added, which the
+    /// transport sends to the device if `VIRTIO_F_NOTIFICATION_DATA` has been negotiated.
+    pub fn notify(&self, transport: &mut impl Transport) {
+        self.producer().notify(transport)
+    }
+
+    /// Advise the device whether used buffer notifications are needed.
+    pub fn set_dev_notify(&mut self, enable: bool) {
+        self.consumer().set_dev_notify(enable)
+    }
+
+    /// Enables used buffer notifications, and then checks whether the device used any buffers
+    /// before it could have seen that they were enabled.
+    ///
+    /// Returns true if there are no used buffers waiting to be popped, in which case the device
+    /// will notify the driver when it next uses one. Otherwise it returns false, and the driver
+    /// must pop the waiting buffers without expecting a notification for them. This avoids losing
+    /// a notification when a buffer is used while switching from polling back to interrupts; see
+    /// [`InterruptModerator`] for a policy built on it.
+    pub fn rearm_dev_notify(&mut self) -> bool {
+        self.consumer().rearm_dev_notify()
+    }
+
+    /// Waits for the device to use a buffer, by calling [`Hal::wait_for_interrupt`] unless there is
+    /// already a used element that can be popped.
+    ///
+    /// Used buffer notifications are enabled while waiting, so that the HAL can halt the CPU until
+    /// the device interrupts it, and are suppressed again afterwards if the driver had suppressed
+    /// them with [`set_dev_notify`](Self::set_dev_notify). This may return before the device has
+    /// used any buffers, so callers should check [`can_pop`](Self::can_pop) and call it again in a
+    /// loop.
+    pub fn wait_used(&mut self) {
+        self.consumer().wait_used()
+    }
+
+    /// Returns whether the driver should notify the device after adding a new buffer to the
+    /// virtqueue.
+    ///
+    /// This will be false if the device has supressed notifications. Calling this consumes the
+    /// decision for the buffers added since the last call: they aren't considered again by the next
+    /// call, so the driver must [`notify`](Self::notify) the device if this returns true.
+    pub fn should_notify(&mut self) -> bool {
+        self.producer().should_notify()
     }
 
     /// Returns whether there is a used element that can be popped.
     pub fn can_pop(&self) -> bool {
-        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-        // instance of UsedRing.
-        self.last_used_idx != unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) }
+        !self.reset && self.inner.lock().can_pop()
     }
 
     /// Returns the descriptor index (a.k.a. token) of the next used element without popping it, or
     /// `None` if the used ring is empty.
+    ///
+    /// With the `hardened` feature this also returns `None` if the device returned an ID which
+    /// isn't an outstanding token, in which case [`pop_used`](Self::pop_used) will fail with
+    /// [`Error::InvalidUsedId`].
     pub fn peek_used(&self) -> Option<u16> {
-        if self.can_pop() {
-            let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
-            // Safe because self.used points to a valid, aligned, initialised, dereferenceable,
-            // readable instance of UsedRing.
-            Some(unsafe { (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16 })
-        } else {
-            None
+        if self.reset {
+            return None;
         }
+        self.inner.lock().peek_used()
     }
 
     /// Returns the number of free descriptors.
     pub fn available_desc(&self) -> usize {
-        #[cfg(feature = "alloc")]
-        if self.indirect {
-            return if usize::from(self.num_used) == SIZE {
-                0
-            } else {
-                SIZE
-            };
-        }
+        self.inner.lock().available_desc()
+    }
 
-        SIZE - usize::from(self.num_used)
+    /// If the given token is next on the device used queue, pops it and returns the total buffer
+    /// length which was used (written) by the device.
+    ///
+    /// With the `hardened` feature the used element is validated first. If its ID isn't an
+    /// outstanding token this fails with [`Error::InvalidUsedId`] and nothing is popped. If the
+    /// length is more than the total length of `outputs` this fails with [`Error::InvalidUsedLen`],
+    /// but the token is still popped and the buffers may be reused.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn pop_used<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        // Safe because our caller upholds the same contract.
+        unsafe { self.consumer().pop_used(token, inputs, outputs) }
+    }
+
+    /// Returns [`Poll::Ready`] if the given token is next on the used ring, so that it can be
+    /// popped. Otherwise registers the waker from `cx` to be woken by
+    /// [`wake_used`](Self::wake_used) once it is.
+    pub fn poll_used(&mut self, token: u16, cx: &mut Context) -> Poll<()> {
+        self.consumer().poll_used(token, cx)
+    }
+
+    /// Wakes the task waiting in [`poll_used`](Self::poll_used) for the next used token, if it is
+    /// ready to be popped.
+    ///
+    /// Drivers call this after acknowledging an interrupt from the device.
+    pub fn wake_used(&mut self) {
+        self.consumer().wake_used()
+    }
+
+    /// Add the given buffers to the virtqueue and notifies the device, returning a future which
+    /// resolves once the device has used them and they have been popped.
+    ///
+    /// This is the asynchronous equivalent of [`add_notify_wait_pop`](Self::add_notify_wait_pop),
+    /// and likewise assumes that the device isn't processing any other buffers at the same time.
+    /// The buffers are added when the future is first polled.
+    ///
+    /// As the future borrows the queue and transport until it completes, the interrupt handler
+    /// can't acknowledge the interrupt or call [`wake_used`](Self::wake_used) in the meantime.
+    /// Instead it should call [`UsedWaker::wake`] on `waker`, and the future acknowledges the
+    /// interrupt with [`Transport::ack_interrupt`] each time it is polled.
+    ///
+    /// The buffers must not be empty.
+    ///
+    /// # Safety
+    ///
+    /// Once the returned future has been polled, it must be polled until it completes. It must not
+    /// be dropped or leaked (e.g. with [`core::mem::forget`]) before then, as the device may still
+    /// be accessing the buffers.
+    pub unsafe fn add_notify_pop_async<'a, T: Transport>(
+        &'a mut self,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+        transport: &'a mut T,
+        waker: &'a UsedWaker,
+    ) -> AddNotifyPop<'a, H, T, SIZE> {
+        AddNotifyPop {
+            queue: self,
+            transport,
+            waker,
+            inputs,
+            outputs,
+            state: AddNotifyPopState::Start,
+        }
     }
 
-    /// Unshares buffers in the list starting at descriptor index `head` and adds them to the free
-    /// list. Unsharing may involve copying data back to the original buffers, so they must be
-    /// passed in too.
+    /// Resets the queue without resetting the rest of the device.
     ///
-    /// This will push all linked descriptors at the front of the free list.
+    /// Once this returns the device will not use any buffers which were added but not yet popped.
+    /// They may be unshared with [`reclaim`](Self::reclaim), and then the queue can be set up again
+    /// with [`reenable`](Self::reenable). Until then, adding or popping buffers fails with
+    /// [`Error::NotReady`], and any tasks waiting in [`poll_used`](Self::poll_used) are woken.
+    ///
+    /// This must only be used if the `VIRTIO_F_RING_RESET` feature has been negotiated with the
+    /// device.
+    pub fn reset<T: Transport>(&mut self, transport: &mut T) -> Result {
+        if self.reset {
+            return Err(Error::InvalidParam);
+        }
+        transport.queue_reset(self.queue_idx())?;
+        self.mark_reset();
+        Ok(())
+    }
+
+    /// Marks the queue as reset once the device has stopped using it, and wakes any waiting tasks.
+    fn mark_reset(&mut self) {
+        self.reset = true;
+        for waker in self.wakers.iter_mut().filter_map(Option::take) {
+            waker.wake();
+        }
+    }
+
+    /// Unshares the buffers which were added with the given token but not popped before the queue
+    /// was [reset](Self::reset), so that they can be used again by the caller.
     ///
     /// # Safety
     ///
     /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
-    /// queue by `add`.
-    unsafe fn recycle_descriptors<'a>(
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn reclaim<'a>(
         &mut self,
-        head: u16,
+        token: u16,
         inputs: &'a [&'a [u8]],
         outputs: &'a mut [&'a mut [u8]],
-    ) {
-        let original_free_head = self.free_head;
-        self.free_head = head;
+    ) -> Result {
+        if !self.reset {
+            return Err(Error::InvalidParam);
+        }
+        // Safe because our caller upholds the same contract, and the device is no longer accessing
+        // the buffers as the queue has been reset.
+        dispatch!(self.inner.get_mut(), queue => unsafe { queue.reclaim(token, inputs, outputs) })
+    }
 
-        let head_desc = &mut self.desc_shadow[usize::from(head)];
-        if head_desc.flags.contains(DescFlags::INDIRECT) {
-            #[cfg(feature = "alloc")]
-            {
-                // Find the indirect descriptor list, unshare it and move its descriptor to the free
-                // list.
-                let indirect_list = self.indirect_lists[usize::from(head)].take().unwrap();
-                // SAFETY: We allocated the indirect list in `add_indirect`, and the device has
-                // finished accessing it by this point.
-                let mut indirect_list = unsafe { Box::from_raw(indirect_list.as_ptr()) };
-                let paddr = head_desc.addr;
-                head_desc.unset_buf();
-                self.num_used -= 1;
-                head_desc.next = original_free_head;
-
-                unsafe {
-                    H::unshare(
-                        paddr as usize,
-                        indirect_list.as_bytes_mut().into(),
-                        BufferDirection::DriverToDevice,
-                    );
-                }
+    /// Sets the queue up again after it was [reset](Self::reset), with the same options as it was
+    /// created with but a new size.
+    ///
+    /// The size is picked as for [`VirtQueue::with_size`]. The previous tokens are all invalidated,
+    /// and any buffers which weren't reclaimed are leaked.
+    pub fn reenable<T: Transport>(&mut self, transport: &mut T, size: u16) -> Result {
+        if !self.reset {
+            return Err(Error::InvalidParam);
+        }
+        let idx = self.queue_idx();
+        let size = Self::pick_size(transport, idx, size, self.options.packed)?;
+        let wakers = Bookkeeping::new(size.into(), None)?;
+        *self.inner.get_mut() = Inner::new(transport, idx, size, self.options, self.premapped)?;
+        self.wakers = wakers;
+        self.reset = false;
+        let dev_notify = self.dev_notify;
+        self.set_dev_notify(dev_notify);
+        Ok(())
+    }
 
-                // Unshare the buffers in the indirect descriptor list, and free it.
-                assert_eq!(indirect_list.len(), inputs.len() + outputs.len());
-                for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
-                    assert_ne!(buffer.len(), 0);
-
-                    // SAFETY: The caller ensures that the buffer is valid and matches the
-                    // descriptor from which we got `paddr`.
-                    unsafe {
-                        // Unshare the buffer (and perhaps copy its contents back to the original
-                        // buffer).
-                        H::unshare(indirect_list[i].addr as usize, buffer, direction);
-                    }
-                }
-                drop(indirect_list);
-            }
-        } else {
-            let mut next = Some(head);
+    /// Returns the index of the queue on its transport.
+    fn queue_idx(&self) -> u16 {
+        self.inner.lock().queue_idx()
+    }
 
-            for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
-                assert_ne!(buffer.len(), 0);
+    /// Sets the pool whose buffers are passed to the device directly, without being shared and
+    /// unshared with the [`Hal`], or removes it.
+    ///
+    /// Buffers from the pool may then be added to the queue like any others, and are recognised by
+    /// their address. Other buffers are still shared as usual. The pool is kept alive as long as it
+    /// is set on the queue.
+    ///
+    /// This fails with [`Error::AlreadyUsed`] if any buffers are in the queue, as they might be
+    /// treated differently when they are popped.
+    #[cfg(feature = "alloc")]
+    pub fn set_dma_pool(&mut self, pool: Option<Arc<DmaPool<H>>>) -> Result {
+        let inner = self.inner.get_mut();
+        if !inner.is_idle() {
+            return Err(Error::AlreadyUsed);
+        }
+        self.premapped = pool.as_ref().map(|pool| pool.region());
+        dispatch!(inner, queue => queue.set_premapped(self.premapped));
+        self.dma_pool = pool;
+        Ok(())
+    }
 
-                let desc_index = next.expect("Descriptor chain was shorter than expected.");
-                let desc = &mut self.desc_shadow[usize::from(desc_index)];
+    /// Returns a snapshot of the counters for operations on the queue.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.instruments.snapshot()
+    }
 
-                let paddr = desc.addr;
-                desc.unset_buf();
-                self.num_used -= 1;
-                next = desc.next();
-                if next.is_none() {
-                    desc.next = original_free_head;
-                }
+    /// Sets a tracer to be called for each operation on the queue, or removes it.
+    #[cfg(feature = "stats")]
+    pub fn set_tracer(&mut self, tracer: Option<&'static dyn QueueTracer>) {
+        self.instruments.set_tracer(tracer);
+    }
 
-                self.write_desc(desc_index);
+    /// Splits the queue into a producer half, which adds buffers and notifies the device, and a
+    /// consumer half, which waits for used buffers and pops them.
+    ///
+    /// The two halves may be used concurrently from different threads or CPUs, for example to
+    /// submit requests on one CPU while handling completions on another. The state which they
+    /// share, such as the list of free descriptors, is protected by a spin lock which is only held
+    /// for the duration of each operation, never while waiting for the device. Neither half should
+    /// therefore be used from an interrupt handler which may interrupt the other half on the same
+    /// CPU.
+    pub fn split(
+        &mut self,
+    ) -> (
+        VirtQueueProducer<'_, H, SIZE>,
+        VirtQueueConsumer<'_, H, SIZE>,
+    ) {
+        let producer = VirtQueueProducer {
+            inner: &self.inner,
+            reset: self.reset,
+            #[cfg(feature = "stats")]
+            instruments: &self.instruments,
+        };
+        let consumer = VirtQueueConsumer {
+            inner: &self.inner,
+            reset: self.reset,
+            wakers: &mut self.wakers,
+            dev_notify: &mut self.dev_notify,
+            #[cfg(feature = "stats")]
+            instruments: &self.instruments,
+        };
+        (producer, consumer)
+    }
 
-                // SAFETY: The caller ensures that the buffer is valid and matches the descriptor
-                // from which we got `paddr`.
-                unsafe {
-                    // Unshare the buffer (and perhaps copy its contents back to the original buffer).
-                    H::unshare(paddr as usize, buffer, direction);
-                }
-            }
+    /// Returns a producer half for a single operation on the whole queue.
+    fn producer(&self) -> VirtQueueProducer<'_, H, SIZE> {
+        VirtQueueProducer {
+            inner: &self.inner,
+            reset: self.reset,
+            #[cfg(feature = "stats")]
+            instruments: &self.instruments,
+        }
+    }
 
-            if next.is_some() {
-                panic!("Descriptor chain was longer than expected.");
-            }
+    /// Returns a consumer half for a single operation on the whole queue.
+    fn consumer(&mut self) -> VirtQueueConsumer<'_, H, SIZE> {
+        self.split().1
+    }
+}
+
+/// The half of a [split](VirtQueue::split) virtqueue which adds buffers and notifies the device.
+#[derive(Debug)]
+pub struct VirtQueueProducer<'a, H: Hal, const SIZE: usize> {
+    inner: &'a SpinLock<Inner<H, SIZE>>,
+    /// Whether the queue had been reset when it was split.
+    reset: bool,
+    #[cfg(feature = "stats")]
+    instruments: &'a QueueInstruments,
+}
+
+impl<H: Hal, const SIZE: usize> VirtQueueProducer<'_, H, SIZE> {
+    /// Adds buffers to the virtqueue, as for [`VirtQueue::add`].
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        if self.reset {
+            return Err(Error::NotReady);
         }
+        let mut inner = self.inner.lock();
+        #[cfg(feature = "stats")]
+        let available_desc = inner.available_desc();
+        // Safe because our caller upholds the same contract.
+        let token = dispatch!(&mut *inner, queue => unsafe { queue.add(inputs, outputs) })?;
+        #[cfg(feature = "stats")]
+        self.record_added(&inner, token, available_desc);
+        Ok(token)
     }
 
-    /// If the given token is next on the device used queue, pops it and returns the total buffer
-    /// length which was used (written) by the device.
+    /// Adds buffers to the virtqueue without making them available to the device yet, as for
+    /// [`VirtQueue::add_deferred`].
     ///
-    /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add_deferred<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        if self.reset {
+            return Err(Error::NotReady);
+        }
+        let mut inner = self.inner.lock();
+        #[cfg(feature = "stats")]
+        let available_desc = inner.available_desc();
+        // Safe because our caller upholds the same contract.
+        let token =
+            dispatch!(&mut *inner, queue => unsafe { queue.add_deferred(inputs, outputs) })?;
+        #[cfg(feature = "stats")]
+        self.record_added(&inner, token, available_desc);
+        Ok(token)
+    }
+
+    /// Makes all deferred buffers available to the device and notifies it if necessary, as for
+    /// [`VirtQueue::publish_notify`].
+    pub fn publish_notify(&mut self, transport: &mut impl Transport) -> bool {
+        dispatch!(&mut *self.inner.lock(), queue => queue.publish());
+        let notify = self.should_notify();
+        if notify {
+            self.notify(transport);
+        }
+        notify
+    }
+
+    /// Notifies the device that buffers have been added to the queue, as for
+    /// [`VirtQueue::notify`].
+    pub fn notify(&self, transport: &mut impl Transport) {
+        let notification = {
+            let inner = self.inner.lock();
+            #[cfg(feature = "stats")]
+            self.instruments
+                .record(inner.queue_idx(), TraceEvent::Notified);
+            dispatch!(&*inner, queue => queue.notification())
+        };
+        transport.notify(notification);
+    }
+
+    /// Returns whether the driver should notify the device after adding a new buffer to the
+    /// virtqueue, as for [`VirtQueue::should_notify`].
+    ///
+    /// Like that, this consumes the decision for the buffers added since the last call.
+    pub fn should_notify(&mut self) -> bool {
+        let mut inner = self.inner.lock();
+        let notify = dispatch!(&mut *inner, queue => queue.should_notify());
+        #[cfg(feature = "stats")]
+        if !notify {
+            self.instruments
+                .record(inner.queue_idx(), TraceEvent::NotificationSuppressed);
+        }
+        notify
+    }
+
+    /// Returns the number of free descriptors.
+    pub fn available_desc(&self) -> usize {
+        self.inner.lock().available_desc()
+    }
+
+    /// Records that a descriptor chain was added with the given token, when there were
+    /// `available_desc` free descriptors before it.
+    #[cfg(feature = "stats")]
+    fn record_added(&self, inner: &Inner<H, SIZE>, token: u16, available_desc: usize) {
+        let descriptors = available_desc - inner.available_desc();
+        self.instruments.record(
+            inner.queue_idx(),
+            TraceEvent::Added {
+                token,
+                descriptors: descriptors as u16,
+            },
+        );
+        self.instruments
+            .record_in_use(usize::from(inner.size()) - inner.available_desc());
+    }
+}
+
+/// The half of a [split](VirtQueue::split) virtqueue which waits for used buffers and pops them.
+#[derive(Debug)]
+pub struct VirtQueueConsumer<'a, H: Hal, const SIZE: usize> {
+    inner: &'a SpinLock<Inner<H, SIZE>>,
+    /// Whether the queue had been reset when it was split.
+    reset: bool,
+    wakers: &'a mut Bookkeeping<Option<Waker>, SIZE>,
+    dev_notify: &'a mut bool,
+    #[cfg(feature = "stats")]
+    instruments: &'a QueueInstruments,
+}
+
+impl<H: Hal, const SIZE: usize> VirtQueueConsumer<'_, H, SIZE> {
+    /// Advise the device whether used buffer notifications are needed.
+    pub fn set_dev_notify(&mut self, enable: bool) {
+        *self.dev_notify = enable;
+        dispatch!(&mut *self.inner.lock(), queue => queue.set_dev_notify(enable))
+    }
+
+    /// Enables used buffer notifications and checks for buffers used in the meantime, as for
+    /// [`VirtQueue::rearm_dev_notify`].
+    pub fn rearm_dev_notify(&mut self) -> bool {
+        if self.reset {
+            return true;
+        }
+        self.set_dev_notify(true);
+        // Make sure the device sees that notifications are enabled before we check the used ring,
+        // otherwise it might have used a buffer without notifying us in between.
+        fence(Ordering::SeqCst);
+        !self.can_pop()
+    }
+
+    /// Waits for the device to use a buffer, as for [`VirtQueue::wait_used`].
+    pub fn wait_used(&mut self) {
+        if self.reset || self.can_pop() {
+            return;
+        }
+        if !*self.dev_notify {
+            dispatch!(&mut *self.inner.lock(), queue => queue.set_dev_notify(true));
+            // Make sure the device sees that notifications are enabled before we check the used
+            // ring again, otherwise it might use a buffer without notifying us in between.
+            fence(Ordering::SeqCst);
+        }
+        if !self.can_pop() {
+            H::wait_for_interrupt();
+        }
+        if !*self.dev_notify {
+            dispatch!(&mut *self.inner.lock(), queue => queue.set_dev_notify(false));
+        }
+    }
+
+    /// Returns whether there is a used element that can be popped.
+    pub fn can_pop(&self) -> bool {
+        !self.reset && self.inner.lock().can_pop()
+    }
+
+    /// Returns the token of the next used element without popping it, as for
+    /// [`VirtQueue::peek_used`].
+    pub fn peek_used(&self) -> Option<u16> {
+        if self.reset {
+            return None;
+        }
+        self.inner.lock().peek_used()
+    }
+
+    /// Pops the given token if it is next on the used ring, as for [`VirtQueue::pop_used`].
     ///
     /// # Safety
     ///
@@ -500,54 +935,223 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         inputs: &'a [&'a [u8]],
         outputs: &'a mut [&'a mut [u8]],
     ) -> Result<u32> {
-        if !self.can_pop() {
+        if self.reset {
             return Err(Error::NotReady);
         }
-
-        // Get the index of the start of the descriptor chain for the next element in the used ring.
-        let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
-        let index;
-        let len;
-        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-        // instance of UsedRing.
-        unsafe {
-            index = (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16;
-            len = (*self.used.as_ptr()).ring[last_used_slot as usize].len;
+        let result = {
+            let mut inner = self.inner.lock();
+            // Safe because our caller upholds the same contract.
+            let result =
+                dispatch!(&mut *inner, queue => unsafe { queue.pop_used(token, inputs, outputs) });
+            #[cfg(feature = "stats")]
+            if let Ok(len) = result {
+                self.instruments
+                    .record(inner.queue_idx(), TraceEvent::Used { token, len });
+            }
+            result
+        };
+        if let Ok(_) | Err(Error::InvalidUsedLen) = result {
+            // The token has been popped either way.
+            self.wakers[usize::from(token)] = None;
+            // The next token may have been used already, in which case there won't be another
+            // interrupt for it.
+            self.wake_used();
         }
+        result
+    }
 
-        if index != token {
-            // The device used a different descriptor chain to the one we were expecting.
-            return Err(Error::WrongToken);
+    /// Returns [`Poll::Ready`] if the given token can be popped, or otherwise registers the waker
+    /// from `cx`, as for [`VirtQueue::poll_used`].
+    pub fn poll_used(&mut self, token: u16, cx: &mut Context) -> Poll<()> {
+        if self.reset || self.peek_used() == Some(token) {
+            // If the queue has been reset then the token will never be used; let `pop_used` report
+            // the error.
+            return Poll::Ready(());
         }
-
-        // Safe because the caller ensures the buffers are valid and match the descriptor.
-        unsafe {
-            self.recycle_descriptors(index, inputs, outputs);
+        #[cfg(feature = "hardened")]
+        if self.peek_used().is_none() && self.can_pop() {
+            // The device returned an invalid used element; let `pop_used` report the error.
+            return Poll::Ready(());
+        }
+        match self.wakers.get_mut(usize::from(token)) {
+            Some(waker) => {
+                if !waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+                    *waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            // The token is invalid so will never be used; let `pop_used` report the error.
+            None => Poll::Ready(()),
         }
-        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+    }
 
-        if self.event_idx {
-            unsafe {
-                (*self.avail.as_ptr())
-                    .used_event
-                    .store(self.last_used_idx, Ordering::Release);
+    /// Wakes the task waiting in [`poll_used`](Self::poll_used) for the next used token, if it is
+    /// ready to be popped.
+    pub fn wake_used(&mut self) {
+        if let Some(token) = self.peek_used() {
+            if let Some(waker) = self
+                .wakers
+                .get_mut(usize::from(token))
+                .and_then(Option::take)
+            {
+                waker.wake();
             }
         }
+    }
+}
+
+impl<H: Hal, const SIZE: usize> Inner<H, SIZE> {
+    fn size(&self) -> u16 {
+        dispatch!(self, queue => queue.size())
+    }
+
+    fn queue_idx(&self) -> u16 {
+        dispatch!(self, queue => queue.queue_idx())
+    }
+
+    fn available_desc(&self) -> usize {
+        dispatch!(self, queue => queue.available_desc())
+    }
+
+    fn can_pop(&self) -> bool {
+        dispatch!(self, queue => queue.can_pop())
+    }
+
+    fn peek_used(&self) -> Option<u16> {
+        dispatch!(self, queue => queue.peek_used())
+    }
+
+    fn is_idle(&self) -> bool {
+        dispatch!(self, queue => queue.is_idle())
+    }
+
+    fn new<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        size: u16,
+        options: QueueOptions,
+        premapped: Option<PremappedRegion>,
+    ) -> Result<Self> {
+        let QueueOptions {
+            indirect,
+            event_idx,
+            packed,
+            in_order,
+            ring_reset: _,
+        } = options;
+        let mut inner = if packed {
+            Self::Packed(PackedQueue::new(
+                transport, idx, size, indirect, event_idx, in_order,
+            )?)
+        } else {
+            Self::Split(SplitQueue::new(
+                transport, idx, size, indirect, event_idx, in_order,
+            )?)
+        };
+        dispatch!(&mut inner, queue => queue.set_premapped(premapped));
+        Ok(inner)
+    }
+}
+
+/// A slot through which an interrupt handler wakes the task waiting for an
+/// [`AddNotifyPop`] future, or one of the async driver methods built on it.
+///
+/// This only needs a shared reference, so it can be kept outside the driver, e.g. in a `static`,
+/// and woken while the future mutably borrows the driver. It never blocks, so it is safe to wake
+/// from an interrupt handler.
+#[derive(Debug, Default)]
+pub struct UsedWaker {
+    waker: AtomicWaker,
+}
+
+impl UsedWaker {
+    /// Creates a new `UsedWaker` with no task waiting.
+    pub const fn new() -> Self {
+        Self {
+            waker: AtomicWaker::new(),
+        }
+    }
 
-        Ok(len)
+    /// Wakes the task waiting on this, if any.
+    ///
+    /// The interrupt handler should call this when the device raises an interrupt.
+    pub fn wake(&self) {
+        self.waker.wake();
     }
 }
 
-// SAFETY: None of the virt queue resources are tied to a particular thread.
-unsafe impl<H: Hal, const SIZE: usize> Send for VirtQueue<H, SIZE> {}
+/// Future returned by [`VirtQueue::add_notify_pop_async`], resolving to the total buffer length
+/// which was used (written) by the device.
+#[must_use = "futures do nothing unless polled"]
+pub struct AddNotifyPop<'a, H: Hal, T: Transport, const SIZE: usize> {
+    queue: &'a mut VirtQueue<H, SIZE>,
+    transport: &'a mut T,
+    waker: &'a UsedWaker,
+    inputs: &'a [&'a [u8]],
+    outputs: &'a mut [&'a mut [u8]],
+    state: AddNotifyPopState,
+}
+
+enum AddNotifyPopState {
+    /// The buffers haven't been added yet.
+    Start,
+    /// The buffers have been added with the given token, and not yet popped.
+    Waiting(u16),
+    /// The buffers have been popped, or adding them failed.
+    Done,
+}
+
+impl<H: Hal, T: Transport, const SIZE: usize> Future for AddNotifyPop<'_, H, T, SIZE> {
+    type Output = Result<u32>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let token = match this.state {
+            AddNotifyPopState::Start => {
+                // Safe because the buffers are borrowed until the future completes, and our
+                // caller promises to poll it until then.
+                let token = match unsafe { this.queue.add(this.inputs, this.outputs) } {
+                    Ok(token) => token,
+                    Err(e) => {
+                        this.state = AddNotifyPopState::Done;
+                        return Poll::Ready(Err(e));
+                    }
+                };
+                this.state = AddNotifyPopState::Waiting(token);
+                if this.queue.should_notify() {
+                    this.queue.notify(this.transport);
+                }
+                token
+            }
+            AddNotifyPopState::Waiting(token) => {
+                this.transport.ack_interrupt();
+                token
+            }
+            AddNotifyPopState::Done => panic!("AddNotifyPop polled after completion"),
+        };
 
-// SAFETY: A `&VirtQueue` only allows reading from the various pointers it contains, so there is no
-// data race.
-unsafe impl<H: Hal, const SIZE: usize> Sync for VirtQueue<H, SIZE> {}
+        // Register before checking the used ring, so that an interrupt in between isn't missed.
+        this.waker.waker.register(cx.waker());
+        if this.queue.poll_used(token, cx).is_pending() {
+            return Poll::Pending;
+        }
+        this.state = AddNotifyPopState::Done;
+        // Safe because these are the same buffers as we passed to `add` above and they are still
+        // valid.
+        Poll::Ready(unsafe {
+            this.queue
+                .pop_used(token, this.inputs, take(&mut this.outputs))
+        })
+    }
+}
 
 /// The inner layout of a VirtQueue.
 ///
-/// Ref: 2.6 Split Virtqueues
+/// For a packed virtqueue the descriptor area holds the descriptor ring, the driver area holds the
+/// driver event suppression structure and the device area holds the device event suppression
+/// structure.
+///
+/// Ref: 2.6 Split Virtqueues, 2.7 Packed Virtqueues
 #[derive(Debug)]
 enum VirtQueueLayout<H: Hal> {
     Legacy {
@@ -572,7 +1176,7 @@ impl<H: Hal> VirtQueueLayout<H> {
     ///
     /// Ref: 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
     fn allocate_legacy(queue_size: u16) -> Result<Self> {
-        let (desc, avail, used) = queue_part_sizes(queue_size);
+        let (desc, avail, used) = split::queue_part_sizes(queue_size);
         let size = align_up(desc + avail) + align_up(used);
         // Allocate contiguous pages.
         let dma = Dma::new(size / PAGE_SIZE, BufferDirection::Both)?;
@@ -589,7 +1193,7 @@ impl<H: Hal> VirtQueueLayout<H> {
     /// This is preferred over `allocate_legacy` where possible as it reduces memory fragmentation
     /// and allows the HAL to know which DMA regions are used in which direction.
     fn allocate_flexible(queue_size: u16) -> Result<Self> {
-        let (desc, avail, used) = queue_part_sizes(queue_size);
+        let (desc, avail, used) = split::queue_part_sizes(queue_size);
         let driver_to_device_dma = Dma::new(pages(desc + avail), BufferDirection::DriverToDevice)?;
         let device_to_driver_dma = Dma::new(pages(used), BufferDirection::DeviceToDriver)?;
         Ok(Self::Modern {
@@ -599,6 +1203,22 @@ impl<H: Hal> VirtQueueLayout<H> {
         })
     }
 
+    /// Allocates DMA regions for the descriptor ring and event suppression structures of a packed
+    /// virtqueue.
+    ///
+    /// Unlike the split layout the device writes used descriptors back into the descriptor ring,
+    /// so that region is shared in both directions.
+    fn allocate_packed(queue_size: u16) -> Result<Self> {
+        let (desc, driver_event, device_event) = packed::queue_part_sizes(queue_size);
+        let driver_to_device_dma = Dma::new(pages(desc + driver_event), BufferDirection::Both)?;
+        let device_to_driver_dma = Dma::new(pages(device_event), BufferDirection::DeviceToDriver)?;
+        Ok(Self::Modern {
+            driver_to_device_dma,
+            device_to_driver_dma,
+            avail_offset: desc,
+        })
+    }
+
     /// Returns the physical address of the descriptor area.
     fn descriptors_paddr(&self) -> PhysAddr {
         match self {
@@ -662,87 +1282,176 @@ impl<H: Hal> VirtQueueLayout<H> {
         }
     }
 
-    /// Returns a pointer to the used ring (in the driver area).
-    fn used_vaddr(&self) -> NonNull<u8> {
-        match self {
-            Self::Legacy {
-                dma, used_offset, ..
-            } => dma.vaddr(*used_offset),
-            Self::Modern {
-                device_to_driver_dma,
-                ..
-            } => device_to_driver_dma.vaddr(0),
-        }
+    /// Returns a pointer to the used ring (in the driver area).
+    fn used_vaddr(&self) -> NonNull<u8> {
+        match self {
+            Self::Legacy {
+                dma, used_offset, ..
+            } => dma.vaddr(*used_offset),
+            Self::Modern {
+                device_to_driver_dma,
+                ..
+            } => device_to_driver_dma.vaddr(0),
+        }
+    }
+}
+
+/// The maximum number of descriptors in each indirect descriptor table. Buffers with more parts than
+/// this are added with direct descriptors instead.
+const INDIRECT_TABLE_LEN: usize = 16;
+
+/// A pool of indirect descriptor tables in DMA memory, with one table for each entry of a
+/// virtqueue, so that indirect descriptors can be used without allocating on the heap.
+///
+/// * `D`: The type of descriptor in the tables.
+#[derive(Debug)]
+struct IndirectPool<H: Hal, D> {
+    dma: Dma<H>,
+    /// The maximum number of descriptors in each table.
+    table_len: usize,
+    _descriptor: PhantomData<D>,
+}
+
+impl<H: Hal, D> IndirectPool<H, D> {
+    /// Allocates a table for each of the `queue_size` entries of a virtqueue.
+    fn new(queue_size: u16) -> Result<Self> {
+        let table_len = min(usize::from(queue_size), INDIRECT_TABLE_LEN);
+        let dma = Dma::new(
+            pages(usize::from(queue_size) * table_len * size_of::<D>()),
+            BufferDirection::DriverToDevice,
+        )?;
+        Ok(Self {
+            dma,
+            table_len,
+            _descriptor: PhantomData,
+        })
+    }
+
+    /// Returns whether a buffer with the given number of parts fits in a single table.
+    fn fits(&self, descriptors: usize) -> bool {
+        descriptors <= self.table_len
+    }
+
+    /// Returns a pointer to the first `len` descriptors of the table for the given queue entry,
+    /// and the physical address of the table.
+    fn table(&self, index: u16, len: usize) -> (NonNull<[D]>, PhysAddr) {
+        assert!(len <= self.table_len);
+        let offset = usize::from(index) * self.table_len * size_of::<D>();
+        (
+            nonnull_slice_from_raw_parts(self.dma.vaddr(offset).cast(), len),
+            self.dma.paddr() + offset,
+        )
+    }
+}
+
+/// A region of DMA memory which is already shared with the device, such as a [`DmaPool`], so
+/// buffers within it can be passed to the device directly.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct PremappedRegion {
+    vaddr: usize,
+    paddr: PhysAddr,
+    len: usize,
+}
+
+impl PremappedRegion {
+    /// Returns the physical address of the given buffer if it lies entirely within the region.
+    fn paddr_of(&self, buffer: NonNull<[u8]>) -> Option<PhysAddr> {
+        let offset = (buffer.as_ptr() as *mut u8 as usize).checked_sub(self.vaddr)?;
+        if offset.checked_add(buffer.len())? <= self.len {
+            Some(self.paddr + offset)
+        } else {
+            None
+        }
+    }
+}
+
+/// Shares the given buffer with the device and returns its physical address, or just returns its
+/// physical address if it is within the premapped region.
+///
+/// # Safety
+///
+/// As for [`Hal::share`].
+unsafe fn share_buffer<H: Hal>(
+    premapped: Option<PremappedRegion>,
+    buffer: NonNull<[u8]>,
+    direction: BufferDirection,
+) -> PhysAddr {
+    if let Some(paddr) = premapped.and_then(|region| region.paddr_of(buffer)) {
+        return paddr;
     }
+    // Safe because our caller upholds the same contract.
+    unsafe { H::share(buffer, direction) }
 }
 
-/// Returns the size in bytes of the descriptor table, available ring and used ring for a given
-/// queue size.
+/// Unshares the given buffer from the device, unless it is within the premapped region.
+///
+/// # Safety
 ///
-/// Ref: 2.6 Split Virtqueues
-fn queue_part_sizes(queue_size: u16) -> (usize, usize, usize) {
-    assert!(
-        queue_size.is_power_of_two(),
-        "queue size should be a power of 2"
-    );
-    let queue_size = queue_size as usize;
-    let desc = size_of::<Descriptor>() * queue_size;
-    let avail = size_of::<u16>() * (3 + queue_size);
-    let used = size_of::<u16>() * 3 + size_of::<UsedElem>() * queue_size;
-    (desc, avail, used)
+/// As for [`Hal::unshare`], and `premapped` must be the same as when the buffer was shared.
+unsafe fn unshare_buffer<H: Hal>(
+    premapped: Option<PremappedRegion>,
+    paddr: PhysAddr,
+    buffer: NonNull<[u8]>,
+    direction: BufferDirection,
+) {
+    if premapped.is_some_and(|region| region.paddr_of(buffer).is_some()) {
+        return;
+    }
+    // Safe because our caller upholds the same contract.
+    unsafe { H::unshare(paddr, buffer, direction) }
 }
 
-#[repr(C, align(16))]
-#[derive(AsBytes, Clone, Debug, FromBytes, FromZeroes)]
-pub(crate) struct Descriptor {
-    addr: u64,
-    len: u32,
-    flags: DescFlags,
-    next: u16,
+/// Driver-private state kept for each descriptor or buffer ID of a virtqueue, or each queue of a
+/// device.
+///
+/// This is stored inline if there are no more than `SIZE` entries, or on the heap otherwise.
+#[derive(Debug)]
+pub(crate) enum Bookkeeping<T, const SIZE: usize> {
+    Inline {
+        entries: [T; SIZE],
+        len: usize,
+    },
+    #[cfg(feature = "alloc")]
+    Heap(Box<[T]>),
 }
 
-impl Descriptor {
-    /// Sets the buffer address, length and flags, and shares it with the device.
-    ///
-    /// # Safety
+impl<T: Clone, const SIZE: usize> Bookkeeping<T, SIZE> {
+    /// Creates bookkeeping for `len` entries, each initialised to `value`.
     ///
-    /// The caller must ensure that the buffer lives at least as long as the descriptor is active.
-    unsafe fn set_buf<H: Hal>(
-        &mut self,
-        buf: NonNull<[u8]>,
-        direction: BufferDirection,
-        extra_flags: DescFlags,
-    ) {
-        // Safe because our caller promises that the buffer is valid.
-        unsafe {
-            self.addr = H::share(buf, direction) as u64;
+    /// Returns [`Error::InvalidParam`] if `len` is bigger than `SIZE` and the `alloc` feature is
+    /// not enabled.
+    pub(crate) fn new(len: usize, value: T) -> Result<Self> {
+        if len <= SIZE {
+            return Ok(Self::Inline {
+                entries: core::array::from_fn(|_| value.clone()),
+                len,
+            });
         }
-        self.len = buf.len() as u32;
-        self.flags = extra_flags
-            | match direction {
-                BufferDirection::DeviceToDriver => DescFlags::WRITE,
-                BufferDirection::DriverToDevice => DescFlags::empty(),
-                BufferDirection::Both => {
-                    panic!("Buffer passed to device should never use BufferDirection::Both.")
-                }
-            };
+        #[cfg(feature = "alloc")]
+        return Ok(Self::Heap(vec![value; len].into_boxed_slice()));
+        #[cfg(not(feature = "alloc"))]
+        Err(Error::InvalidParam)
     }
+}
 
-    /// Sets the buffer address and length to 0.
-    ///
-    /// This must only be called once the device has finished using the descriptor.
-    fn unset_buf(&mut self) {
-        self.addr = 0;
-        self.len = 0;
+impl<T, const SIZE: usize> Deref for Bookkeeping<T, SIZE> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Self::Inline { entries, len } => &entries[..*len],
+            #[cfg(feature = "alloc")]
+            Self::Heap(entries) => entries,
+        }
     }
+}
 
-    /// Returns the index of the next descriptor in the chain if the `NEXT` flag is set, or `None`
-    /// if it is not (and thus this descriptor is the end of the chain).
-    fn next(&self) -> Option<u16> {
-        if self.flags.contains(DescFlags::NEXT) {
-            Some(self.next)
-        } else {
-            None
+impl<T, const SIZE: usize> DerefMut for Bookkeeping<T, SIZE> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match self {
+            Self::Inline { entries, len } => &mut entries[..*len],
+            #[cfg(feature = "alloc")]
+            Self::Heap(entries) => entries,
         }
     }
 }
@@ -757,42 +1466,13 @@ bitflags! {
         const NEXT = 1;
         const WRITE = 2;
         const INDIRECT = 4;
+        /// Only used by packed virtqueues.
+        const AVAIL = 1 << 7;
+        /// Only used by packed virtqueues.
+        const USED = 1 << 15;
     }
 }
 
-/// The driver uses the available ring to offer buffers to the device:
-/// each ring entry refers to the head of a descriptor chain.
-/// It is only written by the driver and read by the device.
-#[repr(C)]
-#[derive(Debug)]
-struct AvailRing<const SIZE: usize> {
-    flags: AtomicU16,
-    /// A driver MUST NOT decrement the idx.
-    idx: AtomicU16,
-    ring: [u16; SIZE],
-    /// Only used if `VIRTIO_F_EVENT_IDX` is negotiated.
-    used_event: AtomicU16,
-}
-
-/// The used ring is where the device returns buffers once it is done with them:
-/// it is only written to by the device, and read by the driver.
-#[repr(C)]
-#[derive(Debug)]
-struct UsedRing<const SIZE: usize> {
-    flags: AtomicU16,
-    idx: AtomicU16,
-    ring: [UsedElem; SIZE],
-    /// Only used if `VIRTIO_F_EVENT_IDX` is negotiated.
-    avail_event: AtomicU16,
-}
-
-#[repr(C)]
-#[derive(Debug)]
-struct UsedElem {
-    id: u32,
-    len: u32,
-}
-
 struct InputOutputIter<'a, 'b> {
     inputs: &'a [&'b [u8]],
     outputs: &'a mut [&'b mut [u8]],
@@ -817,6 +1497,14 @@ impl<'a, 'b> Iterator for InputOutputIter<'a, 'b> {
     }
 }
 
+/// Returns whether moving the available index from `old_idx` to `new_idx` passed `event_idx`, so
+/// the device asked to be notified about it.
+///
+/// Ref: linux virtio_ring.h vring_need_event
+fn vring_need_event(event_idx: u16, new_idx: u16, old_idx: u16) -> bool {
+    new_idx.wrapping_sub(event_idx).wrapping_sub(1) < new_idx.wrapping_sub(old_idx)
+}
+
 // TODO: Use `slice::take_first` once it is stable
 // (https://github.com/rust-lang/rust/issues/62280).
 fn take_first<'a, T>(slice: &mut &'a [T]) -> Option<&'a T> {
@@ -833,136 +1521,6 @@ fn take_first_mut<'a, T>(slice: &mut &'a mut [T]) -> Option<&'a mut T> {
     Some(first)
 }
 
-/// Simulates the device reading from a VirtIO queue and writing a response back, for use in tests.
-///
-/// The fake device always uses descriptors in order.
-#[cfg(test)]
-pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
-    descriptors: *const [Descriptor; QUEUE_SIZE],
-    queue_driver_area: *const u8,
-    queue_device_area: *mut u8,
-    handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
-) {
-    use core::{ops::Deref, slice};
-
-    let available_ring = queue_driver_area as *const AvailRing<QUEUE_SIZE>;
-    let used_ring = queue_device_area as *mut UsedRing<QUEUE_SIZE>;
-
-    // Safe because the various pointers are properly aligned, dereferenceable, initialised, and
-    // nothing else accesses them during this block.
-    unsafe {
-        // Make sure there is actually at least one descriptor available to read from.
-        assert_ne!(
-            (*available_ring).idx.load(Ordering::Acquire),
-            (*used_ring).idx.load(Ordering::Acquire)
-        );
-        // The fake device always uses descriptors in order, like VIRTIO_F_IN_ORDER, so
-        // `used_ring.idx` marks the next descriptor we should take from the available ring.
-        let next_slot = (*used_ring).idx.load(Ordering::Acquire) & (QUEUE_SIZE as u16 - 1);
-        let head_descriptor_index = (*available_ring).ring[next_slot as usize];
-        let mut descriptor = &(*descriptors)[head_descriptor_index as usize];
-
-        let input_length;
-        let output;
-        if descriptor.flags.contains(DescFlags::INDIRECT) {
-            // The descriptor shouldn't have any other flags if it is indirect.
-            assert_eq!(descriptor.flags, DescFlags::INDIRECT);
-
-            // Loop through all input descriptors in the indirect descriptor list, reading data from
-            // them.
-            let indirect_descriptor_list: &[Descriptor] = zerocopy::Ref::new_slice(
-                slice::from_raw_parts(descriptor.addr as *const u8, descriptor.len as usize),
-            )
-            .unwrap()
-            .into_slice();
-            let mut input = Vec::new();
-            let mut indirect_descriptor_index = 0;
-            while indirect_descriptor_index < indirect_descriptor_list.len() {
-                let indirect_descriptor = &indirect_descriptor_list[indirect_descriptor_index];
-                if indirect_descriptor.flags.contains(DescFlags::WRITE) {
-                    break;
-                }
-
-                input.extend_from_slice(slice::from_raw_parts(
-                    indirect_descriptor.addr as *const u8,
-                    indirect_descriptor.len as usize,
-                ));
-
-                indirect_descriptor_index += 1;
-            }
-            input_length = input.len();
-
-            // Let the test handle the request.
-            output = handler(input);
-
-            // Write the response to the remaining descriptors.
-            let mut remaining_output = output.deref();
-            while indirect_descriptor_index < indirect_descriptor_list.len() {
-                let indirect_descriptor = &indirect_descriptor_list[indirect_descriptor_index];
-                assert!(indirect_descriptor.flags.contains(DescFlags::WRITE));
-
-                let length_to_write = min(remaining_output.len(), indirect_descriptor.len as usize);
-                ptr::copy(
-                    remaining_output.as_ptr(),
-                    indirect_descriptor.addr as *mut u8,
-                    length_to_write,
-                );
-                remaining_output = &remaining_output[length_to_write..];
-
-                indirect_descriptor_index += 1;
-            }
-            assert_eq!(remaining_output.len(), 0);
-        } else {
-            // Loop through all input descriptors in the chain, reading data from them.
-            let mut input = Vec::new();
-            while !descriptor.flags.contains(DescFlags::WRITE) {
-                input.extend_from_slice(slice::from_raw_parts(
-                    descriptor.addr as *const u8,
-                    descriptor.len as usize,
-                ));
-
-                if let Some(next) = descriptor.next() {
-                    descriptor = &(*descriptors)[next as usize];
-                } else {
-                    break;
-                }
-            }
-            input_length = input.len();
-
-            // Let the test handle the request.
-            output = handler(input);
-
-            // Write the response to the remaining descriptors.
-            let mut remaining_output = output.deref();
-            if descriptor.flags.contains(DescFlags::WRITE) {
-                loop {
-                    assert!(descriptor.flags.contains(DescFlags::WRITE));
-
-                    let length_to_write = min(remaining_output.len(), descriptor.len as usize);
-                    ptr::copy(
-                        remaining_output.as_ptr(),
-                        descriptor.addr as *mut u8,
-                        length_to_write,
-                    );
-                    remaining_output = &remaining_output[length_to_write..];
-
-                    if let Some(next) = descriptor.next() {
-                        descriptor = &(*descriptors)[next as usize];
-                    } else {
-                        break;
-                    }
-                }
-            }
-            assert_eq!(remaining_output.len(), 0);
-        }
-
-        // Mark the buffer as used.
-        (*used_ring).ring[next_slot as usize].id = head_descriptor_index as u32;
-        (*used_ring).ring[next_slot as usize].len = (input_length + output.len()) as u32;
-        (*used_ring).idx.fetch_add(1, Ordering::AcqRel);
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -972,172 +1530,319 @@ mod tests {
         transport::{
             fake::{FakeTransport, QueueStatus, State},
             mmio::{MmioTransport, VirtIOHeader, MODERN_VERSION},
-            DeviceType,
+            DeviceStatus, DeviceType,
         },
     };
-    use core::ptr::NonNull;
-    use std::sync::{Arc, Mutex};
+    use core::{
+        pin::pin,
+        sync::atomic::{AtomicU16, AtomicUsize, Ordering},
+    };
+    use std::{
+        sync::{mpsc, Arc, Mutex},
+        task::Wake,
+        thread,
+    };
 
-    #[test]
-    fn queue_too_big() {
-        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
-        assert_eq!(
-            VirtQueue::<FakeHal, 8>::new(&mut transport, 0, false, false).unwrap_err(),
-            Error::InvalidParam
-        );
+    /// A waker which counts how many times it has been woken.
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
     }
 
     #[test]
-    fn queue_already_used() {
+    fn with_size_limited_by_device() {
         let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
         let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
-        VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
-        assert_eq!(
-            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap_err(),
-            Error::AlreadyUsed
-        );
+        let queue =
+            VirtQueue::<FakeHal, 4>::with_size(&mut transport, 0, 16, QueueOptions::default())
+                .unwrap();
+        assert_eq!(queue.size(), 4);
+        assert_eq!(queue.available_desc(), 4);
     }
 
     #[test]
-    fn add_empty() {
-        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+    fn with_size_rounds_down() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 16);
         let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let queue =
+            VirtQueue::<FakeHal, 4>::with_size(&mut transport, 0, 3, QueueOptions::default())
+                .unwrap();
+        assert_eq!(queue.size(), 2);
         assert_eq!(
-            unsafe { queue.add(&[], &mut []) }.unwrap_err(),
+            VirtQueue::<FakeHal, 4>::with_size(&mut transport, 1, 0, QueueOptions::default())
+                .unwrap_err(),
             Error::InvalidParam
         );
     }
 
+    /// Tests that a queue bigger than `SIZE` can be created, with its bookkeeping on the heap.
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn with_size_bigger_than_inline() {
+        for packed in [false, true] {
+            let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 16);
+            let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+            let mut queue = VirtQueue::<FakeHal, 2>::with_size(
+                &mut transport,
+                0,
+                8,
+                QueueOptions {
+                    packed,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(queue.size(), 8);
+            for token in 0..8 {
+                assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), token);
+            }
+            assert_eq!(queue.available_desc(), 0);
+            assert_eq!(
+                unsafe { queue.add(&[&[42]], &mut []) }.unwrap_err(),
+                Error::QueueFull
+            );
+        }
+    }
+
+    /// Tests that a task waiting for a token is woken once the device has used it.
     #[test]
-    fn add_too_many() {
-        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
-        assert_eq!(queue.available_desc(), 4);
-        assert_eq!(
-            unsafe { queue.add(&[&[], &[], &[]], &mut [&mut [], &mut []]) }.unwrap_err(),
-            Error::QueueFull
-        );
+    fn poll_used_wake_used() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, QueueOptions::default()).unwrap();
+        let counting_waker = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counting_waker.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let token = unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        assert_eq!(queue.poll_used(token, &mut cx), Poll::Pending);
+        queue.wake_used();
+        assert_eq!(counting_waker.0.load(Ordering::SeqCst), 0);
+
+        state.lock().unwrap().read_write_queue(0, |input| {
+            assert_eq!(input, vec![42]);
+            Vec::new()
+        });
+        queue.wake_used();
+        assert_eq!(counting_waker.0.load(Ordering::SeqCst), 1);
+        assert_eq!(queue.poll_used(token, &mut cx), Poll::Ready(()));
+        assert_eq!(unsafe { queue.pop_used(token, &[&[42]], &mut []) }, Ok(0));
     }
 
+    /// Tests that an `AddNotifyPop` future is woken through its `UsedWaker` while it borrows the
+    /// queue, and acknowledges the interrupt when it is polled again.
     #[test]
-    fn add_buffers() {
-        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
-        assert_eq!(queue.available_desc(), 4);
+    fn add_notify_pop_async_used_waker() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, QueueOptions::default()).unwrap();
+        let counting_waker = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counting_waker.clone());
+        let mut cx = Context::from_waker(&waker);
+        let used_waker = UsedWaker::new();
+
+        let mut future = pin!(unsafe {
+            queue.add_notify_pop_async(&[&[42]], &mut [], &mut transport, &used_waker)
+        });
+        assert_eq!(future.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(counting_waker.0.load(Ordering::SeqCst), 0);
+
+        // The device uses the buffer and raises an interrupt, whose handler wakes the task.
+        {
+            let mut state = state.lock().unwrap();
+            state.read_write_queue(0, |input| {
+                assert_eq!(input, vec![42]);
+                Vec::new()
+            });
+            state.interrupt_pending = true;
+        }
+        used_waker.wake();
+        assert_eq!(counting_waker.0.load(Ordering::SeqCst), 1);
 
-        // Add a buffer chain consisting of two device-readable parts followed by two
-        // device-writable parts.
-        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
+        assert_eq!(future.as_mut().poll(&mut cx), Poll::Ready(Ok(0)));
+        assert!(!state.lock().unwrap().interrupt_pending);
+    }
 
-        assert_eq!(queue.available_desc(), 0);
-        assert!(!queue.can_pop());
+    /// Tests that a queue can be reset, its outstanding buffers reclaimed and then set up again with
+    /// a different size.
+    #[test]
+    fn reset_reclaim_reenable() {
+        for packed in [false, true] {
+            let mut config_space = ();
+            let mut driver_features = Feature::RING_RESET;
+            if packed {
+                driver_features |= Feature::RING_PACKED;
+            }
+            let state = Arc::new(Mutex::new(State {
+                driver_features: driver_features.bits(),
+                queues: vec![QueueStatus::default()],
+                ..Default::default()
+            }));
+            let mut transport = FakeTransport {
+                device_type: DeviceType::Network,
+                max_queue_size: 4,
+                device_features: 0,
+                config_space: NonNull::from(&mut config_space),
+                state: state.clone(),
+            };
+            let mut queue = VirtQueue::<FakeHal, 4>::new(
+                &mut transport,
+                0,
+                QueueOptions {
+                    packed,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
 
-        // Safe because the various parts of the queue are properly aligned, dereferenceable and
-        // initialised, and nothing else is accessing them at the same time.
-        unsafe {
-            let first_descriptor_index = (*queue.avail.as_ptr()).ring[0];
-            assert_eq!(first_descriptor_index, token);
+            let mut buffer = [0; 4];
+            let token = unsafe { queue.add(&[], &mut [&mut buffer]) }.unwrap();
             assert_eq!(
-                (*queue.desc.as_ptr())[first_descriptor_index as usize].len,
-                2
+                queue.reenable(&mut transport, 4).unwrap_err(),
+                Error::InvalidParam
             );
+            queue.reset(&mut transport).unwrap();
+            assert!(!transport.queue_used(0));
             assert_eq!(
-                (*queue.desc.as_ptr())[first_descriptor_index as usize].flags,
-                DescFlags::NEXT
-            );
-            let second_descriptor_index =
-                (*queue.desc.as_ptr())[first_descriptor_index as usize].next;
-            assert_eq!(
-                (*queue.desc.as_ptr())[second_descriptor_index as usize].len,
-                1
-            );
-            assert_eq!(
-                (*queue.desc.as_ptr())[second_descriptor_index as usize].flags,
-                DescFlags::NEXT
-            );
-            let third_descriptor_index =
-                (*queue.desc.as_ptr())[second_descriptor_index as usize].next;
-            assert_eq!(
-                (*queue.desc.as_ptr())[third_descriptor_index as usize].len,
-                2
-            );
-            assert_eq!(
-                (*queue.desc.as_ptr())[third_descriptor_index as usize].flags,
-                DescFlags::NEXT | DescFlags::WRITE
-            );
-            let fourth_descriptor_index =
-                (*queue.desc.as_ptr())[third_descriptor_index as usize].next;
-            assert_eq!(
-                (*queue.desc.as_ptr())[fourth_descriptor_index as usize].len,
-                1
+                unsafe { queue.add(&[&[42]], &mut []) }.unwrap_err(),
+                Error::NotReady
             );
+            assert_eq!(queue.peek_used(), None);
+
+            unsafe { queue.reclaim(token, &[], &mut [&mut buffer]) }.unwrap();
             assert_eq!(
-                (*queue.desc.as_ptr())[fourth_descriptor_index as usize].flags,
-                DescFlags::WRITE
+                unsafe { queue.reclaim(token, &[], &mut [&mut buffer]) }.unwrap_err(),
+                Error::WrongToken
             );
+
+            queue.reenable(&mut transport, 2).unwrap();
+            assert_eq!(queue.size(), 2);
+            assert_eq!(state.lock().unwrap().queues[0].size, 2);
+            let token = unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+            state.lock().unwrap().read_write_queue(0, |input| {
+                assert_eq!(input, vec![42]);
+                Vec::new()
+            });
+            assert_eq!(unsafe { queue.pop_used(token, &[&[42]], &mut []) }, Ok(0));
         }
     }
 
-    #[cfg(feature = "alloc")]
     #[test]
-    fn add_buffers_indirect() {
-        use core::ptr::slice_from_raw_parts;
-
-        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, true, false).unwrap();
-        assert_eq!(queue.available_desc(), 4);
-
-        // Add a buffer chain consisting of two device-readable parts followed by two
-        // device-writable parts.
-        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
+    fn reset_unsupported() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state,
+        };
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, QueueOptions::default()).unwrap();
+        assert_eq!(queue.reset(&mut transport), Err(Error::Unsupported));
+        assert!(unsafe { queue.add(&[&[42]], &mut []) }.is_ok());
+    }
 
-        assert_eq!(queue.available_desc(), 4);
-        assert!(!queue.can_pop());
+    /// Tests that buffers from a DMA pool set on the queue are passed to the device directly,
+    /// rather than through the bounce buffers which `FakeHal` uses when sharing.
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn dma_pool_buffers_not_shared() {
+        for packed in [false, true] {
+            let mut config_space = ();
+            let driver_features = if packed {
+                Feature::RING_PACKED
+            } else {
+                Feature::empty()
+            };
+            let state = Arc::new(Mutex::new(State {
+                driver_features: driver_features.bits(),
+                queues: vec![QueueStatus::default()],
+                ..Default::default()
+            }));
+            let mut transport = FakeTransport {
+                device_type: DeviceType::Network,
+                max_queue_size: 4,
+                device_features: 0,
+                config_space: NonNull::from(&mut config_space),
+                state: state.clone(),
+            };
+            let mut queue = VirtQueue::<FakeHal, 4>::new(
+                &mut transport,
+                0,
+                QueueOptions {
+                    packed,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let pool = DmaPool::<FakeHal>::new(2, 4, BufferDirection::DeviceToDriver).unwrap();
 
-        // Safe because the various parts of the queue are properly aligned, dereferenceable and
-        // initialised, and nothing else is accessing them at the same time.
-        unsafe {
-            let indirect_descriptor_index = (*queue.avail.as_ptr()).ring[0];
-            assert_eq!(indirect_descriptor_index, token);
-            assert_eq!(
-                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].len as usize,
-                4 * size_of::<Descriptor>()
-            );
+            // The pool can't be set while there are buffers in the queue.
+            let token = unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
             assert_eq!(
-                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].flags,
-                DescFlags::INDIRECT
-            );
-
-            let indirect_descriptors = slice_from_raw_parts(
-                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].addr
-                    as *const Descriptor,
-                4,
+                queue.set_dma_pool(Some(pool.clone())),
+                Err(Error::AlreadyUsed)
             );
-            assert_eq!((*indirect_descriptors)[0].len, 2);
-            assert_eq!((*indirect_descriptors)[0].flags, DescFlags::NEXT);
-            assert_eq!((*indirect_descriptors)[0].next, 1);
-            assert_eq!((*indirect_descriptors)[1].len, 1);
-            assert_eq!((*indirect_descriptors)[1].flags, DescFlags::NEXT);
-            assert_eq!((*indirect_descriptors)[1].next, 2);
-            assert_eq!((*indirect_descriptors)[2].len, 2);
+            state.lock().unwrap().read_write_queue(0, |_| Vec::new());
+            assert_eq!(unsafe { queue.pop_used(token, &[&[42]], &mut []) }, Ok(0));
+            queue.set_dma_pool(Some(pool.clone())).unwrap();
+
+            let mut buffer = pool.alloc().unwrap();
+            let token = unsafe { queue.add(&[], &mut [&mut buffer]) }.unwrap();
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue(0, |_| vec![1, 2, 3, 4]);
+            // The device wrote to the buffer itself, so the data is there even before it is popped.
+            assert_eq!(&*buffer, &[1, 2, 3, 4]);
             assert_eq!(
-                (*indirect_descriptors)[2].flags,
-                DescFlags::NEXT | DescFlags::WRITE
+                unsafe { queue.pop_used(token, &[], &mut [&mut buffer]) },
+                Ok(4)
             );
-            assert_eq!((*indirect_descriptors)[2].next, 3);
-            assert_eq!((*indirect_descriptors)[3].len, 1);
-            assert_eq!((*indirect_descriptors)[3].flags, DescFlags::WRITE);
+            assert_eq!(&*buffer, &[1, 2, 3, 4]);
+
+            // Buffers are returned to the pool when dropped.
+            let other = pool.alloc().unwrap();
+            assert!(pool.alloc().is_none());
+            drop(buffer);
+            assert!(pool.alloc().is_some());
+            drop(other);
         }
     }
 
-    /// Tests that the queue advises the device that notifications are needed.
     #[test]
-    fn set_dev_notify() {
+    fn interrupt_moderator() {
         let mut config_space = ();
         let state = Arc::new(Mutex::new(State {
             queues: vec![QueueStatus::default()],
@@ -1146,39 +1851,69 @@ mod tests {
         let mut transport = FakeTransport {
             device_type: DeviceType::Block,
             max_queue_size: 4,
-            device_features: 0,
+            device_features: Feature::RING_EVENT_IDX.bits(),
             config_space: NonNull::from(&mut config_space),
             state: state.clone(),
         };
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
-
-        // Check that the avail ring's flag is zero by default.
-        assert_eq!(
-            unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) },
-            0x0
-        );
-
-        queue.set_dev_notify(false);
-
-        // Check that the avail ring's flag is 1 after `disable_dev_notify`.
-        assert_eq!(
-            unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) },
-            0x1
-        );
+        let mut queue = VirtQueue::<FakeHal, 4>::new(
+            &mut transport,
+            0,
+            QueueOptions {
+                event_idx: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let driver_area = state.lock().unwrap().queues[0].driver_area;
+        // `used_event` follows the 16-bit flags and idx fields and the 4 slots of the available
+        // ring.
+        let used_event = unsafe { &*(driver_area as *const AtomicU16).add(6) };
+        let mut moderator = InterruptModerator::new(2);
+        assert_eq!(moderator.mode(), NotifyMode::Interrupt);
+
+        for i in 0..3 {
+            assert_eq!(unsafe { queue.add(&[&[i]], &mut []) }, Ok(i.into()));
+            state.lock().unwrap().read_write_queue(0, |_| Vec::new());
+        }
 
-        queue.set_dev_notify(true);
+        // An interrupt switches to polling, moving `used_event` behind the used ring.
+        moderator.interrupt(&mut queue);
+        assert_eq!(moderator.mode(), NotifyMode::Polling);
+        assert_eq!(used_event.load(Ordering::Acquire), 0xffff);
 
-        // Check that the avail ring's flag is 0 after `enable_dev_notify`.
-        assert_eq!(
-            unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) },
-            0x0
-        );
+        // Using up the budget keeps polling, without re-arming notifications as buffers are popped.
+        for i in 0..2 {
+            assert_eq!(unsafe { queue.pop_used(i.into(), &[&[i]], &mut []) }, Ok(0));
+        }
+        assert!(moderator.poll_done(&mut queue, 2));
+        assert_eq!(used_event.load(Ordering::Acquire), 1);
+
+        // The average is still high, so keep polling.
+        assert_eq!(unsafe { queue.pop_used(2, &[&[2]], &mut []) }, Ok(0));
+        assert!(moderator.poll_done(&mut queue, 1));
+        assert_eq!(moderator.mode(), NotifyMode::Polling);
+
+        // Once the queue goes quiet, switch back to interrupts.
+        assert!(!moderator.poll_done(&mut queue, 0));
+        assert_eq!(moderator.mode(), NotifyMode::Interrupt);
+        assert_eq!(used_event.load(Ordering::Acquire), 3);
+
+        // A buffer used while switching back keeps the queue in polling mode, as the device may not
+        // have seen that notifications were enabled.
+        moderator.interrupt(&mut queue);
+        let token = unsafe { queue.add(&[&[3]], &mut []) }.unwrap();
+        state.lock().unwrap().read_write_queue(0, |_| Vec::new());
+        assert!(moderator.poll_done(&mut queue, 0));
+        assert_eq!(moderator.mode(), NotifyMode::Polling);
+        assert_eq!(used_event.load(Ordering::Acquire), 2);
+        assert_eq!(unsafe { queue.pop_used(token, &[&[3]], &mut []) }, Ok(0));
+        assert!(!moderator.poll_done(&mut queue, 1));
+        assert_eq!(moderator.mode(), NotifyMode::Interrupt);
+        assert_eq!(used_event.load(Ordering::Acquire), 4);
     }
 
-    /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
-    /// notifications.
     #[test]
-    fn add_notify() {
+    fn add_notify_wait_pop_enables_notifications() {
         let mut config_space = ();
         let state = Arc::new(Mutex::new(State {
             queues: vec![QueueStatus::default()],
@@ -1191,29 +1926,46 @@ mod tests {
             config_space: NonNull::from(&mut config_space),
             state: state.clone(),
         };
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
-
-        // Add a buffer chain with a single device-readable part.
-        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
-
-        // Check that the transport would be notified.
-        assert_eq!(queue.should_notify(), true);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, QueueOptions::default()).unwrap();
+        queue.set_dev_notify(false);
+        let avail_flags = |state: &Mutex<State>| {
+            let driver_area = state.lock().unwrap().queues[0].driver_area;
+            // The flags are the first field of the available ring, which is at the start of the
+            // driver area.
+            unsafe { (*(driver_area as *const AtomicU16)).load(Ordering::SeqCst) }
+        };
 
-        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
-        // initialised, and nothing else is accessing them at the same time.
-        unsafe {
-            // Suppress notifications.
-            (*queue.used.as_ptr()).flags.store(0x01, Ordering::Release);
-        }
+        let handle = thread::spawn({
+            let state = state.clone();
+            move || {
+                State::wait_until_queue_notified(&state, 0);
+                // The driver should enable notifications while it is waiting.
+                while avail_flags(&state) != 0 {
+                    thread::yield_now();
+                }
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue(0, |request| vec![request[0] + 1]);
+            }
+        });
 
-        // Check that the transport would not be notified.
-        assert_eq!(queue.should_notify(), false);
+        let mut response = [0; 1];
+        assert_eq!(
+            queue.add_notify_wait_pop(&[&[42]], &mut [&mut response], &mut transport),
+            Ok(1)
+        );
+        handle.join().unwrap();
+        assert_eq!(response, [43]);
+        // Notifications should be suppressed again afterwards.
+        assert_eq!(avail_flags(&state), 1);
     }
 
-    /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
-    /// notifications with the `avail_event` index.
     #[test]
-    fn add_notify_event_idx() {
+    fn split_concurrent() {
+        const REQUESTS: u8 = 16;
+
         let mut config_space = ();
         let state = Arc::new(Mutex::new(State {
             queues: vec![QueueStatus::default()],
@@ -1222,34 +1974,191 @@ mod tests {
         let mut transport = FakeTransport {
             device_type: DeviceType::Block,
             max_queue_size: 4,
-            device_features: Feature::RING_EVENT_IDX.bits(),
+            device_features: 0,
             config_space: NonNull::from(&mut config_space),
             state: state.clone(),
         };
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, QueueOptions::default()).unwrap();
+        let (driver_area, device_area) = {
+            let state = state.lock().unwrap();
+            (state.queues[0].driver_area, state.queues[0].device_area)
+        };
+        // The `idx` fields of the available and used rings both follow a 16-bit flags field.
+        let avail_idx = unsafe { &*(driver_area as *const AtomicU16).add(1) };
+        let used_idx = unsafe { &*(device_area as *const AtomicU16).add(1) };
+        let requests: Vec<[u8; 1]> = (0..REQUESTS).map(|i| [i]).collect();
+
+        let (mut producer, mut consumer) = queue.split();
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+
+            // The device handles each request as soon as it is available.
+            scope.spawn(|| {
+                for i in 0..REQUESTS {
+                    while avail_idx.load(Ordering::SeqCst) == used_idx.load(Ordering::SeqCst) {
+                        thread::yield_now();
+                    }
+                    state.lock().unwrap().read_write_queue(0, |request| {
+                        assert_eq!(request, vec![i]);
+                        vec![]
+                    });
+                }
+            });
+
+            // The producer adds requests as fast as the consumer frees descriptors for them.
+            let requests = &requests;
+            scope.spawn(move || {
+                for request in requests {
+                    let token = loop {
+                        // Safe because the request isn't modified until the scope ends.
+                        match unsafe { producer.add(&[request], &mut []) } {
+                            Err(Error::QueueFull) => thread::yield_now(),
+                            result => break result.unwrap(),
+                        }
+                    };
+                    sender.send((token, request)).unwrap();
+                }
+            });
+
+            for (token, request) in receiver.iter().take(REQUESTS.into()) {
+                while consumer.peek_used() != Some(token) {
+                    consumer.wait_used();
+                }
+                assert_eq!(
+                    unsafe { consumer.pop_used(token, &[request], &mut []) },
+                    Ok(0)
+                );
+            }
+        });
+
+        assert_eq!(queue.available_desc(), 4);
+        assert!(!queue.can_pop());
+    }
 
-        // Add a buffer chain with a single device-readable part.
-        assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 0);
+    #[test]
+    fn add_notify_wait_pop_timeout() {
+        for ring_reset in [false, true] {
+            let mut config_space = ();
+            let driver_features = if ring_reset {
+                Feature::RING_RESET.bits()
+            } else {
+                0
+            };
+            let state = Arc::new(Mutex::new(State {
+                status: DeviceStatus::DRIVER_OK,
+                driver_features,
+                queues: vec![QueueStatus::default()],
+                ..Default::default()
+            }));
+            let mut transport = FakeTransport {
+                device_type: DeviceType::Block,
+                max_queue_size: 4,
+                device_features: 0,
+                config_space: NonNull::from(&mut config_space),
+                state: state.clone(),
+            };
+            let mut queue = VirtQueue::<FakeHal, 4>::new(
+                &mut transport,
+                0,
+                QueueOptions::from_features(driver_features),
+            )
+            .unwrap();
+
+            // The device never uses the buffers.
+            let mut response = [0; 1];
+            let result = queue.add_notify_wait_pop_timeout(
+                &[&[42]],
+                &mut [&mut response],
+                &mut transport,
+                Duration::from_millis(10),
+            );
+            assert_eq!(state.lock().unwrap().status, DeviceStatus::DRIVER_OK);
+            assert_eq!(queue.available_desc(), 4);
+            if ring_reset {
+                assert_eq!(result, Err(Error::Timeout));
+                assert!(state.lock().unwrap().queues[0]
+                    .notified
+                    .load(Ordering::SeqCst));
+                assert!(queue.is_reset());
+                assert!(!transport.queue_used(0));
+            } else {
+                // Without a way to stop the device the buffers aren't even added.
+                assert_eq!(result, Err(Error::Unsupported));
+                assert!(!state.lock().unwrap().queues[0]
+                    .notified
+                    .load(Ordering::SeqCst));
+                assert!(!queue.is_reset());
+            }
+        }
+    }
 
-        // Check that the transport would be notified.
-        assert_eq!(queue.should_notify(), true);
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats_and_tracer() {
+        struct RecordingTracer(Mutex<Vec<(u16, TraceEvent)>>);
 
-        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
-        // initialised, and nothing else is accessing them at the same time.
-        unsafe {
-            // Suppress notifications.
-            (*queue.used.as_ptr())
-                .avail_event
-                .store(1, Ordering::Release);
+        impl QueueTracer for RecordingTracer {
+            fn trace(&self, queue_idx: u16, event: TraceEvent) {
+                self.0.lock().unwrap().push((queue_idx, event));
+            }
         }
 
-        // Check that the transport would not be notified.
-        assert_eq!(queue.should_notify(), false);
+        static TRACER: RecordingTracer = RecordingTracer(Mutex::new(Vec::new()));
 
-        // Add another buffer chain.
-        assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 1);
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 1, QueueOptions::default()).unwrap();
+        queue.set_tracer(Some(&TRACER));
+
+        let mut response = [0; 1];
+        let token = unsafe { queue.add(&[&[1], &[2]], &mut [&mut response]) }.unwrap();
+        assert!(queue.should_notify());
+        queue.notify(&mut transport);
+        state
+            .lock()
+            .unwrap()
+            .read_write_queue(1, |request| vec![request[0] + request[1]]);
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[1], &[2]], &mut [&mut response]) },
+            Ok(1)
+        );
+        assert_eq!(response, [3]);
 
-        // Check that the transport should be notified again now.
-        assert_eq!(queue.should_notify(), true);
+        assert_eq!(
+            queue.stats(),
+            QueueStats {
+                added: 1,
+                used: 1,
+                notifications: 1,
+                notifications_suppressed: 0,
+                peak_descriptors: 3,
+            }
+        );
+        assert_eq!(
+            *TRACER.0.lock().unwrap(),
+            vec![
+                (
+                    1,
+                    TraceEvent::Added {
+                        token,
+                        descriptors: 3
+                    }
+                ),
+                (1, TraceEvent::Notified),
+                (1, TraceEvent::Used { token, len: 1 }),
+            ]
+        );
     }
 }
@@ -0,0 +1,143 @@
+//! Adaptive suppression of used buffer notifications, switching a queue between interrupts and
+//! busy polling depending on how many buffers the device is using.
+
+use super::VirtQueue;
+use crate::hal::Hal;
+
+/// How a driver finds out about used buffers on a queue moderated by an [`InterruptModerator`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NotifyMode {
+    /// Used buffer notifications are enabled, and the driver waits for an interrupt.
+    Interrupt,
+    /// Used buffer notifications are suppressed, and the driver polls the queue.
+    Polling,
+}
+
+/// A NAPI-style policy for switching a queue between interrupt mode and polling mode.
+///
+/// While few buffers are being used, taking an interrupt for each of them costs little, so the
+/// queue stays in interrupt mode. When the device interrupts, the driver calls
+/// [`interrupt`](Self::interrupt), which suppresses further notifications, and then polls the queue
+/// in rounds, popping at most [`budget`](Self::budget) buffers per round and reporting how many
+/// it popped to [`poll_done`](Self::poll_done). This keeps polling while rounds are using up the
+/// budget or the recent average is high, and otherwise switches back to interrupt mode.
+///
+/// Switching back uses [`VirtQueue::rearm_dev_notify`], so a buffer which the device uses while
+/// notifications are being re-enabled is picked up by another round of polling rather than
+/// waiting for an interrupt which may never come. With `VIRTIO_F_EVENT_IDX` the queue suppresses
+/// notifications through the `used_event` index or the driver event suppression structure, which
+/// the device honours even though it ignores the plain flag in that case.
+///
+/// A driver would typically use it like this:
+///
+/// ```
+/// use virtio_drivers::{queue::{InterruptModerator, VirtQueue}, Hal};
+///
+/// /// Called from the interrupt handler for the queue.
+/// fn handle_interrupt<H: Hal>(
+///     moderator: &mut InterruptModerator,
+///     queue: &mut VirtQueue<H, 16>,
+///     mut handle: impl FnMut(&mut VirtQueue<H, 16>),
+/// ) {
+///     moderator.interrupt(queue);
+///     loop {
+///         let mut completed = 0;
+///         while completed < moderator.budget() && queue.can_pop() {
+///             // Pop a used buffer and process it.
+///             handle(queue);
+///             completed += 1;
+///         }
+///         if !moderator.poll_done(queue, completed) {
+///             // Back in interrupt mode.
+///             break;
+///         }
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct InterruptModerator {
+    budget: usize,
+    mode: NotifyMode,
+    /// A moving average of the number of buffers popped in each round of polling.
+    average: usize,
+}
+
+impl InterruptModerator {
+    /// Creates a new moderator for a queue in interrupt mode, which pops at most `budget` buffers
+    /// in each round of polling. A budget of 0 is treated as 1.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget: budget.max(1),
+            mode: NotifyMode::Interrupt,
+            average: 0,
+        }
+    }
+
+    /// Returns the maximum number of buffers which the driver should pop in a round of polling.
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Returns whether the queue is currently in interrupt mode or polling mode.
+    pub fn mode(&self) -> NotifyMode {
+        self.mode
+    }
+
+    /// Switches the queue to polling mode, suppressing used buffer notifications.
+    ///
+    /// This should be called when the device interrupts the driver for the queue, before polling
+    /// it.
+    pub fn interrupt<H: Hal, const SIZE: usize>(&mut self, queue: &mut VirtQueue<H, SIZE>) {
+        if self.mode == NotifyMode::Interrupt {
+            queue.set_dev_notify(false);
+            self.mode = NotifyMode::Polling;
+        }
+    }
+
+    /// Records that the driver popped `completed` buffers from the queue in a round of polling,
+    /// and decides whether to keep polling.
+    ///
+    /// Returns true if the queue is in polling mode and the driver should poll it again soon, or
+    /// false if it is in interrupt mode and the driver should wait for an interrupt.
+    pub fn poll_done<H: Hal, const SIZE: usize>(
+        &mut self,
+        queue: &mut VirtQueue<H, SIZE>,
+        completed: usize,
+    ) -> bool {
+        self.average = (self.average + completed) / 2;
+        let busy = completed >= self.budget || self.average * 2 >= self.budget;
+        match self.mode {
+            NotifyMode::Interrupt => {
+                if completed >= self.budget {
+                    // There is more work than fits in the budget, so stop taking interrupts.
+                    self.interrupt(queue);
+                    true
+                } else {
+                    false
+                }
+            }
+            NotifyMode::Polling if busy => true,
+            NotifyMode::Polling => {
+                if queue.rearm_dev_notify() {
+                    self.mode = NotifyMode::Interrupt;
+                    false
+                } else {
+                    // The device used more buffers while notifications were being enabled, and
+                    // might not notify us about them, so keep polling.
+                    queue.set_dev_notify(false);
+                    true
+                }
+            }
+        }
+    }
+
+    /// Switches the queue back to interrupt mode, re-enabling used buffer notifications.
+    ///
+    /// Returns false if there are already used buffers waiting to be popped, as for
+    /// [`VirtQueue::rearm_dev_notify`].
+    pub fn reset<H: Hal, const SIZE: usize>(&mut self, queue: &mut VirtQueue<H, SIZE>) -> bool {
+        self.mode = NotifyMode::Interrupt;
+        self.average = 0;
+        queue.rearm_dev_notify()
+    }
+}
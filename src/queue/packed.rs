@@ -0,0 +1,1582 @@
+//! Packed virtqueue layout.
+
+use super::{
+    share_buffer, unshare_buffer, vring_need_event, Bookkeeping, DescFlags, IndirectPool,
+    InputOutputIter, PremappedRegion, VirtQueueLayout,
+};
+use crate::hal::{BufferDirection, Hal};
+use crate::transport::{Notification, Transport};
+use crate::{nonnull_slice_from_raw_parts, Error, Result};
+use core::mem::{self, size_of, size_of_val};
+use core::ptr::{addr_of, addr_of_mut, NonNull};
+use core::sync::atomic::{fence, AtomicU16, Ordering};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// Notifications are enabled.
+const RING_EVENT_FLAGS_ENABLE: u16 = 0x0;
+/// Notifications are disabled.
+const RING_EVENT_FLAGS_DISABLE: u16 = 0x1;
+/// Notifications are only wanted for the descriptor ring position given by `off_wrap`.
+const RING_EVENT_FLAGS_DESC: u16 = 0x2;
+/// The bit of `off_wrap` which holds the wrap counter.
+const RING_EVENT_WRAP_COUNTER: u16 = 1 << 15;
+/// The largest size of a packed virtqueue.
+pub(super) const MAX_QUEUE_SIZE: u16 = 1 << 15;
+
+/// A virtqueue using the packed layout, where the driver and device share a single descriptor ring.
+///
+/// * `SIZE`: The largest queue size for which bookkeeping is stored inline rather than on the heap.
+///   It must be a power of 2 and no greater than 2^15.
+///
+/// Ref: 2.7 Packed Virtqueues
+#[derive(Debug)]
+pub(super) struct PackedQueue<H: Hal, const SIZE: usize> {
+    /// DMA guard
+    layout: VirtQueueLayout<H>,
+    /// Descriptor ring
+    ///
+    /// The device writes used descriptors back into this ring, so we shouldn't trust anything read
+    /// from it other than the used buffer ID, length and flags. Use `desc_shadow` instead to keep
+    /// track of what we wrote to it.
+    desc: NonNull<[PackedDescriptor]>,
+    /// Driver event suppression structure, written by us and read by the device.
+    driver_event: NonNull<EventSuppress>,
+    /// Device event suppression structure, written by the device and read by us.
+    device_event: NonNull<EventSuppress>,
+
+    /// The index of queue
+    queue_idx: u16,
+    /// The size of the queue. This is the number of slots in the descriptor ring, and the number of
+    /// buffer IDs available.
+    size: u16,
+    /// The number of descriptor ring slots currently in use.
+    num_used: u16,
+    /// The head of the free list of buffer IDs.
+    free_head: u16,
+    /// Our trusted copy of `desc`, indexed by ring slot.
+    desc_shadow: Bookkeeping<PackedDescriptor, SIZE>,
+    /// Bookkeeping for each buffer ID, indexed by ID.
+    buffers: Bookkeeping<BufferState, SIZE>,
+    /// The ring slot which the next available descriptor will be written to.
+    avail_idx: u16,
+    /// The driver ring wrap counter, which is flipped every time `avail_idx` wraps around.
+    avail_wrap_counter: bool,
+    /// The number of ring slots used by the buffers added since `should_notify` was last called.
+    num_added: u16,
+    /// The ring slot and flags of the head descriptor of the first buffer added by `add_deferred`
+    /// since the last `publish`. The flags are held back until then, so that the device doesn't see
+    /// any of the following buffers either.
+    pending_head: Option<(u16, DescFlags)>,
+    /// The ring slot which the device will write the next used descriptor to.
+    last_used_idx: u16,
+    /// The device ring wrap counter, which is flipped every time `last_used_idx` wraps around.
+    used_wrap_counter: bool,
+    /// Whether the `VIRTIO_F_EVENT_IDX` feature has been negotiated.
+    event_idx: bool,
+    /// Whether the `VIRTIO_F_IN_ORDER` feature has been negotiated.
+    in_order: bool,
+    /// The buffer ID and length from the used descriptor which completed the current batch, if we
+    /// are part way through popping the buffers it covers. Only used if `in_order` is set.
+    batch_last: Option<(u16, u32)>,
+    /// The indirect descriptor tables, indexed by buffer ID, if indirect descriptors are enabled.
+    indirect_pool: Option<IndirectPool<H, PackedDescriptor>>,
+    /// The region of DMA memory whose buffers are passed to the device without being shared, if
+    /// any.
+    premapped: Option<PremappedRegion>,
+}
+
+impl<H: Hal, const SIZE: usize> PackedQueue<H, SIZE> {
+    const SIZE_OK: () = assert!(SIZE.is_power_of_two() && SIZE <= MAX_QUEUE_SIZE as usize);
+
+    /// Creates a new packed virtqueue with `size` descriptors, which must be a power of 2 no
+    /// greater than 2^15.
+    ///
+    /// * `indirect`: Whether to use indirect descriptors. This should be set if the
+    ///   `VIRTIO_F_INDIRECT_DESC` feature has been negotiated with the device.
+    /// * `event_idx`: Whether to use the descriptor ring position in the event suppression
+    ///   structures for notification suppression. This should be set if the `VIRTIO_F_EVENT_IDX`
+    ///   feature has been negotiated with the device.
+    /// * `in_order`: Whether the device uses buffers in the order they were made available. This
+    ///   should be set if the `VIRTIO_F_IN_ORDER` feature has been negotiated with the device.
+    pub fn new<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        size: u16,
+        indirect: bool,
+        event_idx: bool,
+        in_order: bool,
+    ) -> Result<Self> {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::SIZE_OK;
+
+        if transport.queue_used(idx) {
+            return Err(Error::AlreadyUsed);
+        }
+        if transport.max_queue_size(idx) < size.into() || size > MAX_QUEUE_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        if transport.requires_legacy_layout() {
+            // Packed virtqueues can't be used with legacy interfaces.
+            return Err(Error::Unsupported);
+        }
+        let desc_shadow = Bookkeeping::new(size.into(), FromZeroes::new_zeroed())?;
+        let mut buffers = Bookkeeping::new(size.into(), BufferState::default())?;
+        let indirect_pool = if indirect {
+            Some(IndirectPool::new(size)?)
+        } else {
+            None
+        };
+
+        let layout = VirtQueueLayout::allocate_packed(size)?;
+
+        transport.queue_set(
+            idx,
+            size.into(),
+            layout.descriptors_paddr(),
+            layout.driver_area_paddr(),
+            layout.device_area_paddr(),
+        );
+
+        let desc = nonnull_slice_from_raw_parts(
+            layout.descriptors_vaddr().cast::<PackedDescriptor>(),
+            size.into(),
+        );
+        let driver_event = layout.avail_vaddr().cast::<EventSuppress>();
+        let device_event = layout.used_vaddr().cast::<EventSuppress>();
+
+        // Link buffer IDs together.
+        for (i, buffer) in buffers.iter_mut().enumerate().take(usize::from(size) - 1) {
+            buffer.next = i as u16 + 1;
+        }
+
+        if event_idx {
+            // Safe because `driver_event` is properly aligned, dereferenceable and initialised.
+            unsafe {
+                (*driver_event.as_ptr())
+                    .off_wrap
+                    .store(RING_EVENT_WRAP_COUNTER, Ordering::Release);
+                (*driver_event.as_ptr())
+                    .flags
+                    .store(RING_EVENT_FLAGS_DESC, Ordering::Release);
+            }
+        }
+
+        Ok(PackedQueue {
+            layout,
+            desc,
+            driver_event,
+            device_event,
+            queue_idx: idx,
+            size,
+            num_used: 0,
+            free_head: 0,
+            desc_shadow,
+            buffers,
+            avail_idx: 0,
+            avail_wrap_counter: true,
+            num_added: 0,
+            pending_head: None,
+            last_used_idx: 0,
+            used_wrap_counter: true,
+            event_idx,
+            in_order,
+            batch_last: None,
+            indirect_pool,
+            premapped: None,
+        })
+    }
+
+    /// Add buffers to the virtqueue, return a token.
+    ///
+    /// The token is the buffer ID, which the device will return in the used descriptor.
+    ///
+    /// The buffers must not be empty.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_add_packed
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        // Safe because our caller upholds the same contract.
+        let id = unsafe { self.add_deferred(inputs, outputs) }?;
+        self.publish();
+        Ok(id)
+    }
+
+    /// Adds buffers to the virtqueue like `add`, but doesn't make them available to the device
+    /// until `publish` is called.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add_deferred<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        if inputs.is_empty() && outputs.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let descriptors_needed = inputs.len() + outputs.len();
+        let indirect = descriptors_needed > 1
+            && self
+                .indirect_pool
+                .as_ref()
+                .is_some_and(|pool| pool.fits(descriptors_needed));
+        let ring_slots_needed = if indirect { 1 } else { descriptors_needed };
+        if usize::from(self.num_used) + ring_slots_needed > usize::from(self.size) {
+            return Err(Error::QueueFull);
+        }
+
+        // Allocate a buffer ID from the free list. There is always one free if there is a free
+        // ring slot, as every buffer in use takes at least one slot.
+        let id = self.free_head;
+        self.free_head = self.buffers[usize::from(id)].next;
+
+        let head = self.avail_idx;
+        let head_flags = if indirect {
+            self.add_indirect(id, inputs, outputs)
+        } else {
+            self.add_direct(id, inputs, outputs)
+        };
+        #[cfg(feature = "hardened")]
+        {
+            self.buffers[usize::from(id)].writable_len =
+                outputs.iter().map(|buffer| buffer.len() as u32).sum();
+        }
+
+        if self.pending_head.is_none() {
+            self.pending_head = Some((head, head_flags));
+        } else {
+            // The device won't read past the first pending buffer, so this one can be made
+            // available straight away.
+            self.write_head_flags(head, head_flags);
+        }
+
+        Ok(id)
+    }
+
+    /// Makes all buffers added so far available to the device, by marking the head descriptor of
+    /// the first one which was held back as available.
+    pub fn publish(&mut self) {
+        if let Some((head, head_flags)) = self.pending_head.take() {
+            self.write_head_flags(head, head_flags);
+        }
+    }
+
+    /// Writes the flags of the head descriptor of a buffer, making it available to the device.
+    fn write_head_flags(&mut self, head: u16, head_flags: DescFlags) {
+        // Write barrier so that device sees changes to the rest of the descriptors before the head
+        // descriptor is made available.
+        fence(Ordering::SeqCst);
+
+        // Safe because self.desc is properly aligned, dereferenceable and initialised, and the
+        // device won't access the head descriptor until its flags mark it available.
+        unsafe {
+            addr_of_mut!((*self.desc.as_ptr())[usize::from(head)].flags).write_volatile(head_flags);
+        }
+    }
+
+    /// Writes a descriptor for each of the given buffers to consecutive ring slots, except for the
+    /// flags of the first one, which are returned instead.
+    fn add_direct<'a, 'b>(
+        &mut self,
+        id: u16,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> DescFlags {
+        let descriptors_needed = inputs.len() + outputs.len();
+        let head = self.avail_idx;
+        let mut head_flags = DescFlags::empty();
+
+        for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
+            assert_ne!(buffer.len(), 0);
+
+            let extra_flags = if i + 1 == descriptors_needed {
+                DescFlags::empty()
+            } else {
+                DescFlags::NEXT
+            };
+            let slot = self.avail_idx;
+            let desc = &mut self.desc_shadow[usize::from(slot)];
+            // Safe because our caller promises that the buffers live at least until `pop_used`
+            // returns them.
+            unsafe {
+                desc.set_buf::<H>(
+                    self.premapped,
+                    buffer,
+                    direction,
+                    extra_flags | avail_flags(self.avail_wrap_counter),
+                );
+            }
+            desc.id = id;
+
+            if slot == head {
+                head_flags = desc.flags;
+                self.write_desc_without_flags(slot);
+            } else {
+                self.write_desc(slot);
+            }
+            self.advance_avail_idx();
+        }
+
+        self.buffers[usize::from(id)].head = head;
+        self.buffers[usize::from(id)].num = descriptors_needed as u16;
+        self.num_used += descriptors_needed as u16;
+        self.num_added = self.num_added.saturating_add(descriptors_needed as u16);
+
+        head_flags
+    }
+
+    /// Writes a single descriptor pointing to the indirect descriptor table for the buffer ID,
+    /// filled in with the given buffers, except for its flags, which are returned instead.
+    ///
+    /// Ref: 2.8.19 Indirect Flag: Scatter-Gather Support
+    fn add_indirect<'a, 'b>(
+        &mut self,
+        id: u16,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> DescFlags {
+        let head = self.avail_idx;
+
+        // Fill in the indirect descriptor table for the buffer ID from the pool. Descriptors in the
+        // table are used in order, so they don't need the `NEXT` flag.
+        let (indirect_list, indirect_paddr) = self
+            .indirect_pool
+            .as_ref()
+            .unwrap()
+            .table(id, inputs.len() + outputs.len());
+        // SAFETY: The table is in DMA memory owned by the pool, and isn't in use by the device
+        // because `id` is free.
+        let indirect_list = unsafe { &mut *indirect_list.as_ptr() };
+        for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
+            // Safe because our caller promises that the buffers live at least until `pop_used`
+            // returns them.
+            unsafe {
+                indirect_list[i].set_buf::<H>(
+                    self.premapped,
+                    buffer,
+                    direction,
+                    DescFlags::empty(),
+                );
+            }
+        }
+
+        // Write a descriptor pointing to the indirect descriptor table. It is already in DMA
+        // memory, so doesn't need to be shared.
+        let direct_desc = &mut self.desc_shadow[usize::from(head)];
+        direct_desc.addr = indirect_paddr as u64;
+        direct_desc.len = size_of_val(indirect_list) as u32;
+        direct_desc.flags = DescFlags::INDIRECT | avail_flags(self.avail_wrap_counter);
+        direct_desc.id = id;
+        let head_flags = direct_desc.flags;
+        self.write_desc_without_flags(head);
+        self.advance_avail_idx();
+
+        self.buffers[usize::from(id)].head = head;
+        self.buffers[usize::from(id)].num = 1;
+        self.num_used += 1;
+        self.num_added = self.num_added.saturating_add(1);
+
+        head_flags
+    }
+
+    /// Moves `avail_idx` on to the next ring slot, flipping the wrap counter if it wraps around.
+    fn advance_avail_idx(&mut self) {
+        self.avail_idx += 1;
+        if self.avail_idx == self.size {
+            self.avail_idx = 0;
+            self.avail_wrap_counter = !self.avail_wrap_counter;
+        }
+    }
+
+    /// Advise the device whether used buffer notifications are needed.
+    ///
+    /// See Virtio v1.1 2.7.10 Driver and Device Event Suppression
+    pub fn set_dev_notify(&mut self, enable: bool) {
+        let flags = if !enable {
+            RING_EVENT_FLAGS_DISABLE
+        } else if self.event_idx {
+            // `off_wrap` is kept up to date as buffers are popped, so this re-arms notifications
+            // from the next used descriptor.
+            RING_EVENT_FLAGS_DESC
+        } else {
+            RING_EVENT_FLAGS_ENABLE
+        };
+        // Safe because self.driver_event points to a valid, aligned, initialised,
+        // dereferenceable instance of EventSuppress.
+        unsafe {
+            (*self.driver_event.as_ptr())
+                .flags
+                .store(flags, Ordering::Release)
+        }
+    }
+
+    /// Returns whether the driver should notify the device after adding a new buffer to the
+    /// virtqueue.
+    ///
+    /// This will be false if the device has supressed notifications. Buffers added before this is
+    /// called are not considered again by the next call, so the driver must notify the device if
+    /// this returns true.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_kick_prepare_packed
+    pub fn should_notify(&mut self) -> bool {
+        // Make sure the device sees the new descriptors before we check whether it wants to be
+        // notified.
+        fence(Ordering::SeqCst);
+        let num_added = mem::take(&mut self.num_added);
+        // Safe because self.device_event points to a valid, aligned, initialised, dereferenceable,
+        // readable instance of EventSuppress.
+        let (off_wrap, flags) = unsafe {
+            (
+                (*self.device_event.as_ptr())
+                    .off_wrap
+                    .load(Ordering::Acquire),
+                (*self.device_event.as_ptr()).flags.load(Ordering::Acquire),
+            )
+        };
+        if flags != RING_EVENT_FLAGS_DESC || !self.event_idx {
+            return flags != RING_EVENT_FLAGS_DISABLE;
+        }
+
+        // The ring positions are compared as if the ring continued past its end, so that the
+        // position which the device asked to be notified about can be compared with the slots used
+        // by the buffers added since the last check even if either wrapped around.
+        let new = self.avail_idx;
+        let old = new.wrapping_sub(num_added);
+        let mut event_idx = off_wrap & !RING_EVENT_WRAP_COUNTER;
+        if (off_wrap & RING_EVENT_WRAP_COUNTER != 0) != self.avail_wrap_counter {
+            event_idx = event_idx.wrapping_sub(self.size);
+        }
+        vring_need_event(event_idx, new, old)
+    }
+
+    /// Copies the descriptor at the given ring slot from `desc_shadow` to `desc`, so it can be seen
+    /// by the device.
+    fn write_desc(&mut self, index: u16) {
+        let index = usize::from(index);
+        // Safe because self.desc is properly aligned, dereferenceable and initialised, and nothing
+        // else reads or writes the descriptor during this block.
+        unsafe {
+            (*self.desc.as_ptr())[index] = self.desc_shadow[index].clone();
+        }
+    }
+
+    /// Copies everything except the flags of the descriptor at the given ring slot from
+    /// `desc_shadow` to `desc`.
+    ///
+    /// This is used for the head descriptor of a buffer, whose flags must only be written once the
+    /// rest of the buffer is ready as writing them makes it available to the device.
+    fn write_desc_without_flags(&mut self, index: u16) {
+        let index = usize::from(index);
+        let shadow = &self.desc_shadow[index];
+        // Safe because self.desc is properly aligned, dereferenceable and initialised, and the
+        // device won't read the descriptor until its flags mark it available.
+        unsafe {
+            let desc = &mut (*self.desc.as_ptr())[index];
+            desc.addr = shadow.addr;
+            desc.len = shadow.len;
+            desc.id = shadow.id;
+        }
+    }
+
+    /// Returns whether there is a used element that can be popped.
+    pub fn can_pop(&self) -> bool {
+        if self.batch_last.is_some() {
+            return true;
+        }
+        // Safe because self.desc is properly aligned, dereferenceable and initialised.
+        let flags = unsafe {
+            addr_of!((*self.desc.as_ptr())[usize::from(self.last_used_idx)].flags).read_volatile()
+        };
+        is_used(flags, self.used_wrap_counter)
+    }
+
+    /// Returns the buffer ID (a.k.a. token) of the next used element without popping it, or `None`
+    /// if there is no used element ready.
+    pub fn peek_used(&self) -> Option<u16> {
+        if self.in_order {
+            // The used descriptor may be for a later buffer, but that means this one is done too.
+            self.can_pop()
+                .then(|| self.desc_shadow[usize::from(self.last_used_idx)].id)
+        } else if self.can_pop() {
+            let id = self.read_used().0;
+            #[cfg(feature = "hardened")]
+            if self.used_writable_len(id).is_err() {
+                return None;
+            }
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of free descriptors.
+    pub fn available_desc(&self) -> usize {
+        if self.indirect_pool.is_some() {
+            return if self.num_used == self.size {
+                0
+            } else {
+                self.size.into()
+            };
+        }
+
+        usize::from(self.size - self.num_used)
+    }
+
+    /// Unshares the buffers of the buffer with the given ID and returns the ID to the free list.
+    /// Unsharing may involve copying data back to the original buffers, so they must be passed in
+    /// too.
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add`.
+    unsafe fn recycle_descriptors<'a>(
+        &mut self,
+        id: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) {
+        let BufferState { head, num, .. } = self.buffers[usize::from(id)];
+        let head_desc = &mut self.desc_shadow[usize::from(head)];
+        if head_desc.flags.contains(DescFlags::INDIRECT) {
+            // Find the indirect descriptor table in the pool, and free its ring slot.
+            assert_eq!(
+                head_desc.len as usize,
+                (inputs.len() + outputs.len()) * size_of::<PackedDescriptor>(),
+                "Indirect descriptor table had a different length than expected."
+            );
+            head_desc.unset_buf();
+            let (indirect_list, _) = self
+                .indirect_pool
+                .as_ref()
+                .unwrap()
+                .table(id, inputs.len() + outputs.len());
+
+            // Unshare the buffers in the indirect descriptor table.
+            for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
+                assert_ne!(buffer.len(), 0);
+
+                // SAFETY: The caller ensures that the buffer is valid and matches the descriptor
+                // from which we got the address, and the device has finished accessing the
+                // indirect descriptor table by this point.
+                unsafe {
+                    // Unshare the buffer (and perhaps copy its contents back to the original
+                    // buffer).
+                    unshare_buffer::<H>(
+                        self.premapped,
+                        (*indirect_list.as_ptr())[i].addr as usize,
+                        buffer,
+                        direction,
+                    );
+                }
+            }
+        } else {
+            assert_eq!(
+                usize::from(num),
+                inputs.len() + outputs.len(),
+                "Buffer had a different number of descriptors than expected."
+            );
+            for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
+                assert_ne!(buffer.len(), 0);
+
+                let slot = (usize::from(head) + i) % usize::from(self.size);
+                let desc = &mut self.desc_shadow[slot];
+                let paddr = desc.addr;
+                desc.unset_buf();
+
+                // SAFETY: The caller ensures that the buffer is valid and matches the descriptor
+                // from which we got `paddr`.
+                unsafe {
+                    // Unshare the buffer (and perhaps copy its contents back to the original buffer).
+                    unshare_buffer::<H>(self.premapped, paddr as usize, buffer, direction);
+                }
+            }
+        }
+
+        self.num_used -= num;
+        self.buffers[usize::from(id)] = BufferState {
+            next: self.free_head,
+            ..Default::default()
+        };
+        self.free_head = id;
+    }
+
+    /// If the given token is next on the device used queue, pops it and returns the total buffer
+    /// length which was used (written) by the device.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx_packed
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn pop_used<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        if !self.can_pop() {
+            return Err(Error::NotReady);
+        }
+
+        let index = token;
+        let len = if self.in_order {
+            // With `VIRTIO_F_IN_ORDER` the device may write a single used descriptor for a batch of
+            // buffers, with the ID of the last buffer in the batch. The buffers before it are then
+            // also complete, and are assumed to have had all of their device-writable buffers
+            // written.
+            if token != self.desc_shadow[usize::from(self.last_used_idx)].id {
+                // The caller tried to pop buffers out of order.
+                return Err(Error::WrongToken);
+            }
+            let (last_index, last_len) = match self.batch_last.take() {
+                Some(batch_last) => batch_last,
+                None => {
+                    let batch_last = self.read_used();
+                    #[cfg(feature = "hardened")]
+                    self.used_writable_len(batch_last.0)?;
+                    batch_last
+                }
+            };
+            if last_index == token {
+                last_len
+            } else {
+                self.batch_last = Some((last_index, last_len));
+                outputs.iter().map(|buffer| buffer.len() as u32).sum()
+            }
+        } else {
+            let (used_index, len) = self.read_used();
+            #[cfg(feature = "hardened")]
+            self.used_writable_len(used_index)?;
+            if used_index != token {
+                // The device used a different buffer to the one we were expecting.
+                return Err(Error::WrongToken);
+            }
+            len
+        };
+
+        #[cfg(feature = "hardened")]
+        let writable_len = self.used_writable_len(index)?;
+        // The device skips over all the ring slots used by the buffer.
+        let num = self.buffers[usize::from(index)].num;
+        // Safe because the caller ensures the buffers are valid and match the descriptor.
+        unsafe {
+            self.recycle_descriptors(index, inputs, outputs);
+        }
+        self.last_used_idx += num;
+        if self.last_used_idx >= self.size {
+            self.last_used_idx -= self.size;
+            self.used_wrap_counter = !self.used_wrap_counter;
+        }
+
+        if self.event_idx {
+            let wrap = if self.used_wrap_counter {
+                RING_EVENT_WRAP_COUNTER
+            } else {
+                0
+            };
+            // Safe because self.driver_event points to a valid, aligned, initialised,
+            // dereferenceable instance of EventSuppress.
+            unsafe {
+                (*self.driver_event.as_ptr())
+                    .off_wrap
+                    .store(self.last_used_idx | wrap, Ordering::Release);
+            }
+        }
+
+        #[cfg(feature = "hardened")]
+        if len > writable_len {
+            return Err(Error::InvalidUsedLen);
+        }
+        Ok(len)
+    }
+
+    /// Returns the total length of the device-writable buffers of the outstanding buffer with the
+    /// given ID from a used descriptor, or [`Error::InvalidUsedId`] if there is no such buffer.
+    #[cfg(feature = "hardened")]
+    fn used_writable_len(&self, id: u16) -> Result<u32> {
+        match self.buffers.get(usize::from(id)) {
+            Some(buffer) if buffer.num != 0 => Ok(buffer.writable_len),
+            _ => Err(Error::InvalidUsedId),
+        }
+    }
+
+    /// Reads the buffer ID and length from the used descriptor at `last_used_idx`.
+    ///
+    /// This must only be called once `can_pop` has returned true.
+    fn read_used(&self) -> (u16, u32) {
+        // Make sure we read the rest of the used descriptor after its flags.
+        fence(Ordering::SeqCst);
+        // Safe because self.desc is properly aligned, dereferenceable and initialised.
+        unsafe {
+            let used = addr_of!((*self.desc.as_ptr())[usize::from(self.last_used_idx)]);
+            (
+                addr_of!((*used).id).read_volatile(),
+                addr_of!((*used).len).read_volatile(),
+            )
+        }
+    }
+
+    /// Unshares the buffers which were added with the given token, without waiting for the device
+    /// to use them.
+    ///
+    /// # Safety
+    ///
+    /// The queue must have been reset, so that the device is no longer accessing the buffers. The
+    /// buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn reclaim<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result {
+        match self.buffers.get(usize::from(token)) {
+            Some(buffer) if buffer.num != 0 => {}
+            // The buffer ID is free.
+            _ => return Err(Error::WrongToken),
+        }
+        // Safe because the caller ensures the buffers are valid and match the descriptor.
+        unsafe {
+            self.recycle_descriptors(token, inputs, outputs);
+        }
+        Ok(())
+    }
+
+    /// Returns the index of the queue on its transport.
+    pub fn queue_idx(&self) -> u16 {
+        self.queue_idx
+    }
+
+    /// Returns the notification to send to the device about the buffers added so far.
+    pub fn notification(&self) -> Notification {
+        Notification {
+            queue: self.queue_idx,
+            next_off: self.avail_idx,
+            next_wrap: self.avail_wrap_counter,
+        }
+    }
+
+    /// Returns the size of the queue.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Sets the region of DMA memory whose buffers are passed to the device without being shared.
+    pub fn set_premapped(&mut self, premapped: Option<PremappedRegion>) {
+        self.premapped = premapped;
+    }
+
+    /// Returns whether all buffers which have been added to the queue have also been popped.
+    pub fn is_idle(&self) -> bool {
+        self.num_used == 0
+    }
+}
+
+// SAFETY: None of the virt queue resources are tied to a particular thread.
+unsafe impl<H: Hal, const SIZE: usize> Send for PackedQueue<H, SIZE> {}
+
+// SAFETY: A `&PackedQueue` only allows reading from the various pointers it contains, so there is
+// no data race.
+unsafe impl<H: Hal, const SIZE: usize> Sync for PackedQueue<H, SIZE> {}
+
+/// Returns the size in bytes of the descriptor ring, driver event suppression structure and device
+/// event suppression structure for a given queue size.
+///
+/// Ref: 2.7 Packed Virtqueues
+pub(super) fn queue_part_sizes(queue_size: u16) -> (usize, usize, usize) {
+    let queue_size = queue_size as usize;
+    let desc = size_of::<PackedDescriptor>() * queue_size;
+    (desc, size_of::<EventSuppress>(), size_of::<EventSuppress>())
+}
+
+/// Returns the `AVAIL` and `USED` flags to mark a descriptor as available with the given driver
+/// ring wrap counter.
+fn avail_flags(wrap_counter: bool) -> DescFlags {
+    if wrap_counter {
+        DescFlags::AVAIL
+    } else {
+        DescFlags::USED
+    }
+}
+
+/// Returns whether the given descriptor flags mark it as used with the given device ring wrap
+/// counter.
+fn is_used(flags: DescFlags, wrap_counter: bool) -> bool {
+    flags.contains(DescFlags::AVAIL) == wrap_counter
+        && flags.contains(DescFlags::USED) == wrap_counter
+}
+
+#[repr(C, align(16))]
+#[derive(AsBytes, Clone, Debug, FromBytes, FromZeroes)]
+pub(crate) struct PackedDescriptor {
+    addr: u64,
+    len: u32,
+    id: u16,
+    flags: DescFlags,
+}
+
+impl PackedDescriptor {
+    /// Sets the buffer address, length and flags, and shares it with the device unless it is within
+    /// the premapped region.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the buffer lives at least as long as the descriptor is active.
+    unsafe fn set_buf<H: Hal>(
+        &mut self,
+        premapped: Option<PremappedRegion>,
+        buf: NonNull<[u8]>,
+        direction: BufferDirection,
+        extra_flags: DescFlags,
+    ) {
+        // Safe because our caller promises that the buffer is valid.
+        unsafe {
+            self.addr = share_buffer::<H>(premapped, buf, direction) as u64;
+        }
+        self.len = buf.len() as u32;
+        self.flags = extra_flags
+            | match direction {
+                BufferDirection::DeviceToDriver => DescFlags::WRITE,
+                BufferDirection::DriverToDevice => DescFlags::empty(),
+                BufferDirection::Both => {
+                    panic!("Buffer passed to device should never use BufferDirection::Both.")
+                }
+            };
+    }
+
+    /// Sets the buffer address and length to 0.
+    ///
+    /// This must only be called once the device has finished using the descriptor.
+    fn unset_buf(&mut self) {
+        self.addr = 0;
+        self.len = 0;
+    }
+}
+
+/// Our bookkeeping for a buffer ID.
+#[derive(Clone, Copy, Debug, Default)]
+struct BufferState {
+    /// The next free buffer ID, if this one is free.
+    next: u16,
+    /// The ring slot of the first descriptor of the buffer.
+    head: u16,
+    /// The number of ring slots used by the buffer, or 0 if the ID is free.
+    num: u16,
+    /// The total length of the device-writable buffers. Used descriptors are checked against this.
+    #[cfg(feature = "hardened")]
+    writable_len: u32,
+}
+
+/// An event suppression structure, used by the driver and device to tell each other when they want
+/// to be notified.
+///
+/// Ref: 2.7.14 Event Suppression Structure Format
+#[repr(C)]
+#[derive(Debug)]
+struct EventSuppress {
+    /// The ring position and wrap counter to be notified about, if `flags` is
+    /// `RING_EVENT_FLAGS_DESC`.
+    off_wrap: AtomicU16,
+    flags: AtomicU16,
+}
+
+/// The state of a fake device processing a packed virtqueue, for use in tests.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct FakePackedDevice {
+    /// The ring slot of the next buffer the device will use.
+    next: u16,
+    /// The device's ring wrap counter.
+    wrap_counter: bool,
+}
+
+#[cfg(test)]
+impl Default for FakePackedDevice {
+    fn default() -> Self {
+        Self {
+            next: 0,
+            wrap_counter: true,
+        }
+    }
+}
+
+/// Simulates the device reading from a packed VirtIO queue and writing a response back, for use in
+/// tests.
+///
+/// The fake device always uses buffers in order.
+#[cfg(test)]
+pub(crate) fn fake_read_write_packed_queue(
+    descriptors: *mut [PackedDescriptor],
+    device: &mut FakePackedDevice,
+    handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
+) {
+    use core::{cmp::min, ops::Deref, ptr, slice};
+
+    let queue_size = descriptors.len();
+
+    // Safe because the various pointers are properly aligned, dereferenceable, initialised, and
+    // nothing else accesses them during this block other than to write the flags of the head
+    // descriptor.
+    unsafe {
+        let head = usize::from(device.next);
+        let head_flags = addr_of!((*descriptors)[head].flags).read_volatile();
+        // Make sure there is actually a buffer available to read from.
+        assert_eq!(head_flags.contains(DescFlags::AVAIL), device.wrap_counter);
+        assert_ne!(head_flags.contains(DescFlags::USED), device.wrap_counter);
+        fence(Ordering::SeqCst);
+
+        // Collect the buffer's descriptors, either from the ring or from an indirect table.
+        let mut chain: Vec<PackedDescriptor> = Vec::new();
+        let mut slots = 1;
+        if head_flags.contains(DescFlags::INDIRECT) {
+            let descriptor = &(*descriptors)[head];
+            chain.extend_from_slice(
+                zerocopy::Ref::<_, [PackedDescriptor]>::new_slice(slice::from_raw_parts(
+                    descriptor.addr as *const u8,
+                    descriptor.len as usize,
+                ))
+                .unwrap()
+                .into_slice(),
+            );
+        } else {
+            let mut slot = head;
+            loop {
+                let descriptor = (*descriptors)[slot].clone();
+                let next = descriptor.flags.contains(DescFlags::NEXT);
+                chain.push(descriptor);
+                if !next {
+                    break;
+                }
+                slot = (slot + 1) % queue_size;
+                slots += 1;
+            }
+        }
+        let id = chain.last().unwrap().id;
+
+        // Read data from the device-readable descriptors.
+        let mut input = Vec::new();
+        let mut descriptors_iter = chain.iter().peekable();
+        while let Some(descriptor) =
+            descriptors_iter.next_if(|d| !d.flags.contains(DescFlags::WRITE))
+        {
+            input.extend_from_slice(slice::from_raw_parts(
+                descriptor.addr as *const u8,
+                descriptor.len as usize,
+            ));
+        }
+
+        // Let the test handle the request.
+        let output = handler(input);
+
+        // Write the response to the remaining descriptors.
+        let mut remaining_output = output.deref();
+        for descriptor in descriptors_iter {
+            assert!(descriptor.flags.contains(DescFlags::WRITE));
+
+            let length_to_write = min(remaining_output.len(), descriptor.len as usize);
+            ptr::copy(
+                remaining_output.as_ptr(),
+                descriptor.addr as *mut u8,
+                length_to_write,
+            );
+            remaining_output = &remaining_output[length_to_write..];
+        }
+        assert_eq!(remaining_output.len(), 0);
+
+        // Mark the buffer as used.
+        (*descriptors)[head].id = id;
+        (*descriptors)[head].len = output.len() as u32;
+        fence(Ordering::SeqCst);
+        let used_flags = if device.wrap_counter {
+            DescFlags::AVAIL | DescFlags::USED
+        } else {
+            DescFlags::empty()
+        };
+        addr_of_mut!((*descriptors)[head].flags).write_volatile(used_flags);
+
+        device.next += slots;
+        if usize::from(device.next) >= queue_size {
+            device.next -= queue_size as u16;
+            device.wrap_counter = !device.wrap_counter;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::common::Feature,
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            mmio::{MmioTransport, VirtIOHeader, LEGACY_VERSION, MODERN_VERSION},
+            DeviceType,
+        },
+    };
+    use std::sync::{Arc, Mutex};
+
+    fn fake_transport(device_features: u64) -> FakeTransport<()> {
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features,
+            config_space: NonNull::dangling(),
+            state,
+        }
+    }
+
+    #[test]
+    fn queue_too_big() {
+        let mut transport = fake_transport(0);
+        assert_eq!(
+            PackedQueue::<FakeHal, 8>::new(&mut transport, 0, 8, false, false, false).unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    #[test]
+    fn queue_already_used() {
+        let mut transport = fake_transport(0);
+        PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+        assert_eq!(
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap_err(),
+            Error::AlreadyUsed
+        );
+    }
+
+    #[test]
+    fn legacy_unsupported() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        assert_eq!(
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap_err(),
+            Error::Unsupported
+        );
+    }
+
+    #[test]
+    fn modern_mmio() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+    }
+
+    #[test]
+    fn add_empty() {
+        let mut transport = fake_transport(0);
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+        assert_eq!(
+            unsafe { queue.add(&[], &mut []) }.unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    #[test]
+    fn add_too_many() {
+        let mut transport = fake_transport(0);
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+        assert_eq!(queue.available_desc(), 4);
+        assert_eq!(
+            unsafe { queue.add(&[&[], &[], &[]], &mut [&mut [], &mut []]) }.unwrap_err(),
+            Error::QueueFull
+        );
+    }
+
+    #[test]
+    fn add_buffers() {
+        let mut transport = fake_transport(0);
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+        assert_eq!(queue.available_desc(), 4);
+
+        // Add a buffer chain consisting of two device-readable parts followed by two
+        // device-writable parts.
+        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
+
+        assert_eq!(token, 0);
+        assert_eq!(queue.available_desc(), 0);
+        assert!(!queue.can_pop());
+
+        // Safe because the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            let descriptors = &*queue.desc.as_ptr();
+            assert_eq!(descriptors[0].len, 2);
+            assert_eq!(descriptors[0].flags, DescFlags::NEXT | DescFlags::AVAIL);
+            assert_eq!(descriptors[1].len, 1);
+            assert_eq!(descriptors[1].flags, DescFlags::NEXT | DescFlags::AVAIL);
+            assert_eq!(descriptors[2].len, 2);
+            assert_eq!(
+                descriptors[2].flags,
+                DescFlags::NEXT | DescFlags::WRITE | DescFlags::AVAIL
+            );
+            assert_eq!(descriptors[3].len, 1);
+            assert_eq!(descriptors[3].flags, DescFlags::WRITE | DescFlags::AVAIL);
+            for descriptor in descriptors {
+                assert_eq!(descriptor.id, token);
+            }
+        }
+    }
+
+    #[test]
+    fn add_buffers_indirect() {
+        use core::ptr::slice_from_raw_parts;
+
+        let mut transport = fake_transport(0);
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, true, false, false).unwrap();
+        assert_eq!(queue.available_desc(), 4);
+
+        // Add a buffer chain consisting of two device-readable parts followed by two
+        // device-writable parts.
+        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
+
+        assert_eq!(queue.available_desc(), 4);
+        assert!(!queue.can_pop());
+
+        // Safe because the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            let indirect_descriptor = &(*queue.desc.as_ptr())[0];
+            assert_eq!(indirect_descriptor.id, token);
+            assert_eq!(
+                indirect_descriptor.len as usize,
+                4 * size_of::<PackedDescriptor>()
+            );
+            assert_eq!(
+                indirect_descriptor.flags,
+                DescFlags::INDIRECT | DescFlags::AVAIL
+            );
+
+            let indirect_descriptors =
+                slice_from_raw_parts(indirect_descriptor.addr as *const PackedDescriptor, 4);
+            assert_eq!((*indirect_descriptors)[0].len, 2);
+            assert_eq!((*indirect_descriptors)[0].flags, DescFlags::empty());
+            assert_eq!((*indirect_descriptors)[1].len, 1);
+            assert_eq!((*indirect_descriptors)[1].flags, DescFlags::empty());
+            assert_eq!((*indirect_descriptors)[2].len, 2);
+            assert_eq!((*indirect_descriptors)[2].flags, DescFlags::WRITE);
+            assert_eq!((*indirect_descriptors)[3].len, 1);
+            assert_eq!((*indirect_descriptors)[3].flags, DescFlags::WRITE);
+        }
+    }
+
+    /// Tests that buffers can be added and popped repeatedly, with the ring and wrap counters
+    /// wrapping around several times.
+    #[test]
+    fn add_pop_wrap_around() {
+        let mut transport = fake_transport(0);
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+        let mut device = FakePackedDevice::default();
+
+        for i in 0..10u8 {
+            let request = [i, i + 1, i + 2];
+            let mut response = [0; 2];
+            let token = unsafe { queue.add(&[&request[..1], &request[1..]], &mut [&mut response]) }
+                .unwrap();
+            assert_eq!(queue.available_desc(), 1);
+            assert_eq!(queue.peek_used(), None);
+
+            fake_read_write_packed_queue(queue.desc.as_ptr(), &mut device, |input| {
+                assert_eq!(input, request);
+                vec![i, 42]
+            });
+
+            assert!(queue.can_pop());
+            assert_eq!(queue.peek_used(), Some(token));
+            assert_eq!(
+                unsafe {
+                    queue.pop_used(token, &[&request[..1], &request[1..]], &mut [&mut response])
+                },
+                Ok(2)
+            );
+            assert_eq!(response, [i, 42]);
+            assert_eq!(queue.available_desc(), 4);
+            assert!(!queue.can_pop());
+        }
+        // 10 buffers of 3 descriptors each is 30 slots, so the ring has wrapped around 7 times.
+        assert_eq!(queue.avail_idx, 2);
+        assert!(!queue.avail_wrap_counter);
+        assert_eq!(queue.last_used_idx, 2);
+        assert!(!queue.used_wrap_counter);
+    }
+
+    /// Tests that buffers added with `add_deferred` are only made available to the device once
+    /// `publish` is called, and that the device is notified if it asked to be about any of them.
+    #[test]
+    fn add_deferred_publish() {
+        let mut transport = fake_transport(0);
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, true, false).unwrap();
+        let mut device = FakePackedDevice::default();
+
+        // Ask to be notified about the first ring slot, which is used by the first buffer of the
+        // batch rather than the last.
+        unsafe {
+            (*queue.device_event.as_ptr())
+                .off_wrap
+                .store(RING_EVENT_WRAP_COUNTER, Ordering::Release);
+            (*queue.device_event.as_ptr())
+                .flags
+                .store(RING_EVENT_FLAGS_DESC, Ordering::Release);
+        }
+
+        let first = unsafe { queue.add_deferred(&[&[1]], &mut []) }.unwrap();
+        let second = unsafe { queue.add_deferred(&[&[2], &[3]], &mut []) }.unwrap();
+        let desc = queue.desc;
+        let flags = |slot: usize| unsafe { (*desc.as_ptr())[slot].flags };
+        // The first buffer is held back, but the second can already be marked available.
+        assert_eq!(flags(0), DescFlags::empty());
+        assert_eq!(flags(1), avail_flags(true) | DescFlags::NEXT);
+
+        queue.publish();
+        assert_eq!(flags(0), avail_flags(true));
+        assert!(queue.should_notify());
+
+        for (token, request) in [(first, vec![1]), (second, vec![2, 3])] {
+            fake_read_write_packed_queue(queue.desc.as_ptr(), &mut device, |input| {
+                assert_eq!(input, request);
+                vec![]
+            });
+            assert_eq!(queue.peek_used(), Some(token));
+            let inputs: Vec<&[u8]> = request.chunks(1).collect();
+            assert_eq!(unsafe { queue.pop_used(token, &inputs, &mut []) }, Ok(0));
+        }
+    }
+
+    /// Tests that notifications include the next ring position and wrap counter for
+    /// `VIRTIO_F_NOTIFICATION_DATA`.
+    #[test]
+    fn notification_data() {
+        let mut transport = fake_transport(0);
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+
+        unsafe { queue.add(&[&[1], &[2], &[3]], &mut []) }.unwrap();
+        assert_eq!(
+            queue.notification(),
+            Notification {
+                queue: 0,
+                next_off: 3,
+                next_wrap: true,
+            }
+        );
+        assert_eq!(queue.notification().data(), 0x8003_0000);
+
+        // Wrap around the ring.
+        unsafe { queue.add(&[&[4]], &mut []) }.unwrap();
+        assert_eq!(queue.notification().data(), 0x0000_0000);
+    }
+
+    /// Tests that popping a different buffer to the one the device used fails.
+    #[test]
+    fn pop_wrong_token() {
+        let mut transport = fake_transport(0);
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+        let mut device = FakePackedDevice::default();
+
+        let first = unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+        let second = unsafe { queue.add(&[&[2]], &mut []) }.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(
+            unsafe { queue.pop_used(first, &[&[1]], &mut []) },
+            Err(Error::NotReady)
+        );
+
+        fake_read_write_packed_queue(queue.desc.as_ptr(), &mut device, |input| {
+            assert_eq!(input, [1]);
+            vec![]
+        });
+        assert_eq!(
+            unsafe { queue.pop_used(second, &[&[2]], &mut []) },
+            Err(Error::WrongToken)
+        );
+        assert_eq!(unsafe { queue.pop_used(first, &[&[1]], &mut []) }, Ok(0));
+    }
+
+    #[cfg(feature = "hardened")]
+    #[test]
+    fn pop_used_invalid() {
+        let mut transport = fake_transport(0);
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+        let mut device = FakePackedDevice::default();
+
+        let mut response = [0; 2];
+        let token = unsafe { queue.add(&[&[1]], &mut [&mut response]) }.unwrap();
+        fake_read_write_packed_queue(queue.desc.as_ptr(), &mut device, |input| {
+            assert_eq!(input, [1]);
+            vec![1, 2]
+        });
+        assert_eq!(queue.peek_used(), Some(token));
+
+        for id in [token + 1, 42] {
+            // SAFETY: the descriptor ring is properly aligned, dereferenceable and initialised,
+            // and nothing else is accessing it at the same time.
+            unsafe {
+                (*queue.desc.as_ptr())[0].id = id;
+            }
+            assert!(queue.can_pop());
+            assert_eq!(queue.peek_used(), None);
+            assert_eq!(
+                unsafe { queue.pop_used(id, &[&[1]], &mut [&mut response]) },
+                Err(Error::InvalidUsedId)
+            );
+        }
+
+        // SAFETY: as above.
+        unsafe {
+            (*queue.desc.as_ptr())[0].id = token;
+            (*queue.desc.as_ptr())[0].len = 3;
+        }
+        assert_eq!(queue.peek_used(), Some(token));
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[1]], &mut [&mut response]) },
+            Err(Error::InvalidUsedLen)
+        );
+        // The buffer was still freed.
+        assert!(!queue.can_pop());
+        assert_eq!(queue.available_desc(), 4);
+    }
+
+    /// Tests that with `VIRTIO_F_IN_ORDER` a single used descriptor completes all earlier buffers
+    /// too.
+    #[test]
+    fn pop_used_in_order_batch() {
+        let mut transport = fake_transport(0);
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, true).unwrap();
+
+        let mut first = [0; 2];
+        let mut second = [0];
+        let mut third = [0];
+        assert_eq!(unsafe { queue.add(&[], &mut [&mut first]) }.unwrap(), 0);
+        assert_eq!(
+            unsafe { queue.add(&[&[1]], &mut [&mut second]) }.unwrap(),
+            1
+        );
+        assert_eq!(unsafe { queue.add(&[], &mut [&mut third]) }.unwrap(), 2);
+        assert!(!queue.can_pop());
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Use all three buffers with a single used descriptor for the last one, written where
+            // the first one started.
+            let descriptors = &mut *queue.desc.as_ptr();
+            descriptors[0].id = 2;
+            descriptors[0].len = 1;
+            descriptors[0].flags = DescFlags::AVAIL | DescFlags::USED;
+        }
+
+        assert_eq!(queue.peek_used(), Some(0));
+        assert_eq!(
+            unsafe { queue.pop_used(1, &[&[1]], &mut [&mut second]) },
+            Err(Error::WrongToken)
+        );
+        assert_eq!(unsafe { queue.pop_used(0, &[], &mut [&mut first]) }, Ok(2));
+        assert_eq!(queue.peek_used(), Some(1));
+        assert_eq!(
+            unsafe { queue.pop_used(1, &[&[1]], &mut [&mut second]) },
+            Ok(1)
+        );
+        assert_eq!(queue.peek_used(), Some(2));
+        assert_eq!(unsafe { queue.pop_used(2, &[], &mut [&mut third]) }, Ok(1));
+        assert!(!queue.can_pop());
+        assert_eq!(queue.available_desc(), 4);
+        assert_eq!(queue.last_used_idx, 0);
+        assert!(!queue.used_wrap_counter);
+    }
+
+    /// Tests that the queue advises the device that notifications are needed.
+    #[test]
+    fn set_dev_notify() {
+        let mut transport = fake_transport(0);
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+
+        // Check that the driver event flags are zero by default.
+        assert_eq!(
+            unsafe { (*queue.driver_event.as_ptr()).flags.load(Ordering::Acquire) },
+            RING_EVENT_FLAGS_ENABLE
+        );
+
+        queue.set_dev_notify(false);
+
+        assert_eq!(
+            unsafe { (*queue.driver_event.as_ptr()).flags.load(Ordering::Acquire) },
+            RING_EVENT_FLAGS_DISABLE
+        );
+
+        queue.set_dev_notify(true);
+
+        assert_eq!(
+            unsafe { (*queue.driver_event.as_ptr()).flags.load(Ordering::Acquire) },
+            RING_EVENT_FLAGS_ENABLE
+        );
+    }
+
+    /// Tests that the queue re-arms notifications for the next used descriptor if
+    /// `VIRTIO_F_EVENT_IDX` has been negotiated.
+    #[test]
+    fn set_dev_notify_event_idx() {
+        let mut transport = fake_transport(Feature::RING_EVENT_IDX.bits());
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, true, false).unwrap();
+
+        queue.set_dev_notify(false);
+
+        assert_eq!(
+            unsafe { (*queue.driver_event.as_ptr()).flags.load(Ordering::Acquire) },
+            RING_EVENT_FLAGS_DISABLE
+        );
+
+        queue.set_dev_notify(true);
+
+        assert_eq!(
+            unsafe { (*queue.driver_event.as_ptr()).flags.load(Ordering::Acquire) },
+            RING_EVENT_FLAGS_DESC
+        );
+    }
+
+    /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
+    /// notifications.
+    #[test]
+    fn add_notify() {
+        let mut transport = fake_transport(0);
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+
+        // Add a buffer chain with a single device-readable part.
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+
+        // Check that the transport would be notified.
+        assert!(queue.should_notify());
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Suppress notifications.
+            (*queue.device_event.as_ptr())
+                .flags
+                .store(RING_EVENT_FLAGS_DISABLE, Ordering::Release);
+        }
+
+        // Check that the transport would not be notified.
+        assert!(!queue.should_notify());
+    }
+
+    /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
+    /// notifications with the event suppression descriptor position, including across the ring
+    /// wrapping around.
+    #[test]
+    fn add_notify_event_idx() {
+        let mut transport = fake_transport(Feature::RING_EVENT_IDX.bits());
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, true, false).unwrap();
+        let mut device = FakePackedDevice::default();
+
+        // The driver asks to be notified about the first used descriptor.
+        assert_eq!(
+            unsafe { (*queue.driver_event.as_ptr()).flags.load(Ordering::Acquire) },
+            RING_EVENT_FLAGS_DESC
+        );
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Ask to be notified only once slot 2 of the first lap is made available.
+            (*queue.device_event.as_ptr())
+                .off_wrap
+                .store(2 | RING_EVENT_WRAP_COUNTER, Ordering::Release);
+            (*queue.device_event.as_ptr())
+                .flags
+                .store(RING_EVENT_FLAGS_DESC, Ordering::Release);
+        }
+
+        // Slots 0 and 1.
+        assert_eq!(unsafe { queue.add(&[&[1], &[2]], &mut []) }.unwrap(), 0);
+        assert!(!queue.should_notify());
+        // Slot 2.
+        assert_eq!(unsafe { queue.add(&[&[3]], &mut []) }.unwrap(), 1);
+        assert!(queue.should_notify());
+
+        for (token, inputs) in [(0, &[&[1][..], &[2]][..]), (1, &[&[3]])] {
+            fake_read_write_packed_queue(queue.desc.as_ptr(), &mut device, |_| vec![]);
+            unsafe { queue.pop_used(token, inputs, &mut []) }.unwrap();
+        }
+        // The driver asks to be notified about the next used descriptor.
+        assert_eq!(
+            unsafe {
+                (*queue.driver_event.as_ptr())
+                    .off_wrap
+                    .load(Ordering::Acquire)
+            },
+            3 | RING_EVENT_WRAP_COUNTER
+        );
+
+        // SAFETY: as above.
+        unsafe {
+            // Ask to be notified once slot 0 of the second lap is made available.
+            (*queue.device_event.as_ptr())
+                .off_wrap
+                .store(0, Ordering::Release);
+        }
+
+        // Slot 3.
+        unsafe { queue.add(&[&[4]], &mut []) }.unwrap();
+        assert!(!queue.should_notify());
+        // Slots 0 and 1 of the second lap.
+        unsafe { queue.add(&[&[5], &[6]], &mut []) }.unwrap();
+        assert!(queue.should_notify());
+    }
+
+    /// Tests that the queue notifies the device if any of the buffers added since it last checked
+    /// reach the event suppression descriptor position, not just the last one.
+    #[test]
+    fn add_twice_notify_event_idx() {
+        let mut transport = fake_transport(Feature::RING_EVENT_IDX.bits());
+        let mut queue =
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, true, false).unwrap();
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Ask to be notified once slot 0 of the first lap is made available.
+            (*queue.device_event.as_ptr())
+                .off_wrap
+                .store(RING_EVENT_WRAP_COUNTER, Ordering::Release);
+            (*queue.device_event.as_ptr())
+                .flags
+                .store(RING_EVENT_FLAGS_DESC, Ordering::Release);
+        }
+
+        // Slots 0 and 1, without checking in between.
+        unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+        unsafe { queue.add(&[&[2]], &mut []) }.unwrap();
+        assert!(queue.should_notify());
+        // Nothing has been added since the last check.
+        assert!(!queue.should_notify());
+    }
+}
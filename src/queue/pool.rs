@@ -0,0 +1,145 @@
+//! Pools of buffers in DMA memory which can be passed to the device without being shared and
+//! unshared for each request.
+
+use super::PremappedRegion;
+use crate::hal::{BufferDirection, Dma, Hal};
+use crate::sync::SpinLock;
+use crate::{pages, Error, Result};
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt::{self, Debug, Formatter};
+use core::mem::align_of;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::slice;
+
+/// A pool of equally sized buffers in DMA memory.
+///
+/// The memory for the whole pool is allocated, and so shared with the device, once when the pool
+/// is created. Buffers allocated from the pool can then be added to any [`VirtQueue`] which the
+/// pool has been [set on](super::VirtQueue::set_dma_pool), and the queue passes their physical
+/// addresses to the device directly rather than calling [`Hal::share`] and [`Hal::unshare`] for
+/// each request. On a HAL which uses bounce buffers this avoids copying every buffer, and on one
+/// which uses an IOMMU it avoids mapping every buffer.
+///
+/// The pool is reference counted, and each [`DmaBuffer`] keeps it alive until the buffer is
+/// dropped, at which point the buffer is returned to the pool.
+///
+/// [`VirtQueue`]: super::VirtQueue
+/// [`VirtQueue::set_dma_pool`]: super::VirtQueue::set_dma_pool
+pub struct DmaPool<H: Hal> {
+    dma: Dma<H>,
+    /// The length of each buffer in bytes.
+    buffer_len: usize,
+    /// The distance between the start of one buffer and the start of the next.
+    stride: usize,
+    /// The indices of the buffers which are not currently allocated.
+    free: SpinLock<Vec<usize>>,
+}
+
+impl<H: Hal> DmaPool<H> {
+    /// Allocates a pool of `count` buffers of `buffer_len` bytes each, which the device will access
+    /// in the given direction.
+    ///
+    /// Each buffer is aligned to at least `align_of::<usize>()`. This fails with
+    /// [`Error::InvalidParam`] if either `count` or `buffer_len` is 0.
+    pub fn new(count: usize, buffer_len: usize, direction: BufferDirection) -> Result<Arc<Self>> {
+        if count == 0 || buffer_len == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let stride = buffer_len
+            .checked_next_multiple_of(align_of::<usize>())
+            .ok_or(Error::InvalidParam)?;
+        let size = stride.checked_mul(count).ok_or(Error::InvalidParam)?;
+        let dma = Dma::new(pages(size), direction)?;
+        Ok(Arc::new(Self {
+            dma,
+            buffer_len,
+            stride,
+            // Hand out the lowest indices first.
+            free: SpinLock::new((0..count).rev().collect()),
+        }))
+    }
+
+    /// Returns the length in bytes of each buffer in the pool.
+    pub fn buffer_len(&self) -> usize {
+        self.buffer_len
+    }
+
+    /// Allocates a buffer from the pool, or returns `None` if they are all in use.
+    ///
+    /// The buffer may contain data from its previous use.
+    pub fn alloc(self: &Arc<Self>) -> Option<DmaBuffer<H>> {
+        let index = self.free.lock().pop()?;
+        Some(DmaBuffer {
+            pool: self.clone(),
+            index,
+        })
+    }
+
+    /// Returns the region of DMA memory containing all the buffers of the pool.
+    pub(super) fn region(&self) -> PremappedRegion {
+        PremappedRegion {
+            vaddr: self.dma.vaddr(0).as_ptr() as usize,
+            paddr: self.dma.paddr(),
+            len: self.dma.raw_slice().len(),
+        }
+    }
+}
+
+impl<H: Hal> Debug for DmaPool<H> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("DmaPool")
+            .field("paddr", &self.dma.paddr())
+            .field("buffer_len", &self.buffer_len)
+            .field("free", &self.free.lock().len())
+            .finish()
+    }
+}
+
+/// A buffer allocated from a [`DmaPool`], which is returned to the pool when dropped.
+///
+/// It dereferences to a byte slice of the pool's buffer length.
+pub struct DmaBuffer<H: Hal> {
+    pool: Arc<DmaPool<H>>,
+    index: usize,
+}
+
+impl<H: Hal> DmaBuffer<H> {
+    /// Returns a pointer to the start of the buffer.
+    fn start(&self) -> NonNull<u8> {
+        self.pool.dma.vaddr(self.index * self.pool.stride)
+    }
+}
+
+impl<H: Hal> Deref for DmaBuffer<H> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Safe because the buffer is within the pool's DMA region, which lives as long as the pool,
+        // and no other `DmaBuffer` has the same index until this one is dropped.
+        unsafe { slice::from_raw_parts(self.start().as_ptr(), self.pool.buffer_len) }
+    }
+}
+
+impl<H: Hal> DerefMut for DmaBuffer<H> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // Safe because the buffer is within the pool's DMA region, which lives as long as the pool,
+        // and no other `DmaBuffer` has the same index until this one is dropped.
+        unsafe { slice::from_raw_parts_mut(self.start().as_ptr(), self.pool.buffer_len) }
+    }
+}
+
+impl<H: Hal> Debug for DmaBuffer<H> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("DmaBuffer")
+            .field("index", &self.index)
+            .field("len", &self.pool.buffer_len)
+            .finish()
+    }
+}
+
+impl<H: Hal> Drop for DmaBuffer<H> {
+    fn drop(&mut self) {
+        self.pool.free.lock().push(self.index);
+    }
+}
@@ -0,0 +1,1642 @@
+//! Split virtqueue layout.
+
+use super::{
+    share_buffer, unshare_buffer, vring_need_event, Bookkeeping, DescFlags, IndirectPool,
+    InputOutputIter, PremappedRegion, VirtQueueLayout,
+};
+use crate::hal::{BufferDirection, Hal};
+use crate::transport::{Notification, Transport};
+use crate::{nonnull_slice_from_raw_parts, Error, Result};
+#[cfg(test)]
+use core::cmp::min;
+use core::mem::{self, size_of, size_of_val};
+#[cfg(test)]
+use core::ptr;
+use core::ptr::{addr_of_mut, NonNull};
+use core::sync::atomic::{fence, AtomicU16, Ordering};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// A virtqueue using the split layout, with separate descriptor table, available ring and used
+/// ring.
+///
+/// * `SIZE`: The largest queue size for which bookkeeping is stored inline rather than on the heap.
+///   It must be a power of 2 and fit in a [`u16`].
+///
+/// Ref: 2.6 Split Virtqueues
+#[derive(Debug)]
+pub(super) struct SplitQueue<H: Hal, const SIZE: usize> {
+    /// DMA guard
+    layout: VirtQueueLayout<H>,
+    /// Descriptor table
+    ///
+    /// The device may be able to modify this, even though it's not supposed to, so we shouldn't
+    /// trust values read back from it. Use `desc_shadow` instead to keep track of what we wrote to
+    /// it.
+    desc: NonNull<[Descriptor]>,
+    /// Available ring
+    ///
+    /// The device may be able to modify this, even though it's not supposed to, so we shouldn't
+    /// trust values read back from it. The only field we need to read currently is `idx`, so we
+    /// have `avail_idx` below to use instead.
+    avail: NonNull<AvailRing>,
+    /// Used ring
+    used: NonNull<UsedRing>,
+
+    /// The index of queue
+    queue_idx: u16,
+    /// The size of the queue. This is both the number of descriptors, and the number of slots in
+    /// the available and used rings.
+    size: u16,
+    /// The number of descriptors currently in use.
+    num_used: u16,
+    /// The head desc index of the free list.
+    free_head: u16,
+    /// Our trusted copy of `desc` that the device can't access.
+    desc_shadow: Bookkeeping<Descriptor, SIZE>,
+    /// Our trusted copy of `avail.idx`.
+    avail_idx: u16,
+    /// The value of `avail_idx` when `should_notify` was last called.
+    checked_avail_idx: u16,
+    last_used_idx: u16,
+    /// Whether the `VIRTIO_F_EVENT_IDX` feature has been negotiated.
+    event_idx: bool,
+    /// Whether the driver wants used buffer notifications.
+    dev_notify: bool,
+    /// Whether the `VIRTIO_F_IN_ORDER` feature has been negotiated.
+    ///
+    /// If so, descriptors are allocated sequentially around the table and buffers are always used
+    /// in the order they were added, so the free list never needs to be relinked.
+    in_order: bool,
+    /// The head descriptor index of the oldest buffer chain which hasn't yet been popped. Only used
+    /// if `in_order` is set.
+    next_in_order: u16,
+    /// The descriptor index and length from the used element which completed the current batch,
+    /// if we are part way through popping the chains it covers. Only used if `in_order` is set.
+    batch_last: Option<(u16, u32)>,
+    /// The indirect descriptor tables, indexed by head descriptor, if indirect descriptors are
+    /// enabled.
+    indirect_pool: Option<IndirectPool<H, Descriptor>>,
+    /// The region of DMA memory whose buffers are passed to the device without being shared, if
+    /// any.
+    premapped: Option<PremappedRegion>,
+    /// The total length of the device-writable buffers of each outstanding descriptor chain,
+    /// indexed by the head descriptor, or `None` if the descriptor isn't the head of an outstanding
+    /// chain. Used entries in the used ring are checked against this.
+    #[cfg(feature = "hardened")]
+    writable_lens: Bookkeeping<Option<u32>, SIZE>,
+}
+
+impl<H: Hal, const SIZE: usize> SplitQueue<H, SIZE> {
+    const SIZE_OK: () = assert!(SIZE.is_power_of_two() && SIZE <= u16::MAX as usize);
+
+    /// Creates a new split virtqueue with `size` descriptors, which must be a power of 2.
+    ///
+    /// * `indirect`: Whether to use indirect descriptors. This should be set if the
+    ///   `VIRTIO_F_INDIRECT_DESC` feature has been negotiated with the device.
+    /// * `event_idx`: Whether to use the `used_event` and `avail_event` fields for notification
+    ///   suppression. This should be set if the `VIRTIO_F_EVENT_IDX` feature has been negotiated
+    ///   with the device.
+    /// * `in_order`: Whether the device uses buffers in the order they were made available. This
+    ///   should be set if the `VIRTIO_F_IN_ORDER` feature has been negotiated with the device.
+    pub fn new<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        size: u16,
+        indirect: bool,
+        event_idx: bool,
+        in_order: bool,
+    ) -> Result<Self> {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::SIZE_OK;
+
+        if transport.queue_used(idx) {
+            return Err(Error::AlreadyUsed);
+        }
+        if transport.max_queue_size(idx) < size.into() {
+            return Err(Error::InvalidParam);
+        }
+        let mut desc_shadow: Bookkeeping<Descriptor, SIZE> =
+            Bookkeeping::new(size.into(), FromZeroes::new_zeroed())?;
+        let indirect_pool = if indirect {
+            Some(IndirectPool::new(size)?)
+        } else {
+            None
+        };
+        #[cfg(feature = "hardened")]
+        let writable_lens = Bookkeeping::new(size.into(), None)?;
+
+        let layout = if transport.requires_legacy_layout() {
+            VirtQueueLayout::allocate_legacy(size)?
+        } else {
+            VirtQueueLayout::allocate_flexible(size)?
+        };
+
+        transport.queue_set(
+            idx,
+            size.into(),
+            layout.descriptors_paddr(),
+            layout.driver_area_paddr(),
+            layout.device_area_paddr(),
+        );
+
+        let desc = nonnull_slice_from_raw_parts(
+            layout.descriptors_vaddr().cast::<Descriptor>(),
+            size.into(),
+        );
+        let avail = layout.avail_vaddr().cast();
+        let used = layout.used_vaddr().cast();
+
+        // Link descriptors together. The last one is left pointing back to the first, which is
+        // relied on when allocating sequentially for `in_order`.
+        for i in 0..(size - 1) {
+            desc_shadow[i as usize].next = i + 1;
+            // Safe because `desc` is properly aligned, dereferenceable, initialised, and the device
+            // won't access the descriptors for the duration of this unsafe block.
+            unsafe {
+                (*desc.as_ptr())[i as usize].next = i + 1;
+            }
+        }
+
+        Ok(SplitQueue {
+            layout,
+            desc,
+            avail,
+            used,
+            queue_idx: idx,
+            size,
+            num_used: 0,
+            free_head: 0,
+            desc_shadow,
+            avail_idx: 0,
+            checked_avail_idx: 0,
+            last_used_idx: 0,
+            event_idx,
+            dev_notify: true,
+            in_order,
+            next_in_order: 0,
+            batch_last: None,
+            indirect_pool,
+            premapped: None,
+            #[cfg(feature = "hardened")]
+            writable_lens,
+        })
+    }
+
+    /// Add buffers to the virtqueue, return a token.
+    ///
+    /// The buffers must not be empty.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_add
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        // Safe because our caller upholds the same contract.
+        let head = unsafe { self.add_deferred(inputs, outputs) }?;
+        self.publish();
+        Ok(head)
+    }
+
+    /// Adds buffers to the virtqueue like `add`, but doesn't update the available index, so the
+    /// device won't see them until `publish` is called.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add_deferred<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        if inputs.is_empty() && outputs.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let descriptors_needed = inputs.len() + outputs.len();
+        let indirect = descriptors_needed > 1
+            && self
+                .indirect_pool
+                .as_ref()
+                .is_some_and(|pool| pool.fits(descriptors_needed));
+        let direct_descriptors_needed = if indirect { 1 } else { descriptors_needed };
+        if usize::from(self.num_used) + direct_descriptors_needed > usize::from(self.size) {
+            return Err(Error::QueueFull);
+        }
+
+        let head = if indirect {
+            self.add_indirect(inputs, outputs)
+        } else {
+            self.add_direct(inputs, outputs)
+        };
+
+        #[cfg(feature = "hardened")]
+        {
+            self.writable_lens[usize::from(head)] =
+                Some(outputs.iter().map(|buffer| buffer.len() as u32).sum());
+        }
+
+        let avail_slot = self.avail_idx & (self.size - 1);
+        // Safe because self.avail is properly aligned, dereferenceable and initialised.
+        unsafe {
+            *self.avail_ring(avail_slot) = head;
+        }
+
+        // increase head of avail ring
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+
+        Ok(head)
+    }
+
+    /// Makes all buffers added so far available to the device, by updating the available index.
+    pub fn publish(&mut self) {
+        // Write barrier so that device sees changes to descriptor table and available ring before
+        // change to available index.
+        fence(Ordering::SeqCst);
+
+        // Safe because self.avail is properly aligned, dereferenceable and initialised.
+        unsafe {
+            (*self.avail.as_ptr())
+                .idx
+                .store(self.avail_idx, Ordering::Release);
+        }
+    }
+
+    fn add_direct<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> u16 {
+        // allocate descriptors from free list
+        let head = self.free_head;
+        let mut last = self.free_head;
+
+        for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
+            assert_ne!(buffer.len(), 0);
+
+            // Write to desc_shadow then copy.
+            let desc = &mut self.desc_shadow[usize::from(self.free_head)];
+            // Safe because our caller promises that the buffers live at least until `pop_used`
+            // returns them.
+            unsafe {
+                desc.set_buf::<H>(self.premapped, buffer, direction, DescFlags::NEXT);
+            }
+            last = self.free_head;
+            self.free_head = desc.next;
+
+            self.write_desc(last);
+        }
+
+        // set last_elem.next = NULL
+        self.desc_shadow[usize::from(last)]
+            .flags
+            .remove(DescFlags::NEXT);
+        self.write_desc(last);
+
+        self.num_used += (inputs.len() + outputs.len()) as u16;
+
+        head
+    }
+
+    fn add_indirect<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> u16 {
+        let head = self.free_head;
+
+        // Fill in the indirect descriptor table for the head descriptor from the pool.
+        let (indirect_list, indirect_paddr) = self
+            .indirect_pool
+            .as_ref()
+            .unwrap()
+            .table(head, inputs.len() + outputs.len());
+        // SAFETY: The table is in DMA memory owned by the pool, and isn't in use by the device
+        // because `head` is free.
+        let indirect_list = unsafe { &mut *indirect_list.as_ptr() };
+        for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
+            let desc = &mut indirect_list[i];
+            // Safe because our caller promises that the buffers live at least until `pop_used`
+            // returns them.
+            unsafe {
+                desc.set_buf::<H>(self.premapped, buffer, direction, DescFlags::NEXT);
+            }
+            desc.next = (i + 1) as u16;
+        }
+        indirect_list
+            .last_mut()
+            .unwrap()
+            .flags
+            .remove(DescFlags::NEXT);
+
+        // Write a descriptor pointing to the indirect descriptor table. It is already in DMA
+        // memory, so doesn't need to be shared.
+        let direct_desc = &mut self.desc_shadow[usize::from(head)];
+        self.free_head = direct_desc.next;
+        direct_desc.addr = indirect_paddr as u64;
+        direct_desc.len = size_of_val(indirect_list) as u32;
+        direct_desc.flags = DescFlags::INDIRECT;
+        self.write_desc(head);
+        self.num_used += 1;
+
+        head
+    }
+
+    /// Advise the device whether used buffer notifications are needed.
+    ///
+    /// See Virtio v1.1 2.6.7 Used Buffer Notification Suppression
+    pub fn set_dev_notify(&mut self, enable: bool) {
+        self.dev_notify = enable;
+        let avail_ring_flags = if enable { 0x0000 } else { 0x0001 };
+        if self.event_idx {
+            // The device ignores the flags if `VIRTIO_F_EVENT_IDX` has been negotiated.
+            self.update_used_event();
+        } else {
+            // Safe because self.avail points to a valid, aligned, initialised, dereferenceable, readable
+            // instance of AvailRing.
+            unsafe {
+                (*self.avail.as_ptr())
+                    .flags
+                    .store(avail_ring_flags, Ordering::Release)
+            }
+        }
+    }
+
+    /// Sets `used_event` so that the device notifies us when it uses the next buffer, or if
+    /// notifications are disabled, just behind `last_used_idx` so that it doesn't.
+    ///
+    /// Must only be called if `VIRTIO_F_EVENT_IDX` has been negotiated.
+    fn update_used_event(&self) {
+        let used_event = if self.dev_notify {
+            self.last_used_idx
+        } else {
+            // The device only notifies when its used index moves past `used_event`, which it can
+            // never do while `used_event` is behind the entries we have already popped.
+            self.last_used_idx.wrapping_sub(1)
+        };
+        self.used_event().store(used_event, Ordering::Release);
+    }
+
+    /// Returns whether the driver should notify the device after adding a new buffer to the
+    /// virtqueue.
+    ///
+    /// This will be false if the device has supressed notifications. Buffers added before this is
+    /// called are not considered again by the next call, so the driver must notify the device if
+    /// this returns true.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_kick_prepare_split
+    pub fn should_notify(&mut self) -> bool {
+        let old = mem::replace(&mut self.checked_avail_idx, self.avail_idx);
+        if self.event_idx {
+            let avail_event = self.avail_event().load(Ordering::Acquire);
+            vring_need_event(avail_event, self.avail_idx, old)
+        } else {
+            // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+            // instance of UsedRing.
+            unsafe { (*self.used.as_ptr()).flags.load(Ordering::Acquire) & 0x0001 == 0 }
+        }
+    }
+
+    /// Copies the descriptor at the given index from `desc_shadow` to `desc`, so it can be seen by
+    /// the device.
+    fn write_desc(&mut self, index: u16) {
+        let index = usize::from(index);
+        // Safe because self.desc is properly aligned, dereferenceable and initialised, and nothing
+        // else reads or writes the descriptor during this block.
+        unsafe {
+            (*self.desc.as_ptr())[index] = self.desc_shadow[index].clone();
+        }
+    }
+
+    /// Returns whether there is a used element that can be popped.
+    pub fn can_pop(&self) -> bool {
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing.
+        self.batch_last.is_some()
+            || self.last_used_idx != unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) }
+    }
+
+    /// Returns the descriptor index (a.k.a. token) of the next used element without popping it, or
+    /// `None` if the used ring is empty.
+    pub fn peek_used(&self) -> Option<u16> {
+        if self.in_order {
+            // The used element may be for a later chain, but that means this one is done too.
+            self.can_pop().then_some(self.next_in_order)
+        } else if self.can_pop() {
+            let last_used_slot = self.last_used_idx & (self.size - 1);
+            // Safe because self.used points to a valid, aligned, initialised, dereferenceable,
+            // readable instance of UsedRing.
+            let id = unsafe { (*self.used_elem(last_used_slot)).id };
+            #[cfg(feature = "hardened")]
+            if self.used_writable_len(id).is_err() {
+                return None;
+            }
+            Some(id as u16)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of free descriptors.
+    pub fn available_desc(&self) -> usize {
+        if self.indirect_pool.is_some() {
+            return if self.num_used == self.size {
+                0
+            } else {
+                self.size.into()
+            };
+        }
+
+        usize::from(self.size - self.num_used)
+    }
+
+    /// Unshares buffers in the list starting at descriptor index `head` and adds them to the free
+    /// list. Unsharing may involve copying data back to the original buffers, so they must be
+    /// passed in too.
+    ///
+    /// This will push all linked descriptors at the front of the free list, unless `in_order` is
+    /// set in which case they are already in the right place.
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add`.
+    unsafe fn recycle_descriptors<'a>(
+        &mut self,
+        head: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) {
+        let original_free_head = self.free_head;
+        if !self.in_order {
+            self.free_head = head;
+        }
+        #[cfg(feature = "hardened")]
+        {
+            self.writable_lens[usize::from(head)] = None;
+        }
+
+        let head_desc = &mut self.desc_shadow[usize::from(head)];
+        if head_desc.flags.contains(DescFlags::INDIRECT) {
+            // Find the indirect descriptor list in the pool, and move its descriptor to the free
+            // list.
+            assert_eq!(
+                head_desc.len as usize,
+                (inputs.len() + outputs.len()) * size_of::<Descriptor>(),
+                "Indirect descriptor list had a different length than expected."
+            );
+            head_desc.unset_buf();
+            self.num_used -= 1;
+            if !self.in_order {
+                head_desc.next = original_free_head;
+            }
+            let (indirect_list, _) = self
+                .indirect_pool
+                .as_ref()
+                .unwrap()
+                .table(head, inputs.len() + outputs.len());
+
+            // Unshare the buffers in the indirect descriptor list.
+            for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
+                assert_ne!(buffer.len(), 0);
+
+                // SAFETY: The caller ensures that the buffer is valid and matches the descriptor
+                // from which we got the address, and the device has finished accessing the
+                // indirect descriptor list by this point.
+                unsafe {
+                    // Unshare the buffer (and perhaps copy its contents back to the original
+                    // buffer).
+                    unshare_buffer::<H>(
+                        self.premapped,
+                        (*indirect_list.as_ptr())[i].addr as usize,
+                        buffer,
+                        direction,
+                    );
+                }
+            }
+        } else {
+            let mut next = Some(head);
+
+            for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
+                assert_ne!(buffer.len(), 0);
+
+                let desc_index = next.expect("Descriptor chain was shorter than expected.");
+                let desc = &mut self.desc_shadow[usize::from(desc_index)];
+
+                let paddr = desc.addr;
+                desc.unset_buf();
+                self.num_used -= 1;
+                next = desc.next();
+                if next.is_none() && !self.in_order {
+                    desc.next = original_free_head;
+                }
+
+                self.write_desc(desc_index);
+
+                // SAFETY: The caller ensures that the buffer is valid and matches the descriptor
+                // from which we got `paddr`.
+                unsafe {
+                    // Unshare the buffer (and perhaps copy its contents back to the original buffer).
+                    unshare_buffer::<H>(self.premapped, paddr as usize, buffer, direction);
+                }
+            }
+
+            if next.is_some() {
+                panic!("Descriptor chain was longer than expected.");
+            }
+        }
+    }
+
+    /// If the given token is next on the device used queue, pops it and returns the total buffer
+    /// length which was used (written) by the device.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn pop_used<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        if !self.can_pop() {
+            return Err(Error::NotReady);
+        }
+        if self.in_order {
+            // Safe because the caller ensures the buffers are valid and match the descriptor.
+            return unsafe { self.pop_used_in_order(token, inputs, outputs) };
+        }
+
+        // Get the index of the start of the descriptor chain for the next element in the used ring.
+        let last_used_slot = self.last_used_idx & (self.size - 1);
+        let id;
+        let len;
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing.
+        unsafe {
+            id = (*self.used_elem(last_used_slot)).id;
+            len = (*self.used_elem(last_used_slot)).len;
+        }
+        #[cfg(feature = "hardened")]
+        let writable_len = self.used_writable_len(id)?;
+        let index = id as u16;
+
+        if index != token {
+            // The device used a different descriptor chain to the one we were expecting.
+            return Err(Error::WrongToken);
+        }
+
+        // Safe because the caller ensures the buffers are valid and match the descriptor.
+        unsafe {
+            self.recycle_descriptors(index, inputs, outputs);
+        }
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        if self.event_idx {
+            self.update_used_event();
+        }
+
+        #[cfg(feature = "hardened")]
+        if len > writable_len {
+            return Err(Error::InvalidUsedLen);
+        }
+        Ok(len)
+    }
+
+    /// Pops the oldest outstanding descriptor chain, which must be `token`, once the device has
+    /// used it.
+    ///
+    /// With `VIRTIO_F_IN_ORDER` the device may write a single used element for a batch of chains,
+    /// with the index of the last chain in the batch, and skip the used index forward past the
+    /// whole batch. The chains before it are then also complete, and are assumed to have had all of
+    /// their device-writable buffers written.
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    unsafe fn pop_used_in_order<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        if token != self.next_in_order {
+            // The caller tried to pop chains out of order.
+            return Err(Error::WrongToken);
+        }
+
+        let (last_index, last_len) = match self.batch_last.take() {
+            Some(batch_last) => batch_last,
+            None => {
+                let last_used_slot = self.last_used_idx & (self.size - 1);
+                // Safe because self.used points to a valid, aligned, initialised, dereferenceable,
+                // readable instance of UsedRing.
+                let (id, len) = unsafe {
+                    (
+                        (*self.used_elem(last_used_slot)).id,
+                        (*self.used_elem(last_used_slot)).len,
+                    )
+                };
+                #[cfg(feature = "hardened")]
+                self.used_writable_len(id)?;
+                (id as u16, len)
+            }
+        };
+        let len = if last_index == token {
+            last_len
+        } else {
+            self.batch_last = Some((last_index, last_len));
+            outputs.iter().map(|buffer| buffer.len() as u32).sum()
+        };
+
+        #[cfg(feature = "hardened")]
+        let writable_len = self.used_writable_len(token.into())?;
+        let descriptors = if self.desc_shadow[usize::from(token)]
+            .flags
+            .contains(DescFlags::INDIRECT)
+        {
+            1
+        } else {
+            (inputs.len() + outputs.len()) as u16
+        };
+        // Safe because the caller ensures the buffers are valid and match the descriptor.
+        unsafe {
+            self.recycle_descriptors(token, inputs, outputs);
+        }
+        self.next_in_order = token.wrapping_add(descriptors) & (self.size - 1);
+        // Each chain in a batch takes a slot of the used ring, even though the device only wrote
+        // an element in the first one.
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        if self.event_idx {
+            self.update_used_event();
+        }
+
+        #[cfg(feature = "hardened")]
+        if len > writable_len {
+            return Err(Error::InvalidUsedLen);
+        }
+        Ok(len)
+    }
+
+    /// Returns the total length of the device-writable buffers in the outstanding descriptor chain
+    /// with the given head descriptor ID from the used ring, or [`Error::InvalidUsedId`] if there
+    /// is no such chain.
+    #[cfg(feature = "hardened")]
+    fn used_writable_len(&self, id: u32) -> Result<u32> {
+        self.writable_lens
+            .get(id as usize)
+            .copied()
+            .flatten()
+            .ok_or(Error::InvalidUsedId)
+    }
+
+    /// Unshares the buffers which were added with the given token, without waiting for the device
+    /// to use them.
+    ///
+    /// # Safety
+    ///
+    /// The queue must have been reset, so that the device is no longer accessing the buffers. The
+    /// buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn reclaim<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result {
+        match self.desc_shadow.get(usize::from(token)) {
+            // Free descriptors have no buffer.
+            Some(desc) if desc.addr != 0 => {}
+            _ => return Err(Error::WrongToken),
+        }
+        // Safe because the caller ensures the buffers are valid and match the descriptor.
+        unsafe {
+            self.recycle_descriptors(token, inputs, outputs);
+        }
+        Ok(())
+    }
+
+    /// Returns the index of the queue on its transport.
+    pub fn queue_idx(&self) -> u16 {
+        self.queue_idx
+    }
+
+    /// Returns the notification to send to the device about the buffers added so far.
+    pub fn notification(&self) -> Notification {
+        Notification {
+            queue: self.queue_idx,
+            next_off: self.avail_idx & 0x7fff,
+            next_wrap: self.avail_idx & 0x8000 != 0,
+        }
+    }
+
+    /// Returns the size of the queue.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Sets the region of DMA memory whose buffers are passed to the device without being shared.
+    pub fn set_premapped(&mut self, premapped: Option<PremappedRegion>) {
+        self.premapped = premapped;
+    }
+
+    /// Returns whether all buffers which have been added to the queue have also been popped.
+    pub fn is_idle(&self) -> bool {
+        self.num_used == 0
+    }
+
+    /// Returns a pointer to the given slot of the available ring.
+    fn avail_ring(&self, slot: u16) -> *mut u16 {
+        assert!(slot < self.size);
+        // Safe because self.avail points to an available ring with `self.size` slots.
+        unsafe { AvailRing::ring(self.avail.as_ptr(), slot) }
+    }
+
+    /// Returns the `used_event` field of the available ring.
+    fn used_event(&self) -> &AtomicU16 {
+        // Safe because self.avail points to a valid, aligned, initialised, dereferenceable
+        // available ring with `self.size` slots.
+        unsafe { &*AvailRing::used_event(self.avail.as_ptr(), self.size) }
+    }
+
+    /// Returns a pointer to the given slot of the used ring.
+    fn used_elem(&self, slot: u16) -> *mut UsedElem {
+        assert!(slot < self.size);
+        // Safe because self.used points to a used ring with `self.size` slots.
+        unsafe { UsedRing::ring(self.used.as_ptr(), slot) }
+    }
+
+    /// Returns the `avail_event` field of the used ring.
+    fn avail_event(&self) -> &AtomicU16 {
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // used ring with `self.size` slots.
+        unsafe { &*UsedRing::avail_event(self.used.as_ptr(), self.size) }
+    }
+}
+
+// SAFETY: None of the virt queue resources are tied to a particular thread.
+unsafe impl<H: Hal, const SIZE: usize> Send for SplitQueue<H, SIZE> {}
+
+// SAFETY: A `&SplitQueue` only allows reading from the various pointers it contains, so there is no
+// data race.
+unsafe impl<H: Hal, const SIZE: usize> Sync for SplitQueue<H, SIZE> {}
+
+/// Returns the size in bytes of the descriptor table, available ring and used ring for a given
+/// queue size.
+///
+/// Ref: 2.6 Split Virtqueues
+pub(super) fn queue_part_sizes(queue_size: u16) -> (usize, usize, usize) {
+    assert!(
+        queue_size.is_power_of_two(),
+        "queue size should be a power of 2"
+    );
+    let queue_size = queue_size as usize;
+    let desc = size_of::<Descriptor>() * queue_size;
+    let avail = size_of::<u16>() * (3 + queue_size);
+    let used = size_of::<u16>() * 3 + size_of::<UsedElem>() * queue_size;
+    (desc, avail, used)
+}
+
+#[repr(C, align(16))]
+#[derive(AsBytes, Clone, Debug, FromBytes, FromZeroes)]
+pub(crate) struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: DescFlags,
+    next: u16,
+}
+
+impl Descriptor {
+    /// Sets the buffer address, length and flags, and shares it with the device unless it is within
+    /// the premapped region.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the buffer lives at least as long as the descriptor is active.
+    unsafe fn set_buf<H: Hal>(
+        &mut self,
+        premapped: Option<PremappedRegion>,
+        buf: NonNull<[u8]>,
+        direction: BufferDirection,
+        extra_flags: DescFlags,
+    ) {
+        // Safe because our caller promises that the buffer is valid.
+        unsafe {
+            self.addr = share_buffer::<H>(premapped, buf, direction) as u64;
+        }
+        self.len = buf.len() as u32;
+        self.flags = extra_flags
+            | match direction {
+                BufferDirection::DeviceToDriver => DescFlags::WRITE,
+                BufferDirection::DriverToDevice => DescFlags::empty(),
+                BufferDirection::Both => {
+                    panic!("Buffer passed to device should never use BufferDirection::Both.")
+                }
+            };
+    }
+
+    /// Sets the buffer address and length to 0.
+    ///
+    /// This must only be called once the device has finished using the descriptor.
+    fn unset_buf(&mut self) {
+        self.addr = 0;
+        self.len = 0;
+    }
+
+    /// Returns the index of the next descriptor in the chain if the `NEXT` flag is set, or `None`
+    /// if it is not (and thus this descriptor is the end of the chain).
+    fn next(&self) -> Option<u16> {
+        if self.flags.contains(DescFlags::NEXT) {
+            Some(self.next)
+        } else {
+            None
+        }
+    }
+}
+
+/// The driver uses the available ring to offer buffers to the device:
+/// each ring entry refers to the head of a descriptor chain.
+/// It is only written by the driver and read by the device.
+///
+/// The length of the ring depends on the queue size, so it is followed by a `used_event: AtomicU16`
+/// field (only used if `VIRTIO_F_EVENT_IDX` is negotiated) which must be found with
+/// [`AvailRing::used_event`].
+#[repr(C)]
+#[derive(Debug)]
+struct AvailRing {
+    flags: AtomicU16,
+    /// A driver MUST NOT decrement the idx.
+    idx: AtomicU16,
+    ring: [u16; 0],
+}
+
+impl AvailRing {
+    /// Returns a pointer to the given slot of the ring.
+    ///
+    /// # Safety
+    ///
+    /// `avail` must point to an available ring with more than `slot` slots.
+    unsafe fn ring(avail: *mut Self, slot: u16) -> *mut u16 {
+        // Safe because our caller promises that the slot is within the ring.
+        unsafe { addr_of_mut!((*avail).ring).cast::<u16>().add(slot.into()) }
+    }
+
+    /// Returns a pointer to the `used_event` field, which follows the ring.
+    ///
+    /// # Safety
+    ///
+    /// `avail` must point to an available ring with `queue_size` slots.
+    unsafe fn used_event(avail: *mut Self, queue_size: u16) -> *mut AtomicU16 {
+        // Safe because our caller promises that the ring has `queue_size` slots, so `used_event`
+        // comes straight after the last of them.
+        unsafe { Self::ring(avail, queue_size).cast() }
+    }
+}
+
+/// The used ring is where the device returns buffers once it is done with them:
+/// it is only written to by the device, and read by the driver.
+///
+/// The length of the ring depends on the queue size, so it is followed by an
+/// `avail_event: AtomicU16` field (only used if `VIRTIO_F_EVENT_IDX` is negotiated) which must be
+/// found with [`UsedRing::avail_event`].
+#[repr(C)]
+#[derive(Debug)]
+struct UsedRing {
+    flags: AtomicU16,
+    idx: AtomicU16,
+    ring: [UsedElem; 0],
+}
+
+impl UsedRing {
+    /// Returns a pointer to the given slot of the ring.
+    ///
+    /// # Safety
+    ///
+    /// `used` must point to a used ring with more than `slot` slots.
+    unsafe fn ring(used: *mut Self, slot: u16) -> *mut UsedElem {
+        // Safe because our caller promises that the slot is within the ring.
+        unsafe {
+            addr_of_mut!((*used).ring)
+                .cast::<UsedElem>()
+                .add(slot.into())
+        }
+    }
+
+    /// Returns a pointer to the `avail_event` field, which follows the ring.
+    ///
+    /// # Safety
+    ///
+    /// `used` must point to a used ring with `queue_size` slots.
+    unsafe fn avail_event(used: *mut Self, queue_size: u16) -> *mut AtomicU16 {
+        // Safe because our caller promises that the ring has `queue_size` slots, so `avail_event`
+        // comes straight after the last of them.
+        unsafe { Self::ring(used, queue_size).cast() }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// Simulates the device reading from a VirtIO queue and writing a response back, for use in tests.
+///
+/// The fake device always uses descriptors in order.
+#[cfg(test)]
+pub(crate) fn fake_read_write_queue(
+    descriptors: *const [Descriptor],
+    queue_driver_area: *const u8,
+    queue_device_area: *mut u8,
+    handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
+) {
+    use core::{ops::Deref, slice};
+
+    let queue_size = descriptors.len() as u16;
+    let available_ring = queue_driver_area as *mut AvailRing;
+    let used_ring = queue_device_area as *mut UsedRing;
+
+    // Safe because the various pointers are properly aligned, dereferenceable, initialised, and
+    // nothing else accesses them during this block.
+    unsafe {
+        // Make sure there is actually at least one descriptor available to read from.
+        assert_ne!(
+            (*available_ring).idx.load(Ordering::Acquire),
+            (*used_ring).idx.load(Ordering::Acquire)
+        );
+        // The fake device always uses descriptors in order, like VIRTIO_F_IN_ORDER, so
+        // `used_ring.idx` marks the next descriptor we should take from the available ring.
+        let next_slot = (*used_ring).idx.load(Ordering::Acquire) & (queue_size - 1);
+        let head_descriptor_index = *AvailRing::ring(available_ring, next_slot);
+        let mut descriptor = &(*descriptors)[head_descriptor_index as usize];
+
+        let output;
+        if descriptor.flags.contains(DescFlags::INDIRECT) {
+            // The descriptor shouldn't have any other flags if it is indirect.
+            assert_eq!(descriptor.flags, DescFlags::INDIRECT);
+
+            // Loop through all input descriptors in the indirect descriptor list, reading data from
+            // them.
+            let indirect_descriptor_list: &[Descriptor] = zerocopy::Ref::new_slice(
+                slice::from_raw_parts(descriptor.addr as *const u8, descriptor.len as usize),
+            )
+            .unwrap()
+            .into_slice();
+            let mut input = Vec::new();
+            let mut indirect_descriptor_index = 0;
+            while indirect_descriptor_index < indirect_descriptor_list.len() {
+                let indirect_descriptor = &indirect_descriptor_list[indirect_descriptor_index];
+                if indirect_descriptor.flags.contains(DescFlags::WRITE) {
+                    break;
+                }
+
+                input.extend_from_slice(slice::from_raw_parts(
+                    indirect_descriptor.addr as *const u8,
+                    indirect_descriptor.len as usize,
+                ));
+
+                indirect_descriptor_index += 1;
+            }
+
+            // Let the test handle the request.
+            output = handler(input);
+
+            // Write the response to the remaining descriptors.
+            let mut remaining_output = output.deref();
+            while indirect_descriptor_index < indirect_descriptor_list.len() {
+                let indirect_descriptor = &indirect_descriptor_list[indirect_descriptor_index];
+                assert!(indirect_descriptor.flags.contains(DescFlags::WRITE));
+
+                let length_to_write = min(remaining_output.len(), indirect_descriptor.len as usize);
+                ptr::copy(
+                    remaining_output.as_ptr(),
+                    indirect_descriptor.addr as *mut u8,
+                    length_to_write,
+                );
+                remaining_output = &remaining_output[length_to_write..];
+
+                indirect_descriptor_index += 1;
+            }
+            assert_eq!(remaining_output.len(), 0);
+        } else {
+            // Loop through all input descriptors in the chain, reading data from them.
+            let mut input = Vec::new();
+            while !descriptor.flags.contains(DescFlags::WRITE) {
+                input.extend_from_slice(slice::from_raw_parts(
+                    descriptor.addr as *const u8,
+                    descriptor.len as usize,
+                ));
+
+                if let Some(next) = descriptor.next() {
+                    descriptor = &(*descriptors)[next as usize];
+                } else {
+                    break;
+                }
+            }
+
+            // Let the test handle the request.
+            output = handler(input);
+
+            // Write the response to the remaining descriptors.
+            let mut remaining_output = output.deref();
+            if descriptor.flags.contains(DescFlags::WRITE) {
+                loop {
+                    assert!(descriptor.flags.contains(DescFlags::WRITE));
+
+                    let length_to_write = min(remaining_output.len(), descriptor.len as usize);
+                    ptr::copy(
+                        remaining_output.as_ptr(),
+                        descriptor.addr as *mut u8,
+                        length_to_write,
+                    );
+                    remaining_output = &remaining_output[length_to_write..];
+
+                    if let Some(next) = descriptor.next() {
+                        descriptor = &(*descriptors)[next as usize];
+                    } else {
+                        break;
+                    }
+                }
+            }
+            assert_eq!(remaining_output.len(), 0);
+        }
+
+        // Mark the buffer as used.
+        let used_elem = UsedRing::ring(used_ring, next_slot);
+        (*used_elem).id = head_descriptor_index as u32;
+        (*used_elem).len = output.len() as u32;
+        (*used_ring).idx.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::common::Feature,
+        hal::fake::FakeHal,
+        queue::INDIRECT_TABLE_LEN,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            mmio::{MmioTransport, VirtIOHeader, MODERN_VERSION},
+            DeviceType,
+        },
+    };
+    use core::ptr::NonNull;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn queue_too_big() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        assert_eq!(
+            SplitQueue::<FakeHal, 8>::new(&mut transport, 0, 8, false, false, false).unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    #[test]
+    fn queue_already_used() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+        assert_eq!(
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap_err(),
+            Error::AlreadyUsed
+        );
+    }
+
+    #[test]
+    fn add_empty() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue =
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+        assert_eq!(
+            unsafe { queue.add(&[], &mut []) }.unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    #[test]
+    fn add_too_many() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue =
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+        assert_eq!(queue.available_desc(), 4);
+        assert_eq!(
+            unsafe { queue.add(&[&[], &[], &[]], &mut [&mut [], &mut []]) }.unwrap_err(),
+            Error::QueueFull
+        );
+    }
+
+    #[test]
+    fn add_buffers() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue =
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+        assert_eq!(queue.available_desc(), 4);
+
+        // Add a buffer chain consisting of two device-readable parts followed by two
+        // device-writable parts.
+        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
+
+        assert_eq!(queue.available_desc(), 0);
+        assert!(!queue.can_pop());
+
+        // Safe because the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            let first_descriptor_index = *queue.avail_ring(0);
+            assert_eq!(first_descriptor_index, token);
+            assert_eq!(
+                (*queue.desc.as_ptr())[first_descriptor_index as usize].len,
+                2
+            );
+            assert_eq!(
+                (*queue.desc.as_ptr())[first_descriptor_index as usize].flags,
+                DescFlags::NEXT
+            );
+            let second_descriptor_index =
+                (*queue.desc.as_ptr())[first_descriptor_index as usize].next;
+            assert_eq!(
+                (*queue.desc.as_ptr())[second_descriptor_index as usize].len,
+                1
+            );
+            assert_eq!(
+                (*queue.desc.as_ptr())[second_descriptor_index as usize].flags,
+                DescFlags::NEXT
+            );
+            let third_descriptor_index =
+                (*queue.desc.as_ptr())[second_descriptor_index as usize].next;
+            assert_eq!(
+                (*queue.desc.as_ptr())[third_descriptor_index as usize].len,
+                2
+            );
+            assert_eq!(
+                (*queue.desc.as_ptr())[third_descriptor_index as usize].flags,
+                DescFlags::NEXT | DescFlags::WRITE
+            );
+            let fourth_descriptor_index =
+                (*queue.desc.as_ptr())[third_descriptor_index as usize].next;
+            assert_eq!(
+                (*queue.desc.as_ptr())[fourth_descriptor_index as usize].len,
+                1
+            );
+            assert_eq!(
+                (*queue.desc.as_ptr())[fourth_descriptor_index as usize].flags,
+                DescFlags::WRITE
+            );
+        }
+    }
+
+    #[test]
+    fn add_buffers_indirect() {
+        use core::ptr::slice_from_raw_parts;
+
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue =
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, true, false, false).unwrap();
+        assert_eq!(queue.available_desc(), 4);
+
+        // Add a buffer chain consisting of two device-readable parts followed by two
+        // device-writable parts.
+        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
+
+        assert_eq!(queue.available_desc(), 4);
+        assert!(!queue.can_pop());
+
+        // Safe because the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            let indirect_descriptor_index = *queue.avail_ring(0);
+            assert_eq!(indirect_descriptor_index, token);
+            assert_eq!(
+                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].len as usize,
+                4 * size_of::<Descriptor>()
+            );
+            assert_eq!(
+                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].flags,
+                DescFlags::INDIRECT
+            );
+
+            let indirect_descriptors = slice_from_raw_parts(
+                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].addr
+                    as *const Descriptor,
+                4,
+            );
+            assert_eq!((*indirect_descriptors)[0].len, 2);
+            assert_eq!((*indirect_descriptors)[0].flags, DescFlags::NEXT);
+            assert_eq!((*indirect_descriptors)[0].next, 1);
+            assert_eq!((*indirect_descriptors)[1].len, 1);
+            assert_eq!((*indirect_descriptors)[1].flags, DescFlags::NEXT);
+            assert_eq!((*indirect_descriptors)[1].next, 2);
+            assert_eq!((*indirect_descriptors)[2].len, 2);
+            assert_eq!(
+                (*indirect_descriptors)[2].flags,
+                DescFlags::NEXT | DescFlags::WRITE
+            );
+            assert_eq!((*indirect_descriptors)[2].next, 3);
+            assert_eq!((*indirect_descriptors)[3].len, 1);
+            assert_eq!((*indirect_descriptors)[3].flags, DescFlags::WRITE);
+        }
+    }
+
+    /// Tests that a chain too long for an indirect descriptor table uses direct descriptors.
+    #[test]
+    fn add_buffers_indirect_too_long() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 32);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue =
+            SplitQueue::<FakeHal, 32>::new(&mut transport, 0, 32, true, false, false).unwrap();
+
+        let inputs = [&[42][..]; INDIRECT_TABLE_LEN + 1];
+        let token = unsafe { queue.add(&inputs, &mut []) }.unwrap();
+        assert_eq!(queue.num_used, INDIRECT_TABLE_LEN as u16 + 1);
+        assert_eq!(queue.desc_shadow[usize::from(token)].flags, DescFlags::NEXT);
+
+        let token = unsafe { queue.add(&inputs[..INDIRECT_TABLE_LEN], &mut []) }.unwrap();
+        assert_eq!(queue.num_used, INDIRECT_TABLE_LEN as u16 + 2);
+        assert_eq!(
+            queue.desc_shadow[usize::from(token)].flags,
+            DescFlags::INDIRECT
+        );
+    }
+
+    /// Tests that with `VIRTIO_F_IN_ORDER` a single used element completes all earlier chains too,
+    /// and that descriptors are allocated sequentially.
+    #[test]
+    fn pop_used_in_order_batch() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue =
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, true).unwrap();
+
+        let mut first = [0; 2];
+        let mut second = [0];
+        let mut third = [0];
+        assert_eq!(unsafe { queue.add(&[], &mut [&mut first]) }.unwrap(), 0);
+        assert_eq!(
+            unsafe { queue.add(&[&[1]], &mut [&mut second]) }.unwrap(),
+            1
+        );
+        assert_eq!(unsafe { queue.add(&[], &mut [&mut third]) }.unwrap(), 3);
+        assert!(!queue.can_pop());
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Use all three chains with a single used element for the last one, skipping the used
+            // index past the whole batch.
+            (*queue.used_elem(0)).id = 3;
+            (*queue.used_elem(0)).len = 1;
+            (*queue.used.as_ptr()).idx.store(3, Ordering::Release);
+        }
+
+        assert_eq!(queue.peek_used(), Some(0));
+        assert_eq!(
+            unsafe { queue.pop_used(1, &[&[1]], &mut [&mut second]) },
+            Err(Error::WrongToken)
+        );
+        assert_eq!(unsafe { queue.pop_used(0, &[], &mut [&mut first]) }, Ok(2));
+        assert_eq!(queue.peek_used(), Some(1));
+        assert_eq!(
+            unsafe { queue.pop_used(1, &[&[1]], &mut [&mut second]) },
+            Ok(1)
+        );
+        assert_eq!(queue.peek_used(), Some(3));
+        assert_eq!(unsafe { queue.pop_used(3, &[], &mut [&mut third]) }, Ok(1));
+        assert!(!queue.can_pop());
+        assert_eq!(queue.available_desc(), 4);
+        assert_eq!(queue.last_used_idx, 3);
+
+        // Descriptors carry on being allocated from where the last chain ended.
+        assert_eq!(unsafe { queue.add(&[&[1]], &mut []) }.unwrap(), 0);
+        assert_eq!(unsafe { queue.add(&[&[1], &[2]], &mut []) }.unwrap(), 1);
+    }
+
+    #[cfg(feature = "hardened")]
+    #[test]
+    fn pop_used_invalid() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue =
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+
+        let mut response = [0; 2];
+        let token = unsafe { queue.add(&[&[1], &[2]], &mut [&mut response]) }.unwrap();
+        assert_eq!(token, 0);
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Descriptor 1 is part of the chain, but not its head.
+            (*queue.used_elem(0)).id = 1;
+            (*queue.used_elem(0)).len = 2;
+            (*queue.used.as_ptr()).idx.store(1, Ordering::Release);
+        }
+        assert!(queue.can_pop());
+        assert_eq!(queue.peek_used(), None);
+        assert_eq!(
+            unsafe { queue.pop_used(1, &[&[2]], &mut []) },
+            Err(Error::InvalidUsedId)
+        );
+
+        // SAFETY: as above.
+        unsafe {
+            (*queue.used_elem(0)).id = 0x10000;
+        }
+        assert_eq!(queue.peek_used(), None);
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[1], &[2]], &mut [&mut response]) },
+            Err(Error::InvalidUsedId)
+        );
+
+        // SAFETY: as above.
+        unsafe {
+            // The right chain, but longer than the response buffer.
+            (*queue.used_elem(0)).id = token.into();
+            (*queue.used_elem(0)).len = 3;
+        }
+        assert_eq!(queue.peek_used(), Some(token));
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[1], &[2]], &mut [&mut response]) },
+            Err(Error::InvalidUsedLen)
+        );
+        // The chain was still freed, so can't be used again.
+        assert!(!queue.can_pop());
+        assert_eq!(queue.available_desc(), 4);
+
+        // SAFETY: as above.
+        unsafe {
+            (*queue.used_elem(1)).id = token.into();
+            (*queue.used_elem(1)).len = 0;
+            (*queue.used.as_ptr()).idx.store(2, Ordering::Release);
+        }
+        assert_eq!(queue.peek_used(), None);
+    }
+
+    /// Tests that the queue advises the device that notifications are needed.
+    #[test]
+    fn set_dev_notify() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue =
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+
+        // Check that the avail ring's flag is zero by default.
+        assert_eq!(
+            unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) },
+            0x0
+        );
+
+        queue.set_dev_notify(false);
+
+        // Check that the avail ring's flag is 1 after `disable_dev_notify`.
+        assert_eq!(
+            unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) },
+            0x1
+        );
+
+        queue.set_dev_notify(true);
+
+        // Check that the avail ring's flag is 0 after `enable_dev_notify`.
+        assert_eq!(
+            unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) },
+            0x0
+        );
+    }
+
+    /// Tests that the queue suppresses notifications with the `used_event` index, if
+    /// `VIRTIO_F_EVENT_IDX` has been negotiated.
+    #[test]
+    fn set_dev_notify_event_idx() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: Feature::RING_EVENT_IDX.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue =
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, true, false).unwrap();
+        queue.last_used_idx = 5;
+
+        queue.set_dev_notify(false);
+
+        // The avail ring's flag is ignored, so `used_event` is moved behind the used entries
+        // which have already been popped instead.
+        assert_eq!(
+            unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) },
+            0x0
+        );
+        assert_eq!(queue.used_event().load(Ordering::Acquire), 4);
+
+        queue.set_dev_notify(true);
+
+        assert_eq!(queue.used_event().load(Ordering::Acquire), 5);
+    }
+
+    /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
+    /// notifications.
+    #[test]
+    fn add_notify() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue =
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+
+        // Add a buffer chain with a single device-readable part.
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+
+        // Check that the transport would be notified.
+        assert_eq!(queue.should_notify(), true);
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Suppress notifications.
+            (*queue.used.as_ptr()).flags.store(0x01, Ordering::Release);
+        }
+
+        // Check that the transport would not be notified.
+        assert_eq!(queue.should_notify(), false);
+    }
+
+    /// Tests that buffers added with `add_deferred` are only made available to the device once
+    /// `publish` is called.
+    #[test]
+    fn add_deferred_publish() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue =
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+
+        let first = unsafe { queue.add_deferred(&[&[1]], &mut []) }.unwrap();
+        let second = unsafe { queue.add_deferred(&[&[2]], &mut []) }.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(queue.available_desc(), 2);
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            assert_eq!((*queue.avail.as_ptr()).idx.load(Ordering::Acquire), 0);
+            assert_eq!(*queue.avail_ring(0), first);
+            assert_eq!(*queue.avail_ring(1), second);
+        }
+
+        queue.publish();
+        // SAFETY: as above.
+        unsafe {
+            assert_eq!((*queue.avail.as_ptr()).idx.load(Ordering::Acquire), 2);
+        }
+    }
+
+    /// Tests that notifications include the available index for `VIRTIO_F_NOTIFICATION_DATA`.
+    #[test]
+    fn notification_data() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue =
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, false, false).unwrap();
+
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        transport.notify(queue.notification());
+        let notification = state.lock().unwrap().queues[0].last_notification.unwrap();
+        assert_eq!(
+            notification,
+            Notification {
+                queue: 0,
+                next_off: 1,
+                next_wrap: false,
+            }
+        );
+        assert_eq!(notification.data(), 0x0001_0000);
+
+        // The most significant bit of the available index is sent as the wrap flag.
+        queue.avail_idx = 0x8002;
+        assert_eq!(queue.notification().data(), 0x8002_0000);
+    }
+
+    /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
+    /// notifications with the `avail_event` index.
+    #[test]
+    fn add_notify_event_idx() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: Feature::RING_EVENT_IDX.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue =
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, true, false).unwrap();
+
+        // Add a buffer chain with a single device-readable part.
+        assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 0);
+
+        // Check that the transport would be notified.
+        assert_eq!(queue.should_notify(), true);
+
+        // Suppress notifications.
+        queue.avail_event().store(1, Ordering::Release);
+
+        // Check that the transport would not be notified.
+        assert_eq!(queue.should_notify(), false);
+
+        // Add another buffer chain.
+        assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 1);
+
+        // Check that the transport should be notified again now.
+        assert_eq!(queue.should_notify(), true);
+    }
+
+    /// Tests that the queue notifies the device once the available index passes the `avail_event`
+    /// index, even if the available index wrapped around in the meantime.
+    #[test]
+    fn add_notify_event_idx_wrap() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: Feature::RING_EVENT_IDX.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue =
+            SplitQueue::<FakeHal, 4>::new(&mut transport, 0, 4, false, true, false).unwrap();
+
+        // Pretend that lots of buffers have already been added and used.
+        queue.avail_idx = 65533;
+        queue.checked_avail_idx = 65533;
+        queue.avail_event().store(65534, Ordering::Release);
+
+        // Available index 65534, which doesn't pass the event index yet.
+        unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+        assert!(!queue.should_notify());
+
+        // Available index 1, wrapping around past the event index.
+        for _ in 0..3 {
+            unsafe { queue.add(&[&[2]], &mut []) }.unwrap();
+        }
+        assert_eq!(queue.avail_idx, 1);
+        assert!(queue.should_notify());
+    }
+}
@@ -0,0 +1,132 @@
+//! Optional counters and tracing for virtqueues, enabled by the `stats` feature.
+
+use core::fmt::{self, Debug, Formatter};
+use core::iter::Sum;
+use core::ops::Add;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A snapshot of the counters of a virtqueue, or the sum of the counters of several virtqueues.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QueueStats {
+    /// The number of descriptor chains which have been added to the queue.
+    pub added: usize,
+    /// The number of descriptor chains which the device has used and the driver has popped.
+    pub used: usize,
+    /// The number of notifications which have been sent to the device.
+    pub notifications: usize,
+    /// The number of times that the driver didn't need to notify the device after adding buffers,
+    /// because the device had suppressed notifications with a flag or with `VIRTIO_F_EVENT_IDX`.
+    pub notifications_suppressed: usize,
+    /// The largest number of descriptors which have been in use at once. When several queues are
+    /// added together this is the sum of their peaks.
+    pub peak_descriptors: usize,
+}
+
+impl Add for QueueStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            added: self.added + other.added,
+            used: self.used + other.used,
+            notifications: self.notifications + other.notifications,
+            notifications_suppressed: self.notifications_suppressed
+                + other.notifications_suppressed,
+            peak_descriptors: self.peak_descriptors + other.peak_descriptors,
+        }
+    }
+}
+
+impl Sum for QueueStats {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), Add::add)
+    }
+}
+
+/// An event on a virtqueue, as passed to a [`QueueTracer`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TraceEvent {
+    /// A descriptor chain was added to the queue.
+    Added {
+        /// The token which was returned for the chain.
+        token: u16,
+        /// The number of descriptors which the chain took from the queue.
+        descriptors: u16,
+    },
+    /// A descriptor chain was used by the device and popped from the queue.
+    Used {
+        /// The token of the chain.
+        token: u16,
+        /// The length which the device wrote to the chain.
+        len: u32,
+    },
+    /// The driver notified the device about the queue.
+    Notified,
+    /// The driver didn't need to notify the device about the queue, because the device had
+    /// suppressed notifications.
+    NotificationSuppressed,
+}
+
+/// A callback for events on virtqueues, for example to log them.
+pub trait QueueTracer: Sync {
+    /// Called for each event on the virtqueue with the given index on its device.
+    ///
+    /// This is called while the driver is in the middle of an operation on the queue, so it should
+    /// return quickly.
+    fn trace(&self, queue_idx: u16, event: TraceEvent);
+}
+
+/// The counters and tracer of a single virtqueue.
+#[derive(Default)]
+pub(crate) struct QueueInstruments {
+    added: AtomicUsize,
+    used: AtomicUsize,
+    notifications: AtomicUsize,
+    notifications_suppressed: AtomicUsize,
+    peak_descriptors: AtomicUsize,
+    tracer: Option<&'static dyn QueueTracer>,
+}
+
+impl QueueInstruments {
+    /// Counts the given event and passes it to the tracer, if any.
+    pub fn record(&self, queue_idx: u16, event: TraceEvent) {
+        let counter = match event {
+            TraceEvent::Added { .. } => &self.added,
+            TraceEvent::Used { .. } => &self.used,
+            TraceEvent::Notified => &self.notifications,
+            TraceEvent::NotificationSuppressed => &self.notifications_suppressed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if let Some(tracer) = self.tracer {
+            tracer.trace(queue_idx, event);
+        }
+    }
+
+    /// Updates the peak number of descriptors in use, if `in_use` is more than it.
+    pub fn record_in_use(&self, in_use: usize) {
+        self.peak_descriptors.fetch_max(in_use, Ordering::Relaxed);
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<&'static dyn QueueTracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn snapshot(&self) -> QueueStats {
+        QueueStats {
+            added: self.added.load(Ordering::Relaxed),
+            used: self.used.load(Ordering::Relaxed),
+            notifications: self.notifications.load(Ordering::Relaxed),
+            notifications_suppressed: self.notifications_suppressed.load(Ordering::Relaxed),
+            peak_descriptors: self.peak_descriptors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Debug for QueueInstruments {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("QueueInstruments")
+            .field("stats", &self.snapshot())
+            .field("tracer", &self.tracer.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,175 @@
+//! Synchronisation primitives for state which is shared between the halves of a split driver or
+//! queue, or with an interrupt handler.
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::Waker;
+
+/// A mutual exclusion lock which spins while it is held by another thread.
+///
+/// This is only used for short critical sections which never wait for the device, such as adding a
+/// buffer to a virtqueue, so spinning is cheaper than any alternative. Code which has exclusive
+/// access to the lock can use [`get_mut`](Self::get_mut) to skip locking.
+#[derive(Debug, Default)]
+pub(crate) struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: The value is only accessed while the lock is held, or through a unique reference to the
+// lock, so it is never accessed from more than one thread at a time.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    /// Creates a new unlocked lock containing the given value.
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Waits until the lock is free, then takes it.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+        SpinLockGuard { lock: self }
+    }
+
+    /// Returns a mutable reference to the value, without locking as nothing else can hold the lock.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+/// A guard which gives access to the value of a [`SpinLock`], and releases the lock when dropped.
+pub(crate) struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safe because the guard holds the lock, so nothing else is accessing the value.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safe because the guard holds the lock, so nothing else is accessing the value.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+/// A slot for a single waker, which can be registered and woken through shared references.
+///
+/// Unlike a [`SpinLock`], this never waits, so it may be woken from an interrupt handler which
+/// interrupted a registration on the same CPU. This is the same algorithm as `AtomicWaker` in the
+/// `futures` crate: whichever of `register` and `wake` loses a race leaves the other to do its work.
+#[derive(Debug, Default)]
+pub(crate) struct AtomicWaker {
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// SAFETY: The waker is only accessed by whichever of `register` or `wake` has set the corresponding
+// bit of `state`, so it is never accessed from more than one thread at a time.
+unsafe impl Send for AtomicWaker {}
+
+// SAFETY: As above.
+unsafe impl Sync for AtomicWaker {}
+
+impl AtomicWaker {
+    /// Nothing is accessing the waker.
+    const WAITING: u8 = 0;
+    /// `register` is replacing the waker.
+    const REGISTERING: u8 = 1 << 0;
+    /// `wake` is taking the waker, or wants `register` to wake it.
+    const WAKING: u8 = 1 << 1;
+
+    /// Creates a new slot with no waker.
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(Self::WAITING),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Registers the given waker to be woken by the next call to [`wake`](Self::wake), replacing
+    /// any previous one.
+    pub fn register(&self, waker: &Waker) {
+        match self
+            .state
+            .compare_exchange(
+                Self::WAITING,
+                Self::REGISTERING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            )
+            .unwrap_or_else(|state| state)
+        {
+            Self::WAITING => {
+                // Safe because we set `REGISTERING`, so nothing else is accessing the waker.
+                let slot = unsafe { &mut *self.waker.get() };
+                if !slot.as_ref().is_some_and(|w| w.will_wake(waker)) {
+                    *slot = Some(waker.clone());
+                }
+                if self
+                    .state
+                    .compare_exchange(
+                        Self::REGISTERING,
+                        Self::WAITING,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    )
+                    .is_err()
+                {
+                    // `wake` was called in the meantime and left it to us, so wake the waker we
+                    // just registered.
+                    let waker = slot.take();
+                    self.state.swap(Self::WAITING, Ordering::AcqRel);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            Self::WAKING => {
+                // `wake` is running now, so it may miss the new waker; wake it straight away.
+                waker.wake_by_ref();
+            }
+            _ => {
+                // Another `register` is running concurrently, which callers shouldn't do.
+            }
+        }
+    }
+
+    /// Wakes the registered waker, if any, and removes it.
+    pub fn wake(&self) {
+        if self.state.fetch_or(Self::WAKING, Ordering::AcqRel) == Self::WAITING {
+            // Safe because we set `WAKING` while nothing else was accessing the waker, so
+            // `register` won't access it until we clear it again.
+            let waker = unsafe { (*self.waker.get()).take() };
+            self.state.fetch_and(!Self::WAKING, Ordering::Release);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
@@ -1,12 +1,18 @@
-use super::{DeviceStatus, DeviceType, Transport};
+//! A fake transport for unit tests.
+
+use super::{DeviceStatus, DeviceType, Notification, Transport};
 use crate::{
-    queue::{fake_read_write_queue, Descriptor},
-    PhysAddr, Result,
+    device::common::Feature,
+    queue::{
+        fake_read_write_packed_queue, fake_read_write_queue, Descriptor, FakePackedDevice,
+        PackedDescriptor,
+    },
+    Error, PhysAddr, Result,
 };
 use alloc::{sync::Arc, vec::Vec};
 use core::{
     any::TypeId,
-    ptr::NonNull,
+    ptr::{self, NonNull},
     sync::atomic::{AtomicBool, Ordering},
     time::Duration,
 };
@@ -15,10 +21,15 @@ use std::{sync::Mutex, thread};
 /// A fake implementation of [`Transport`] for unit tests.
 #[derive(Debug)]
 pub struct FakeTransport<C: 'static> {
+    /// The type of device to report.
     pub device_type: DeviceType,
+    /// The maximum size to report for every queue.
     pub max_queue_size: u32,
+    /// The features which the device offers.
     pub device_features: u64,
+    /// The device's config space.
     pub config_space: NonNull<C>,
+    /// The state of the fake device, which is shared with the test.
     pub state: Arc<Mutex<State>>,
 }
 
@@ -39,10 +50,11 @@ impl<C> Transport for FakeTransport<C> {
         self.max_queue_size
     }
 
-    fn notify(&mut self, queue: u16) {
-        self.state.lock().unwrap().queues[queue as usize]
-            .notified
-            .store(true, Ordering::SeqCst);
+    fn notify(&mut self, notification: Notification) {
+        let mut state = self.state.lock().unwrap();
+        let queue = &mut state.queues[usize::from(notification.queue)];
+        queue.last_notification = Some(notification);
+        queue.notified.store(true, Ordering::SeqCst);
     }
 
     fn get_status(&self) -> DeviceStatus {
@@ -53,6 +65,10 @@ impl<C> Transport for FakeTransport<C> {
         self.state.lock().unwrap().status = status;
     }
 
+    fn read_config_generation(&self) -> u32 {
+        self.state.lock().unwrap().config_generation
+    }
+
     fn set_guest_page_size(&mut self, guest_page_size: u32) {
         self.state.lock().unwrap().guest_page_size = guest_page_size;
     }
@@ -61,6 +77,10 @@ impl<C> Transport for FakeTransport<C> {
         false
     }
 
+    fn requires_max_queue_size(&self) -> bool {
+        self.state.lock().unwrap().requires_max_queue_size
+    }
+
     fn queue_set(
         &mut self,
         queue: u16,
@@ -82,12 +102,21 @@ impl<C> Transport for FakeTransport<C> {
         state.queues[queue as usize].descriptors = 0;
         state.queues[queue as usize].driver_area = 0;
         state.queues[queue as usize].device_area = 0;
+        state.queues[queue as usize].packed = FakePackedDevice::default();
     }
 
     fn queue_used(&mut self, queue: u16) -> bool {
         self.state.lock().unwrap().queues[queue as usize].descriptors != 0
     }
 
+    fn queue_reset(&mut self, queue: u16) -> Result {
+        if self.state.lock().unwrap().driver_features & Feature::RING_RESET.bits() == 0 {
+            return Err(Error::Unsupported);
+        }
+        self.queue_unset(queue);
+        Ok(())
+    }
+
     fn ack_interrupt(&mut self) -> bool {
         let mut state = self.state.lock().unwrap();
         let pending = state.interrupt_pending;
@@ -98,7 +127,9 @@ impl<C> Transport for FakeTransport<C> {
     }
 
     fn config_space<T: 'static>(&self) -> Result<NonNull<T>> {
-        if TypeId::of::<T>() == TypeId::of::<C>() {
+        // `u8` is used by the default implementations of `read_config_space` and
+        // `write_config_space` to get a pointer to the start of the config space.
+        if TypeId::of::<T>() == TypeId::of::<C>() || TypeId::of::<T>() == TypeId::of::<u8>() {
             Ok(self.config_space.cast())
         } else {
             panic!("Unexpected config space type.");
@@ -106,12 +137,23 @@ impl<C> Transport for FakeTransport<C> {
     }
 }
 
+/// The state of a fake device.
 #[derive(Debug, Default)]
 pub struct State {
+    /// The device status set by the driver.
     pub status: DeviceStatus,
+    /// The features negotiated by the driver.
     pub driver_features: u64,
+    /// The guest page size set by the driver.
     pub guest_page_size: u32,
+    /// Whether the device has an interrupt pending.
     pub interrupt_pending: bool,
+    /// The configuration generation, which tests may change to simulate the device changing its
+    /// config space.
+    pub config_generation: u32,
+    /// Whether queues must have the maximum size, as for a legacy PCI device.
+    pub requires_max_queue_size: bool,
+    /// The state of each of the device's queues.
     pub queues: Vec<QueueStatus>,
 }
 
@@ -119,18 +161,11 @@ impl State {
     /// Simulates the device writing to the given queue.
     ///
     /// The fake device always uses descriptors in order.
-    pub fn write_to_queue<const QUEUE_SIZE: usize>(&mut self, queue_index: u16, data: &[u8]) {
-        let queue = &self.queues[queue_index as usize];
-        assert_ne!(queue.descriptors, 0);
-        fake_read_write_queue(
-            queue.descriptors as *const [Descriptor; QUEUE_SIZE],
-            queue.driver_area as *const u8,
-            queue.device_area as *mut u8,
-            |input| {
-                assert_eq!(input, Vec::new());
-                data.to_owned()
-            },
-        );
+    pub fn write_to_queue(&mut self, queue_index: u16, data: &[u8]) {
+        self.read_write_queue(queue_index, |input| {
+            assert_eq!(input, Vec::new());
+            data.to_owned()
+        });
     }
 
     /// Simulates the device reading from the given queue.
@@ -138,42 +173,43 @@ impl State {
     /// Data is read into the `data` buffer passed in. Returns the number of bytes actually read.
     ///
     /// The fake device always uses descriptors in order.
-    pub fn read_from_queue<const QUEUE_SIZE: usize>(&mut self, queue_index: u16) -> Vec<u8> {
-        let queue = &self.queues[queue_index as usize];
-        assert_ne!(queue.descriptors, 0);
-
+    pub fn read_from_queue(&mut self, queue_index: u16) -> Vec<u8> {
         let mut ret = None;
 
         // Read data from the queue but don't write any response.
-        fake_read_write_queue(
-            queue.descriptors as *const [Descriptor; QUEUE_SIZE],
-            queue.driver_area as *const u8,
-            queue.device_area as *mut u8,
-            |input| {
-                ret = Some(input);
-                Vec::new()
-            },
-        );
+        self.read_write_queue(queue_index, |input| {
+            ret = Some(input);
+            Vec::new()
+        });
 
         ret.unwrap()
     }
 
     /// Simulates the device reading data from the given queue and then writing a response back.
     ///
+    /// The queue may use either the split or the packed layout, depending on whether the driver
+    /// negotiated `VIRTIO_F_RING_PACKED`.
+    ///
     /// The fake device always uses descriptors in order.
-    pub fn read_write_queue<const QUEUE_SIZE: usize>(
-        &mut self,
-        queue_index: u16,
-        handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
-    ) {
-        let queue = &self.queues[queue_index as usize];
+    pub fn read_write_queue(&mut self, queue_index: u16, handler: impl FnOnce(Vec<u8>) -> Vec<u8>) {
+        let packed = self.driver_features & Feature::RING_PACKED.bits() != 0;
+        let queue = &mut self.queues[queue_index as usize];
         assert_ne!(queue.descriptors, 0);
-        fake_read_write_queue(
-            queue.descriptors as *const [Descriptor; QUEUE_SIZE],
-            queue.driver_area as *const u8,
-            queue.device_area as *mut u8,
-            handler,
-        )
+        let size = queue.size as usize;
+        if packed {
+            fake_read_write_packed_queue(
+                ptr::slice_from_raw_parts_mut(queue.descriptors as *mut PackedDescriptor, size),
+                &mut queue.packed,
+                handler,
+            )
+        } else {
+            fake_read_write_queue(
+                ptr::slice_from_raw_parts(queue.descriptors as *const Descriptor, size),
+                queue.driver_area as *const u8,
+                queue.device_area as *mut u8,
+                handler,
+            )
+        }
     }
 
     /// Waits until the given queue is notified.
@@ -187,11 +223,21 @@ impl State {
     }
 }
 
+/// The state of a queue of a fake device.
 #[derive(Debug, Default)]
 pub struct QueueStatus {
+    /// The size of the queue set by the driver.
     pub size: u32,
+    /// The physical address of the descriptor area.
     pub descriptors: PhysAddr,
+    /// The physical address of the driver area.
     pub driver_area: PhysAddr,
+    /// The physical address of the device area.
     pub device_area: PhysAddr,
+    /// Whether the driver has notified the device about the queue since it was last checked.
     pub notified: AtomicBool,
+    /// The most recent notification which the driver sent about the queue.
+    pub last_notification: Option<Notification>,
+    /// The device's position in the queue, if it uses the packed layout.
+    pub(crate) packed: FakePackedDevice,
 }
@@ -1,11 +1,16 @@
 //! MMIO transport for VirtIO.
 
-use super::{DeviceStatus, DeviceType, Transport};
+pub mod cmdline;
+#[cfg(feature = "fdt")]
+pub mod fdt;
+
+use super::{DeviceStatus, DeviceType, Notification, SharedMemoryRegion, Transport};
 use crate::{
     align_up,
+    device::common::Feature,
     queue::Descriptor,
     volatile::{volread, volwrite, ReadOnly, Volatile, WriteOnly},
-    Error, PhysAddr, PAGE_SIZE,
+    Error, Hal, PhysAddr, PAGE_SIZE,
 };
 use core::{
     convert::{TryFrom, TryInto},
@@ -215,7 +220,34 @@ pub struct VirtIOHeader {
     queue_device_high: WriteOnly<u32>,
 
     /// Reserved
-    __r9: [ReadOnly<u32>; 21],
+    __r9: ReadOnly<u32>,
+
+    /// Shared memory region id selector
+    ///
+    /// Writing to this register selects the shared memory region which SHMLen and SHMBase apply
+    /// to.
+    shm_sel: WriteOnly<u32>,
+
+    /// Shared memory region length
+    ///
+    /// Reading from these registers returns the length in bytes of the selected shared memory
+    /// region, or all ones (0xffffffffffffffff) if there is no such region.
+    shm_len_low: ReadOnly<u32>,
+    shm_len_high: ReadOnly<u32>,
+
+    /// Shared memory region physical base address
+    shm_base_low: ReadOnly<u32>,
+    shm_base_high: ReadOnly<u32>,
+
+    /// Queue reset
+    ///
+    /// Writing one (0x1) to this register resets the queue selected by writing to QueueSel, if
+    /// VIRTIO_F_RING_RESET has been negotiated. Reading from it returns one (0x1) while the reset is
+    /// ongoing, and zero (0x0) once it has completed.
+    queue_reset: Volatile<u32>,
+
+    /// Reserved
+    __r10: [ReadOnly<u32>; 14],
 
     config_generation: ReadOnly<u32>,
 }
@@ -265,6 +297,13 @@ impl VirtIOHeader {
             queue_device_low: Default::default(),
             queue_device_high: Default::default(),
             __r9: Default::default(),
+            shm_sel: Default::default(),
+            shm_len_low: Default::default(),
+            shm_len_high: Default::default(),
+            shm_base_low: Default::default(),
+            shm_base_high: Default::default(),
+            queue_reset: Default::default(),
+            __r10: Default::default(),
             config_generation: Default::default(),
         }
     }
@@ -277,6 +316,10 @@ impl VirtIOHeader {
 pub struct MmioTransport {
     header: NonNull<VirtIOHeader>,
     version: MmioVersion,
+    /// Whether `VIRTIO_F_NOTIFICATION_DATA` has been negotiated.
+    notification_data: bool,
+    /// Whether `VIRTIO_F_RING_RESET` has been negotiated.
+    ring_reset: bool,
 }
 
 impl MmioTransport {
@@ -295,7 +338,12 @@ impl MmioTransport {
             return Err(MmioError::ZeroDeviceId);
         }
         let version = volread!(header, version).try_into()?;
-        Ok(Self { header, version })
+        Ok(Self {
+            header,
+            version,
+            notification_data: false,
+            ring_reset: false,
+        })
     }
 
     /// Gets the version of the VirtIO MMIO transport.
@@ -343,6 +391,8 @@ impl Transport for MmioTransport {
             volwrite!(self.header, driver_features_sel, 1); // driver features [32, 64)
             volwrite!(self.header, driver_features, (driver_features >> 32) as u32);
         }
+        self.notification_data = driver_features & Feature::NOTIFICATION_DATA.bits() != 0;
+        self.ring_reset = driver_features & Feature::RING_RESET.bits() != 0;
     }
 
     fn max_queue_size(&mut self, queue: u16) -> u32 {
@@ -353,10 +403,15 @@ impl Transport for MmioTransport {
         }
     }
 
-    fn notify(&mut self, queue: u16) {
+    fn notify(&mut self, notification: Notification) {
+        let value = if self.notification_data {
+            notification.data()
+        } else {
+            notification.queue.into()
+        };
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
-            volwrite!(self.header, queue_notify, queue.into());
+            volwrite!(self.header, queue_notify, value);
         }
     }
 
@@ -372,6 +427,15 @@ impl Transport for MmioTransport {
         }
     }
 
+    fn read_config_generation(&self) -> u32 {
+        match self.version {
+            // The legacy interface has no configuration generation.
+            MmioVersion::Legacy => 0,
+            // Safe because self.header points to a valid VirtIO MMIO region.
+            MmioVersion::Modern => unsafe { volread!(self.header, config_generation) },
+        }
+    }
+
     fn set_guest_page_size(&mut self, guest_page_size: u32) {
         match self.version {
             MmioVersion::Legacy => {
@@ -485,6 +549,22 @@ impl Transport for MmioTransport {
         }
     }
 
+    fn queue_reset(&mut self, queue: u16) -> Result<(), Error> {
+        match self.version {
+            MmioVersion::Modern if self.ring_reset => {
+                // Safe because self.header points to a valid VirtIO MMIO region.
+                unsafe {
+                    volwrite!(self.header, queue_sel, queue.into());
+                    volwrite!(self.header, queue_reset, 1);
+                    // Wait until the device reports that the reset has completed (see 4.2.2.1).
+                    while volread!(self.header, queue_reset) != 0 {}
+                }
+                Ok(())
+            }
+            _ => Err(Error::Unsupported),
+        }
+    }
+
     fn ack_interrupt(&mut self) -> bool {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
@@ -508,6 +588,26 @@ impl Transport for MmioTransport {
         }
         Ok(NonNull::new((self.header.as_ptr() as usize + CONFIG_SPACE_OFFSET) as _).unwrap())
     }
+
+    fn shared_memory_region<H: Hal>(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        if self.version == MmioVersion::Legacy {
+            return None;
+        }
+        // Safe because self.header points to a valid VirtIO MMIO region.
+        let (length, paddr) = unsafe {
+            volwrite!(self.header, shm_sel, id.into());
+            let length = u64::from(volread!(self.header, shm_len_low))
+                | u64::from(volread!(self.header, shm_len_high)) << 32;
+            let paddr = u64::from(volread!(self.header, shm_base_low))
+                | u64::from(volread!(self.header, shm_base_high)) << 32;
+            (length, paddr)
+        };
+        if length == u64::MAX {
+            return None;
+        }
+        // Safe because the device reported the region.
+        unsafe { SharedMemoryRegion::map::<H>(id, paddr as PhysAddr, length) }
+    }
 }
 
 impl Drop for MmioTransport {
@@ -0,0 +1,259 @@
+//! Parsing of VirtIO MMIO devices from `virtio_mmio.device=` kernel command-line arguments.
+//!
+//! VMMs such as Firecracker don't provide a device tree or ACPI tables, but instead describe each
+//! VirtIO MMIO device with an argument of the form `virtio_mmio.device=<size>@<addr>:<irq>[:<id>]`
+//! on the kernel command line, as understood by Linux.
+
+use super::VirtIOHeader;
+use crate::{Hal, PhysAddr};
+use core::{
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+    ptr::NonNull,
+};
+
+/// The prefix of command-line arguments describing VirtIO MMIO devices.
+const DEVICE_PARAMETER: &str = "virtio_mmio.device=";
+
+/// A VirtIO MMIO device described by a `virtio_mmio.device=` command-line argument.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MmioDeviceSpec {
+    /// The size in bytes of the device's MMIO region.
+    pub size: usize,
+    /// The physical address of the device's MMIO region.
+    pub paddr: PhysAddr,
+    /// The device's interrupt number.
+    pub irq: u32,
+    /// The platform device ID, if one was given.
+    pub id: Option<u32>,
+}
+
+impl MmioDeviceSpec {
+    /// Parses a device spec of the form `<size>@<addr>:<irq>[:<id>]`.
+    ///
+    /// The size may have a `K`, `M`, `G`, `T`, `P` or `E` suffix, and it and the address may be
+    /// given in hexadecimal with a `0x` prefix or in octal with a `0` prefix, as for Linux.
+    pub fn parse(spec: &str) -> Result<Self, CmdlineError> {
+        let (size, rest) = spec.split_once('@').ok_or(CmdlineError::MissingAddress)?;
+        let size = parse_size(size).ok_or(CmdlineError::InvalidSize)?;
+        let (paddr, rest) = rest.split_once(':').ok_or(CmdlineError::MissingIrq)?;
+        let paddr = parse_number(paddr)
+            .and_then(|paddr| PhysAddr::try_from(paddr).ok())
+            .ok_or(CmdlineError::InvalidAddress)?;
+        let (irq, id) = match rest.split_once(':') {
+            Some((irq, id)) => (irq, Some(id)),
+            None => (rest, None),
+        };
+        let irq = irq
+            .parse()
+            .ok()
+            .filter(|&irq| irq != 0)
+            .ok_or(CmdlineError::InvalidIrq)?;
+        let id = id
+            .map(|id| id.parse().map_err(|_| CmdlineError::InvalidId))
+            .transpose()?;
+        Ok(Self {
+            size,
+            paddr,
+            irq,
+            id,
+        })
+    }
+
+    /// Maps the device's MMIO region with [`Hal::mmio_phys_to_virt`], returning the header to pass
+    /// to [`MmioTransport::new`](super::MmioTransport::new).
+    ///
+    /// # Safety
+    ///
+    /// The spec must describe a valid MMIO region.
+    pub unsafe fn map<H: Hal>(&self) -> NonNull<VirtIOHeader> {
+        // Safe because the caller promises that the region is valid MMIO.
+        unsafe { H::mmio_phys_to_virt(self.paddr, self.size) }.cast()
+    }
+}
+
+/// Returns an iterator over the VirtIO MMIO devices described by `virtio_mmio.device=` arguments on
+/// the given kernel command line, in the order they appear.
+///
+/// Arguments which can't be parsed are returned as errors, so the caller can report them and carry
+/// on with the rest.
+pub fn devices_from_cmdline(
+    cmdline: &str,
+) -> impl Iterator<Item = Result<MmioDeviceSpec, CmdlineError>> + '_ {
+    cmdline
+        .split_ascii_whitespace()
+        .filter_map(|argument| argument.strip_prefix(DEVICE_PARAMETER))
+        .map(MmioDeviceSpec::parse)
+}
+
+/// An error parsing a `virtio_mmio.device=` command-line argument.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CmdlineError {
+    /// The `@<addr>` part was missing.
+    MissingAddress,
+    /// The `:<irq>` part was missing.
+    MissingIrq,
+    /// The size wasn't a valid number or had an unknown suffix.
+    InvalidSize,
+    /// The address wasn't a valid number.
+    InvalidAddress,
+    /// The interrupt number wasn't a valid non-zero number.
+    InvalidIrq,
+    /// The platform device ID wasn't a valid number.
+    InvalidId,
+}
+
+impl Display for CmdlineError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::MissingAddress => write!(f, "VirtIO MMIO device spec is missing the address."),
+            Self::MissingIrq => write!(f, "VirtIO MMIO device spec is missing the IRQ."),
+            Self::InvalidSize => write!(f, "Invalid VirtIO MMIO device size."),
+            Self::InvalidAddress => write!(f, "Invalid VirtIO MMIO device address."),
+            Self::InvalidIrq => write!(f, "Invalid VirtIO MMIO device IRQ."),
+            Self::InvalidId => write!(f, "Invalid VirtIO MMIO device ID."),
+        }
+    }
+}
+
+/// Parses a size with an optional binary unit suffix, like Linux's `memparse`.
+fn parse_size(size: &str) -> Option<usize> {
+    let hex = size.starts_with("0x") || size.starts_with("0X");
+    let shift = match size.chars().last()? {
+        'k' | 'K' => 10,
+        'm' | 'M' => 20,
+        'g' | 'G' => 30,
+        't' | 'T' => 40,
+        'p' | 'P' => 50,
+        // A hexadecimal size may end with the digit E rather than the exbibyte suffix.
+        'e' | 'E' if !hex => 60,
+        _ => 0,
+    };
+    let number = if shift == 0 {
+        size
+    } else {
+        &size[..size.len() - 1]
+    };
+    let size = parse_number(number)?;
+    if size.leading_zeros() < shift {
+        return None;
+    }
+    usize::try_from(size << shift).ok()
+}
+
+/// Parses an unsigned number in hexadecimal with a `0x` prefix, octal with a `0` prefix, or else
+/// decimal.
+fn parse_number(number: &str) -> Option<u64> {
+    if let Some(hex) = number
+        .strip_prefix("0x")
+        .or_else(|| number.strip_prefix("0X"))
+    {
+        u64::from_str_radix(hex, 16).ok()
+    } else if number.len() > 1 && number.starts_with('0') {
+        u64::from_str_radix(&number[1..], 8).ok()
+    } else {
+        number.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_spec() {
+        assert_eq!(
+            MmioDeviceSpec::parse("4K@0xd0000000:5"),
+            Ok(MmioDeviceSpec {
+                size: 0x1000,
+                paddr: 0xd000_0000,
+                irq: 5,
+                id: None,
+            })
+        );
+        assert_eq!(
+            MmioDeviceSpec::parse("0x200@0x1000:12:3"),
+            Ok(MmioDeviceSpec {
+                size: 0x200,
+                paddr: 0x1000,
+                irq: 12,
+                id: Some(3),
+            })
+        );
+        assert_eq!(
+            MmioDeviceSpec::parse("0x1e@0x1000:2"),
+            Ok(MmioDeviceSpec {
+                size: 0x1e,
+                paddr: 0x1000,
+                irq: 2,
+                id: None,
+            })
+        );
+        assert_eq!(
+            MmioDeviceSpec::parse("1m@010000:1"),
+            Ok(MmioDeviceSpec {
+                size: 0x10_0000,
+                paddr: 0o10000,
+                irq: 1,
+                id: None,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_invalid_spec() {
+        assert_eq!(
+            MmioDeviceSpec::parse("4K"),
+            Err(CmdlineError::MissingAddress)
+        );
+        assert_eq!(
+            MmioDeviceSpec::parse("4K@0x1000"),
+            Err(CmdlineError::MissingIrq)
+        );
+        assert_eq!(
+            MmioDeviceSpec::parse("4X@0x1000:5"),
+            Err(CmdlineError::InvalidSize)
+        );
+        assert_eq!(
+            MmioDeviceSpec::parse("16E@0x1000:5"),
+            Err(CmdlineError::InvalidSize)
+        );
+        assert_eq!(
+            MmioDeviceSpec::parse("4K@0xg000:5"),
+            Err(CmdlineError::InvalidAddress)
+        );
+        assert_eq!(
+            MmioDeviceSpec::parse("4K@0x1000:0"),
+            Err(CmdlineError::InvalidIrq)
+        );
+        assert_eq!(
+            MmioDeviceSpec::parse("4K@0x1000:5:"),
+            Err(CmdlineError::InvalidId)
+        );
+    }
+
+    #[test]
+    fn multiple_devices() {
+        let cmdline = "console=ttyS0 virtio_mmio.device=4K@0xd0000000:5 reboot=k \
+                       virtio_mmio.device=4K@0xd0001000:6:1 virtio_mmio.device=4K";
+        let devices: Vec<_> = devices_from_cmdline(cmdline).collect();
+        assert_eq!(
+            devices,
+            vec![
+                Ok(MmioDeviceSpec {
+                    size: 0x1000,
+                    paddr: 0xd000_0000,
+                    irq: 5,
+                    id: None,
+                }),
+                Ok(MmioDeviceSpec {
+                    size: 0x1000,
+                    paddr: 0xd000_1000,
+                    irq: 6,
+                    id: Some(1),
+                }),
+                Err(CmdlineError::MissingAddress),
+            ]
+        );
+    }
+}
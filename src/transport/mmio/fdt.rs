@@ -0,0 +1,563 @@
+//! Discovery of VirtIO MMIO devices from a flattened device tree, without a heap allocator.
+
+use super::{VirtIOHeader, MAGIC_VALUE};
+use crate::{volatile::volread, Hal, PhysAddr};
+use core::{
+    convert::{TryFrom, TryInto},
+    fmt::{self, Display, Formatter},
+    marker::PhantomData,
+    mem::size_of,
+    ptr::NonNull,
+};
+use log::{debug, warn};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// The version of the flattened device tree format which we understand. Later versions are
+/// backwards compatible with it if their `last_comp_version` says so.
+const FDT_VERSION: u32 = 17;
+
+// The indices of the 32-bit fields of the flattened device tree header.
+const HEADER_MAGIC: usize = 0;
+const HEADER_TOTAL_SIZE: usize = 1;
+const HEADER_OFF_DT_STRUCT: usize = 2;
+const HEADER_OFF_DT_STRINGS: usize = 3;
+const HEADER_VERSION: usize = 5;
+const HEADER_LAST_COMP_VERSION: usize = 6;
+const HEADER_SIZE_DT_STRINGS: usize = 8;
+const HEADER_SIZE_DT_STRUCT: usize = 9;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// The maximum depth of nodes in the device tree.
+const MAX_DEPTH: usize = 16;
+/// The `#address-cells` of a node which doesn't specify it.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+/// The `#size-cells` of a node which doesn't specify it.
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+/// The `compatible` string of VirtIO MMIO devices.
+const VIRTIO_MMIO_COMPATIBLE: &[u8] = b"virtio,mmio";
+
+/// An error parsing a flattened device tree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FdtError {
+    /// The header didn't start with the flattened device tree magic number.
+    BadMagic(u32),
+    /// The flattened device tree version isn't compatible with version 17.
+    UnsupportedVersion(u32),
+    /// The blob is shorter than its header says.
+    Truncated,
+}
+
+impl Display for FdtError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::BadMagic(magic) => write!(
+                f,
+                "Invalid flattened device tree magic number {:#010x} (expected {:#010x}).",
+                magic, FDT_MAGIC
+            ),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported flattened device tree version {}.", version)
+            }
+            Self::Truncated => write!(f, "Flattened device tree is shorter than its header says."),
+        }
+    }
+}
+
+/// A VirtIO MMIO device found in the device tree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VirtioMmioDevice<'a> {
+    /// The physical address of the device's MMIO region.
+    pub paddr: PhysAddr,
+    /// The size in bytes of the device's MMIO region.
+    pub size: usize,
+    /// The device's interrupts.
+    pub interrupts: InterruptSpecifier<'a>,
+    /// The device's MMIO region as mapped by [`Hal::mmio_phys_to_virt`], to pass to
+    /// [`MmioTransport::new`](super::MmioTransport::new).
+    pub header: NonNull<VirtIOHeader>,
+}
+
+/// The `interrupts` property of a device tree node.
+///
+/// This contains one or more interrupt specifiers, whose format is defined by the node's interrupt
+/// parent. For example an Arm GIC uses 3 cells (the interrupt type, number and flags) and a RISC-V
+/// PLIC uses 1 (the interrupt number).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct InterruptSpecifier<'a> {
+    value: &'a [u8],
+}
+
+impl<'a> InterruptSpecifier<'a> {
+    /// Returns whether the node has no interrupts.
+    pub fn is_empty(&self) -> bool {
+        self.value.len() < size_of::<u32>()
+    }
+
+    /// Returns the 32-bit cells of the property.
+    pub fn cells(&self) -> impl Iterator<Item = u32> + 'a {
+        let value = self.value;
+        (0..value.len() / size_of::<u32>())
+            .filter_map(move |i| read_u32(value, i * size_of::<u32>()))
+    }
+}
+
+/// An iterator over the VirtIO MMIO devices in a flattened device tree.
+///
+/// This yields every enabled node compatible with `"virtio,mmio"` whose MMIO region has the VirtIO
+/// magic value and a non-zero device ID. Hypervisors such as QEMU provide many device slots, most of
+/// which are empty and have device ID 0, so these are skipped.
+///
+/// Addresses are taken from the first entry of each node's `reg` property, and aren't translated
+/// through the `ranges` of its parents, so those must be identity mappings.
+#[derive(Debug)]
+pub struct VirtioMmioDevices<'a, H: Hal> {
+    structure: StructureBlock<'a>,
+    /// The `#address-cells` and `#size-cells` of each node on the path to the current node, which
+    /// apply to its children.
+    cells: [(u32, u32); MAX_DEPTH],
+    /// The number of nodes on the path to the current node, including it.
+    depth: usize,
+    /// The relevant properties found so far for the current node, if it may still have some.
+    node: Option<NodeProperties<'a>>,
+    _hal: PhantomData<H>,
+}
+
+impl<'a, H: Hal> VirtioMmioDevices<'a, H> {
+    /// Checks the header of the given flattened device tree blob, and returns an iterator over the
+    /// VirtIO MMIO devices which it describes.
+    ///
+    /// # Safety
+    ///
+    /// The device tree must accurately describe the system's VirtIO MMIO devices, as each
+    /// `virtio,mmio` node's region is mapped with [`Hal::mmio_phys_to_virt`] and read to check
+    /// whether a device is present.
+    pub unsafe fn new(dtb: &'a [u8]) -> Result<Self, FdtError> {
+        let header_word =
+            |index: usize| read_u32(dtb, index * size_of::<u32>()).ok_or(FdtError::Truncated);
+        let magic = header_word(HEADER_MAGIC)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let version = header_word(HEADER_VERSION)?;
+        if version < FDT_VERSION || header_word(HEADER_LAST_COMP_VERSION)? > FDT_VERSION {
+            return Err(FdtError::UnsupportedVersion(version));
+        }
+        let dtb = dtb
+            .get(..header_word(HEADER_TOTAL_SIZE)? as usize)
+            .ok_or(FdtError::Truncated)?;
+        // Returns the block with the offset and size in the given header fields.
+        let block = |offset_index: usize, size_index: usize| {
+            let offset = header_word(offset_index)? as usize;
+            let end = offset.checked_add(header_word(size_index)? as usize);
+            end.and_then(|end| dtb.get(offset..end))
+                .ok_or(FdtError::Truncated)
+        };
+        Ok(Self {
+            structure: StructureBlock {
+                structure: block(HEADER_OFF_DT_STRUCT, HEADER_SIZE_DT_STRUCT)?,
+                strings: block(HEADER_OFF_DT_STRINGS, HEADER_SIZE_DT_STRINGS)?,
+                offset: 0,
+            },
+            cells: [(DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS); MAX_DEPTH],
+            depth: 0,
+            node: None,
+            _hal: PhantomData,
+        })
+    }
+
+    /// Handles a property of the current node.
+    fn property(&mut self, name: &[u8], value: &'a [u8]) {
+        let (Some(node), Some(cells)) = (self.node.as_mut(), self.depth.checked_sub(1)) else {
+            return;
+        };
+        match name {
+            b"#address-cells" => {
+                self.cells[cells].0 = read_u32(value, 0).unwrap_or(DEFAULT_ADDRESS_CELLS);
+            }
+            b"#size-cells" => {
+                self.cells[cells].1 = read_u32(value, 0).unwrap_or(DEFAULT_SIZE_CELLS);
+            }
+            b"compatible" => {
+                node.compatible = value
+                    .split(|&byte| byte == 0)
+                    .any(|compatible| compatible == VIRTIO_MMIO_COMPATIBLE);
+            }
+            b"status" => {
+                let status = value.split(|&byte| byte == 0).next().unwrap_or_default();
+                node.enabled = status == b"okay" || status == b"ok";
+            }
+            b"reg" => node.reg = Some(value),
+            b"interrupts" => node.interrupts = InterruptSpecifier { value },
+            _ => {}
+        }
+    }
+
+    /// Checks whether the given node, which has no more properties, is a VirtIO MMIO device which
+    /// is present.
+    fn probe(&self, node: NodeProperties<'a>) -> Option<VirtioMmioDevice<'a>> {
+        if !node.compatible || !node.enabled {
+            return None;
+        }
+        // The node's `reg` property is in terms of its parent's cells.
+        let (address_cells, size_cells) = self
+            .depth
+            .checked_sub(2)
+            .map_or((DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS), |parent| {
+                self.cells[parent]
+            });
+        let Some((paddr, size)) = node.reg.and_then(|reg| {
+            let paddr = read_cells(reg, 0, address_cells)?;
+            let size = read_cells(reg, address_cells as usize, size_cells)?;
+            Some((PhysAddr::try_from(paddr).ok()?, usize::try_from(size).ok()?))
+        }) else {
+            warn!("Ignoring VirtIO MMIO device with invalid reg property.");
+            return None;
+        };
+        if size < size_of::<VirtIOHeader>() {
+            warn!(
+                "Ignoring VirtIO MMIO device at {:#x} with region of only {:#x} bytes.",
+                paddr, size
+            );
+            return None;
+        }
+
+        // Safe because the caller of `new` promised that the device tree describes VirtIO MMIO
+        // devices accurately, and we checked that the region is big enough for the header.
+        let header = unsafe { H::mmio_phys_to_virt(paddr, size) }.cast::<VirtIOHeader>();
+        // Safe because `header` points to a valid VirtIO MMIO region.
+        let (magic, device_id) = unsafe { (volread!(header, magic), volread!(header, device_id)) };
+        if magic != MAGIC_VALUE {
+            warn!(
+                "Ignoring VirtIO MMIO device at {:#x} with bad magic value {:#010x}.",
+                paddr, magic
+            );
+            return None;
+        }
+        if device_id == 0 {
+            debug!("Skipping empty VirtIO MMIO slot at {:#x}.", paddr);
+            return None;
+        }
+        Some(VirtioMmioDevice {
+            paddr,
+            size,
+            interrupts: node.interrupts,
+            header,
+        })
+    }
+}
+
+impl<'a, H: Hal> Iterator for VirtioMmioDevices<'a, H> {
+    type Item = VirtioMmioDevice<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let token = self.structure.next_token()?;
+            // A node's properties come before its children, so once we see the start of a child
+            // or the end of the node, it has no more properties to find.
+            let device = match token {
+                Token::Property { .. } => None,
+                _ => self.node.take().and_then(|node| self.probe(node)),
+            };
+            match token {
+                Token::BeginNode => {
+                    if self.depth == MAX_DEPTH {
+                        warn!("Device tree is nested more than {} deep.", MAX_DEPTH);
+                        self.structure.finish();
+                        return device;
+                    }
+                    self.cells[self.depth] = (DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS);
+                    self.depth += 1;
+                    self.node = Some(NodeProperties::default());
+                }
+                Token::EndNode => {
+                    self.depth = self.depth.saturating_sub(1);
+                }
+                Token::Property { name, value } => self.property(name, value),
+                Token::End => self.structure.finish(),
+            }
+            if device.is_some() {
+                return device;
+            }
+        }
+    }
+}
+
+/// The properties of a device tree node which are needed to find VirtIO MMIO devices.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct NodeProperties<'a> {
+    compatible: bool,
+    enabled: bool,
+    reg: Option<&'a [u8]>,
+    interrupts: InterruptSpecifier<'a>,
+}
+
+impl Default for NodeProperties<'_> {
+    fn default() -> Self {
+        Self {
+            compatible: false,
+            // Nodes without a status property are enabled.
+            enabled: true,
+            reg: None,
+            interrupts: InterruptSpecifier::default(),
+        }
+    }
+}
+
+/// A token of the structure block of a flattened device tree.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Token<'a> {
+    BeginNode,
+    EndNode,
+    Property { name: &'a [u8], value: &'a [u8] },
+    End,
+}
+
+/// A cursor over the structure block of a flattened device tree.
+#[derive(Clone, Debug)]
+struct StructureBlock<'a> {
+    structure: &'a [u8],
+    strings: &'a [u8],
+    /// The offset of the next token within the structure block.
+    offset: usize,
+}
+
+impl<'a> StructureBlock<'a> {
+    /// Returns the next token, skipping `FDT_NOP`s, or `None` if there are no more or the structure
+    /// block is malformed.
+    fn next_token(&mut self) -> Option<Token<'a>> {
+        let token = loop {
+            let Some(token) = read_u32(self.structure, self.offset) else {
+                if self.offset < self.structure.len() {
+                    warn!("Device tree structure block is truncated.");
+                }
+                return None;
+            };
+            self.offset += size_of::<u32>();
+            if token != FDT_NOP {
+                break token;
+            }
+        };
+        match token {
+            FDT_BEGIN_NODE => {
+                // Skip the node's name, which is NUL terminated.
+                let name_len = self
+                    .structure
+                    .get(self.offset..)?
+                    .iter()
+                    .position(|&byte| byte == 0)?;
+                self.offset = align4(self.offset + name_len + 1);
+                Some(Token::BeginNode)
+            }
+            FDT_END_NODE => Some(Token::EndNode),
+            FDT_PROP => {
+                let len = read_u32(self.structure, self.offset)? as usize;
+                let name_offset = read_u32(self.structure, self.offset + 4)? as usize;
+                let value_start = self.offset + 8;
+                let value = self
+                    .structure
+                    .get(value_start..value_start.checked_add(len)?)?;
+                let name = self.strings.get(name_offset..)?;
+                let name = &name[..name.iter().position(|&byte| byte == 0)?];
+                self.offset = align4(value_start + len);
+                Some(Token::Property { name, value })
+            }
+            FDT_END => Some(Token::End),
+            _ => {
+                warn!("Invalid device tree token {:#x}.", token);
+                self.finish();
+                None
+            }
+        }
+    }
+
+    /// Skips to the end of the structure block, so that no more tokens are returned.
+    fn finish(&mut self) {
+        self.offset = self.structure.len();
+    }
+}
+
+/// Reads a big-endian `u32` from the given offset of the given slice, if it is in bounds.
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(size_of::<u32>())?)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reads a number made up of the given count of big-endian 32-bit cells, starting at the given cell
+/// index of the given property value.
+fn read_cells(value: &[u8], index: usize, count: u32) -> Option<u64> {
+    if count > 2 {
+        return None;
+    }
+    (0..count as usize).try_fold(0, |number, i| {
+        Some(number << 32 | u64::from(read_u32(value, (index + i) * size_of::<u32>())?))
+    })
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hal::fake::FakeHal, transport::mmio::MODERN_VERSION};
+
+    /// Builds a flattened device tree blob.
+    #[derive(Default)]
+    struct DtbBuilder {
+        structure: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl DtbBuilder {
+        fn token(&mut self, token: u32) {
+            self.structure.extend_from_slice(&token.to_be_bytes());
+        }
+
+        fn pad(&mut self) {
+            self.structure.resize(align4(self.structure.len()), 0);
+        }
+
+        fn begin_node(&mut self, name: &str) {
+            self.token(FDT_BEGIN_NODE);
+            self.structure.extend_from_slice(name.as_bytes());
+            self.structure.push(0);
+            self.pad();
+        }
+
+        fn end_node(&mut self) {
+            self.token(FDT_END_NODE);
+        }
+
+        fn property(&mut self, name: &str, value: &[u8]) {
+            self.token(FDT_PROP);
+            self.token(value.len() as u32);
+            self.token(self.strings.len() as u32);
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.structure.extend_from_slice(value);
+            self.pad();
+        }
+
+        fn cells_property(&mut self, name: &str, cells: &[u32]) {
+            let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+            self.property(name, &value);
+        }
+
+        fn virtio_mmio_node(&mut self, status: Option<&str>, reg: &[u32], interrupts: &[u32]) {
+            self.begin_node("virtio_mmio");
+            self.property("compatible", b"virtio,mmio\0");
+            if let Some(status) = status {
+                self.property("status", format!("{}\0", status).as_bytes());
+            }
+            self.cells_property("reg", reg);
+            self.cells_property("interrupts", interrupts);
+            self.end_node();
+        }
+
+        fn build(mut self) -> Vec<u8> {
+            self.token(FDT_END);
+            let header_size = 40;
+            let off_dt_struct = header_size + 16;
+            let off_dt_strings = off_dt_struct + self.structure.len();
+            let total_size = off_dt_strings + self.strings.len();
+            let header = [
+                FDT_MAGIC,
+                total_size as u32,
+                off_dt_struct as u32,
+                off_dt_strings as u32,
+                header_size as u32,
+                FDT_VERSION,
+                16,
+                0,
+                self.strings.len() as u32,
+                self.structure.len() as u32,
+            ];
+            let mut dtb: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+            // Empty memory reservation block.
+            dtb.resize(off_dt_struct, 0);
+            dtb.extend_from_slice(&self.structure);
+            dtb.extend_from_slice(&self.strings);
+            dtb
+        }
+    }
+
+    fn address_cells(header: &VirtIOHeader) -> [u32; 2] {
+        let address = header as *const VirtIOHeader as u64;
+        [(address >> 32) as u32, address as u32]
+    }
+
+    #[test]
+    fn find_devices() {
+        let present = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4);
+        let empty = VirtIOHeader::make_fake_header(MODERN_VERSION, 0, 0, 0, 4);
+        let disabled = VirtIOHeader::make_fake_header(MODERN_VERSION, 3, 0, 0, 4);
+        let at_root = VirtIOHeader::make_fake_header(MODERN_VERSION, 4, 0, 0, 4);
+        let size = size_of::<VirtIOHeader>() as u32;
+
+        let mut builder = DtbBuilder::default();
+        builder.begin_node("");
+        builder.cells_property("#address-cells", &[2]);
+        builder.cells_property("#size-cells", &[2]);
+        builder.begin_node("soc");
+        builder.cells_property("#address-cells", &[2]);
+        builder.cells_property("#size-cells", &[1]);
+        builder.property("compatible", b"simple-bus\0");
+        let [high, low] = address_cells(&present);
+        builder.virtio_mmio_node(None, &[high, low, size], &[0, 16, 4]);
+        let [high, low] = address_cells(&empty);
+        builder.virtio_mmio_node(Some("okay"), &[high, low, size], &[0, 17, 4]);
+        let [high, low] = address_cells(&disabled);
+        builder.virtio_mmio_node(Some("disabled"), &[high, low, size], &[0, 18, 4]);
+        builder.end_node();
+        // The root node's cells apply again after the end of the soc node.
+        let [high, low] = address_cells(&at_root);
+        builder.virtio_mmio_node(None, &[high, low, 0, size], &[0, 19, 4]);
+        builder.end_node();
+        let dtb = builder.build();
+
+        // Safe because the device tree only describes the fake headers above.
+        let devices: Vec<_> = unsafe { VirtioMmioDevices::<FakeHal>::new(&dtb) }
+            .unwrap()
+            .collect();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].header, NonNull::from(&present));
+        assert_eq!(
+            devices[0].paddr,
+            &present as *const VirtIOHeader as PhysAddr
+        );
+        assert_eq!(devices[0].size, size_of::<VirtIOHeader>());
+        assert_eq!(
+            devices[0].interrupts.cells().collect::<Vec<_>>(),
+            vec![0, 16, 4]
+        );
+        assert_eq!(devices[1].header, NonNull::from(&at_root));
+        assert_eq!(
+            devices[1].interrupts.cells().collect::<Vec<_>>(),
+            vec![0, 19, 4]
+        );
+    }
+
+    #[test]
+    fn invalid_header() {
+        let mut dtb = DtbBuilder::default().build();
+        // Safe because the device tree doesn't describe any devices.
+        assert_eq!(
+            unsafe { VirtioMmioDevices::<FakeHal>::new(&dtb[..20]) }.unwrap_err(),
+            FdtError::Truncated
+        );
+
+        dtb[0] = 0;
+        // Safe because the device tree doesn't describe any devices.
+        assert_eq!(
+            unsafe { VirtioMmioDevices::<FakeHal>::new(&dtb) }.unwrap_err(),
+            FdtError::BadMagic(0x000d_feed)
+        );
+    }
+}
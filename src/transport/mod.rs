@@ -5,10 +5,17 @@ pub mod fake;
 pub mod mmio;
 pub mod pci;
 
-use crate::{PhysAddr, Result, PAGE_SIZE};
+use crate::{nonnull_slice_from_raw_parts, Error, Hal, PhysAddr, Result, PAGE_SIZE};
 use bitflags::{bitflags, Flags};
-use core::{fmt::Debug, ops::BitAnd, ptr::NonNull};
+use core::{
+    convert::TryFrom,
+    fmt::Debug,
+    mem::{align_of, size_of},
+    ops::BitAnd,
+    ptr::NonNull,
+};
 use log::debug;
+use zerocopy::{AsBytes, FromBytes};
 
 /// A VirtIO transport layer.
 pub trait Transport {
@@ -24,8 +31,11 @@ pub trait Transport {
     /// Gets the max size of the given queue.
     fn max_queue_size(&mut self, queue: u16) -> u32;
 
-    /// Notifies the given queue on the device.
-    fn notify(&mut self, queue: u16);
+    /// Notifies the device about new buffers in a queue.
+    ///
+    /// If `VIRTIO_F_NOTIFICATION_DATA` has been negotiated this sends all the information in the
+    /// notification to the device, otherwise just the queue index.
+    fn notify(&mut self, notification: Notification);
 
     /// Gets the device status.
     fn get_status(&self) -> DeviceStatus;
@@ -57,6 +67,17 @@ pub trait Transport {
     /// Returns whether the queue is in use, i.e. has a nonzero PFN or is marked as ready.
     fn queue_used(&mut self, queue: u16) -> bool;
 
+    /// Resets the given queue without resetting the rest of the device, and waits for the reset to
+    /// complete. The queue is then disabled, and may be set up again with `queue_set`.
+    ///
+    /// Returns `Error::Unsupported` if `VIRTIO_F_RING_RESET` hasn't been negotiated, or the
+    /// transport doesn't support resetting individual queues.
+    ///
+    /// Ref: 2.6.1 Virtqueue Reset
+    fn queue_reset(&mut self, _queue: u16) -> Result {
+        Err(Error::Unsupported)
+    }
+
     /// Acknowledges an interrupt.
     ///
     /// Returns true on success.
@@ -99,7 +120,194 @@ pub trait Transport {
     }
 
     /// Gets the pointer to the config space.
+    ///
+    /// This is only supported by transports whose device-specific configuration space is memory
+    /// mapped. Drivers should generally use [`read_config_space`](Self::read_config_space) and
+    /// [`write_config_space`](Self::write_config_space) instead, which work with any transport.
     fn config_space<T: 'static>(&self) -> Result<NonNull<T>>;
+
+    /// Reads a value of type `T` from the device-specific configuration space at the given byte
+    /// offset.
+    ///
+    /// Fails with [`Error::ConfigSpaceTooSmall`] if the value doesn't fit within the configuration
+    /// space, or [`Error::ConfigSpaceMissing`] if the device doesn't have one.
+    ///
+    /// The default implementation reads through the pointer returned by
+    /// [`config_space`](Self::config_space), so transports whose configuration space isn't memory
+    /// mapped must override it.
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T> {
+        check_config_space_access::<T>(offset, None)?;
+        let config_space = self.config_space::<u8>()?;
+        // Safe because the transport promises that `config_space` points to the memory mapped
+        // configuration space, and we checked above that the value is aligned.
+        Ok(unsafe {
+            config_space
+                .as_ptr()
+                .add(offset)
+                .cast::<T>()
+                .read_volatile()
+        })
+    }
+
+    /// Writes a value of type `T` to the device-specific configuration space at the given byte
+    /// offset.
+    ///
+    /// Fails as for [`read_config_space`](Self::read_config_space), and likewise the default
+    /// implementation writes through the pointer returned by [`config_space`](Self::config_space).
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result {
+        check_config_space_access::<T>(offset, None)?;
+        let config_space = self.config_space::<u8>()?;
+        // Safe because the transport promises that `config_space` points to the memory mapped
+        // configuration space, and we checked above that the value is aligned.
+        unsafe {
+            config_space
+                .as_ptr()
+                .add(offset)
+                .cast::<T>()
+                .write_volatile(value)
+        };
+        Ok(())
+    }
+
+    /// Returns the device's configuration generation, which the device changes whenever it changes
+    /// the device-specific configuration space.
+    ///
+    /// Transports without a configuration generation, such as the legacy interfaces, always return
+    /// 0.
+    fn read_config_generation(&self) -> u32 {
+        0
+    }
+
+    /// Reads from the device-specific configuration space with the given function, retrying until
+    /// the configuration generation is the same before and after.
+    ///
+    /// Values which span several fields, or fields wider than 32 bits, should be read this way, so
+    /// that they aren't torn by the device changing the configuration space in between accesses.
+    ///
+    /// Ref: 2.5.1 Driver Requirements: Device Configuration Space
+    fn read_config<T>(&self, mut read: impl FnMut() -> Result<T>) -> Result<T> {
+        loop {
+            let generation = self.read_config_generation();
+            let value = read()?;
+            if self.read_config_generation() == generation {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Returns the device's shared memory region with the given ID, mapped with
+    /// [`Hal::mmio_phys_to_virt`], or `None` if the device has no such region.
+    ///
+    /// What each ID means is device-specific, e.g. the GPU device's host visible memory region for
+    /// blob resources has ID 1. Transports which don't support shared memory regions always return
+    /// `None`.
+    ///
+    /// Ref: 2.10 Shared Memory Regions
+    fn shared_memory_region<H: Hal>(&mut self, _id: u8) -> Option<SharedMemoryRegion> {
+        None
+    }
+
+    /// Returns whether queues must be the size returned by
+    /// [`max_queue_size`](Self::max_queue_size), because the driver can't choose a smaller one.
+    ///
+    /// This is the case for the legacy PCI interface, whose queue size register is read-only.
+    fn requires_max_queue_size(&self) -> bool {
+        false
+    }
+}
+
+/// Checks that a value of type `T` at the given offset lies within a configuration space of `len`
+/// bytes (if known), and is suitably aligned to be accessed with a single volatile access.
+fn check_config_space_access<T>(offset: usize, len: Option<usize>) -> Result {
+    if align_of::<T>() > 4 {
+        // Panic as this should only happen if the driver is written incorrectly.
+        panic!(
+            "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
+            align_of::<T>()
+        );
+    }
+    assert_eq!(
+        offset % align_of::<T>(),
+        0,
+        "Config space offset {:#x} is not aligned to {} bytes.",
+        offset,
+        align_of::<T>()
+    );
+    let end = offset
+        .checked_add(size_of::<T>())
+        .ok_or(Error::ConfigSpaceTooSmall)?;
+    match len {
+        Some(len) if end > len => Err(Error::ConfigSpaceTooSmall),
+        _ => Ok(()),
+    }
+}
+
+/// A shared memory region of a device, mapped into the driver's address space.
+///
+/// Unlike a virtqueue buffer, the memory belongs to the device, and the driver accesses it directly.
+///
+/// Ref: 2.10 Shared Memory Regions
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SharedMemoryRegion {
+    /// The ID of the region.
+    pub id: u8,
+    /// The physical address of the start of the region.
+    pub paddr: PhysAddr,
+    /// The region as mapped by [`Hal::mmio_phys_to_virt`].
+    pub vaddr: NonNull<[u8]>,
+}
+
+impl SharedMemoryRegion {
+    /// Maps the shared memory region with the given ID, physical address and length in bytes.
+    ///
+    /// Returns `None` if the region is too big to be mapped.
+    ///
+    /// # Safety
+    ///
+    /// The physical address and length must describe a shared memory region which the device
+    /// reported.
+    unsafe fn map<H: Hal>(id: u8, paddr: PhysAddr, length: u64) -> Option<Self> {
+        let length = usize::try_from(length).ok()?;
+        // Safe because the caller promises that the region is one which the device reported, so it
+        // is valid MMIO.
+        let vaddr = unsafe { H::mmio_phys_to_virt(paddr, length) };
+        Some(Self {
+            id,
+            paddr,
+            vaddr: nonnull_slice_from_raw_parts(vaddr, length),
+        })
+    }
+}
+
+// SAFETY: `vaddr` points to device memory, which can be accessed from any thread or CPU core.
+unsafe impl Send for SharedMemoryRegion {}
+
+// SAFETY: `&SharedMemoryRegion` doesn't allow any access to the region itself.
+unsafe impl Sync for SharedMemoryRegion {}
+
+/// A notification from the driver to the device that there are new buffers in a queue.
+///
+/// Ref: 2.9 Driver Notifications
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Notification {
+    /// The index of the queue.
+    pub queue: u16,
+    /// The offset within the ring where the next available buffer will be written. For the split
+    /// layout this is the 15 least significant bits of the available index.
+    pub next_off: u16,
+    /// For the packed layout this is the driver's wrap counter. For the split layout it is the most
+    /// significant bit of the available index.
+    pub next_wrap: bool,
+}
+
+impl Notification {
+    /// Returns the 32-bit value to write to the device when `VIRTIO_F_NOTIFICATION_DATA` has been
+    /// negotiated.
+    pub fn data(&self) -> u32 {
+        u32::from(self.queue)
+            | u32::from(self.next_off & 0x7fff) << 16
+            | u32::from(self.next_wrap) << 31
+    }
 }
 
 bitflags! {
@@ -160,7 +368,7 @@ pub enum DeviceType {
     Pstore = 22,
     IOMMU = 23,
     Memory = 24,
-    Sound = 25
+    Sound = 25,
 }
 
 impl From<u32> for DeviceType {
@@ -205,3 +413,39 @@ impl From<u8> for DeviceType {
         u32::from(virtio_device_id).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fake::{FakeTransport, State};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn read_config_retries_on_generation_change() {
+        let mut config_space = 0u32;
+        let state = Arc::new(Mutex::new(State::default()));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        // Simulate the device changing its config space during the first attempt.
+        let mut attempts = 0;
+        let result = transport.read_config(|| {
+            attempts += 1;
+            if attempts == 1 {
+                state.lock().unwrap().config_generation += 1;
+            }
+            Ok(attempts)
+        });
+        assert_eq!(result, Ok(2));
+
+        assert_eq!(
+            transport.read_config(|| Err::<(), _>(Error::ConfigSpaceMissing)),
+            Err(Error::ConfigSpaceMissing)
+        );
+    }
+}
@@ -1,22 +1,31 @@
 //! PCI transport for VirtIO.
 
 pub mod bus;
+pub mod cfg_window;
+pub mod legacy;
+pub mod msix;
 
-use self::bus::{DeviceFunction, DeviceFunctionInfo, PciError, PciRoot, PCI_CAP_ID_VNDR};
-use super::{DeviceStatus, DeviceType, Transport};
+use self::bus::{
+    ConfigurationAccess, DeviceFunction, DeviceFunctionInfo, PciError, PciRoot, PCI_CAP_ID_VNDR,
+};
+use super::{DeviceStatus, DeviceType, Notification, SharedMemoryRegion, Transport};
 use crate::{
+    device::common::Feature,
     hal::{Hal, PhysAddr},
     nonnull_slice_from_raw_parts,
+    queue::Bookkeeping,
     volatile::{
         volread, volwrite, ReadOnly, Volatile, VolatileReadable, VolatileWritable, WriteOnly,
     },
     Error,
 };
+use bitflags::bitflags;
 use core::{
     fmt::{self, Display, Formatter},
     mem::{align_of, size_of},
     ptr::{addr_of_mut, NonNull},
 };
+use log::warn;
 
 /// The PCI vendor ID for VirtIO devices.
 const VIRTIO_VENDOR_ID: u16 = 0x1af4;
@@ -49,6 +58,39 @@ const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
 const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
 /// Device specific configuration.
 const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+/// PCI configuration access
+const VIRTIO_PCI_CAP_PCI_CFG: u8 = 5;
+/// The offset of the `pci_cfg_data` field within `virtio_pci_cfg_cap`.
+const CAP_PCI_CFG_DATA_OFFSET: u8 = 16;
+/// Shared memory region.
+const VIRTIO_PCI_CAP_SHARED_MEMORY_CFG: u8 = 8;
+/// The offset of the `offset_hi` field within `virtio_pci_cap64`.
+const CAP_OFFSET_HI_OFFSET: u8 = 16;
+/// The offset of the `length_hi` field within `virtio_pci_cap64`.
+const CAP_LENGTH_HI_OFFSET: u8 = 20;
+
+/// The maximum number of shared memory regions of a device which a transport keeps track of.
+const MAX_SHARED_MEMORY_REGIONS: usize = 8;
+
+/// The MSI-X vector number meaning that no vector is assigned.
+pub const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+/// The number of queues whose MSI-X vectors are stored inline. Devices with more queues need the
+/// `alloc` feature for vectors to be assigned to the rest.
+const INLINE_MSIX_QUEUES: usize = 16;
+
+bitflags! {
+    /// The ISR status register, which says why the device raised an INTx# interrupt.
+    ///
+    /// Ref: 4.1.4.5 ISR status capability
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct IsrStatus: u8 {
+        /// A queue has used buffers.
+        const QUEUE_INTERRUPT = 1 << 0;
+        /// The device-specific configuration has changed.
+        const DEVICE_CONFIGURATION_INTERRUPT = 1 << 1;
+    }
+}
 
 fn device_type(pci_device_id: u16) -> DeviceType {
     match pci_device_id {
@@ -86,13 +128,26 @@ pub struct PciTransport {
     device_function: DeviceFunction,
     /// The common configuration structure within some BAR.
     common_cfg: NonNull<CommonCfg>,
+    /// The length in bytes of the common configuration structure, which may be shorter than
+    /// `CommonCfg` for devices implementing an older version of the spec.
+    common_cfg_len: usize,
     /// The start of the queue notification region within some BAR.
     notify_region: NonNull<[WriteOnly<u16>]>,
     notify_off_multiplier: u32,
+    /// Whether `VIRTIO_F_NOTIFICATION_DATA` has been negotiated.
+    notification_data: bool,
+    /// Whether `VIRTIO_F_RING_RESET` has been negotiated.
+    ring_reset: bool,
     /// The ISR status register within some BAR.
     isr_status: NonNull<Volatile<u8>>,
     /// The VirtIO device-specific configuration within some BAR.
     config_space: Option<NonNull<[u32]>>,
+    /// The MSI-X vector to assign to configuration changes when the device is initialised.
+    config_msix_vector: u16,
+    /// The MSI-X vectors to assign to each queue when it is set up, indexed by queue.
+    queue_msix_vectors: Bookkeeping<u16, INLINE_MSIX_QUEUES>,
+    /// The locations of the device's shared memory regions.
+    shared_memory: [Option<SharedMemoryLocation>; MAX_SHARED_MEMORY_REGIONS],
 }
 
 impl PciTransport {
@@ -101,7 +156,7 @@ impl PciTransport {
     ///
     /// The PCI device must already have had its BARs allocated.
     pub fn new<H: Hal>(
-        root: &mut PciRoot,
+        root: &mut PciRoot<impl ConfigurationAccess>,
         device_function: DeviceFunction,
     ) -> Result<Self, VirtioPciError> {
         let device_vendor = root.config_read_word(device_function, 0);
@@ -112,57 +167,32 @@ impl PciTransport {
         }
         let device_type = device_type(device_id);
 
-        // Find the PCI capabilities we need.
-        let mut common_cfg = None;
-        let mut notify_cfg = None;
-        let mut notify_off_multiplier = 0;
-        let mut isr_cfg = None;
-        let mut device_cfg = None;
-        for capability in root.capabilities(device_function) {
-            if capability.id != PCI_CAP_ID_VNDR {
-                continue;
-            }
-            let cap_len = capability.private_header as u8;
-            let cfg_type = (capability.private_header >> 8) as u8;
-            if cap_len < 16 {
-                continue;
-            }
-            let struct_info = VirtioCapabilityInfo {
-                bar: root.config_read_word(device_function, capability.offset + CAP_BAR_OFFSET)
-                    as u8,
-                offset: root
-                    .config_read_word(device_function, capability.offset + CAP_BAR_OFFSET_OFFSET),
-                length: root
-                    .config_read_word(device_function, capability.offset + CAP_LENGTH_OFFSET),
-            };
-
-            match cfg_type {
-                VIRTIO_PCI_CAP_COMMON_CFG if common_cfg.is_none() => {
-                    common_cfg = Some(struct_info);
-                }
-                VIRTIO_PCI_CAP_NOTIFY_CFG if cap_len >= 20 && notify_cfg.is_none() => {
-                    notify_cfg = Some(struct_info);
-                    notify_off_multiplier = root.config_read_word(
-                        device_function,
-                        capability.offset + CAP_NOTIFY_OFF_MULTIPLIER_OFFSET,
-                    );
-                }
-                VIRTIO_PCI_CAP_ISR_CFG if isr_cfg.is_none() => {
-                    isr_cfg = Some(struct_info);
-                }
-                VIRTIO_PCI_CAP_DEVICE_CFG if device_cfg.is_none() => {
-                    device_cfg = Some(struct_info);
-                }
-                _ => {}
-            }
-        }
-
-        let common_cfg = get_bar_region::<H, _>(
+        let VirtioCapabilities {
+            common_cfg,
+            notify_cfg,
+            notify_off_multiplier,
+            isr_cfg,
+            device_cfg,
+            shared_memory,
+            ..
+        } = VirtioCapabilities::find(root, device_function);
+
+        let common_cfg = common_cfg.ok_or(VirtioPciError::MissingCommonConfig)?;
+        let common_cfg_len = common_cfg.length as usize;
+        let common_cfg = get_bar_region_with_min_size::<H, CommonCfg>(
             root,
             device_function,
-            &common_cfg.ok_or(VirtioPciError::MissingCommonConfig)?,
+            &common_cfg,
+            COMMON_CFG_MIN_SIZE,
         )?;
 
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        let num_queues = usize::from(unsafe { volread!(common_cfg, num_queues) });
+        let queue_msix_vectors = Bookkeeping::new(num_queues, VIRTIO_MSI_NO_VECTOR)
+            .or_else(|_| Bookkeeping::new(INLINE_MSIX_QUEUES, VIRTIO_MSI_NO_VECTOR))
+            .unwrap();
+
         let notify_cfg = notify_cfg.ok_or(VirtioPciError::MissingNotifyConfig)?;
         if notify_off_multiplier % 2 != 0 {
             return Err(VirtioPciError::InvalidNotifyOffMultiplier(
@@ -187,16 +217,76 @@ impl PciTransport {
             None
         };
 
+        let shared_memory = locate_shared_memory(root, device_function, &shared_memory);
+
         Ok(Self {
             device_type,
             device_function,
             common_cfg,
+            common_cfg_len,
             notify_region,
             notify_off_multiplier,
+            notification_data: false,
+            ring_reset: false,
             isr_status,
             config_space,
+            config_msix_vector: VIRTIO_MSI_NO_VECTOR,
+            queue_msix_vectors,
+            shared_memory,
         })
     }
+
+    /// Sets the MSI-X vector with which the device signals changes to its device-specific
+    /// configuration, or [`VIRTIO_MSI_NO_VECTOR`] for none.
+    ///
+    /// The vector is an index into the device's MSI-X table, see [`MsixTable`](msix::MsixTable).
+    /// The device forgets its vectors when it is reset, so the transport assigns this one while the
+    /// device is being initialised, and it must be set before the transport is passed to a driver.
+    pub fn set_config_msix_vector(&mut self, vector: u16) {
+        self.config_msix_vector = vector;
+    }
+
+    /// Sets the MSI-X vector with which the device signals used buffers in the given queue, or
+    /// [`VIRTIO_MSI_NO_VECTOR`] for none.
+    ///
+    /// As for [`set_config_msix_vector`](Self::set_config_msix_vector), this is assigned when the
+    /// queue is set up, so must be set before the transport is passed to a driver. Different queues
+    /// may use different vectors, e.g. so that a network device's receive and transmit queues
+    /// interrupt different CPUs. Returns [`Error::InvalidParam`] if the device doesn't have the
+    /// queue, or if it is beyond the first 16 queues and the `alloc` feature is not enabled.
+    pub fn set_queue_msix_vector(&mut self, queue: u16, vector: u16) -> Result<(), Error> {
+        *self
+            .queue_msix_vectors
+            .get_mut(usize::from(queue))
+            .ok_or(Error::InvalidParam)? = vector;
+        Ok(())
+    }
+
+    /// Reads and clears the ISR status, which de-asserts the device's INTx# interrupt.
+    pub fn ack_isr_status(&mut self) -> IsrStatus {
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        // Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt.
+        let isr_status = unsafe { self.isr_status.as_ptr().vread() };
+        IsrStatus::from_bits_truncate(isr_status)
+    }
+
+    /// Returns whether any MSI-X vectors have been assigned.
+    fn msix_vectors_assigned(&self) -> bool {
+        self.config_msix_vector != VIRTIO_MSI_NO_VECTOR
+            || self
+                .queue_msix_vectors
+                .iter()
+                .any(|&vector| vector != VIRTIO_MSI_NO_VECTOR)
+    }
+
+    /// Returns whether notification data can be written to the notify address of every queue as a
+    /// single aligned 32-bit value, which is needed for `VIRTIO_F_NOTIFICATION_DATA`.
+    fn notify_region_allows_notification_data(&self) -> bool {
+        self.notify_off_multiplier.is_multiple_of(4)
+            && (self.notify_region.as_ptr() as *mut u16 as usize).is_multiple_of(align_of::<u32>())
+            && self.notify_region.len().is_multiple_of(2)
+    }
 }
 
 impl Transport for PciTransport {
@@ -212,6 +302,9 @@ impl Transport for PciTransport {
             let mut device_features_bits = volread!(self.common_cfg, device_feature) as u64;
             volwrite!(self.common_cfg, device_feature_select, 1);
             device_features_bits |= (volread!(self.common_cfg, device_feature) as u64) << 32;
+            if !self.notify_region_allows_notification_data() {
+                device_features_bits &= !Feature::NOTIFICATION_DATA.bits();
+            }
             device_features_bits
         }
     }
@@ -229,6 +322,8 @@ impl Transport for PciTransport {
                 (driver_features >> 32) as u32
             );
         }
+        self.notification_data = driver_features & Feature::NOTIFICATION_DATA.bits() != 0;
+        self.ring_reset = driver_features & Feature::RING_RESET.bits() != 0;
     }
 
     fn max_queue_size(&mut self, queue: u16) -> u32 {
@@ -240,17 +335,26 @@ impl Transport for PciTransport {
         }
     }
 
-    fn notify(&mut self, queue: u16) {
+    fn notify(&mut self, notification: Notification) {
         // Safe because the common config and notify region pointers are valid and we checked in
         // get_bar_region that they were aligned.
         unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
+            volwrite!(self.common_cfg, queue_select, notification.queue);
             // TODO: Consider caching this somewhere (per queue).
             let queue_notify_off = volread!(self.common_cfg, queue_notify_off);
 
             let offset_bytes = usize::from(queue_notify_off) * self.notify_off_multiplier as usize;
             let index = offset_bytes / size_of::<u16>();
-            addr_of_mut!((*self.notify_region.as_ptr())[index]).vwrite(queue);
+            let notify_ptr = addr_of_mut!((*self.notify_region.as_ptr())[index]);
+            if self.notification_data {
+                // The notification data is written as a single 32-bit value. We only offer
+                // `VIRTIO_F_NOTIFICATION_DATA` if every notify address is 4-byte aligned and the
+                // notify region is a whole number of 32-bit values, so as `index` is within the
+                // region, so is the whole value.
+                (notify_ptr as *mut WriteOnly<u32>).vwrite(notification.data());
+            } else {
+                notify_ptr.vwrite(notification.queue);
+            }
         }
     }
 
@@ -261,12 +365,34 @@ impl Transport for PciTransport {
         DeviceStatus::from_bits_truncate(status.into())
     }
 
+    fn read_config_generation(&self) -> u32 {
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        unsafe { volread!(self.common_cfg, config_generation) }.into()
+    }
+
     fn set_status(&mut self, status: DeviceStatus) {
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
         // was aligned.
         unsafe {
             volwrite!(self.common_cfg, device_status, status.bits() as u8);
         }
+        // Assign the configuration vector once features have been negotiated, before any queues
+        // are set up.
+        if status.contains(DeviceStatus::FEATURES_OK) && !status.contains(DeviceStatus::DRIVER_OK) {
+            // Safe because the common config pointer is valid and we checked in get_bar_region that
+            // it was aligned.
+            let vector = unsafe {
+                volwrite!(self.common_cfg, msix_config, self.config_msix_vector);
+                volread!(self.common_cfg, msix_config)
+            };
+            if vector != self.config_msix_vector {
+                warn!(
+                    "Device rejected MSI-X vector {} for configuration changes",
+                    self.config_msix_vector
+                );
+            }
+        }
     }
 
     fn set_guest_page_size(&mut self, _guest_page_size: u32) {
@@ -293,6 +419,15 @@ impl Transport for PciTransport {
             volwrite!(self.common_cfg, queue_desc, descriptors as u64);
             volwrite!(self.common_cfg, queue_driver, driver_area as u64);
             volwrite!(self.common_cfg, queue_device, device_area as u64);
+            if let Some(&vector) = self.queue_msix_vectors.get(usize::from(queue)) {
+                volwrite!(self.common_cfg, queue_msix_vector, vector);
+                if volread!(self.common_cfg, queue_msix_vector) != vector {
+                    warn!(
+                        "Device rejected MSI-X vector {} for queue {}",
+                        vector, queue
+                    );
+                }
+            }
             volwrite!(self.common_cfg, queue_enable, 1);
         }
     }
@@ -311,13 +446,34 @@ impl Transport for PciTransport {
         }
     }
 
-    fn ack_interrupt(&mut self) -> bool {
+    fn queue_reset(&mut self, queue: u16) -> Result<(), Error> {
+        if !self.ring_reset || self.common_cfg_len < COMMON_CFG_SIZE {
+            // The device doesn't have the `queue_reset` field, so can't support VIRTIO_F_RING_RESET.
+            return Err(Error::Unsupported);
+        }
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
-        // was aligned.
-        // Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt.
-        let isr_status = unsafe { self.isr_status.as_ptr().vread() };
-        // TODO: Distinguish between queue interrupt and device configuration interrupt.
-        isr_status & 0x3 != 0
+        // was aligned, and checked above that it is long enough to include `queue_reset`.
+        unsafe {
+            volwrite!(self.common_cfg, queue_select, queue);
+            volwrite!(self.common_cfg, queue_reset, 1);
+            // Wait until the device reports that the reset has completed (see 4.1.4.3.2).
+            while volread!(self.common_cfg, queue_reset) != 0 {}
+            while volread!(self.common_cfg, queue_enable) != 0 {}
+        }
+        Ok(())
+    }
+
+    /// Acknowledges an interrupt.
+    ///
+    /// This reads and clears the ISR status, see [`ack_isr_status`](PciTransport::ack_isr_status)
+    /// to find out whether the interrupt was for a queue or a configuration change. MSI-X vectors
+    /// have no status to acknowledge, as each vector already says what it is for, so if any have
+    /// been assigned this can't tell whether an interrupt was actually pending and always returns
+    /// true. Callers must not use the return value to filter out interrupts in that case, e.g. to
+    /// decide whether a shared interrupt line was raised by this device; only the ISR status is
+    /// meaningful for INTx# interrupts.
+    fn ack_interrupt(&mut self) -> bool {
+        !self.ack_isr_status().is_empty() || self.msix_vectors_assigned()
     }
 
     fn config_space<T>(&self) -> Result<NonNull<T>, Error> {
@@ -339,6 +495,10 @@ impl Transport for PciTransport {
             Err(Error::ConfigSpaceMissing)
         }
     }
+
+    fn shared_memory_region<H: Hal>(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        map_shared_memory::<H>(&self.shared_memory, id)
+    }
 }
 
 // SAFETY: MMIO can be done from any thread or CPU core.
@@ -375,6 +535,112 @@ struct CommonCfg {
     queue_desc: Volatile<u64>,
     queue_driver: Volatile<u64>,
     queue_device: Volatile<u64>,
+    queue_notify_data: ReadOnly<u16>,
+    queue_reset: Volatile<u16>,
+}
+
+/// The size of `virtio_pci_common_cfg` before the `queue_notify_data` and `queue_reset` fields were
+/// added. Devices implementing older versions of the spec may provide a structure of this size.
+const COMMON_CFG_MIN_SIZE: usize = 0x38;
+
+/// The size of `virtio_pci_common_cfg` including `queue_reset`, without any trailing padding.
+const COMMON_CFG_SIZE: usize = 0x3c;
+
+/// The VirtIO vendor-specific capabilities of a PCI device function.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct VirtioCapabilities {
+    common_cfg: Option<VirtioCapabilityInfo>,
+    notify_cfg: Option<VirtioCapabilityInfo>,
+    notify_off_multiplier: u32,
+    isr_cfg: Option<VirtioCapabilityInfo>,
+    device_cfg: Option<VirtioCapabilityInfo>,
+    /// The offset of the `VIRTIO_PCI_CAP_PCI_CFG` capability within the PCI configuration space.
+    pci_cfg_offset: Option<u8>,
+    /// The `VIRTIO_PCI_CAP_SHARED_MEMORY_CFG` capabilities, one for each shared memory region.
+    shared_memory: [Option<VirtioSharedMemoryInfo>; MAX_SHARED_MEMORY_REGIONS],
+}
+
+impl VirtioCapabilities {
+    /// Finds the first valid capability of each type for the given device function.
+    fn find(root: &PciRoot<impl ConfigurationAccess>, device_function: DeviceFunction) -> Self {
+        let mut capabilities = Self::default();
+        for capability in root.capabilities(device_function) {
+            if capability.id != PCI_CAP_ID_VNDR {
+                continue;
+            }
+            let cap_len = capability.private_header as u8;
+            let cfg_type = (capability.private_header >> 8) as u8;
+            if cap_len < 16 {
+                continue;
+            }
+            let struct_info = VirtioCapabilityInfo {
+                bar: root.config_read_word(device_function, capability.offset + CAP_BAR_OFFSET)
+                    as u8,
+                offset: root
+                    .config_read_word(device_function, capability.offset + CAP_BAR_OFFSET_OFFSET),
+                length: root
+                    .config_read_word(device_function, capability.offset + CAP_LENGTH_OFFSET),
+            };
+
+            match cfg_type {
+                VIRTIO_PCI_CAP_COMMON_CFG if capabilities.common_cfg.is_none() => {
+                    capabilities.common_cfg = Some(struct_info);
+                }
+                VIRTIO_PCI_CAP_NOTIFY_CFG if cap_len >= 20 && capabilities.notify_cfg.is_none() => {
+                    capabilities.notify_cfg = Some(struct_info);
+                    capabilities.notify_off_multiplier = root.config_read_word(
+                        device_function,
+                        capability.offset + CAP_NOTIFY_OFF_MULTIPLIER_OFFSET,
+                    );
+                }
+                VIRTIO_PCI_CAP_ISR_CFG if capabilities.isr_cfg.is_none() => {
+                    capabilities.isr_cfg = Some(struct_info);
+                }
+                VIRTIO_PCI_CAP_DEVICE_CFG if capabilities.device_cfg.is_none() => {
+                    capabilities.device_cfg = Some(struct_info);
+                }
+                VIRTIO_PCI_CAP_PCI_CFG
+                    if cap_len >= 20 && capabilities.pci_cfg_offset.is_none() =>
+                {
+                    capabilities.pci_cfg_offset = Some(capability.offset);
+                }
+                VIRTIO_PCI_CAP_SHARED_MEMORY_CFG if cap_len >= 24 => {
+                    // The `id` field follows the `bar` field.
+                    let id = (root
+                        .config_read_word(device_function, capability.offset + CAP_BAR_OFFSET)
+                        >> 8) as u8;
+                    let offset_hi = root.config_read_word(
+                        device_function,
+                        capability.offset + CAP_OFFSET_HI_OFFSET,
+                    );
+                    let length_hi = root.config_read_word(
+                        device_function,
+                        capability.offset + CAP_LENGTH_HI_OFFSET,
+                    );
+                    let info = VirtioSharedMemoryInfo {
+                        id,
+                        bar: struct_info.bar,
+                        offset: u64::from(struct_info.offset) | u64::from(offset_hi) << 32,
+                        length: u64::from(struct_info.length) | u64::from(length_hi) << 32,
+                    };
+                    if let Some(slot) = capabilities
+                        .shared_memory
+                        .iter_mut()
+                        .find(|slot| !matches!(slot, Some(existing) if existing.id != id))
+                    {
+                        slot.get_or_insert(info);
+                    } else {
+                        warn!(
+                            "Ignoring shared memory region {} as there are too many.",
+                            id
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+        capabilities
+    }
 }
 
 /// Information about a VirtIO structure within some BAR, as provided by a `virtio_pci_cap`.
@@ -388,10 +654,97 @@ struct VirtioCapabilityInfo {
     length: u32,
 }
 
+/// Information about a shared memory region within some BAR, as provided by a `virtio_pci_cap64`
+/// with type `VIRTIO_PCI_CAP_SHARED_MEMORY_CFG`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct VirtioSharedMemoryInfo {
+    /// The ID of the region.
+    id: u8,
+    /// The bar in which the region can be found.
+    bar: u8,
+    /// The offset within the bar.
+    offset: u64,
+    /// The length in bytes of the region.
+    length: u64,
+}
+
+/// The physical location of a shared memory region of a device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct SharedMemoryLocation {
+    id: u8,
+    paddr: PhysAddr,
+    length: u64,
+}
+
+/// Finds the physical addresses of the given shared memory regions of a device function, ignoring
+/// any which aren't within an allocated memory BAR.
+fn locate_shared_memory(
+    root: &mut PciRoot<impl ConfigurationAccess>,
+    device_function: DeviceFunction,
+    shared_memory: &[Option<VirtioSharedMemoryInfo>; MAX_SHARED_MEMORY_REGIONS],
+) -> [Option<SharedMemoryLocation>; MAX_SHARED_MEMORY_REGIONS] {
+    let mut locations: [Option<SharedMemoryLocation>; MAX_SHARED_MEMORY_REGIONS] =
+        Default::default();
+    for (location, info) in locations.iter_mut().zip(shared_memory.iter().flatten()) {
+        let (bar_address, bar_size) = match root
+            .bar_info(device_function, info.bar)
+            .ok()
+            .and_then(|bar_info| bar_info.memory_address_size())
+        {
+            Some((bar_address, bar_size)) if bar_address != 0 => (bar_address, bar_size),
+            _ => {
+                warn!(
+                    "Ignoring shared memory region {} in unallocated or IO BAR {}.",
+                    info.id, info.bar
+                );
+                continue;
+            }
+        };
+        if !matches!(info.offset.checked_add(info.length), Some(end) if end <= u64::from(bar_size))
+        {
+            warn!(
+                "Ignoring shared memory region {} beyond the end of BAR {}.",
+                info.id, info.bar
+            );
+            continue;
+        }
+        *location = Some(SharedMemoryLocation {
+            id: info.id,
+            paddr: (bar_address + info.offset) as PhysAddr,
+            length: info.length,
+        });
+    }
+    locations
+}
+
+/// Maps the shared memory region with the given ID, if it is one of the given locations.
+fn map_shared_memory<H: Hal>(
+    locations: &[Option<SharedMemoryLocation>],
+    id: u8,
+) -> Option<SharedMemoryRegion> {
+    let location = locations
+        .iter()
+        .flatten()
+        .find(|location| location.id == id)?;
+    // Safe because the device reported the region, and we checked that it is within a BAR.
+    unsafe { SharedMemoryRegion::map::<H>(id, location.paddr, location.length) }
+}
+
 fn get_bar_region<H: Hal, T>(
-    root: &mut PciRoot,
+    root: &mut PciRoot<impl ConfigurationAccess>,
     device_function: DeviceFunction,
     struct_info: &VirtioCapabilityInfo,
+) -> Result<NonNull<T>, VirtioPciError> {
+    get_bar_region_with_min_size::<H, T>(root, device_function, struct_info, size_of::<T>())
+}
+
+/// Like `get_bar_region`, but only requires the region to be `min_size` bytes long rather than the
+/// full size of `T`, for structures which have grown in later versions of the spec.
+fn get_bar_region_with_min_size<H: Hal, T>(
+    root: &mut PciRoot<impl ConfigurationAccess>,
+    device_function: DeviceFunction,
+    struct_info: &VirtioCapabilityInfo,
+    min_size: usize,
 ) -> Result<NonNull<T>, VirtioPciError> {
     let bar_info = root.bar_info(device_function, struct_info.bar)?;
     let (bar_address, bar_size) = bar_info
@@ -400,8 +753,7 @@ fn get_bar_region<H: Hal, T>(
     if bar_address == 0 {
         return Err(VirtioPciError::BarNotAllocated(struct_info.bar));
     }
-    if struct_info.offset + struct_info.length > bar_size
-        || size_of::<T>() > struct_info.length as usize
+    if struct_info.offset + struct_info.length > bar_size || min_size > struct_info.length as usize
     {
         return Err(VirtioPciError::BarOffsetOutOfRange);
     }
@@ -419,7 +771,7 @@ fn get_bar_region<H: Hal, T>(
 }
 
 fn get_bar_region_slice<H: Hal, T>(
-    root: &mut PciRoot,
+    root: &mut PciRoot<impl ConfigurationAccess>,
     device_function: DeviceFunction,
     struct_info: &VirtioCapabilityInfo,
 ) -> Result<NonNull<[T]>, VirtioPciError> {
@@ -444,8 +796,15 @@ pub enum VirtioPciError {
     InvalidNotifyOffMultiplier(u32),
     /// No valid `VIRTIO_PCI_CAP_ISR_CFG` capability was found.
     MissingIsrConfig,
+    /// No valid `VIRTIO_PCI_CAP_PCI_CFG` capability was found.
+    MissingPciConfig,
     /// An IO BAR was provided rather than a memory BAR.
     UnexpectedIoBar,
+    /// A memory BAR was provided rather than an IO BAR, for the legacy interface.
+    UnexpectedMemoryBar,
+    /// The PCI device ID is not that of a transitional device, so the device has no legacy
+    /// interface.
+    NotTransitional(u16),
     /// A BAR which we need was not allocated an address.
     BarNotAllocated(u8),
     /// The offset for some capability was greater than the length of the BAR.
@@ -487,7 +846,16 @@ impl Display for VirtioPciError {
             Self::MissingIsrConfig => {
                 write!(f, "No valid `VIRTIO_PCI_CAP_ISR_CFG` capability was found.")
             }
+            Self::MissingPciConfig => {
+                write!(f, "No valid `VIRTIO_PCI_CAP_PCI_CFG` capability was found.")
+            }
             Self::UnexpectedIoBar => write!(f, "Unexpected IO BAR (expected memory BAR)."),
+            Self::UnexpectedMemoryBar => write!(f, "Unexpected memory BAR (expected IO BAR)."),
+            Self::NotTransitional(device_id) => write!(
+                f,
+                "PCI device ID {:#06x} is not a transitional device with a legacy interface.",
+                device_id
+            ),
             Self::BarNotAllocated(bar_index) => write!(f, "Bar {} not allocated.", bar_index),
             Self::BarOffsetOutOfRange => write!(f, "Capability offset greater than BAR length."),
             Self::Misaligned { vaddr, alignment } => write!(
@@ -528,8 +896,9 @@ mod tests {
         assert_eq!(device_type(0x1045), DeviceType::MemoryBalloon);
         assert_eq!(device_type(0x1049), DeviceType::_9P);
         assert_eq!(device_type(0x1058), DeviceType::Memory);
+        assert_eq!(device_type(0x1059), DeviceType::Sound);
         assert_eq!(device_type(0x1040), DeviceType::Invalid);
-        assert_eq!(device_type(0x1059), DeviceType::Invalid);
+        assert_eq!(device_type(0x105a), DeviceType::Invalid);
     }
 
     #[test]
@@ -1,9 +1,12 @@
 //! Module for dealing with a PCI bus in general, without anything specific to VirtIO.
 
+use crate::{hal::PortIoHal, sync::SpinLock};
 use bitflags::bitflags;
 use core::{
     convert::TryFrom,
     fmt::{self, Display, Formatter},
+    marker::PhantomData,
+    ops::Range,
 };
 use log::warn;
 
@@ -16,11 +19,66 @@ const MAX_FUNCTIONS: u8 = 8;
 
 /// The offset in bytes to the status and command fields within PCI configuration space.
 const STATUS_COMMAND_OFFSET: u8 = 0x04;
+/// The offset in bytes to the BIST, header type, latency timer and cache line size fields within
+/// PCI configuration space.
+const BIST_TYPE_LATENCY_CACHE_OFFSET: u8 = 0x0c;
 /// The offset in bytes to BAR0 within PCI configuration space.
 const BAR0_OFFSET: u8 = 0x10;
+/// The number of BARs of a standard device function.
+const STANDARD_BAR_COUNT: u8 = 6;
+/// The number of BARs of a PCI-to-PCI bridge.
+const BRIDGE_BAR_COUNT: u8 = 2;
+
+/// The offset in bytes to the primary, secondary and subordinate bus number fields within the
+/// configuration space of a PCI-to-PCI bridge.
+const BRIDGE_BUS_NUMBERS_OFFSET: u8 = 0x18;
+/// The offset in bytes to the I/O base and limit fields within the configuration space of a
+/// PCI-to-PCI bridge.
+const BRIDGE_IO_OFFSET: u8 = 0x1c;
+/// The offset in bytes to the memory base and limit fields within the configuration space of a
+/// PCI-to-PCI bridge.
+const BRIDGE_MEMORY_OFFSET: u8 = 0x20;
+/// The offset in bytes to the prefetchable memory base and limit fields within the configuration
+/// space of a PCI-to-PCI bridge.
+const BRIDGE_PREFETCHABLE_OFFSET: u8 = 0x24;
+/// The offset in bytes to the upper 32 bits of the prefetchable memory base within the
+/// configuration space of a PCI-to-PCI bridge.
+const BRIDGE_PREFETCHABLE_BASE_UPPER_OFFSET: u8 = 0x28;
+/// The offset in bytes to the upper 32 bits of the prefetchable memory limit within the
+/// configuration space of a PCI-to-PCI bridge.
+const BRIDGE_PREFETCHABLE_LIMIT_UPPER_OFFSET: u8 = 0x2c;
+/// The offset in bytes to the upper 16 bits of the I/O base and limit within the configuration space
+/// of a PCI-to-PCI bridge.
+const BRIDGE_IO_UPPER_OFFSET: u8 = 0x30;
+/// The granularity of a PCI-to-PCI bridge's memory windows.
+const BRIDGE_MEMORY_ALIGNMENT: u64 = 0x10_0000;
+/// The granularity of a PCI-to-PCI bridge's I/O window.
+const BRIDGE_IO_ALIGNMENT: u64 = 0x1000;
 
 /// ID for vendor-specific PCI capabilities.
 pub const PCI_CAP_ID_VNDR: u8 = 0x09;
+/// ID for the MSI-X capability.
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+/// The I/O port to which `PortCam` writes the address of the register to access.
+const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+/// The I/O port through which `PortCam` accesses the register selected by the address port.
+const CONFIG_DATA_PORT: u16 = 0xcfc;
+/// The bit of the configuration address which enables access through the data port.
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+
+/// Serialises accesses through `PortCam`, each of which is a write to the address port followed by
+/// an access to the data port, so that they aren't interleaved between CPUs.
+static PORT_CAM_LOCK: SpinLock<()> = SpinLock::new(());
+
+/// The bit of the MSI-X capability's message control register which masks all vectors.
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+/// The bit of the MSI-X capability's message control register which enables MSI-X.
+const MSIX_ENABLE: u16 = 1 << 15;
+/// The offset of the table offset/BIR register within the MSI-X capability.
+const MSIX_TABLE_OFFSET: u8 = 4;
+/// The offset of the PBA offset/BIR register within the MSI-X capability.
+const MSIX_PBA_OFFSET: u8 = 8;
 
 bitflags! {
     /// The status register in PCI configuration space.
@@ -85,21 +143,23 @@ bitflags! {
 pub enum PciError {
     /// The device reported an invalid BAR type.
     InvalidBarType,
+    /// There was no room left in the allocator's windows for a BAR.
+    AllocationFailed,
 }
 
 impl Display for PciError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
             Self::InvalidBarType => write!(f, "Invalid PCI BAR type."),
+            Self::AllocationFailed => write!(f, "No room left to allocate PCI BAR."),
         }
     }
 }
 
 /// The root complex of a PCI bus.
 #[derive(Debug)]
-pub struct PciRoot {
-    mmio_base: *mut u32,
-    cam: Cam,
+pub struct PciRoot<C: ConfigurationAccess = MmioCam> {
+    configuration_access: C,
 }
 
 /// A PCI Configuration Access Mechanism.
@@ -125,8 +185,35 @@ impl Cam {
     }
 }
 
-impl PciRoot {
-    /// Wraps the PCI root complex with the given MMIO base address.
+/// A method to access the configuration space of PCI device functions.
+pub trait ConfigurationAccess {
+    /// Reads 4 bytes from the configuration space of the given device function, at the given
+    /// register offset which must be a multiple of 4.
+    fn read_word(&self, device_function: DeviceFunction, register_offset: u8) -> u32;
+
+    /// Writes 4 bytes to the configuration space of the given device function, at the given
+    /// register offset which must be a multiple of 4.
+    fn write_word(&mut self, device_function: DeviceFunction, register_offset: u8, data: u32);
+
+    /// Makes a clone of the `ConfigurationAccess`, accessing the same configuration space.
+    ///
+    /// # Safety
+    ///
+    /// This function allows concurrent mutable access to the configuration space. To avoid this
+    /// causing problems, the returned instance must only be used to read read-only fields.
+    unsafe fn unsafe_clone(&self) -> Self;
+}
+
+/// The PCI memory-mapped Configuration Access Mechanism or PCIe Enhanced Configuration Access
+/// Mechanism, depending on the [`Cam`].
+#[derive(Debug)]
+pub struct MmioCam {
+    mmio_base: *mut u32,
+    cam: Cam,
+}
+
+impl MmioCam {
+    /// Wraps the memory-mapped configuration space with the given MMIO base address.
     ///
     /// Panics if the base address is not aligned to a 4-byte boundary.
     ///
@@ -144,19 +231,6 @@ impl PciRoot {
         }
     }
 
-    /// Makes a clone of the `PciRoot`, pointing at the same MMIO region.
-    ///
-    /// # Safety
-    ///
-    /// This function allows concurrent mutable access to the PCI CAM. To avoid this causing
-    /// problems, the returned `PciRoot` instance must only be used to read read-only fields.
-    unsafe fn unsafe_clone(&self) -> Self {
-        Self {
-            mmio_base: self.mmio_base,
-            cam: self.cam,
-        }
-    }
-
     fn cam_offset(&self, device_function: DeviceFunction, register_offset: u8) -> u32 {
         assert!(device_function.valid());
 
@@ -174,13 +248,10 @@ impl PciRoot {
         assert!(address & 0x3 == 0);
         address
     }
+}
 
-    /// Reads 4 bytes from configuration space using the appropriate CAM.
-    pub(crate) fn config_read_word(
-        &self,
-        device_function: DeviceFunction,
-        register_offset: u8,
-    ) -> u32 {
+impl ConfigurationAccess for MmioCam {
+    fn read_word(&self, device_function: DeviceFunction, register_offset: u8) -> u32 {
         let address = self.cam_offset(device_function, register_offset);
         // Safe because both the `mmio_base` and the address offset are properly aligned, and the
         // resulting pointer is within the MMIO range of the CAM.
@@ -190,13 +261,7 @@ impl PciRoot {
         }
     }
 
-    /// Writes 4 bytes to configuration space using the appropriate CAM.
-    pub(crate) fn config_write_word(
-        &mut self,
-        device_function: DeviceFunction,
-        register_offset: u8,
-        data: u32,
-    ) {
+    fn write_word(&mut self, device_function: DeviceFunction, register_offset: u8, data: u32) {
         let address = self.cam_offset(device_function, register_offset);
         // Safe because both the `mmio_base` and the address offset are properly aligned, and the
         // resulting pointer is within the MMIO range of the CAM.
@@ -206,8 +271,143 @@ impl PciRoot {
         }
     }
 
+    unsafe fn unsafe_clone(&self) -> Self {
+        Self {
+            mmio_base: self.mmio_base,
+            cam: self.cam,
+        }
+    }
+}
+
+// SAFETY: `mmio_base` is only used for MMIO, which can happen from any thread or CPU core.
+unsafe impl Send for MmioCam {}
+
+// SAFETY: `&MmioCam` only allows MMIO reads, which are fine to happen concurrently on different CPU
+// cores.
+unsafe impl Sync for MmioCam {}
+
+/// The PCI configuration access mechanism using the configuration address and data I/O ports
+/// `0xcf8` and `0xcfc`, as found on x86.
+///
+/// This provides access to 256 bytes of configuration space per device function, and works before
+/// the location of the memory-mapped ECAM region is known, e.g. from the ACPI MCFG table. The ports
+/// are accessed with [`PortIoHal::port_read_u32`] and [`PortIoHal::port_write_u32`].
+#[derive(Debug)]
+pub struct PortCam<H: PortIoHal> {
+    _hal: PhantomData<H>,
+}
+
+impl<H: PortIoHal> PortCam<H> {
+    /// Creates a new port I/O configuration access mechanism.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may access the configuration address and data ports except through a
+    /// `PortCam`, as the address written to one port determines what the other accesses.
+    pub unsafe fn new() -> Self {
+        Self { _hal: PhantomData }
+    }
+
+    /// Selects the given register of the given device function with the configuration address
+    /// port.
+    ///
+    /// The caller must hold `PORT_CAM_LOCK` until it has accessed the data port.
+    fn select(device_function: DeviceFunction, register_offset: u8) {
+        assert!(device_function.valid());
+        assert!(register_offset & 0x3 == 0);
+
+        let address = CONFIG_ADDRESS_ENABLE
+            | u32::from(device_function.bus) << 16
+            | u32::from(device_function.device) << 11
+            | u32::from(device_function.function) << 8
+            | u32::from(register_offset);
+        // Safe because `new` requires that nothing else uses the configuration ports.
+        unsafe { H::port_write_u32(CONFIG_ADDRESS_PORT, address) }
+    }
+}
+
+impl<H: PortIoHal> ConfigurationAccess for PortCam<H> {
+    fn read_word(&self, device_function: DeviceFunction, register_offset: u8) -> u32 {
+        let _guard = PORT_CAM_LOCK.lock();
+        Self::select(device_function, register_offset);
+        // Safe because `new` requires that nothing else uses the configuration ports, and we just
+        // selected the register to read.
+        unsafe { H::port_read_u32(CONFIG_DATA_PORT) }
+    }
+
+    fn write_word(&mut self, device_function: DeviceFunction, register_offset: u8, data: u32) {
+        let _guard = PORT_CAM_LOCK.lock();
+        Self::select(device_function, register_offset);
+        // Safe because `new` requires that nothing else uses the configuration ports, and we just
+        // selected the register to write.
+        unsafe { H::port_write_u32(CONFIG_DATA_PORT, data) }
+    }
+
+    unsafe fn unsafe_clone(&self) -> Self {
+        Self { _hal: PhantomData }
+    }
+}
+
+impl PciRoot {
+    /// Wraps the PCI root complex with the given MMIO base address.
+    ///
+    /// Panics if the base address is not aligned to a 4-byte boundary.
+    ///
+    /// # Safety
+    ///
+    /// `mmio_base` must be a valid pointer to an appropriately-mapped MMIO region of at least
+    /// 16 MiB (if `cam == Cam::MmioCam`) or 256 MiB (if `cam == Cam::Ecam`). The pointer must be
+    /// valid for the entire lifetime of the program (i.e. `'static`), which implies that no Rust
+    /// references may be used to access any of the memory region at any point.
+    pub unsafe fn new(mmio_base: *mut u8, cam: Cam) -> Self {
+        Self::with_configuration_access(MmioCam::new(mmio_base, cam))
+    }
+}
+
+impl<C: ConfigurationAccess> PciRoot<C> {
+    /// Wraps the PCI root complex whose configuration space is accessed with the given mechanism.
+    pub fn with_configuration_access(configuration_access: C) -> Self {
+        Self {
+            configuration_access,
+        }
+    }
+
+    /// Makes a clone of the `PciRoot`, accessing the same configuration space.
+    ///
+    /// # Safety
+    ///
+    /// This function allows concurrent mutable access to the configuration space. To avoid this
+    /// causing problems, the returned `PciRoot` instance must only be used to read read-only
+    /// fields.
+    unsafe fn unsafe_clone(&self) -> Self {
+        Self {
+            configuration_access: self.configuration_access.unsafe_clone(),
+        }
+    }
+
+    /// Reads 4 bytes from configuration space using the appropriate CAM.
+    pub(crate) fn config_read_word(
+        &self,
+        device_function: DeviceFunction,
+        register_offset: u8,
+    ) -> u32 {
+        self.configuration_access
+            .read_word(device_function, register_offset)
+    }
+
+    /// Writes 4 bytes to configuration space using the appropriate CAM.
+    pub(crate) fn config_write_word(
+        &mut self,
+        device_function: DeviceFunction,
+        register_offset: u8,
+        data: u32,
+    ) {
+        self.configuration_access
+            .write_word(device_function, register_offset, data)
+    }
+
     /// Enumerates PCI devices on the given bus.
-    pub fn enumerate_bus(&self, bus: u8) -> BusDeviceIterator {
+    pub fn enumerate_bus(&self, bus: u8) -> BusDeviceIterator<C> {
         // Safe because the BusDeviceIterator only reads read-only fields.
         let root = unsafe { self.unsafe_clone() };
         BusDeviceIterator {
@@ -238,7 +438,7 @@ impl PciRoot {
     }
 
     /// Gets an iterator over the capabilities of the given device function.
-    pub fn capabilities(&self, device_function: DeviceFunction) -> CapabilityIterator {
+    pub fn capabilities(&self, device_function: DeviceFunction) -> CapabilityIterator<'_, C> {
         CapabilityIterator {
             root: self,
             device_function,
@@ -305,6 +505,301 @@ impl PciRoot {
         );
     }
 
+    /// Scans the whole topology below the root complex, recursing through PCI-to-PCI bridges and
+    /// assigning bus numbers to them in depth-first order.
+    ///
+    /// Bus 0 is the root bus, and the buses behind bridges are numbered consecutively from 1, so
+    /// after this every device function can be found by calling
+    /// [`enumerate_bus`](Self::enumerate_bus) for each bus from 0 up to the returned highest bus
+    /// number.
+    pub fn assign_bus_numbers(&mut self) -> u8 {
+        let mut last_bus = 0;
+        self.assign_bus_numbers_below(0, &mut last_bus);
+        last_bus
+    }
+
+    fn assign_bus_numbers_below(&mut self, bus: u8, last_bus: &mut u8) {
+        for (device_function, info) in self.enumerate_bus(bus) {
+            if info.header_type != HeaderType::PciPciBridge {
+                continue;
+            }
+            if *last_bus == u8::MAX {
+                warn!("No bus number left for bridge {}", device_function);
+                continue;
+            }
+            *last_bus += 1;
+            let secondary = *last_bus;
+            // Forward configuration accesses for every later bus number until we know how many
+            // buses there are behind the bridge.
+            self.set_bridge_bus_numbers(device_function, bus, secondary, u8::MAX);
+            self.assign_bus_numbers_below(secondary, last_bus);
+            self.set_bridge_bus_numbers(device_function, bus, secondary, *last_bus);
+        }
+    }
+
+    /// Gets the primary, secondary and subordinate bus numbers of the given PCI-to-PCI bridge.
+    pub fn bridge_bus_numbers(&self, device_function: DeviceFunction) -> (u8, u8, u8) {
+        let bus_numbers = self.config_read_word(device_function, BRIDGE_BUS_NUMBERS_OFFSET);
+        (
+            bus_numbers as u8,
+            (bus_numbers >> 8) as u8,
+            (bus_numbers >> 16) as u8,
+        )
+    }
+
+    /// Sets the primary, secondary and subordinate bus numbers of the given PCI-to-PCI bridge,
+    /// preserving its secondary latency timer.
+    fn set_bridge_bus_numbers(
+        &mut self,
+        device_function: DeviceFunction,
+        primary: u8,
+        secondary: u8,
+        subordinate: u8,
+    ) {
+        let bus_numbers = self.config_read_word(device_function, BRIDGE_BUS_NUMBERS_OFFSET);
+        self.config_write_word(
+            device_function,
+            BRIDGE_BUS_NUMBERS_OFFSET,
+            bus_numbers & 0xff00_0000
+                | u32::from(subordinate) << 16
+                | u32::from(secondary) << 8
+                | u32::from(primary),
+        );
+    }
+
+    /// Allocates addresses for the unprogrammed BARs of every device function below the root
+    /// complex, programs the windows of PCI-to-PCI bridges to cover the BARs behind them, and
+    /// enables decoding.
+    ///
+    /// Bus numbers must already have been assigned, e.g. with
+    /// [`assign_bus_numbers`](Self::assign_bus_numbers).
+    pub fn allocate_resources(&mut self, allocator: &mut BarAllocator) -> Result<(), PciError> {
+        self.allocate_resources_below(0, allocator)
+    }
+
+    fn allocate_resources_below(
+        &mut self,
+        bus: u8,
+        allocator: &mut BarAllocator,
+    ) -> Result<(), PciError> {
+        for (device_function, info) in self.enumerate_bus(bus) {
+            match info.header_type {
+                HeaderType::Standard => self.allocate_bars(device_function, allocator)?,
+                HeaderType::PciPciBridge => self.allocate_bridge(device_function, allocator)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocates the BARs of the given PCI-to-PCI bridge and of everything behind it, and sets its
+    /// windows to cover the latter.
+    fn allocate_bridge(
+        &mut self,
+        device_function: DeviceFunction,
+        allocator: &mut BarAllocator,
+    ) -> Result<(), PciError> {
+        self.allocate_bars(device_function, allocator)?;
+        let (_, secondary, _) = self.bridge_bus_numbers(device_function);
+        if secondary == 0 {
+            warn!("Bridge {} has no secondary bus number", device_function);
+            return Ok(());
+        }
+
+        // The windows can only start and end at multiples of their granularity.
+        allocator.align(BRIDGE_MEMORY_ALIGNMENT, BRIDGE_IO_ALIGNMENT);
+        let start = allocator.clone();
+        self.allocate_resources_below(secondary, allocator)?;
+        allocator.align(BRIDGE_MEMORY_ALIGNMENT, BRIDGE_IO_ALIGNMENT);
+
+        // Empty windows are disabled by setting their base above their limit.
+        let (memory_base, memory_limit) = bridge_window(&start.memory_32, &allocator.memory_32);
+        self.config_write_word(
+            device_function,
+            BRIDGE_MEMORY_OFFSET,
+            ((memory_base >> 16) as u32 & 0xfff0) | ((memory_limit >> 16) as u32 & 0xfff0) << 16,
+        );
+
+        let (prefetchable_base, prefetchable_limit) =
+            bridge_window(&start.memory_64, &allocator.memory_64);
+        let prefetchable_64 =
+            self.config_read_word(device_function, BRIDGE_PREFETCHABLE_OFFSET) & 0xf == 0x1;
+        if !prefetchable_64 && prefetchable_limit > u64::from(u32::MAX) {
+            warn!(
+                "Bridge {} has a 32-bit prefetchable window, so can't forward {:#x}-{:#x}",
+                device_function, prefetchable_base, prefetchable_limit
+            );
+        }
+        self.config_write_word(
+            device_function,
+            BRIDGE_PREFETCHABLE_OFFSET,
+            ((prefetchable_base >> 16) as u32 & 0xfff0)
+                | ((prefetchable_limit >> 16) as u32 & 0xfff0) << 16,
+        );
+        if prefetchable_64 {
+            self.config_write_word(
+                device_function,
+                BRIDGE_PREFETCHABLE_BASE_UPPER_OFFSET,
+                (prefetchable_base >> 32) as u32,
+            );
+            self.config_write_word(
+                device_function,
+                BRIDGE_PREFETCHABLE_LIMIT_UPPER_OFFSET,
+                (prefetchable_limit >> 32) as u32,
+            );
+        }
+
+        let (io_base, io_limit) = bridge_window(&start.io, &allocator.io);
+        // The upper half of this word is the secondary status register, whose bits are cleared by
+        // writing 1, so write 0 to leave them alone.
+        self.config_write_word(
+            device_function,
+            BRIDGE_IO_OFFSET,
+            ((io_base >> 8) as u32 & 0xf0) | ((io_limit >> 8) as u32 & 0xf0) << 8,
+        );
+        self.config_write_word(
+            device_function,
+            BRIDGE_IO_UPPER_OFFSET,
+            ((io_base >> 16) as u32 & 0xffff) | ((io_limit >> 16) as u32 & 0xffff) << 16,
+        );
+
+        let (_, command) = self.get_status_command(device_function);
+        self.set_command(
+            device_function,
+            command | Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER,
+        );
+        Ok(())
+    }
+
+    /// Allocates addresses for the unprogrammed BARs of the given device function, and enables it
+    /// to decode its BARs and to act as a bus master.
+    ///
+    /// BARs which already have an address are left alone. 64-bit prefetchable memory BARs are
+    /// allocated from the allocator's 64-bit window if it has room, and other memory BARs from its
+    /// 32-bit window. If the allocator has no I/O window then I/O BARs are left unprogrammed and
+    /// I/O space decoding isn't enabled, as many devices, including modern VirtIO devices, work
+    /// without them.
+    pub fn allocate_bars(
+        &mut self,
+        device_function: DeviceFunction,
+        allocator: &mut BarAllocator,
+    ) -> Result<(), PciError> {
+        let header_type = HeaderType::from(
+            (self.config_read_word(device_function, BIST_TYPE_LATENCY_CACHE_OFFSET) >> 16) as u8
+                & 0x7f,
+        );
+        let bar_count = match header_type {
+            HeaderType::Standard => STANDARD_BAR_COUNT,
+            HeaderType::PciPciBridge => BRIDGE_BAR_COUNT,
+            _ => 0,
+        };
+
+        let (_, mut command) = self.get_status_command(device_function);
+        let mut bar_index = 0;
+        while bar_index < bar_count {
+            let info = self.bar_info(device_function, bar_index)?;
+            match info {
+                BarInfo::IO { address, size } if size > 0 && (address != 0 || allocator.has_io) => {
+                    if address == 0 {
+                        let address = allocator
+                            .allocate_io(size)
+                            .ok_or(PciError::AllocationFailed)?;
+                        self.set_bar_32(device_function, bar_index, address);
+                    }
+                    command |= Command::IO_SPACE;
+                }
+                BarInfo::Memory {
+                    address_type,
+                    prefetchable,
+                    address,
+                    size,
+                } if size > 0 => {
+                    if address == 0 {
+                        match address_type {
+                            MemoryBarType::Width32 => {
+                                let address = allocator
+                                    .allocate_memory_32(size)
+                                    .ok_or(PciError::AllocationFailed)?;
+                                self.set_bar_32(device_function, bar_index, address);
+                            }
+                            MemoryBarType::Width64 => {
+                                let address = if prefetchable {
+                                    allocator.allocate_memory_64(size.into())
+                                } else {
+                                    None
+                                }
+                                .or_else(|| allocator.allocate_memory_32(size).map(Into::into))
+                                .ok_or(PciError::AllocationFailed)?;
+                                self.set_bar_64(device_function, bar_index, address);
+                            }
+                            MemoryBarType::Below1MiB => return Err(PciError::InvalidBarType),
+                        }
+                    }
+                    command |= Command::MEMORY_SPACE;
+                }
+                _ => {}
+            }
+
+            bar_index += 1;
+            if info.takes_two_entries() {
+                bar_index += 1;
+            }
+        }
+
+        self.set_command(device_function, command | Command::BUS_MASTER);
+        Ok(())
+    }
+
+    /// Gets information about the MSI-X capability of the given device function, if it has one.
+    pub fn msix_info(&self, device_function: DeviceFunction) -> Option<MsixInfo> {
+        let capability = self
+            .capabilities(device_function)
+            .find(|capability| capability.id == PCI_CAP_ID_MSIX)?;
+        let table = self.config_read_word(device_function, capability.offset + MSIX_TABLE_OFFSET);
+        let pba = self.config_read_word(device_function, capability.offset + MSIX_PBA_OFFSET);
+        Some(MsixInfo {
+            offset: capability.offset,
+            table_size: (capability.private_header & 0x07ff) + 1,
+            table_bar: (table & 0x7) as u8,
+            table_offset: table & !0x7,
+            pba_bar: (pba & 0x7) as u8,
+            pba_offset: pba & !0x7,
+        })
+    }
+
+    /// Returns whether MSI-X is enabled for the given device function.
+    pub fn msix_enabled(&self, device_function: DeviceFunction) -> bool {
+        self.capabilities(device_function).any(|capability| {
+            capability.id == PCI_CAP_ID_MSIX && capability.private_header & MSIX_ENABLE != 0
+        })
+    }
+
+    /// Enables or disables MSI-X for the given device function, which must have the given MSI-X
+    /// capability.
+    ///
+    /// When MSI-X is enabled the device signals interrupts by writing to the addresses in its MSI-X
+    /// table rather than through INTx#, so the table should be set up first. Enabling MSI-X also
+    /// clears the function mask, so that individual vectors are only masked by their own entries.
+    pub fn set_msix_enabled(
+        &mut self,
+        device_function: DeviceFunction,
+        msix: &MsixInfo,
+        enabled: bool,
+    ) {
+        let header = self.config_read_word(device_function, msix.offset);
+        let mut message_control = (header >> 16) as u16 & !MSIX_FUNCTION_MASK;
+        if enabled {
+            message_control |= MSIX_ENABLE;
+        } else {
+            message_control &= !MSIX_ENABLE;
+        }
+        self.config_write_word(
+            device_function,
+            msix.offset,
+            (header & 0xffff) | u32::from(message_control) << 16,
+        );
+    }
+
     /// Gets the capabilities 'pointer' for the device function, if any.
     fn capabilities_offset(&self, device_function: DeviceFunction) -> Option<u8> {
         let (status, _) = self.get_status_command(device_function);
@@ -316,12 +811,96 @@ impl PciRoot {
     }
 }
 
-// SAFETY: `mmio_base` is only used for MMIO, which can happen from any thread or CPU core.
-unsafe impl Send for PciRoot {}
+/// Returns the base and limit to program into a PCI-to-PCI bridge window, for the addresses
+/// allocated between the `start` and `end` states of an allocator window.
+///
+/// If nothing was allocated then the base is above the limit, which disables the window.
+fn bridge_window(start: &Range<u64>, end: &Range<u64>) -> (u64, u64) {
+    if end.start > start.start {
+        (start.start, end.start - 1)
+    } else {
+        (u64::MAX, 0)
+    }
+}
 
-// SAFETY: `&PciRoot` only allows MMIO reads, which are fine to happen concurrently on different CPU
-// cores.
-unsafe impl Sync for PciRoot {}
+/// Allocates addresses for PCI BARs from windows of PCI bus address space.
+///
+/// Each window is allocated in order from its start, aligning each BAR to its size.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BarAllocator {
+    /// The unallocated part of the window for 32-bit memory BARs.
+    memory_32: Range<u64>,
+    /// The unallocated part of the window for 64-bit prefetchable memory BARs.
+    memory_64: Range<u64>,
+    /// The unallocated part of the window for I/O BARs.
+    io: Range<u64>,
+    /// Whether the allocator was given a window for I/O BARs at all.
+    has_io: bool,
+}
+
+impl BarAllocator {
+    /// Creates a new allocator for the given windows of 32-bit memory, 64-bit prefetchable memory
+    /// and I/O space.
+    ///
+    /// Any of them may be empty, in which case BARs needing them can't be allocated, except that
+    /// 64-bit memory BARs are also allocated from the 32-bit memory window. Address 0 is never
+    /// allocated, as it means that a BAR is unprogrammed.
+    pub fn new(memory_32: Range<u32>, memory_64: Range<u64>, io: Range<u32>) -> Self {
+        Self {
+            memory_32: memory_32.start.into()..memory_32.end.into(),
+            memory_64,
+            has_io: !io.is_empty(),
+            io: io.start.into()..io.end.into(),
+        }
+    }
+
+    /// Allocates a 32-bit memory region of the given power-of-two size, aligned to its size.
+    pub fn allocate_memory_32(&mut self, size: u32) -> Option<u32> {
+        allocate(&mut self.memory_32, size.into()).map(|address| address as u32)
+    }
+
+    /// Allocates a 64-bit memory region of the given power-of-two size, aligned to its size.
+    pub fn allocate_memory_64(&mut self, size: u64) -> Option<u64> {
+        allocate(&mut self.memory_64, size)
+    }
+
+    /// Allocates an I/O region of the given power-of-two size, aligned to its size.
+    pub fn allocate_io(&mut self, size: u32) -> Option<u32> {
+        allocate(&mut self.io, size.into()).map(|address| address as u32)
+    }
+
+    /// Skips ahead in each window so that the next allocation starts at a multiple of the given
+    /// alignment.
+    fn align(&mut self, memory_alignment: u64, io_alignment: u64) {
+        for (window, alignment) in [
+            (&mut self.memory_32, memory_alignment),
+            (&mut self.memory_64, memory_alignment),
+            (&mut self.io, io_alignment),
+        ] {
+            window.start = align_up(window.start, alignment).min(window.end);
+        }
+    }
+}
+
+/// Allocates a region of the given power-of-two size from the start of the given window, aligned
+/// to its size.
+fn allocate(window: &mut Range<u64>, size: u64) -> Option<u64> {
+    if !size.is_power_of_two() {
+        return None;
+    }
+    let address = align_up(window.start.max(1), size);
+    if address.checked_add(size)? > window.end {
+        return None;
+    }
+    window.start = address + size;
+    Some(address)
+}
+
+/// Rounds the given address up to a multiple of the given power-of-two alignment, saturating on
+/// overflow.
+fn align_up(address: u64, alignment: u64) -> u64 {
+    address.saturating_add(alignment - 1) & !(alignment - 1)
+}
 
 /// Information about a PCI Base Address Register.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -427,13 +1006,13 @@ impl TryFrom<u8> for MemoryBarType {
 
 /// Iterator over capabilities for a device.
 #[derive(Debug)]
-pub struct CapabilityIterator<'a> {
-    root: &'a PciRoot,
+pub struct CapabilityIterator<'a, C: ConfigurationAccess = MmioCam> {
+    root: &'a PciRoot<C>,
     device_function: DeviceFunction,
     next_capability_offset: Option<u8>,
 }
 
-impl<'a> Iterator for CapabilityIterator<'a> {
+impl<'a, C: ConfigurationAccess> Iterator for CapabilityIterator<'a, C> {
     type Item = CapabilityInfo;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -473,16 +1052,34 @@ pub struct CapabilityInfo {
     pub private_header: u16,
 }
 
+/// Information about the MSI-X capability of a PCI device function, and where to find its MSI-X
+/// table and pending bit array.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MsixInfo {
+    /// The offset of the capability in the PCI configuration space of the device function.
+    pub offset: u8,
+    /// The number of entries in the MSI-X table, between 1 and 2048.
+    pub table_size: u16,
+    /// The BAR in which the MSI-X table can be found.
+    pub table_bar: u8,
+    /// The offset of the MSI-X table within its BAR.
+    pub table_offset: u32,
+    /// The BAR in which the pending bit array can be found.
+    pub pba_bar: u8,
+    /// The offset of the pending bit array within its BAR.
+    pub pba_offset: u32,
+}
+
 /// An iterator which enumerates PCI devices and functions on a given bus.
 #[derive(Debug)]
-pub struct BusDeviceIterator {
+pub struct BusDeviceIterator<C: ConfigurationAccess = MmioCam> {
     /// This must only be used to read read-only fields, and must not be exposed outside this
     /// module, because it uses the same CAM as the main `PciRoot` instance.
-    root: PciRoot,
+    root: PciRoot<C>,
     next: DeviceFunction,
 }
 
-impl Iterator for BusDeviceIterator {
+impl<C: ConfigurationAccess> Iterator for BusDeviceIterator<C> {
     type Item = (DeviceFunction, DeviceFunctionInfo);
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -608,3 +1205,376 @@ impl From<u8> for HeaderType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hal::fake::FakeHal, BufferDirection, Hal, PhysAddr};
+    use core::{cell::RefCell, ptr::NonNull};
+    use std::{rc::Rc, sync::Mutex};
+
+    /// The value of the configuration address port, and the configuration space of device function
+    /// 00:01.0, for `PortHal`.
+    static PORT_CONFIG: Mutex<(u32, [u32; 64])> = Mutex::new((0, [0; 64]));
+
+    /// A HAL which emulates the configuration address and data ports with `PORT_CONFIG`.
+    struct PortHal;
+
+    unsafe impl Hal for PortHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            FakeHal::dma_alloc(pages, direction)
+        }
+
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            unsafe { FakeHal::share(buffer, direction) }
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            unsafe { FakeHal::unshare(paddr, buffer, direction) }
+        }
+    }
+
+    unsafe impl PortIoHal for PortHal {
+        unsafe fn port_read_u8(_port: u16) -> u8 {
+            panic!("port I/O not used in this test")
+        }
+
+        unsafe fn port_read_u16(_port: u16) -> u16 {
+            panic!("port I/O not used in this test")
+        }
+
+        unsafe fn port_read_u32(port: u16) -> u32 {
+            assert_eq!(port, CONFIG_DATA_PORT);
+            let (address, config_space) = *PORT_CONFIG.lock().unwrap();
+            assert_ne!(address & CONFIG_ADDRESS_ENABLE, 0);
+            if address & 0x7fff_ff00 == 1 << 11 {
+                config_space[(address & 0xff) as usize / 4]
+            } else {
+                INVALID_READ
+            }
+        }
+
+        unsafe fn port_write_u32(port: u16, value: u32) {
+            let (address, config_space) = &mut *PORT_CONFIG.lock().unwrap();
+            if port == CONFIG_ADDRESS_PORT {
+                *address = value;
+            } else {
+                assert_eq!(port, CONFIG_DATA_PORT);
+                assert_eq!(*address & 0x7fff_ff00, 1 << 11);
+                config_space[(*address & 0xff) as usize / 4] = value;
+            }
+        }
+
+        unsafe fn port_write_u8(_port: u16, _value: u8) {
+            panic!("port I/O not used in this test")
+        }
+
+        unsafe fn port_write_u16(_port: u16, _value: u16) {
+            panic!("port I/O not used in this test")
+        }
+    }
+
+    #[test]
+    fn port_cam() {
+        PORT_CONFIG.lock().unwrap().1[0] = 0x1041_1af4;
+        // Safe because nothing else uses the emulated configuration ports.
+        let mut root = PciRoot::with_configuration_access(unsafe { PortCam::<PortHal>::new() });
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 1,
+            function: 0,
+        };
+
+        let devices: Vec<_> = root.enumerate_bus(0).collect();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].0, device_function);
+        assert_eq!(devices[0].1.vendor_id, 0x1af4);
+        assert_eq!(devices[0].1.device_id, 0x1041);
+
+        root.set_command(device_function, Command::MEMORY_SPACE);
+        assert_eq!(PORT_CONFIG.lock().unwrap().1[1], 0x2);
+        assert_eq!(
+            root.get_status_command(device_function),
+            (Status::empty(), Command::MEMORY_SPACE)
+        );
+    }
+
+    #[test]
+    fn msix_capability() {
+        // The configuration space of device function 00:00.0, with a single MSI-X capability.
+        let mut config_space = [0u32; 64];
+        config_space[1] = u32::from(Status::CAPABILITIES_LIST.bits()) << 16;
+        config_space[0x34 / 4] = 0x40;
+        config_space[0x40 / 4] = 0x0003_0000 | u32::from(PCI_CAP_ID_MSIX);
+        config_space[0x44 / 4] = 0x2000 | 1;
+        config_space[0x48 / 4] = 0x3000 | 2;
+        // Safe because the config space array is only accessed through the root below, and we
+        // only use device function 00:00.0, which is within it.
+        let mut root = unsafe { PciRoot::new(config_space.as_mut_ptr() as *mut u8, Cam::MmioCam) };
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 0,
+            function: 0,
+        };
+
+        let msix = root.msix_info(device_function).unwrap();
+        assert_eq!(
+            msix,
+            MsixInfo {
+                offset: 0x40,
+                table_size: 4,
+                table_bar: 1,
+                table_offset: 0x2000,
+                pba_bar: 2,
+                pba_offset: 0x3000,
+            }
+        );
+        assert!(!root.msix_enabled(device_function));
+
+        root.set_msix_enabled(device_function, &msix, true);
+        assert!(root.msix_enabled(device_function));
+        root.set_msix_enabled(device_function, &msix, false);
+        assert!(!root.msix_enabled(device_function));
+        assert_eq!(root.msix_info(device_function), Some(msix));
+    }
+
+    /// A device function in a `FakeConfiguration`.
+    struct FakeFunction {
+        /// The index of the bridge which the function is behind, or `None` if it is on the root bus.
+        parent: Option<usize>,
+        device: u8,
+        registers: [u32; 64],
+        /// The size of each BAR, or `None` for the upper half of a 64-bit BAR.
+        bar_sizes: &'static [Option<u32>],
+    }
+
+    impl FakeFunction {
+        fn new(
+            parent: Option<usize>,
+            device: u8,
+            header_type: HeaderType,
+            bars: &[u32],
+            bar_sizes: &'static [Option<u32>],
+        ) -> Self {
+            let mut registers = [0; 64];
+            registers[0] = 0x1041_1af4;
+            registers[3] = match header_type {
+                HeaderType::PciPciBridge => 0x01 << 16,
+                _ => 0,
+            };
+            registers[4..4 + bars.len()].copy_from_slice(bars);
+            Self {
+                parent,
+                device,
+                registers,
+                bar_sizes,
+            }
+        }
+    }
+
+    /// A configuration space with a fixed topology of single-function devices, in which only the
+    /// buses behind PCI-to-PCI bridges depend on how they are programmed.
+    struct FakeConfiguration {
+        functions: Rc<RefCell<Vec<FakeFunction>>>,
+    }
+
+    impl FakeConfiguration {
+        fn find(&self, device_function: DeviceFunction) -> Option<usize> {
+            let functions = self.functions.borrow();
+            functions.iter().position(|function| {
+                let bus = match function.parent {
+                    None => 0,
+                    Some(parent) => match (functions[parent].registers[6] >> 8) as u8 {
+                        // A bridge without a secondary bus number doesn't forward anything.
+                        0 => return false,
+                        secondary => secondary,
+                    },
+                };
+                device_function.bus == bus
+                    && device_function.device == function.device
+                    && device_function.function == 0
+            })
+        }
+    }
+
+    impl ConfigurationAccess for FakeConfiguration {
+        fn read_word(&self, device_function: DeviceFunction, register_offset: u8) -> u32 {
+            match self.find(device_function) {
+                Some(index) => {
+                    self.functions.borrow()[index].registers[usize::from(register_offset / 4)]
+                }
+                None => INVALID_READ,
+            }
+        }
+
+        fn write_word(&mut self, device_function: DeviceFunction, register_offset: u8, data: u32) {
+            let index = self.find(device_function).unwrap();
+            let function = &mut self.functions.borrow_mut()[index];
+            let register = usize::from(register_offset / 4);
+            let bar_size = register
+                .checked_sub(usize::from(BAR0_OFFSET / 4))
+                .and_then(|bar_index| function.bar_sizes.get(bar_index));
+            function.registers[register] = match bar_size {
+                // The address bits below the size of a BAR and its flags are read-only.
+                Some(Some(size)) => {
+                    let read_only = size.wrapping_sub(1)
+                        | if function.registers[register] & 0x1 == 0 {
+                            0xf
+                        } else {
+                            0x3
+                        };
+                    data & !read_only | function.registers[register] & read_only
+                }
+                _ => data,
+            };
+        }
+
+        unsafe fn unsafe_clone(&self) -> Self {
+            Self {
+                functions: self.functions.clone(),
+            }
+        }
+    }
+
+    #[test]
+    fn allocate_resources_behind_bridge() {
+        let functions = Rc::new(RefCell::new(vec![
+            // 00:00.0, with a 32-bit memory BAR.
+            FakeFunction::new(
+                None,
+                0,
+                HeaderType::Standard,
+                &[0x0],
+                &[Some(0x100), Some(0), Some(0), Some(0), Some(0), Some(0)],
+            ),
+            // 00:01.0, a bridge with a 64-bit prefetchable window.
+            FakeFunction::new(None, 1, HeaderType::PciPciBridge, &[], &[Some(0), Some(0)]),
+            // Device 0 behind the bridge, with an I/O BAR, a 32-bit memory BAR and a 64-bit
+            // prefetchable memory BAR.
+            FakeFunction::new(
+                Some(1),
+                0,
+                HeaderType::Standard,
+                &[0x1, 0x0, 0xc, 0x0],
+                &[
+                    Some(0x20),
+                    Some(0x1000),
+                    Some(0x4000),
+                    None,
+                    Some(0),
+                    Some(0),
+                ],
+            ),
+        ]));
+        functions.borrow_mut()[1].registers[BRIDGE_PREFETCHABLE_OFFSET as usize / 4] = 0x0001_0001;
+        let mut root = PciRoot::with_configuration_access(FakeConfiguration {
+            functions: functions.clone(),
+        });
+        let bridge = DeviceFunction {
+            bus: 0,
+            device: 1,
+            function: 0,
+        };
+        let device = DeviceFunction {
+            bus: 1,
+            device: 0,
+            function: 0,
+        };
+
+        assert_eq!(root.enumerate_bus(1).count(), 0);
+        assert_eq!(root.assign_bus_numbers(), 1);
+        assert_eq!(root.bridge_bus_numbers(bridge), (0, 1, 1));
+        assert_eq!(
+            root.enumerate_bus(1)
+                .map(|(device_function, _)| device_function)
+                .collect::<Vec<_>>(),
+            vec![device]
+        );
+
+        let mut allocator = BarAllocator::new(
+            0x1000_0000..0x2000_0000,
+            0x8_0000_0000..0x9_0000_0000,
+            0x1000..0x3000,
+        );
+        assert_eq!(root.allocate_resources(&mut allocator), Ok(()));
+
+        assert_eq!(
+            root.bar_info(device, 0),
+            Ok(BarInfo::IO {
+                address: 0x1000,
+                size: 0x20
+            })
+        );
+        assert_eq!(
+            root.bar_info(device, 1),
+            Ok(BarInfo::Memory {
+                address_type: MemoryBarType::Width32,
+                prefetchable: false,
+                address: 0x1010_0000,
+                size: 0x1000,
+            })
+        );
+        assert_eq!(
+            root.bar_info(device, 2),
+            Ok(BarInfo::Memory {
+                address_type: MemoryBarType::Width64,
+                prefetchable: true,
+                address: 0x8_0000_0000,
+                size: 0x4000,
+            })
+        );
+        assert_eq!(
+            root.get_status_command(device).1,
+            Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER
+        );
+
+        // The bridge's windows cover the device behind it, but not the one beside it.
+        let registers = functions.borrow()[1].registers;
+        assert_eq!(registers[BRIDGE_MEMORY_OFFSET as usize / 4], 0x1010_1010);
+        assert_eq!(
+            registers[BRIDGE_PREFETCHABLE_OFFSET as usize / 4],
+            0x0000_0000
+        );
+        assert_eq!(
+            registers[BRIDGE_PREFETCHABLE_BASE_UPPER_OFFSET as usize / 4],
+            0x8
+        );
+        assert_eq!(
+            registers[BRIDGE_PREFETCHABLE_LIMIT_UPPER_OFFSET as usize / 4],
+            0x8
+        );
+        assert_eq!(registers[BRIDGE_IO_OFFSET as usize / 4], 0x1010);
+        assert_eq!(registers[BRIDGE_IO_UPPER_OFFSET as usize / 4], 0);
+        assert_eq!(
+            root.get_status_command(bridge).1,
+            Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER
+        );
+
+        assert_eq!(
+            root.bar_info(
+                DeviceFunction {
+                    bus: 0,
+                    device: 0,
+                    function: 0
+                },
+                0
+            ),
+            Ok(BarInfo::Memory {
+                address_type: MemoryBarType::Width32,
+                prefetchable: false,
+                address: 0x1000_0000,
+                size: 0x100,
+            })
+        );
+        assert_eq!(allocator.allocate_memory_32(0x100), Some(0x1020_0000));
+        assert_eq!(allocator.allocate_io(0x4000), None);
+    }
+}
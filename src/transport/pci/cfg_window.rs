@@ -0,0 +1,566 @@
+//! PCI transport for VirtIO which accesses the device through the `VIRTIO_PCI_CAP_PCI_CFG` window
+//! in PCI configuration space, rather than by mapping its BARs.
+
+use super::bus::{ConfigurationAccess, DeviceFunction, PciRoot};
+use super::{
+    device_type, locate_shared_memory, map_shared_memory, CommonCfg, IsrStatus, VirtioCapabilities,
+    VirtioCapabilityInfo, VirtioPciError, VirtioSharedMemoryInfo, CAP_BAR_OFFSET,
+    CAP_BAR_OFFSET_OFFSET, CAP_LENGTH_OFFSET, CAP_PCI_CFG_DATA_OFFSET, COMMON_CFG_MIN_SIZE,
+    COMMON_CFG_SIZE, MAX_SHARED_MEMORY_REGIONS, VIRTIO_VENDOR_ID,
+};
+use crate::{
+    device::common::Feature,
+    sync::{SpinLock, SpinLockGuard},
+    transport::{
+        check_config_space_access, DeviceStatus, DeviceType, Notification, SharedMemoryRegion,
+        Transport,
+    },
+    Error, Hal, PhysAddr, Result,
+};
+use core::{mem::offset_of, ptr::NonNull};
+use zerocopy::{AsBytes, FromBytes};
+
+/// PCI transport for VirtIO which accesses the device's structures through the
+/// `VIRTIO_PCI_CAP_PCI_CFG` window in its PCI configuration space.
+///
+/// Each access to the common configuration, notification, ISR status or device-specific
+/// configuration structure programs the window with the BAR, offset and length to access, and then
+/// reads or writes the window's data field, so none of the device's BARs need to be mapped. This is
+/// useful where they can't be, e.g. in early firmware before paging is set up, but much slower than
+/// [`PciTransport`](super::PciTransport), as every register access takes several configuration
+/// space accesses.
+///
+/// As the window is needed for every register access, the transport takes ownership of a
+/// [`PciRoot`]. Accesses to the window are serialised with a spin lock, so the transport may be
+/// shared between CPUs like the other transports. The device-specific configuration space isn't
+/// memory mapped, so [`Transport::config_space`] isn't supported, but
+/// [`Transport::read_config_space`] and [`Transport::write_config_space`] are. Shared memory
+/// regions can only be accessed by mapping them, so [`Transport::shared_memory_region`] requires the
+/// BARs containing them to have been allocated.
+///
+/// Ref: 4.1.4.9 PCI configuration access capability
+#[derive(Debug)]
+pub struct PciCfgTransport<C: ConfigurationAccess> {
+    device_type: DeviceType,
+    /// The bus, device and function identifier for the VirtIO device.
+    device_function: DeviceFunction,
+    /// The PCI root through which the window is accessed.
+    root: SpinLock<PciRoot<C>>,
+    /// The offset of the `VIRTIO_PCI_CAP_PCI_CFG` capability within the PCI configuration space.
+    pci_cfg_offset: u8,
+    /// The location of the common configuration structure.
+    common_cfg: VirtioCapabilityInfo,
+    /// The location of the queue notification region.
+    notify_cfg: VirtioCapabilityInfo,
+    notify_off_multiplier: u32,
+    /// The location of the ISR status register.
+    isr_cfg: VirtioCapabilityInfo,
+    /// The location of the VirtIO device-specific configuration.
+    device_cfg: Option<VirtioCapabilityInfo>,
+    /// The locations of the device's shared memory regions within its BARs.
+    shared_memory: [Option<VirtioSharedMemoryInfo>; MAX_SHARED_MEMORY_REGIONS],
+    /// Whether `VIRTIO_F_NOTIFICATION_DATA` has been negotiated.
+    notification_data: bool,
+    /// Whether `VIRTIO_F_RING_RESET` has been negotiated.
+    ring_reset: bool,
+}
+
+impl<C: ConfigurationAccess> PciCfgTransport<C> {
+    /// Constructs a new PCI VirtIO transport for the given device function, which accesses the
+    /// device through the `VIRTIO_PCI_CAP_PCI_CFG` window using the given PCI root.
+    ///
+    /// The device's BARs needn't be mapped, or even allocated.
+    pub fn new(
+        root: PciRoot<C>,
+        device_function: DeviceFunction,
+    ) -> core::result::Result<Self, VirtioPciError> {
+        let device_vendor = root.config_read_word(device_function, 0);
+        let device_id = (device_vendor >> 16) as u16;
+        let vendor_id = device_vendor as u16;
+        if vendor_id != VIRTIO_VENDOR_ID {
+            return Err(VirtioPciError::InvalidVendorId(vendor_id));
+        }
+        let device_type = device_type(device_id);
+
+        let capabilities = VirtioCapabilities::find(&root, device_function);
+        let pci_cfg_offset = capabilities
+            .pci_cfg_offset
+            .ok_or(VirtioPciError::MissingPciConfig)?;
+        let common_cfg = capabilities
+            .common_cfg
+            .ok_or(VirtioPciError::MissingCommonConfig)?;
+        if (common_cfg.length as usize) < COMMON_CFG_MIN_SIZE {
+            return Err(VirtioPciError::BarOffsetOutOfRange);
+        }
+        let notify_cfg = capabilities
+            .notify_cfg
+            .ok_or(VirtioPciError::MissingNotifyConfig)?;
+        if !capabilities.notify_off_multiplier.is_multiple_of(2) {
+            return Err(VirtioPciError::InvalidNotifyOffMultiplier(
+                capabilities.notify_off_multiplier,
+            ));
+        }
+        let isr_cfg = capabilities
+            .isr_cfg
+            .ok_or(VirtioPciError::MissingIsrConfig)?;
+
+        Ok(Self {
+            device_type,
+            device_function,
+            root: SpinLock::new(root),
+            pci_cfg_offset,
+            common_cfg,
+            notify_cfg,
+            notify_off_multiplier: capabilities.notify_off_multiplier,
+            isr_cfg,
+            device_cfg: capabilities.device_cfg,
+            shared_memory: capabilities.shared_memory,
+            notification_data: false,
+            ring_reset: false,
+        })
+    }
+
+    /// Points the window at `size` bytes at the given offset within the given structure, and
+    /// returns the PCI root locked so that the window's data field can be accessed.
+    ///
+    /// Panics if the access isn't within the structure, which would be a bug in the transport.
+    fn select(
+        &self,
+        structure: &VirtioCapabilityInfo,
+        offset: usize,
+        size: u32,
+    ) -> SpinLockGuard<'_, PciRoot<C>> {
+        assert!(offset + size as usize <= structure.length as usize);
+        let mut root = self.root.lock();
+        root.config_write_word(
+            self.device_function,
+            self.pci_cfg_offset + CAP_BAR_OFFSET,
+            structure.bar.into(),
+        );
+        root.config_write_word(
+            self.device_function,
+            self.pci_cfg_offset + CAP_BAR_OFFSET_OFFSET,
+            structure.offset + offset as u32,
+        );
+        root.config_write_word(
+            self.device_function,
+            self.pci_cfg_offset + CAP_LENGTH_OFFSET,
+            size,
+        );
+        root
+    }
+
+    /// Reads `size` bytes, which must be 1, 2 or 4, at the given offset within the given structure.
+    fn read(&self, structure: &VirtioCapabilityInfo, offset: usize, size: u32) -> u32 {
+        let root = self.select(structure, offset, size);
+        // The device fills in the first `size` bytes of the data field, which is little-endian like
+        // the rest of the configuration space.
+        let data = root.config_read_word(
+            self.device_function,
+            self.pci_cfg_offset + CAP_PCI_CFG_DATA_OFFSET,
+        );
+        match size {
+            4 => data,
+            _ => data & ((1 << (size * 8)) - 1),
+        }
+    }
+
+    /// Writes the low `size` bytes of `value`, where `size` must be 1, 2 or 4, to the given offset
+    /// within the given structure.
+    fn write(&self, structure: &VirtioCapabilityInfo, offset: usize, size: u32, value: u32) {
+        let mut root = self.select(structure, offset, size);
+        root.config_write_word(
+            self.device_function,
+            self.pci_cfg_offset + CAP_PCI_CFG_DATA_OFFSET,
+            value,
+        );
+    }
+
+    fn read_common(&self, offset: usize, size: u32) -> u32 {
+        self.read(&self.common_cfg, offset, size)
+    }
+
+    fn write_common(&self, offset: usize, size: u32, value: u32) {
+        self.write(&self.common_cfg, offset, size, value)
+    }
+
+    /// Reads and clears the ISR status, which de-asserts the device's INTx# interrupt.
+    pub fn ack_isr_status(&mut self) -> IsrStatus {
+        IsrStatus::from_bits_truncate(self.read(&self.isr_cfg, 0, 1) as u8)
+    }
+}
+
+impl<C: ConfigurationAccess> Transport for PciCfgTransport<C> {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.write_common(offset_of!(CommonCfg, device_feature_select), 4, 0);
+        let mut device_features_bits =
+            u64::from(self.read_common(offset_of!(CommonCfg, device_feature), 4));
+        self.write_common(offset_of!(CommonCfg, device_feature_select), 4, 1);
+        device_features_bits |=
+            u64::from(self.read_common(offset_of!(CommonCfg, device_feature), 4)) << 32;
+        device_features_bits
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        self.write_common(offset_of!(CommonCfg, driver_feature_select), 4, 0);
+        self.write_common(
+            offset_of!(CommonCfg, driver_feature),
+            4,
+            driver_features as u32,
+        );
+        self.write_common(offset_of!(CommonCfg, driver_feature_select), 4, 1);
+        self.write_common(
+            offset_of!(CommonCfg, driver_feature),
+            4,
+            (driver_features >> 32) as u32,
+        );
+        self.notification_data = driver_features & Feature::NOTIFICATION_DATA.bits() != 0;
+        self.ring_reset = driver_features & Feature::RING_RESET.bits() != 0;
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        self.write_common(offset_of!(CommonCfg, queue_select), 2, queue.into());
+        self.read_common(offset_of!(CommonCfg, queue_size), 2)
+    }
+
+    fn notify(&mut self, notification: Notification) {
+        self.write_common(
+            offset_of!(CommonCfg, queue_select),
+            2,
+            notification.queue.into(),
+        );
+        let queue_notify_off = self.read_common(offset_of!(CommonCfg, queue_notify_off), 2);
+        let offset = queue_notify_off as usize * self.notify_off_multiplier as usize;
+        if self.notification_data {
+            self.write(&self.notify_cfg, offset, 4, notification.data());
+        } else {
+            self.write(&self.notify_cfg, offset, 2, notification.queue.into());
+        }
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        let status = self.read_common(offset_of!(CommonCfg, device_status), 1);
+        DeviceStatus::from_bits_truncate(status)
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.write_common(offset_of!(CommonCfg, device_status), 1, status.bits());
+    }
+
+    fn read_config_generation(&self) -> u32 {
+        self.read_common(offset_of!(CommonCfg, config_generation), 1)
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        // No-op, the PCI transport doesn't care.
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        false
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        self.write_common(offset_of!(CommonCfg, queue_select), 2, queue.into());
+        self.write_common(offset_of!(CommonCfg, queue_size), 2, size);
+        for (offset, address) in [
+            (offset_of!(CommonCfg, queue_desc), descriptors as u64),
+            (offset_of!(CommonCfg, queue_driver), driver_area as u64),
+            (offset_of!(CommonCfg, queue_device), device_area as u64),
+        ] {
+            self.write_common(offset, 4, address as u32);
+            self.write_common(offset + 4, 4, (address >> 32) as u32);
+        }
+        self.write_common(offset_of!(CommonCfg, queue_enable), 2, 1);
+    }
+
+    fn queue_unset(&mut self, _queue: u16) {
+        // The VirtIO spec doesn't allow queues to be unset once they have been set up for the PCI
+        // transport, so this is a no-op.
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.write_common(offset_of!(CommonCfg, queue_select), 2, queue.into());
+        self.read_common(offset_of!(CommonCfg, queue_enable), 2) == 1
+    }
+
+    fn queue_reset(&mut self, queue: u16) -> Result {
+        if !self.ring_reset || (self.common_cfg.length as usize) < COMMON_CFG_SIZE {
+            // The device doesn't have the `queue_reset` field, so can't support VIRTIO_F_RING_RESET.
+            return Err(Error::Unsupported);
+        }
+        self.write_common(offset_of!(CommonCfg, queue_select), 2, queue.into());
+        self.write_common(offset_of!(CommonCfg, queue_reset), 2, 1);
+        // Wait until the device reports that the reset has completed (see 4.1.4.3.2).
+        while self.read_common(offset_of!(CommonCfg, queue_reset), 2) != 0 {}
+        while self.read_common(offset_of!(CommonCfg, queue_enable), 2) != 0 {}
+        Ok(())
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        !self.ack_isr_status().is_empty()
+    }
+
+    fn config_space<T>(&self) -> Result<NonNull<T>> {
+        Err(Error::Unsupported)
+    }
+
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T> {
+        let device_cfg = self.device_cfg.as_ref().ok_or(Error::ConfigSpaceMissing)?;
+        check_config_space_access::<T>(offset, Some(device_cfg.length as usize))?;
+        let mut value = T::new_zeroed();
+        let bytes = value.as_bytes_mut();
+        let size = access_size(offset, bytes.len());
+        for (i, chunk) in bytes.chunks_mut(size).enumerate() {
+            let data = self.read(device_cfg, offset + i * size, size as u32);
+            chunk.copy_from_slice(&data.to_le_bytes()[..size]);
+        }
+        Ok(value)
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result {
+        let device_cfg = self.device_cfg.as_ref().ok_or(Error::ConfigSpaceMissing)?;
+        check_config_space_access::<T>(offset, Some(device_cfg.length as usize))?;
+        let bytes = value.as_bytes();
+        let size = access_size(offset, bytes.len());
+        for (i, chunk) in bytes.chunks(size).enumerate() {
+            let mut data = [0; 4];
+            data[..size].copy_from_slice(chunk);
+            self.write(
+                device_cfg,
+                offset + i * size,
+                size as u32,
+                u32::from_le_bytes(data),
+            );
+        }
+        Ok(())
+    }
+
+    fn shared_memory_region<H: Hal>(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        // The BARs needn't have been allocated when the transport was constructed, so find where
+        // the regions are now.
+        let locations = locate_shared_memory(
+            &mut self.root.lock(),
+            self.device_function,
+            &self.shared_memory,
+        );
+        map_shared_memory::<H>(&locations, id)
+    }
+}
+
+/// Returns the size of the accesses through the window with which to access a value of the given
+/// length at the given offset, as each access must be 1, 2 or 4 bytes and aligned to its size.
+fn access_size(offset: usize, len: usize) -> usize {
+    match len {
+        1 | 2 | 4 if offset.is_multiple_of(len) => len,
+        _ if offset.is_multiple_of(4) && len.is_multiple_of(4) => 4,
+        _ => 1,
+    }
+}
+
+impl<C: ConfigurationAccess> Drop for PciCfgTransport<C> {
+    fn drop(&mut self) {
+        // Reset the device when the transport is dropped.
+        self.set_status(DeviceStatus::empty());
+        while self.get_status() != DeviceStatus::empty() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hal::fake::FakeHal, transport::pci::PCI_CAP_ID_VNDR};
+    use core::cell::RefCell;
+    use std::rc::Rc;
+
+    const DEVICE_FUNCTION: DeviceFunction = DeviceFunction {
+        bus: 0,
+        device: 0,
+        function: 0,
+    };
+
+    /// A VirtIO device function whose structures are all in one BAR, which can only be accessed
+    /// through the `VIRTIO_PCI_CAP_PCI_CFG` window.
+    #[derive(Default)]
+    struct FakeDevice {
+        registers: Vec<u32>,
+        bar: Vec<u8>,
+    }
+
+    struct FakeConfiguration {
+        device: Rc<RefCell<FakeDevice>>,
+    }
+
+    impl ConfigurationAccess for FakeConfiguration {
+        fn read_word(&self, device_function: DeviceFunction, register_offset: u8) -> u32 {
+            assert_eq!(device_function, DEVICE_FUNCTION);
+            let device = self.device.borrow();
+            let mut data = device.registers[usize::from(register_offset / 4)];
+            if register_offset == PCI_CFG_CAP + CAP_PCI_CFG_DATA_OFFSET {
+                let offset = device.registers[usize::from(PCI_CFG_CAP + CAP_BAR_OFFSET_OFFSET) / 4];
+                let length = device.registers[usize::from(PCI_CFG_CAP + CAP_LENGTH_OFFSET) / 4];
+                let mut bytes = [0; 4];
+                bytes[..length as usize].copy_from_slice(
+                    &device.bar[offset as usize..offset as usize + length as usize],
+                );
+                data = u32::from_le_bytes(bytes);
+            }
+            data
+        }
+
+        fn write_word(&mut self, device_function: DeviceFunction, register_offset: u8, data: u32) {
+            assert_eq!(device_function, DEVICE_FUNCTION);
+            let mut device = self.device.borrow_mut();
+            device.registers[usize::from(register_offset / 4)] = if register_offset == BAR1_OFFSET {
+                data & !(BAR1_SIZE - 1)
+            } else {
+                data
+            };
+            if register_offset == PCI_CFG_CAP + CAP_PCI_CFG_DATA_OFFSET {
+                let offset = device.registers[usize::from(PCI_CFG_CAP + CAP_BAR_OFFSET_OFFSET) / 4];
+                let length = device.registers[usize::from(PCI_CFG_CAP + CAP_LENGTH_OFFSET) / 4];
+                assert_eq!(offset % length, 0);
+                device.bar[offset as usize..offset as usize + length as usize]
+                    .copy_from_slice(&data.to_le_bytes()[..length as usize]);
+            }
+        }
+
+        unsafe fn unsafe_clone(&self) -> Self {
+            Self {
+                device: self.device.clone(),
+            }
+        }
+    }
+
+    /// The offset of the `VIRTIO_PCI_CAP_PCI_CFG` capability in configuration space.
+    const PCI_CFG_CAP: u8 = 0x90;
+    const NOTIFY_OFFSET: u32 = 0x40;
+    const ISR_OFFSET: u32 = 0x50;
+    const DEVICE_CFG_OFFSET: u32 = 0x60;
+    /// BAR 1 contains shared memory region 3, at offset 0x1000.
+    const BAR1_OFFSET: u8 = 0x14;
+    const BAR1_SIZE: u32 = 0x4000;
+
+    fn fake_device() -> Rc<RefCell<FakeDevice>> {
+        let mut registers = vec![0; 64];
+        registers[0] = 0x1042_1af4;
+        registers[1] = 1 << 20;
+        registers[0x34 / 4] = 0x40;
+        // Vendor-specific capabilities, with their cap_len, cfg_type, BAR, offset and length.
+        let capabilities = [
+            (0x40, 16, 1, 0, COMMON_CFG_SIZE as u32),
+            (0x50, 20, 2, NOTIFY_OFFSET, 0x10),
+            (0x70, 16, 3, ISR_OFFSET, 1),
+            (0x80, 16, 4, DEVICE_CFG_OFFSET, 0x10),
+            (PCI_CFG_CAP, 20, 5, 0, 0),
+            (0xa8, 24, 8, 0x1000, 0x2000),
+        ];
+        for (i, &(offset, cap_len, cfg_type, bar_offset, length)) in capabilities.iter().enumerate()
+        {
+            let next = capabilities.get(i + 1).map_or(0, |capability| capability.0);
+            let index = usize::from(offset / 4);
+            registers[index] =
+                u32::from(PCI_CAP_ID_VNDR) | u32::from(next) << 8 | cap_len << 16 | cfg_type << 24;
+            registers[index + 2] = bar_offset;
+            registers[index + 3] = length;
+        }
+        // notify_off_multiplier
+        registers[0x60 / 4] = 4;
+        // The shared memory region's BAR and ID.
+        registers[0xac / 4] = 1 | 3 << 8;
+        Rc::new(RefCell::new(FakeDevice {
+            registers,
+            bar: vec![0; 0x70],
+        }))
+    }
+
+    #[test]
+    fn registers_through_window() {
+        let device = fake_device();
+        let root = PciRoot::with_configuration_access(FakeConfiguration {
+            device: device.clone(),
+        });
+        let mut transport = PciCfgTransport::new(root, DEVICE_FUNCTION).unwrap();
+        assert_eq!(transport.device_type(), DeviceType::Block);
+
+        transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+        assert_eq!(device.borrow().bar[offset_of!(CommonCfg, device_status)], 3);
+        assert_eq!(
+            transport.get_status(),
+            DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER
+        );
+
+        transport.queue_set(1, 16, 0x1234_5678_9abc_def0, 0x2000, 0x3000);
+        {
+            let bar = &device.borrow().bar;
+            let queue_desc = offset_of!(CommonCfg, queue_desc);
+            assert_eq!(
+                bar[queue_desc..queue_desc + 8],
+                0x1234_5678_9abc_def0u64.to_le_bytes()
+            );
+            assert_eq!(bar[offset_of!(CommonCfg, queue_select)], 1);
+            assert_eq!(bar[offset_of!(CommonCfg, queue_enable)], 1);
+        }
+
+        // Queue 1 notifies at offset 2 * notify_off_multiplier within the notify region.
+        device.borrow_mut().bar[offset_of!(CommonCfg, queue_notify_off)] = 2;
+        transport.notify(Notification {
+            queue: 1,
+            ..Default::default()
+        });
+        assert_eq!(device.borrow().bar[NOTIFY_OFFSET as usize + 8], 1);
+
+        device.borrow_mut().bar[ISR_OFFSET as usize] = 0x2;
+        assert_eq!(
+            transport.ack_isr_status(),
+            IsrStatus::DEVICE_CONFIGURATION_INTERRUPT
+        );
+
+        device.borrow_mut().bar[DEVICE_CFG_OFFSET as usize..DEVICE_CFG_OFFSET as usize + 8]
+            .copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(transport.read_config_space::<u16>(2), Ok(0x0403));
+        assert_eq!(transport.read_config_space::<[u8; 3]>(1), Ok([2, 3, 4]));
+        assert_eq!(
+            transport.read_config_space::<[u32; 2]>(0),
+            Ok([0x0403_0201, 0x0807_0605])
+        );
+        assert_eq!(transport.write_config_space::<u32>(4, 0xaabb_ccdd), Ok(()));
+        assert_eq!(
+            device.borrow().bar[DEVICE_CFG_OFFSET as usize + 4..DEVICE_CFG_OFFSET as usize + 8],
+            [0xdd, 0xcc, 0xbb, 0xaa]
+        );
+        assert_eq!(
+            transport.read_config_space::<u32>(16),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+    }
+
+    #[test]
+    fn shared_memory_region() {
+        let device = fake_device();
+        let root = PciRoot::with_configuration_access(FakeConfiguration {
+            device: device.clone(),
+        });
+        let mut transport = PciCfgTransport::new(root, DEVICE_FUNCTION).unwrap();
+
+        // BAR 1 hasn't been allocated yet.
+        assert_eq!(transport.shared_memory_region::<FakeHal>(3), None);
+
+        device.borrow_mut().registers[usize::from(BAR1_OFFSET / 4)] = 0x8000_0000;
+        let region = transport.shared_memory_region::<FakeHal>(3).unwrap();
+        assert_eq!(region.id, 3);
+        assert_eq!(region.paddr, 0x8000_1000);
+        assert_eq!(region.vaddr.len(), 0x2000);
+        assert_eq!(transport.shared_memory_region::<FakeHal>(1), None);
+    }
+}
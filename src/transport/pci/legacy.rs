@@ -0,0 +1,418 @@
+//! Legacy PCI transport for VirtIO, for transitional devices driven through their I/O BAR.
+
+use super::bus::{BarInfo, ConfigurationAccess, DeviceFunction, PciRoot};
+use super::{device_type, VirtioPciError, VIRTIO_VENDOR_ID};
+use crate::{
+    align_up,
+    queue::Descriptor,
+    transport::{check_config_space_access, DeviceStatus, DeviceType, Notification, Transport},
+    Error, PhysAddr, PortIoHal, Result, PAGE_SIZE,
+};
+use core::{
+    convert::{TryFrom, TryInto},
+    marker::PhantomData,
+    mem::size_of,
+    ptr::NonNull,
+};
+use zerocopy::{AsBytes, FromBytes};
+
+/// The range of PCI device IDs used by transitional devices, which have a legacy interface.
+const TRANSITIONAL_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103f;
+
+/// The BAR containing the legacy registers.
+const LEGACY_BAR: u8 = 0;
+
+// Offsets of the legacy registers within the I/O BAR, see 4.1.4.10 "Legacy Interfaces: A Note on PCI
+// Device Layout".
+const DEVICE_FEATURES: u16 = 0x00;
+const DRIVER_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0c;
+const QUEUE_SELECT: u16 = 0x0e;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+const ISR_STATUS: u16 = 0x13;
+/// The offset of the device-specific configuration if MSI-X is disabled.
+const CONFIG_SPACE: u16 = 0x14;
+/// The offset of the device-specific configuration if MSI-X is enabled, after the
+/// `config_msix_vector` and `queue_msix_vector` registers.
+const CONFIG_SPACE_MSIX: u16 = 0x18;
+
+/// Legacy PCI transport for VirtIO.
+///
+/// This drives a transitional device through the legacy register layout in its I/O BAR 0, which
+/// is accessed with the port I/O methods of a [`PortIoHal`], such as
+/// [`PortIoHal::port_read_u8`]. The device must use the legacy virtqueue layout, which drivers pick
+/// up from [`Transport::requires_legacy_layout`], and the legacy interface only supports the first
+/// 32 feature bits.
+///
+/// The size of each queue is fixed by the device, so queues created with
+/// [`VirtQueue::new`](crate::queue::VirtQueue::new) or
+/// [`VirtQueue::with_size`](crate::queue::VirtQueue::with_size) take that size, which needs the
+/// `alloc` feature if it is bigger than the queue's `SIZE`. The device-specific
+/// configuration space isn't memory mapped, so [`Transport::config_space`] isn't supported, but
+/// [`Transport::read_config_space`] and [`Transport::write_config_space`] are.
+///
+/// Ref: 4.1.4.10 Legacy Interfaces: A Note on PCI Device Layout
+#[derive(Debug)]
+pub struct LegacyPciTransport<H: PortIoHal> {
+    device_type: DeviceType,
+    /// The bus, device and function identifier for the VirtIO device.
+    device_function: DeviceFunction,
+    /// The first port of the I/O BAR.
+    port: u16,
+    /// The offset of the device-specific configuration within the I/O BAR, which depends on whether
+    /// MSI-X is enabled.
+    config_offset: u16,
+    /// The length in bytes of the device-specific configuration.
+    config_len: usize,
+    _hal: PhantomData<H>,
+}
+
+impl<H: PortIoHal> LegacyPciTransport<H> {
+    /// Constructs a new legacy PCI VirtIO transport for the given device function on the given PCI
+    /// root controller.
+    ///
+    /// The device must be a transitional device, and its BAR 0 must already have been allocated an
+    /// I/O address with I/O space decoding enabled.
+    pub fn new(
+        root: &mut PciRoot<impl ConfigurationAccess>,
+        device_function: DeviceFunction,
+    ) -> core::result::Result<Self, VirtioPciError> {
+        let device_vendor = root.config_read_word(device_function, 0);
+        let device_id = (device_vendor >> 16) as u16;
+        let vendor_id = device_vendor as u16;
+        if vendor_id != VIRTIO_VENDOR_ID {
+            return Err(VirtioPciError::InvalidVendorId(vendor_id));
+        }
+        if !TRANSITIONAL_DEVICE_IDS.contains(&device_id) {
+            return Err(VirtioPciError::NotTransitional(device_id));
+        }
+        let device_type = device_type(device_id);
+
+        let (address, size) = match root.bar_info(device_function, LEGACY_BAR)? {
+            BarInfo::IO { address, size } => (address, size),
+            BarInfo::Memory { .. } => return Err(VirtioPciError::UnexpectedMemoryBar),
+        };
+        if address == 0 {
+            return Err(VirtioPciError::BarNotAllocated(LEGACY_BAR));
+        }
+        let port = u16::try_from(address).map_err(|_| VirtioPciError::BarOffsetOutOfRange)?;
+        if u64::from(address) + u64::from(size) > 0x1_0000 {
+            return Err(VirtioPciError::BarOffsetOutOfRange);
+        }
+
+        let config_offset = if root.msix_enabled(device_function) {
+            CONFIG_SPACE_MSIX
+        } else {
+            CONFIG_SPACE
+        };
+        let config_len = (size as usize).saturating_sub(config_offset.into());
+
+        Ok(Self {
+            device_type,
+            device_function,
+            port,
+            config_offset,
+            config_len,
+            _hal: PhantomData,
+        })
+    }
+
+    /// Checks that a value of type `T` at the given offset lies within the device-specific
+    /// configuration, and returns the offset of its first byte within the I/O BAR.
+    fn config_port<T>(&self, offset: usize) -> Result<u16> {
+        check_config_space_access::<T>(offset, Some(self.config_len))?;
+        let offset = u16::try_from(offset).map_err(|_| Error::ConfigSpaceTooSmall)?;
+        // This can't overflow, as the access lies within the configuration, and we checked when
+        // constructing the transport that the whole BAR is within the I/O space.
+        Ok(self.config_offset + offset)
+    }
+
+    fn read_u8(&self, offset: u16) -> u8 {
+        // Safe because the offset is within the device's I/O BAR.
+        unsafe { H::port_read_u8(self.port + offset) }
+    }
+
+    fn read_u16(&self, offset: u16) -> u16 {
+        // Safe because the offset is within the device's I/O BAR.
+        unsafe { H::port_read_u16(self.port + offset) }
+    }
+
+    fn read_u32(&self, offset: u16) -> u32 {
+        // Safe because the offset is within the device's I/O BAR.
+        unsafe { H::port_read_u32(self.port + offset) }
+    }
+
+    fn write_u8(&mut self, offset: u16, value: u8) {
+        // Safe because the offset is within the device's I/O BAR.
+        unsafe { H::port_write_u8(self.port + offset, value) }
+    }
+
+    fn write_u16(&mut self, offset: u16, value: u16) {
+        // Safe because the offset is within the device's I/O BAR.
+        unsafe { H::port_write_u16(self.port + offset, value) }
+    }
+
+    fn write_u32(&mut self, offset: u16, value: u32) {
+        // Safe because the offset is within the device's I/O BAR.
+        unsafe { H::port_write_u32(self.port + offset, value) }
+    }
+}
+
+impl<H: PortIoHal> Transport for LegacyPciTransport<H> {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.read_u32(DEVICE_FEATURES).into()
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        // The legacy interface only has the first 32 feature bits.
+        self.write_u32(DRIVER_FEATURES, driver_features as u32);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        self.write_u16(QUEUE_SELECT, queue);
+        self.read_u16(QUEUE_SIZE).into()
+    }
+
+    fn notify(&mut self, notification: Notification) {
+        // The legacy interface doesn't support `VIRTIO_F_NOTIFICATION_DATA`.
+        self.write_u16(QUEUE_NOTIFY, notification.queue);
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(self.read_u8(DEVICE_STATUS).into())
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.write_u8(DEVICE_STATUS, status.bits() as u8);
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        // No-op, the legacy PCI interface always uses 4 KiB pages.
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        true
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        assert_eq!(
+            driver_area - descriptors,
+            size_of::<Descriptor>() * size as usize
+        );
+        assert_eq!(
+            device_area - descriptors,
+            align_up(
+                size_of::<Descriptor>() * size as usize + size_of::<u16>() * (size as usize + 3)
+            )
+        );
+        let pfn = (descriptors / PAGE_SIZE) as u32;
+        assert_eq!(pfn as usize * PAGE_SIZE, descriptors);
+        self.write_u16(QUEUE_SELECT, queue);
+        self.write_u32(QUEUE_ADDRESS, pfn);
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        self.write_u16(QUEUE_SELECT, queue);
+        self.write_u32(QUEUE_ADDRESS, 0);
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.write_u16(QUEUE_SELECT, queue);
+        self.read_u32(QUEUE_ADDRESS) != 0
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        // Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt.
+        let isr_status = self.read_u8(ISR_STATUS);
+        isr_status & 0x3 != 0
+    }
+
+    fn config_space<T>(&self) -> Result<NonNull<T>> {
+        // The config space is in I/O space, so there is no pointer to it.
+        Err(Error::Unsupported)
+    }
+
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T> {
+        let offset = self.config_port::<T>(offset)?;
+        let mut value = T::new_zeroed();
+        let bytes = value.as_bytes_mut();
+        match bytes.len() {
+            4 if offset.is_multiple_of(4) => {
+                bytes.copy_from_slice(&self.read_u32(offset).to_ne_bytes())
+            }
+            2 if offset.is_multiple_of(2) => {
+                bytes.copy_from_slice(&self.read_u16(offset).to_ne_bytes())
+            }
+            _ => {
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = self.read_u8(offset + i as u16);
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result {
+        let offset = self.config_port::<T>(offset)?;
+        let bytes = value.as_bytes();
+        match bytes.len() {
+            4 if offset.is_multiple_of(4) => {
+                self.write_u32(offset, u32::from_ne_bytes(bytes.try_into().unwrap()))
+            }
+            2 if offset.is_multiple_of(2) => {
+                self.write_u16(offset, u16::from_ne_bytes(bytes.try_into().unwrap()))
+            }
+            _ => {
+                for (i, byte) in bytes.iter().enumerate() {
+                    self.write_u8(offset + i as u16, *byte);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn requires_max_queue_size(&self) -> bool {
+        // The queue size register is read-only.
+        true
+    }
+}
+
+// SAFETY: Port I/O can be done from any thread or CPU core.
+unsafe impl<H: PortIoHal> Send for LegacyPciTransport<H> {}
+
+// SAFETY: `&LegacyPciTransport` only allows port reads, which are fine to happen concurrently on
+// different CPU cores.
+unsafe impl<H: PortIoHal> Sync for LegacyPciTransport<H> {}
+
+impl<H: PortIoHal> Drop for LegacyPciTransport<H> {
+    fn drop(&mut self) {
+        // Reset the device when the transport is dropped.
+        self.set_status(DeviceStatus::empty());
+        while self.get_status() != DeviceStatus::empty() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::FakeHal;
+    use crate::{BufferDirection, Hal};
+    use std::sync::Mutex;
+
+    const BASE_PORT: u16 = 0xc000;
+
+    /// The contents of the fake device's I/O BAR.
+    static PORTS: Mutex<[u8; 0x20]> = Mutex::new([0; 0x20]);
+
+    /// A HAL which implements port I/O with `PORTS`.
+    struct PortHal;
+
+    unsafe impl Hal for PortHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            FakeHal::dma_alloc(pages, direction)
+        }
+
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            unsafe { FakeHal::share(buffer, direction) }
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            unsafe { FakeHal::unshare(paddr, buffer, direction) }
+        }
+    }
+
+    unsafe impl PortIoHal for PortHal {
+        unsafe fn port_read_u8(port: u16) -> u8 {
+            PORTS.lock().unwrap()[usize::from(port - BASE_PORT)]
+        }
+
+        unsafe fn port_read_u16(port: u16) -> u16 {
+            let index = usize::from(port - BASE_PORT);
+            u16::from_ne_bytes(PORTS.lock().unwrap()[index..index + 2].try_into().unwrap())
+        }
+
+        unsafe fn port_read_u32(port: u16) -> u32 {
+            let index = usize::from(port - BASE_PORT);
+            u32::from_ne_bytes(PORTS.lock().unwrap()[index..index + 4].try_into().unwrap())
+        }
+
+        unsafe fn port_write_u8(port: u16, value: u8) {
+            PORTS.lock().unwrap()[usize::from(port - BASE_PORT)] = value;
+        }
+
+        unsafe fn port_write_u16(port: u16, value: u16) {
+            let index = usize::from(port - BASE_PORT);
+            PORTS.lock().unwrap()[index..index + 2].copy_from_slice(&value.to_ne_bytes());
+        }
+
+        unsafe fn port_write_u32(port: u16, value: u32) {
+            let index = usize::from(port - BASE_PORT);
+            PORTS.lock().unwrap()[index..index + 4].copy_from_slice(&value.to_ne_bytes());
+        }
+    }
+
+    #[test]
+    fn registers_and_config_space() {
+        let mut transport = LegacyPciTransport::<PortHal> {
+            device_type: DeviceType::Block,
+            device_function: DeviceFunction {
+                bus: 0,
+                device: 1,
+                function: 0,
+            },
+            port: BASE_PORT,
+            config_offset: CONFIG_SPACE,
+            config_len: 0x20 - usize::from(CONFIG_SPACE),
+            _hal: PhantomData,
+        };
+        PORTS.lock().unwrap()[..4].copy_from_slice(&0x1234_5678u32.to_ne_bytes());
+        PORTS.lock().unwrap()[0x14..0x1c].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        assert_eq!(transport.read_device_features(), 0x1234_5678);
+        transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+        assert_eq!(PORTS.lock().unwrap()[0x12], 3);
+
+        // Config space accesses are relative to the end of the legacy registers.
+        assert_eq!(transport.read_config_space::<u8>(1), Ok(2));
+        assert_eq!(
+            transport.read_config_space::<u32>(4),
+            Ok(u32::from_ne_bytes([5, 6, 7, 8]))
+        );
+        assert_eq!(
+            transport.read_config_space::<[u8; 6]>(1),
+            Ok([2, 3, 4, 5, 6, 7])
+        );
+        assert_eq!(transport.write_config_space::<u16>(2, 0xabcd), Ok(()));
+        assert_eq!(PORTS.lock().unwrap()[0x16..0x18], 0xabcdu16.to_ne_bytes());
+        assert_eq!(
+            transport.read_config_space::<u32>(12),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+        assert_eq!(
+            transport.read_config_space::<u32>(usize::MAX & !3),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+        assert_eq!(transport.config_space::<u32>(), Err(Error::Unsupported));
+    }
+}
@@ -0,0 +1,212 @@
+//! Access to the MSI-X table and pending bit array of a PCI device function.
+
+use super::bus::{ConfigurationAccess, DeviceFunction, MsixInfo, PciRoot};
+use super::{get_bar_region_slice, VirtioCapabilityInfo, VirtioPciError};
+use crate::{
+    hal::Hal,
+    volatile::{ReadOnly, Volatile, VolatileReadable, VolatileWritable},
+    Error, Result,
+};
+use core::{
+    mem::size_of,
+    ptr::{addr_of, addr_of_mut, NonNull},
+};
+
+/// The bit of an MSI-X table entry's vector control register which masks the vector.
+const VECTOR_CONTROL_MASK_BIT: u32 = 1 << 0;
+
+/// An entry of the MSI-X table, see 6.8.2 "MSI-X Capability and Table Structure" in the PCI Local
+/// Bus Specification.
+#[repr(C)]
+struct MsixTableEntry {
+    message_address: Volatile<u32>,
+    message_upper_address: Volatile<u32>,
+    message_data: Volatile<u32>,
+    vector_control: Volatile<u32>,
+}
+
+/// The MSI-X table and pending bit array of a PCI device function.
+///
+/// Each entry of the table is a vector, which the device signals by writing the entry's message
+/// data to the entry's message address. For a VirtIO device, vectors are assigned to configuration
+/// changes and to each queue with
+/// [`PciTransport::set_config_msix_vector`](super::PciTransport::set_config_msix_vector) and
+/// [`PciTransport::set_queue_msix_vector`](super::PciTransport::set_queue_msix_vector). What
+/// address and data to use depends on the interrupt controller, e.g. for x86 they select the
+/// destination APIC and the interrupt vector on it, so different vectors may be routed to
+/// different CPUs.
+#[derive(Debug)]
+pub struct MsixTable {
+    table: NonNull<[MsixTableEntry]>,
+    pending_bits: NonNull<[ReadOnly<u32>]>,
+}
+
+impl MsixTable {
+    /// Maps the MSI-X table and pending bit array described by the given MSI-X capability of the
+    /// given device function.
+    ///
+    /// The BARs containing them must already have been allocated.
+    pub fn new<H: Hal>(
+        root: &mut PciRoot<impl ConfigurationAccess>,
+        device_function: DeviceFunction,
+        msix: &MsixInfo,
+    ) -> core::result::Result<Self, VirtioPciError> {
+        let table_size = usize::from(msix.table_size);
+        let table = get_bar_region_slice::<H, _>(
+            root,
+            device_function,
+            &VirtioCapabilityInfo {
+                bar: msix.table_bar,
+                offset: msix.table_offset,
+                length: (table_size * size_of::<MsixTableEntry>()) as u32,
+            },
+        )?;
+        // The pending bit array has one bit per vector, padded to a multiple of 64 bits.
+        let pending_bits = get_bar_region_slice::<H, _>(
+            root,
+            device_function,
+            &VirtioCapabilityInfo {
+                bar: msix.pba_bar,
+                offset: msix.pba_offset,
+                length: (table_size.div_ceil(64) * size_of::<u64>()) as u32,
+            },
+        )?;
+        Ok(Self {
+            table,
+            pending_bits,
+        })
+    }
+
+    /// Returns the number of vectors in the table.
+    pub fn table_size(&self) -> u16 {
+        self.table.len() as u16
+    }
+
+    /// Sets the message address and data which the device writes to signal the given vector, and
+    /// unmasks the vector.
+    ///
+    /// The vector is masked while its entry is being changed, as the PCI specification requires.
+    /// Returns [`Error::InvalidParam`] if the vector is not in the table.
+    pub fn set_entry(&mut self, vector: u16, address: u64, data: u32) -> Result {
+        let entry = self.entry(vector)?;
+        // Safe because `entry` checked that the vector is within the table, which is a valid MMIO
+        // region.
+        unsafe {
+            let vector_control = addr_of!((*entry).vector_control).vread();
+            addr_of_mut!((*entry).vector_control).vwrite(vector_control | VECTOR_CONTROL_MASK_BIT);
+            addr_of_mut!((*entry).message_address).vwrite(address as u32);
+            addr_of_mut!((*entry).message_upper_address).vwrite((address >> 32) as u32);
+            addr_of_mut!((*entry).message_data).vwrite(data);
+            addr_of_mut!((*entry).vector_control).vwrite(vector_control & !VECTOR_CONTROL_MASK_BIT);
+        }
+        Ok(())
+    }
+
+    /// Masks or unmasks the given vector.
+    ///
+    /// While a vector is masked the device doesn't signal it, but sets its pending bit instead, and
+    /// signals it once it is unmasked. Returns [`Error::InvalidParam`] if the vector is not in the
+    /// table.
+    pub fn set_masked(&mut self, vector: u16, masked: bool) -> Result {
+        let entry = self.entry(vector)?;
+        // Safe because `entry` checked that the vector is within the table, which is a valid MMIO
+        // region.
+        unsafe {
+            let vector_control = addr_of!((*entry).vector_control).vread();
+            let vector_control = if masked {
+                vector_control | VECTOR_CONTROL_MASK_BIT
+            } else {
+                vector_control & !VECTOR_CONTROL_MASK_BIT
+            };
+            addr_of_mut!((*entry).vector_control).vwrite(vector_control);
+        }
+        Ok(())
+    }
+
+    /// Returns whether the given vector is pending, i.e. the device wanted to signal it while it was
+    /// masked.
+    ///
+    /// Returns [`Error::InvalidParam`] if the vector is not in the table.
+    pub fn is_pending(&self, vector: u16) -> Result<bool> {
+        self.entry(vector)?;
+        let index = usize::from(vector) / 32;
+        // Safe because the pending bit array has a bit for every vector in the table, which we
+        // checked above, and is a valid MMIO region.
+        let pending_bits = unsafe { addr_of!((*self.pending_bits.as_ptr())[index]).vread() };
+        Ok(pending_bits & (1 << (vector % 32)) != 0)
+    }
+
+    /// Returns a pointer to the table entry for the given vector, or [`Error::InvalidParam`] if it
+    /// is not in the table.
+    fn entry(&self, vector: u16) -> Result<*mut MsixTableEntry> {
+        let index = usize::from(vector);
+        if index >= self.table.len() {
+            return Err(Error::InvalidParam);
+        }
+        // Safe because we just checked that the index is within the table.
+        Ok(unsafe { addr_of_mut!((*self.table.as_ptr())[index]) })
+    }
+}
+
+// SAFETY: MMIO can be done from any thread or CPU core.
+unsafe impl Send for MsixTable {}
+
+// SAFETY: `&MsixTable` only allows MMIO reads, which are fine to happen concurrently on different
+// CPU cores.
+unsafe impl Sync for MsixTable {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nonnull_slice_from_raw_parts;
+
+    #[test]
+    fn set_entry_and_mask() {
+        let mut table = [
+            MsixTableEntry {
+                message_address: Volatile::new(0),
+                message_upper_address: Volatile::new(0),
+                message_data: Volatile::new(0),
+                vector_control: Volatile::new(VECTOR_CONTROL_MASK_BIT),
+            },
+            MsixTableEntry {
+                message_address: Volatile::new(0),
+                message_upper_address: Volatile::new(0),
+                message_data: Volatile::new(0),
+                vector_control: Volatile::new(VECTOR_CONTROL_MASK_BIT),
+            },
+        ];
+        let mut pending_bits = [ReadOnly::new(0b10u32), ReadOnly::new(0)];
+        let mut msix_table = MsixTable {
+            table: nonnull_slice_from_raw_parts(NonNull::from(&mut table[0]), table.len()),
+            pending_bits: nonnull_slice_from_raw_parts(
+                NonNull::from(&mut pending_bits[0]),
+                pending_bits.len(),
+            ),
+        };
+        assert_eq!(msix_table.table_size(), 2);
+
+        assert_eq!(msix_table.set_entry(1, 0x1_fee0_0000, 42), Ok(()));
+        assert_eq!(
+            msix_table.set_entry(2, 0xfee0_0000, 42),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(msix_table.is_pending(0), Ok(false));
+        assert_eq!(msix_table.is_pending(1), Ok(true));
+        assert_eq!(msix_table.is_pending(2), Err(Error::InvalidParam));
+        assert_eq!(msix_table.set_masked(0, false), Ok(()));
+        assert_eq!(msix_table.set_masked(1, true), Ok(()));
+
+        // Safe because the table is only accessed through `msix_table` above.
+        unsafe {
+            assert_eq!(addr_of!(table[0].vector_control).vread(), 0);
+            assert_eq!(addr_of!(table[1].message_address).vread(), 0xfee0_0000);
+            assert_eq!(addr_of!(table[1].message_upper_address).vread(), 1);
+            assert_eq!(addr_of!(table[1].message_data).vread(), 42);
+            assert_eq!(
+                addr_of!(table[1].vector_control).vread(),
+                VECTOR_CONTROL_MASK_BIT
+            );
+        }
+    }
+}